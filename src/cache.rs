@@ -0,0 +1,933 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::Metadata,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use configparser::ini::Ini;
+use eyre::{bail, eyre, Context, Result};
+
+use serde::Deserialize;
+
+use crate::{
+    hashindex::ChunkIndex,
+    integrity::{hashindex_digests, IntegrityData, IntegrityHasher},
+    item_stream,
+    key::{absolute_path, hex_decode, hex_lower, save_security_info, Key},
+    lock::{Lock, LockMode},
+    msgpack::{Bytes, PythonValue},
+    transaction::Transaction,
+    unpack_data, Manifest, Repository,
+};
+
+/// Borg writes this into every cache directory
+const CACHE_README: &str = "This is a Borg Backup cache.\nSee https://borgbackup.readthedocs.io/\n";
+
+/// How many backups a file may be missing from before it is dropped from
+/// the files cache, unless `BORG_FILES_CACHE_TTL` says otherwise
+const DEFAULT_FILES_CACHE_TTL: u32 = 20;
+
+/// The directory borg keeps its caches in
+pub fn cache_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("BORG_CACHE_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+
+    if let Some(dir) = std::env::var_os("XDG_CACHE_HOME") {
+        return Ok(PathBuf::from(dir).join("borg"));
+    }
+
+    let home = std::env::var_os("HOME").ok_or_else(|| eyre!("HOME is not set"))?;
+
+    Ok(PathBuf::from(home).join(".cache/borg"))
+}
+
+/// Name of the files cache, which `BORG_FILES_CACHE_SUFFIX` can change so
+/// that different backups of the same repository keep separate caches
+pub fn files_cache_name() -> String {
+    match std::env::var("BORG_FILES_CACHE_SUFFIX") {
+        Ok(suffix) if !suffix.is_empty() => format!("files.{suffix}"),
+        _ => "files".to_string(),
+    }
+}
+
+/// A repository's local cache, laid out the way borg keeps it so both can
+/// use the same one. Changes are made in a transaction that is rolled back
+/// if it is not committed, using borg's `txn.active` protocol.
+#[derive(Debug)]
+pub struct Cache {
+    path: PathBuf,
+    pub config: CacheConfig,
+
+    /// Every chunk referenced by an archive, valid for the manifest recorded
+    /// in the config
+    pub chunks: ChunkIndex,
+
+    pub files: Option<FilesCache>,
+
+    /// Held for as long as the cache is open
+    _lock: Lock,
+}
+
+impl Cache {
+    /// Open the cache of `repository`, creating it if it does not exist, and
+    /// begin a transaction. The files cache is only loaded if `mode` uses it.
+    pub fn open(
+        repository: &Repository,
+        mode: FilesCacheMode,
+        lock_wait: Duration,
+    ) -> Result<Self> {
+        let path = cache_dir()?.join(&repository.id);
+
+        if !path.exists() {
+            create(&path, &repository.id)?;
+        }
+
+        let lock = Lock::acquire(&path.join("lock"), LockMode::Exclusive, lock_wait)?;
+
+        rollback(&path)?;
+
+        let mut config = CacheConfig::parse(
+            &std::fs::read_to_string(path.join("config")).wrap_err("read cache config")?,
+        )
+        .wrap_err_with(|| format!("invalid cache config in {}", path.display()))?;
+
+        if config.repository != repository.id {
+            bail!(
+                "cache in {} belongs to repository {}",
+                path.display(),
+                config.repository
+            );
+        }
+
+        let chunks = match read_chunks(&path, &config) {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                // the chunks cache can always be rebuilt from the archives
+                eprintln!("warning: {e:#}, rebuilding it");
+                config.manifest.clear();
+                ChunkIndex::new()
+            }
+        };
+
+        let files = if mode.disabled {
+            None
+        } else {
+            Some(FilesCache::load(
+                &path.join(files_cache_name()),
+                config.integrity(&files_cache_name()),
+                mode,
+            ))
+        };
+
+        begin(&path)?;
+
+        Ok(Self {
+            path,
+            config,
+            chunks,
+            files,
+            _lock: lock,
+        })
+    }
+
+    /// Rebuild the chunks cache if it is not in sync with the manifest, which
+    /// happens whenever another client changed the repository. Each archive
+    /// contributes an index of the chunks it references, kept in
+    /// `chunks.archive.d` so later syncs only read archives that are new.
+    pub fn sync(
+        &mut self,
+        key: &Key,
+        manifest_id: &[u8; 32],
+        manifest: &Manifest,
+        transaction: &mut Transaction,
+    ) -> Result<()> {
+        if self.config.manifest == hex_lower(manifest_id) {
+            return Ok(());
+        }
+
+        eprintln!("Synchronizing chunks cache...");
+
+        let archive_dir = self.path.join("chunks.archive.d");
+        std::fs::create_dir_all(&archive_dir)
+            .wrap_err_with(|| format!("create {}", archive_dir.display()))?;
+
+        let mut archives = HashMap::new();
+        for (name, archive) in &manifest.archives {
+            let id: [u8; 32] = archive
+                .id
+                .0
+                .as_slice()
+                .try_into()
+                .map_err(|_| eyre!("manifest entry for archive {name} has an invalid ID"))?;
+            archives.insert(id, name);
+        }
+
+        // indices of archives that were deleted would add references that
+        // no longer exist
+        for entry in std::fs::read_dir(&archive_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let id = name
+                .to_str()
+                .and_then(|name| hex_decode(name.split('.').next()?))
+                .and_then(|id| <[u8; 32]>::try_from(id).ok());
+
+            if !id.is_some_and(|id| archives.contains_key(&id)) {
+                std::fs::remove_file(entry.path())
+                    .wrap_err_with(|| format!("remove {}", entry.path().display()))?;
+            }
+        }
+
+        let mut chunks = ChunkIndex::new();
+
+        for (id, name) in archives {
+            let index = match read_archive_index(&archive_dir, &id) {
+                Ok(Some(index)) => index,
+                result => {
+                    if let Err(e) = result {
+                        eprintln!(
+                            "warning: cached chunk index of archive {name} is unusable: {e:#}"
+                        );
+                    }
+
+                    let index = archive_chunk_index(key, transaction, &id)
+                        .wrap_err_with(|| format!("index chunks of archive {name}"))?;
+                    write_archive_index(&archive_dir, &id, &index)?;
+
+                    index
+                }
+            };
+
+            chunks.merge(&index);
+        }
+
+        self.chunks = chunks;
+
+        Ok(())
+    }
+
+    /// Write the caches for the manifest that was just committed and end the
+    /// transaction
+    pub fn commit(
+        mut self,
+        repository: &Repository,
+        key: &Key,
+        manifest_id: &[u8; 32],
+        manifest_timestamp: &str,
+    ) -> Result<()> {
+        if let Some(files) = &self.files {
+            let name = files_cache_name();
+            let (data, integrity) = files.encode(&name, files_cache_ttl()?)?;
+
+            save_file(&self.path.join(&name), &data)?;
+            self.config
+                .integrity
+                .insert(name, serde_json::to_string(&integrity)?);
+        }
+
+        let mut chunks = Vec::new();
+        self.chunks.inner().write(&mut chunks)?;
+        save_file(&self.path.join("chunks"), &chunks)?;
+        self.config.integrity.insert(
+            "chunks".to_string(),
+            serde_json::to_string(&hashindex_digests("chunks", &chunks))?,
+        );
+
+        let manifest_id = hex_lower(manifest_id);
+        self.config.manifest = manifest_id.clone();
+        self.config
+            .integrity
+            .insert("manifest".to_string(), manifest_id);
+
+        let location = absolute_path(&repository.path)?;
+        for (name, value) in [
+            ("timestamp", manifest_timestamp.to_string()),
+            ("key_type", key.key_type.byte().to_string()),
+            ("previous_location", location.to_string_lossy().into_owned()),
+        ] {
+            self.config.extra.insert(name.to_string(), value);
+        }
+
+        save_file(&self.path.join("config"), self.config.write().as_bytes())?;
+        save_security_info(repository, key, manifest_timestamp)?;
+
+        let txn_tmp = self.path.join("txn.tmp");
+        std::fs::rename(self.path.join("txn.active"), &txn_tmp)
+            .wrap_err("finish cache transaction")?;
+        std::fs::remove_dir_all(&txn_tmp).wrap_err("remove cache transaction")?;
+
+        Ok(())
+    }
+}
+
+/// Remove the cache of a repository, the config first so a half removed
+/// cache is not mistaken for a valid one
+pub fn destroy(repository: &Repository) -> Result<()> {
+    let path = cache_dir()?.join(&repository.id);
+
+    if !path.exists() {
+        return Ok(());
+    }
+
+    match std::fs::remove_file(path.join("config")) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(e).wrap_err("remove cache config")
+        }
+        _ => {}
+    }
+
+    std::fs::remove_dir_all(&path).wrap_err_with(|| format!("remove {}", path.display()))
+}
+
+/// Create an empty cache the same way borg does. The manifest is left
+/// empty, so the chunks cache counts as out of date.
+fn create(path: &Path, repository_id: &str) -> Result<()> {
+    std::fs::create_dir_all(path.join("chunks.archive.d"))
+        .wrap_err_with(|| format!("create cache directory {}", path.display()))?;
+
+    std::fs::write(path.join("README"), CACHE_README).wrap_err("write cache README")?;
+    save_file(
+        &path.join("config"),
+        CacheConfig::new(repository_id.to_string())
+            .write()
+            .as_bytes(),
+    )?;
+
+    let mut chunks = Vec::new();
+    ChunkIndex::new().inner().write(&mut chunks)?;
+    save_file(&path.join("chunks"), &chunks)?;
+    save_file(&path.join(files_cache_name()), &[])?;
+
+    Ok(())
+}
+
+/// Read the chunks cache, checking it against its digests
+fn read_chunks(path: &Path, config: &CacheConfig) -> Result<ChunkIndex> {
+    let data = std::fs::read(path.join("chunks")).wrap_err("read chunks cache")?;
+
+    if let Some(integrity) = config.integrity("chunks") {
+        integrity.verify(&hashindex_digests("chunks", &data), "chunks cache")?;
+    }
+
+    ChunkIndex::read(&mut data.as_slice()).wrap_err("read chunks cache")
+}
+
+/// Read the cached chunk index of an archive, in the compact form borg
+/// writes or the regular form older versions wrote. The digests are in a
+/// separate `.integrity` file, an index without one is used unchecked like
+/// borg does. Returns `None` if there is no index for the archive.
+fn read_archive_index(dir: &Path, id: &[u8; 32]) -> Result<Option<ChunkIndex>> {
+    let name = hex_lower(id);
+
+    for (file_name, compact) in [(format!("{name}.compact"), true), (name, false)] {
+        let path = dir.join(&file_name);
+
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).wrap_err_with(|| format!("read {}", path.display())),
+        };
+
+        if let Ok(integrity) = std::fs::read_to_string(dir.join(format!("{file_name}.integrity"))) {
+            let expected: IntegrityData =
+                serde_json::from_str(&integrity).wrap_err("decode archive index digests")?;
+            expected.verify(&hashindex_digests(&file_name, &data), &file_name)?;
+        }
+
+        let index = if compact {
+            ChunkIndex::read_compact(&mut data.as_slice())?
+        } else {
+            ChunkIndex::read(&mut data.as_slice())?
+        };
+
+        return Ok(Some(index));
+    }
+
+    Ok(None)
+}
+
+/// Write the compact chunk index of an archive with its digests, replacing
+/// any index in the old form
+fn write_archive_index(dir: &Path, id: &[u8; 32], index: &ChunkIndex) -> Result<()> {
+    let name = format!("{}.compact", hex_lower(id));
+
+    let mut data = Vec::new();
+    index.inner().write_compact(&mut data)?;
+
+    save_file(
+        &dir.join(format!("{name}.integrity")),
+        serde_json::to_string(&hashindex_digests(&name, &data))?.as_bytes(),
+    )?;
+    save_file(&dir.join(&name), &data)?;
+
+    for old in [hex_lower(id), format!("{}.integrity", hex_lower(id))] {
+        match std::fs::remove_file(dir.join(old)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// The parts of archive metadata needed to find the chunks it references
+#[derive(Deserialize)]
+struct ArchiveItems {
+    version: u8,
+    items: Vec<Bytes>,
+}
+
+/// The part of an item needed to find the chunks it references
+#[derive(Deserialize)]
+struct ItemChunks {
+    #[serde(default)]
+    chunks: Vec<(Bytes, u32, u32)>,
+}
+
+/// Build the chunk index of an archive from its metadata: the archive
+/// object itself, its item metadata objects and the chunks of every item
+pub fn archive_chunk_index(
+    key: &Key,
+    transaction: &mut Transaction,
+    id: &[u8; 32],
+) -> Result<ChunkIndex> {
+    let mut index = ChunkIndex::new();
+
+    let mut get = |id: &[u8; 32]| -> Result<Vec<u8>> {
+        let data = transaction
+            .get(id)?
+            .ok_or_else(|| eyre!("object {} is missing", hex_lower(id)))?;
+        let plain = unpack_data(key, &data)?;
+
+        index.add(id, 1, plain.len() as u32, data.len() as u32);
+
+        Ok(plain)
+    };
+
+    let archive: ArchiveItems =
+        rmp_serde::from_slice(&get(id)?).wrap_err("decode archive msgpack")?;
+    if !(1..=2).contains(&archive.version) {
+        bail!("unknown archive metadata version {}", archive.version);
+    }
+
+    let stream = item_stream(&archive.items, &mut get, Err)?;
+
+    let mut cursor = std::io::Cursor::new(&stream[..]);
+    while (cursor.position() as usize) < stream.len() {
+        let item: ItemChunks = rmp_serde::from_read(&mut cursor).wrap_err("decode item")?;
+
+        for (chunk_id, size, csize) in item.chunks {
+            let chunk_id: [u8; 32] = chunk_id
+                .0
+                .as_slice()
+                .try_into()
+                .map_err(|_| eyre!("item lists an invalid chunk ID"))?;
+            index.add(&chunk_id, 1, size, csize);
+        }
+    }
+
+    Ok(index)
+}
+
+/// Snapshot the cache files into `txn.active`, so they can be restored if
+/// the transaction is not committed
+fn begin(path: &Path) -> Result<()> {
+    let txn_tmp = path.join("txn.tmp");
+    std::fs::create_dir(&txn_tmp).wrap_err("begin cache transaction")?;
+
+    for name in ["config", "chunks"] {
+        std::fs::copy(path.join(name), txn_tmp.join(name))
+            .wrap_err_with(|| format!("copy cache {name}"))?;
+    }
+
+    let files = files_cache_name();
+    if path.join(&files).exists() {
+        std::fs::copy(path.join(&files), txn_tmp.join(&files)).wrap_err("copy files cache")?;
+    } else {
+        std::fs::write(txn_tmp.join(&files), []).wrap_err("create files cache")?;
+    }
+
+    std::fs::rename(&txn_tmp, path.join("txn.active")).wrap_err("begin cache transaction")
+}
+
+/// Undo a transaction that was not committed, by borg or by us
+fn rollback(path: &Path) -> Result<()> {
+    let txn_tmp = path.join("txn.tmp");
+    if txn_tmp.exists() {
+        std::fs::remove_dir_all(&txn_tmp).wrap_err("remove partial cache transaction")?;
+    }
+
+    let txn_active = path.join("txn.active");
+    if !txn_active.exists() {
+        return Ok(());
+    }
+
+    eprintln!("warning: rolling back unfinished cache transaction");
+
+    let files = files_cache_name();
+    for name in ["config", "chunks", &files] {
+        if txn_active.join(name).exists() {
+            std::fs::copy(txn_active.join(name), path.join(name))
+                .wrap_err_with(|| format!("restore cache {name}"))?;
+        }
+    }
+
+    std::fs::rename(&txn_active, &txn_tmp).wrap_err("roll back cache transaction")?;
+    std::fs::remove_dir_all(&txn_tmp).wrap_err("remove rolled back cache transaction")
+}
+
+/// Replace a file through a temporary file, like borg's `SaveFile`
+fn save_file(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+
+    std::fs::write(&tmp, data).wrap_err_with(|| format!("write {}", path.display()))?;
+    std::fs::rename(&tmp, path).wrap_err_with(|| format!("replace {}", path.display()))
+}
+
+fn files_cache_ttl() -> Result<u32> {
+    match std::env::var("BORG_FILES_CACHE_TTL") {
+        Ok(ttl) => ttl
+            .parse()
+            .wrap_err_with(|| format!("invalid BORG_FILES_CACHE_TTL {ttl:?}")),
+        Err(_) => Ok(DEFAULT_FILES_CACHE_TTL),
+    }
+}
+
+/// The contents of a cache's `config` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    pub repository: String,
+
+    /// Hex ID of the manifest the chunks cache is in sync with, empty if it
+    /// never was
+    pub manifest: String,
+
+    /// Digests of the cache files, JSON encoded, plus the manifest ID they
+    /// were written for under `manifest`
+    pub integrity: BTreeMap<String, String>,
+
+    /// Keys of the cache section that bork does not use, kept so that
+    /// writing the config back does not lose them
+    pub extra: BTreeMap<String, String>,
+}
+
+impl CacheConfig {
+    pub fn new(repository: String) -> Self {
+        Self {
+            repository,
+            manifest: String::new(),
+            integrity: BTreeMap::from([("manifest".to_string(), String::new())]),
+            extra: BTreeMap::new(),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut ini = Ini::new();
+
+        ini.read(s.to_string())
+            .map_err(|e| eyre!(e))
+            .wrap_err("parse cache config ini")?;
+
+        let mut map = ini.get_map().unwrap_or_default();
+
+        let mut section = map
+            .remove("cache")
+            .ok_or_else(|| eyre!("cache config has no cache section"))?;
+
+        let mut take = |name: &str| section.remove(name).flatten();
+
+        let version = take("version").ok_or_else(|| eyre!("cache config missing version"))?;
+        if version != "1" {
+            bail!("unsupported cache version {version:?}");
+        }
+
+        let repository =
+            take("repository").ok_or_else(|| eyre!("cache config missing repository"))?;
+        let manifest = take("manifest").unwrap_or_default();
+
+        let extra = section
+            .into_iter()
+            .map(|(name, value)| (name, value.unwrap_or_default()))
+            .collect();
+
+        let integrity = map
+            .remove("integrity")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, value)| (name, value.unwrap_or_default()))
+            .collect();
+
+        Ok(Self {
+            repository,
+            manifest,
+            integrity,
+            extra,
+        })
+    }
+
+    /// The digests of a cache file. Borg versions before 1.1 did not know
+    /// about them and did not update them, which borg detects by the manifest
+    /// ID recorded with them, and so do we.
+    pub fn integrity(&self, name: &str) -> Option<IntegrityData> {
+        if self.integrity.get("manifest") != Some(&self.manifest) {
+            return None;
+        }
+
+        serde_json::from_str(self.integrity.get(name)?).ok()
+    }
+
+    /// Render the config in the same layout borg writes it in
+    pub fn write(&self) -> String {
+        let mut out = String::from("[cache]\n");
+
+        out.push_str("version = 1\n");
+        out.push_str(&format!("repository = {}\n", self.repository));
+        out.push_str(&format!("manifest = {}\n", self.manifest));
+
+        for (name, value) in &self.extra {
+            out.push_str(&format!("{name} = {value}\n"));
+        }
+
+        out.push_str("\n[integrity]\n");
+
+        for (name, value) in &self.integrity {
+            out.push_str(&format!("{name} = {value}\n"));
+        }
+
+        out.push('\n');
+
+        out
+    }
+}
+
+/// Which properties of a file must be unchanged for the files cache to
+/// consider it unchanged, as given to borg's `--files-cache`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilesCacheMode {
+    pub ctime: bool,
+    pub mtime: bool,
+    pub size: bool,
+    pub inode: bool,
+
+    /// Chunk every file, but still remember them in the files cache
+    pub rechunk: bool,
+
+    /// Neither use nor update the files cache
+    pub disabled: bool,
+}
+
+impl Default for FilesCacheMode {
+    fn default() -> Self {
+        Self {
+            ctime: true,
+            mtime: false,
+            size: true,
+            inode: true,
+            rechunk: false,
+            disabled: false,
+        }
+    }
+}
+
+impl FilesCacheMode {
+    /// For commands that do not back up files and so have no use for the
+    /// files cache
+    pub fn disabled() -> Self {
+        Self {
+            ctime: false,
+            mtime: false,
+            size: false,
+            inode: false,
+            rechunk: false,
+            disabled: true,
+        }
+    }
+
+    /// Parse a comma separated list of `ctime`, `mtime`, `size`, `inode`,
+    /// `rechunk` and `disabled`, allowing the same combinations borg does
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut mode = Self {
+            ctime: false,
+            mtime: false,
+            size: false,
+            inode: false,
+            rechunk: false,
+            disabled: false,
+        };
+
+        for entry in s.trim().split(',') {
+            match entry {
+                "ctime" => mode.ctime = true,
+                "mtime" => mode.mtime = true,
+                "size" => mode.size = true,
+                "inode" => mode.inode = true,
+                "rechunk" => mode.rechunk = true,
+                "disabled" => mode.disabled = true,
+                _ => bail!(
+                    "cache mode must be a comma-separated list of: ctime,disabled,inode,mtime,rechunk,size"
+                ),
+            }
+        }
+
+        let Self {
+            ctime,
+            mtime,
+            size,
+            inode,
+            rechunk,
+            disabled,
+        } = mode;
+
+        let valid = match (ctime, mtime, size, inode, rechunk, disabled) {
+            // ctime or mtime with size and optionally inode
+            (true, false, true, _, false, false) | (false, true, true, _, false, false) => true,
+            // rechunk with ctime or mtime
+            (true, false, false, false, true, false) | (false, true, false, false, true, false) => {
+                true
+            }
+            // size alone, or disabled alone
+            (false, false, true, false, false, false)
+            | (false, false, false, false, false, true) => true,
+            _ => false,
+        };
+
+        if !valid {
+            bail!("invalid files cache mode {s:?}");
+        }
+
+        Ok(mode)
+    }
+
+    /// The timestamp the cache remembers and compares, ctime unless mtime
+    /// was asked for
+    fn cmtime(self, metadata: &Metadata) -> i64 {
+        if self.mtime {
+            metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec()
+        } else {
+            metadata.ctime() * 1_000_000_000 + metadata.ctime_nsec()
+        }
+    }
+}
+
+/// What the files cache remembers about a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileCacheEntry {
+    /// How many backups ago the file was last seen
+    pub age: u32,
+    pub inode: u64,
+    pub size: u64,
+
+    /// ctime or mtime in nanoseconds, depending on the mode
+    pub cmtime: i64,
+    pub chunk_ids: Vec<[u8; 32]>,
+}
+
+/// Borg's files cache, which maps the ID hash of a file's absolute path to
+/// what it looked like when it was last backed up. Files that look the same
+/// are not read and chunked again.
+#[derive(Debug)]
+pub struct FilesCache {
+    mode: FilesCacheMode,
+    entries: HashMap<[u8; 32], FileCacheEntry>,
+
+    /// The newest cmtime of a file remembered during this backup
+    newest_cmtime: Option<i64>,
+}
+
+impl FilesCache {
+    pub fn new(mode: FilesCacheMode) -> Self {
+        Self {
+            mode,
+            entries: HashMap::new(),
+            newest_cmtime: None,
+        }
+    }
+
+    /// Load the files cache, ageing every entry by one backup. A cache that
+    /// cannot be read or is corrupted only costs performance, so like borg we
+    /// warn and start over.
+    pub fn load(path: &Path, integrity: Option<IntegrityData>, mode: FilesCacheMode) -> Self {
+        let mut cache = Self::new(mode);
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match std::fs::read(path)
+            .wrap_err("the files cache can't be read")
+            .and_then(|data| cache.decode(&name, &data, integrity.as_ref()))
+        {
+            Ok(()) => {}
+            Err(e) => {
+                eprintln!("warning: {e:#}");
+                eprintln!("warning: continuing without files cache - expect lower performance");
+                cache.entries.clear();
+            }
+        }
+
+        cache
+    }
+
+    fn decode(&mut self, name: &str, data: &[u8], integrity: Option<&IntegrityData>) -> Result<()> {
+        if let Some(expected) = integrity {
+            let mut hasher = IntegrityHasher::new(name);
+            hasher.update(data);
+
+            expected
+                .verify(&hasher.finish(), "the files cache")
+                .wrap_err("the files cache is corrupted")?;
+        }
+
+        type Entry = (Bytes, (u32, u64, u64, PythonValue, Vec<Bytes>));
+
+        let mut cursor = std::io::Cursor::new(data);
+        while (cursor.position() as usize) < data.len() {
+            let (path_hash, (age, inode, size, cmtime, chunk_ids)): Entry =
+                rmp_serde::from_read(&mut cursor).wrap_err("the files cache seems invalid")?;
+
+            let path_hash = path_hash
+                .0
+                .try_into()
+                .map_err(|_| eyre!("the files cache seems invalid, bad path hash"))?;
+
+            // borg stores timestamps beyond 2262 as bytes, no file we back up
+            // has one, so those entries are simply dropped
+            let cmtime = match cmtime {
+                PythonValue::U8(x) => x as i64,
+                PythonValue::U16(x) => x as i64,
+                PythonValue::U32(x) => x as i64,
+                PythonValue::U64(x) => x as i64,
+                PythonValue::I8(x) => x as i64,
+                PythonValue::I16(x) => x as i64,
+                PythonValue::I32(x) => x as i64,
+                PythonValue::I64(x) => x,
+                _ => continue,
+            };
+
+            let chunk_ids = chunk_ids
+                .into_iter()
+                .map(|id| id.0.try_into())
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| eyre!("the files cache seems invalid, bad chunk ID"))?;
+
+            self.entries.insert(
+                path_hash,
+                FileCacheEntry {
+                    age: age + 1,
+                    inode,
+                    size,
+                    cmtime,
+                    chunk_ids,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, path_hash: &[u8; 32]) -> Option<&FileCacheEntry> {
+        self.entries.get(path_hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The chunks of a regular file if it is in the cache and has not
+    /// changed since, according to the mode. A match is remembered as seen
+    /// in this backup.
+    pub fn lookup(&mut self, path_hash: &[u8; 32], metadata: &Metadata) -> Option<Vec<[u8; 32]>> {
+        let mode = self.mode;
+
+        if !metadata.is_file() || mode.disabled || mode.rechunk {
+            return None;
+        }
+
+        let entry = self.entries.get_mut(path_hash)?;
+
+        if (mode.size && entry.size != metadata.size())
+            || (mode.inode && entry.inode != metadata.ino())
+            || ((mode.ctime || mode.mtime) && entry.cmtime != mode.cmtime(metadata))
+        {
+            return None;
+        }
+
+        // the inode may have been ignored because the files moved to another
+        // file system, remembering the new one allows comparing it again later
+        entry.inode = metadata.ino();
+        entry.age = 0;
+
+        Some(entry.chunk_ids.clone())
+    }
+
+    /// Remember the chunks of a regular file that was just backed up
+    pub fn memorize(&mut self, path_hash: [u8; 32], metadata: &Metadata, chunk_ids: Vec<[u8; 32]>) {
+        if !metadata.is_file() || self.mode.disabled {
+            return;
+        }
+
+        let cmtime = self.mode.cmtime(metadata);
+
+        self.entries.insert(
+            path_hash,
+            FileCacheEntry {
+                age: 0,
+                inode: metadata.ino(),
+                size: metadata.size(),
+                cmtime,
+                chunk_ids,
+            },
+        );
+
+        self.newest_cmtime = Some(
+            self.newest_cmtime
+                .map_or(cmtime, |newest| newest.max(cmtime)),
+        );
+    }
+
+    /// Encode the entries worth keeping, returning the file contents and
+    /// their digests. Files seen in this backup are kept unless they are as
+    /// new as the newest one, whose cmtime may not have settled yet. Files
+    /// not seen are kept until they reach `ttl`.
+    pub fn encode(&self, name: &str, ttl: u32) -> Result<(Vec<u8>, IntegrityData)> {
+        use rmp::encode::*;
+
+        let newest_cmtime = self.newest_cmtime.unwrap_or(i64::MAX);
+        let mut out = Vec::new();
+
+        for (path_hash, entry) in &self.entries {
+            let keep = if entry.age == 0 {
+                entry.cmtime < newest_cmtime
+            } else {
+                entry.age < ttl
+            };
+
+            if !keep {
+                continue;
+            }
+
+            write_array_len(&mut out, 2)?;
+            write_bin(&mut out, path_hash)?;
+
+            write_array_len(&mut out, 5)?;
+            write_uint(&mut out, entry.age as u64)?;
+            write_uint(&mut out, entry.inode)?;
+            write_uint(&mut out, entry.size)?;
+            write_sint(&mut out, entry.cmtime)?;
+            write_array_len(&mut out, entry.chunk_ids.len() as u32)?;
+            for id in &entry.chunk_ids {
+                write_bin(&mut out, id)?;
+            }
+        }
+
+        let mut hasher = IntegrityHasher::new(name);
+        hasher.update(&out);
+
+        Ok((out, hasher.finish()))
+    }
+}
@@ -0,0 +1,1050 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{BufReader, Cursor, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use eyre::{bail, eyre, Context, Result};
+use serde::de::IgnoredAny;
+
+use crate::{
+    compression::Compression,
+    config::MAX_OBJECT_SIZE,
+    create::{ChunkEntry, ITEMS_BUFFER_SIZE},
+    delete::confirm,
+    hashindex::NsIndex,
+    item_stream,
+    key::{hex_lower, Key},
+    lock::LockMode,
+    msgpack::Bytes,
+    pack_data,
+    recover::{find_archives, load_key, rebuild_manifest},
+    segment_writer::{sync_dir, SegmentWriter},
+    transaction::{RepositoryState, Transaction},
+    unpack_data, Archive, Manifest, Options, Repository, ENTRY_HEADER_SIZE, ENTRY_KEY_HEADER_SIZE,
+    ITEM_KEYS, MANIFEST_ID, SEGMENT_MAGIC, TAG_COMMIT, TAG_DELETE, TAG_PUT,
+};
+
+/// What borg asks before repairing a repository
+const REPAIR_WARNING: &str = "This is a potentially dangerous function.
+check --repair might lead to data loss (for kinds of corruption it is not
+capable of dealing with). BE VERY CAREFUL!
+
+Type 'YES' if you understand this and want to continue: ";
+
+/// Answers the repair question, the same as for borg
+const REPAIR_CONFIRMATION_ENV: &str = "BORG_CHECK_I_KNOW_WHAT_I_AM_DOING";
+
+/// Check a repository the way `borg check` does and report every problem
+/// found: first the segments and the index, then the archives. Returns
+/// whether the repository is free of problems. With `repair`, the problems
+/// are fixed as far as possible and the repository counts as free of them.
+pub fn check(
+    path: PathBuf,
+    repository_only: bool,
+    archives_only: bool,
+    repair: bool,
+    options: &Options,
+) -> Result<bool> {
+    if repair && !confirm(REPAIR_WARNING, REPAIR_CONFIRMATION_ENV)? {
+        bail!("Aborting.");
+    }
+
+    let repository = Repository::open(path, LockMode::Exclusive, options.lock_wait())?;
+
+    let mut ok = true;
+
+    if !archives_only {
+        ok &= check_repository(&repository, repair)?;
+    }
+
+    if !repository_only {
+        ok &= check_archives(&repository, repair, options)?;
+    }
+
+    Ok(ok)
+}
+
+/// Counts the problems a check reports
+#[derive(Debug, Default)]
+struct Problems(usize);
+
+impl Problems {
+    fn report(&mut self, message: impl std::fmt::Display) {
+        eprintln!("{message}");
+        self.0 += 1;
+    }
+
+    fn found(&self) -> bool {
+        self.0 > 0
+    }
+}
+
+/// An entry of a segment whose framing and CRC were found to be intact
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckedEntry {
+    Put { key: [u8; 32], size: u32 },
+    Delete { key: [u8; 32] },
+    Commit,
+}
+
+/// Reads the entries of a segment file, checking the framing and CRC of
+/// each one. Only one entry is held in memory at a time. After the first
+/// error nothing more can be read, as the start of the next entry is
+/// unknown.
+pub struct CheckedSegment {
+    data: BufReader<File>,
+    length: u64,
+    offset: u64,
+    buffer: Vec<u8>,
+}
+
+impl CheckedSegment {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path).wrap_err_with(|| format!("open {}", path.display()))?;
+        let length = file.metadata()?.len();
+        let mut data = BufReader::new(file);
+
+        let mut magic = [0; 8];
+        data.read_exact(&mut magic)
+            .ok()
+            .filter(|_| &magic == SEGMENT_MAGIC)
+            .ok_or_else(|| eyre!("invalid segment magic"))?;
+
+        Ok(Self {
+            data,
+            length,
+            offset: SEGMENT_MAGIC.len() as u64,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// The next entry with its offset, or `None` at the end of the segment
+    pub fn next_entry(&mut self) -> Result<Option<(u32, CheckedEntry)>> {
+        let offset = self.offset;
+
+        let crc = match self.data.read_u32::<LittleEndian>() {
+            Ok(crc) => crc,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && offset == self.length => {
+                return Ok(None)
+            }
+            Err(_) => bail!("truncated entry header at offset {offset}"),
+        };
+
+        let size = self
+            .data
+            .read_u32::<LittleEndian>()
+            .map_err(|_| eyre!("truncated entry header at offset {offset}"))?;
+
+        if size < ENTRY_HEADER_SIZE || size as u64 > MAX_OBJECT_SIZE {
+            bail!("invalid entry size {size} at offset {offset}");
+        }
+
+        if offset + size as u64 > self.length {
+            bail!("entry at offset {offset} runs past the end of the segment");
+        }
+
+        self.buffer.clear();
+        self.buffer.extend_from_slice(&size.to_le_bytes());
+        self.buffer.resize(size as usize - 4, 0);
+        self.data.read_exact(&mut self.buffer[4..])?;
+
+        if crc32fast::hash(&self.buffer) != crc {
+            bail!("CRC mismatch for entry at offset {offset}");
+        }
+
+        let tag = self.buffer[4];
+        let key = || -> [u8; 32] { self.buffer[5..37].try_into().unwrap() };
+
+        let entry = match tag {
+            TAG_PUT if size >= ENTRY_KEY_HEADER_SIZE => CheckedEntry::Put { key: key(), size },
+            TAG_DELETE if size == ENTRY_KEY_HEADER_SIZE => CheckedEntry::Delete { key: key() },
+            TAG_COMMIT if size == ENTRY_HEADER_SIZE => CheckedEntry::Commit,
+            TAG_PUT | TAG_DELETE | TAG_COMMIT => {
+                bail!("invalid size {size} for entry with tag {tag} at offset {offset}")
+            }
+            _ => bail!("unknown tag {tag} at offset {offset}"),
+        };
+
+        self.offset += size as u64;
+
+        Ok(Some((offset as u32, entry)))
+    }
+}
+
+/// Walk all committed segments, checking every entry, and compare the index
+/// rebuilt from them with the newest `index.N`. Only object locations are
+/// kept in memory, never object data. A repair recovers what it can of
+/// damaged segments and commits the result with a rebuilt index.
+fn check_repository(repository: &Repository, repair: bool) -> Result<bool> {
+    eprintln!("Starting repository check");
+
+    let mut problems = Problems::default();
+
+    let mut segments = repository.segments()?;
+
+    let last_commit = repository.last_committed_segment()?;
+    if last_commit.is_none() && !segments.is_empty() {
+        problems.report("No committed transaction found.");
+    }
+
+    // a repair commits every segment that is left, so uncommitted ones are
+    // removed first, the same way a new transaction does it
+    if let Some(last_commit) = last_commit.filter(|_| repair) {
+        for segment in segments.iter().filter(|segment| segment.id > last_commit) {
+            eprintln!("warning: removing uncommitted segment {}", segment.id);
+            std::fs::remove_file(&segment.path)
+                .wrap_err_with(|| format!("remove {}", segment.path.display()))?;
+        }
+
+        segments.retain(|segment| segment.id <= last_commit);
+    }
+
+    let committed_index = newest_index(repository)?;
+    let mut compared = false;
+
+    let mut index = NsIndex::new();
+    let mut checked_segments = 0;
+    let mut entries = 0;
+
+    for segment in &segments {
+        if last_commit.is_some_and(|last_commit| segment.id > last_commit) {
+            continue;
+        }
+
+        checked_segments += 1;
+
+        let (mut segment_entries, error) = read_segment(&segment.path);
+
+        if let Some(e) = error {
+            problems.report(format!("Error reading segment {}: {e:#}", segment.id));
+
+            if repair {
+                recover_segment(&segment.path)?;
+
+                let (recovered, error) = read_segment(&segment.path);
+                if let Some(e) = error {
+                    bail!(
+                        "segment {} is still damaged after recovery: {e:#}",
+                        segment.id
+                    );
+                }
+
+                segment_entries = recovered;
+            }
+        }
+
+        entries += segment_entries.len();
+
+        for (offset, entry) in segment_entries {
+            match entry {
+                CheckedEntry::Put { key, .. } => index.insert(&key, segment.id, offset),
+                CheckedEntry::Delete { key } => {
+                    index.remove(&key);
+                }
+                CheckedEntry::Commit => {}
+            }
+        }
+
+        // the index is compared with the segments as of its transaction,
+        // which usually is the last one
+        if let Some((transaction_id, Ok(state))) = &committed_index {
+            if *transaction_id == segment.id {
+                eprintln!("Starting repository index check");
+                compare_indexes(&state.index, &index, &mut problems);
+                compared = true;
+            }
+        }
+    }
+
+    if let Some(last_commit) = last_commit {
+        eprintln!("finished segment check at segment {last_commit}");
+    }
+
+    if !compared {
+        eprintln!("Starting repository index check");
+    }
+
+    match committed_index {
+        Some((transaction_id, Err(e))) => {
+            problems.report(format!(
+                "Index of transaction {transaction_id} is unreadable: {e:#}"
+            ));
+        }
+        Some((transaction_id, Ok(_))) if !compared => {
+            problems.report(format!(
+                "Index of transaction {transaction_id} does not belong to a committed transaction."
+            ));
+        }
+        Some(_) => {}
+        None => eprintln!("No committed index found, nothing to compare."),
+    }
+
+    eprintln!(
+        "Checked {checked_segments} segments with {entries} entries, {} objects.",
+        index.len()
+    );
+
+    // whatever was wrong, the segments left are intact now, so committing
+    // and replaying them gives a valid index again
+    if repair && problems.found() {
+        let next_segment = segments.last().map_or(0, |segment| segment.id + 1);
+        let transaction_id =
+            SegmentWriter::new(&repository.path, &repository.config, next_segment).commit()?;
+
+        RepositoryState::replay(repository, transaction_id)?.write(&repository.path)?;
+        eprintln!("Rebuilt the index of transaction {transaction_id}.");
+    }
+
+    if problems.found() {
+        eprintln!("Finished full repository check, errors found.");
+    } else {
+        eprintln!("Finished full repository check, no problems found.");
+    }
+
+    Ok(repair || !problems.found())
+}
+
+/// The entries of a segment up to the first error, along with that error
+fn read_segment(path: &Path) -> (Vec<(u32, CheckedEntry)>, Option<eyre::Report>) {
+    let mut reader = match CheckedSegment::open(path) {
+        Ok(reader) => reader,
+        Err(e) => return (Vec::new(), Some(e)),
+    };
+
+    let mut entries = Vec::new();
+    loop {
+        match reader.next_entry() {
+            Ok(Some(entry)) => entries.push(entry),
+            Ok(None) => return (entries, None),
+            Err(e) => return (entries, Some(e)),
+        }
+    }
+}
+
+/// Rewrite a damaged segment with the entries that are still intact, the
+/// way borg recovers segments: wherever no valid entry starts, the next byte
+/// is tried. The original is kept next to it with a `.beforerecover`
+/// suffix.
+fn recover_segment(path: &Path) -> Result<()> {
+    eprintln!("attempting to recover {}", path.display());
+
+    let data = std::fs::read(path).wrap_err_with(|| format!("read {}", path.display()))?;
+
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".beforerecover");
+    std::fs::rename(path, &backup).wrap_err_with(|| format!("rename {}", path.display()))?;
+
+    let mut recovered = SEGMENT_MAGIC.to_vec();
+    let mut rest = &data[..];
+
+    while rest.len() >= ENTRY_HEADER_SIZE as usize {
+        let crc = u32::from_le_bytes(rest[..4].try_into().unwrap());
+        let size = u32::from_le_bytes(rest[4..8].try_into().unwrap());
+
+        let valid_size = match rest[8] {
+            TAG_PUT => size >= ENTRY_KEY_HEADER_SIZE,
+            TAG_DELETE => size == ENTRY_KEY_HEADER_SIZE,
+            TAG_COMMIT => size == ENTRY_HEADER_SIZE,
+            _ => false,
+        };
+
+        if valid_size
+            && size as u64 <= MAX_OBJECT_SIZE
+            && size as usize <= rest.len()
+            && crc32fast::hash(&rest[4..size as usize]) == crc
+        {
+            recovered.extend_from_slice(&rest[..size as usize]);
+            rest = &rest[size as usize..];
+        } else {
+            rest = &rest[1..];
+        }
+    }
+
+    let mut file = File::create(path).wrap_err_with(|| format!("create {}", path.display()))?;
+    file.write_all(&recovered)
+        .and_then(|_| file.sync_all())
+        .wrap_err_with(|| format!("write {}", path.display()))?;
+    sync_dir(path.parent().expect("segment path has a parent"))?;
+
+    Ok(())
+}
+
+/// The newest `index.N` with its transaction ID, or the error that keeps
+/// it from being read
+fn newest_index(repository: &Repository) -> Result<Option<(u32, Result<RepositoryState>)>> {
+    let mut newest = None;
+
+    for dir_entry in std::fs::read_dir(&repository.path)? {
+        let name = dir_entry?.file_name();
+        let Some(transaction_id) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("index."))
+            .and_then(|id| id.parse::<u32>().ok())
+        else {
+            continue;
+        };
+
+        newest = newest.max(Some(transaction_id));
+    }
+
+    Ok(newest.map(|transaction_id| {
+        let state = RepositoryState::read(repository, transaction_id)
+            .and_then(|state| state.ok_or_else(|| eyre!("hints.{transaction_id} is missing")));
+
+        (transaction_id, state)
+    }))
+}
+
+/// Report the objects the committed index and the rebuilt one disagree on,
+/// in the layout borg uses
+fn compare_indexes(committed: &NsIndex, rebuilt: &NsIndex, problems: &mut Problems) {
+    if committed.len() != rebuilt.len() {
+        problems.report("Index object count mismatch.");
+        eprintln!("committed index: {} objects", committed.len());
+        eprintln!("rebuilt index:   {} objects", rebuilt.len());
+    } else {
+        eprintln!("Index object count match.");
+    }
+
+    let location = |entry: Option<(u32, u32)>| match entry {
+        Some((segment, offset)) => format!("({segment}, {offset})"),
+        None => "<not found>".to_string(),
+    };
+
+    let mut mismatch = |id: &[u8; 32], value, current| {
+        problems.report(format!(
+            "ID: {:<64} rebuilt index: {:<16} committed index: {:<16}",
+            hex_lower(id),
+            location(value),
+            location(current)
+        ));
+    };
+
+    for (id, value) in rebuilt.iter() {
+        let current = committed.get(&id);
+
+        if current != Some(value) {
+            mismatch(&id, Some(value), current);
+        }
+    }
+
+    for (id, current) in committed.iter() {
+        if rebuilt.get(&id).is_none() {
+            mismatch(&id, None, Some(current));
+        }
+    }
+}
+
+/// Check that every archive in the manifest can be decoded and that all
+/// objects its items reference exist. Chunk contents are not read. A repair
+/// rebuilds a lost manifest, drops archives that are gone, replaces missing
+/// file chunks and deletes the objects nothing references any more.
+fn check_archives(repository: &Repository, repair: bool, options: &Options) -> Result<bool> {
+    eprintln!("Starting archive consistency check...");
+
+    let mut objects = if repair {
+        Objects::Repair(Transaction::begin(repository)?)
+    } else {
+        Objects::Committed {
+            repository,
+            state: RepositoryState::load(repository)?,
+        }
+    };
+
+    let mut problems = Problems::default();
+
+    let manifest_data = match objects.get(&MANIFEST_ID) {
+        Ok(Some(data)) => Some(data),
+        Ok(None) => {
+            problems.report("Repository manifest not found!");
+            None
+        }
+        Err(e) => {
+            problems.report(format!("Repository manifest is damaged: {e:#}"));
+            None
+        }
+    };
+
+    if manifest_data.is_none() && !repair {
+        return Ok(false);
+    }
+
+    let ids: Vec<_> = objects.index().iter().map(|(id, _)| id).collect();
+    let mut key = load_key(repository, manifest_data.as_deref(), &ids, |id| {
+        objects.get(id)
+    })?;
+    if repair {
+        key.track_ivs(repository)?;
+    }
+
+    let manifest = manifest_data.map(|data| {
+        unpack_data(&key, &data)
+            .and_then(|plain| Manifest::decode(&key, &plain, options.tam.manifest))
+    });
+
+    let mut checker = ArchiveChecker {
+        key,
+        objects,
+        repair,
+        item_keys: HashSet::new(),
+        referenced: HashSet::from([MANIFEST_ID]),
+        problems,
+    };
+
+    let mut manifest = match manifest {
+        Some(Ok(manifest)) => manifest,
+        Some(Err(e)) if !repair => {
+            checker
+                .problems
+                .report(format!("Repository manifest is damaged: {e:#}"));
+            return Ok(false);
+        }
+        Some(Err(e)) => {
+            checker
+                .problems
+                .report(format!("Repository manifest is damaged: {e:#}"));
+            checker.rebuild_manifest(options)?
+        }
+        None => checker.rebuild_manifest(options)?,
+    };
+
+    checker.item_keys = ITEM_KEYS
+        .iter()
+        .map(|key| key.to_string())
+        .chain(manifest.item_keys.iter().cloned())
+        .collect();
+
+    let mut names: Vec<_> = manifest.archives.keys().cloned().collect();
+    names.sort_by(|a, b| manifest.archives[a].time.cmp(&manifest.archives[b].time));
+
+    // a healed archive is rewritten without any problem being left
+    let mut rewritten = false;
+
+    let count = names.len();
+    for (i, name) in names.into_iter().enumerate() {
+        eprintln!("Analyzing archive {name} ({}/{count})", i + 1);
+
+        let archive = &manifest.archives[&name];
+        match checker.check_archive(&name, &archive.id, options)? {
+            Some(id) if archive.id.0 != id => {
+                manifest.archives.get_mut(&name).unwrap().id = Bytes(id.to_vec());
+                rewritten = true;
+            }
+            Some(_) => {}
+            None if repair => {
+                manifest.archives.remove(&name);
+            }
+            None => {}
+        }
+    }
+
+    checker.check_orphans()?;
+
+    if repair && (checker.problems.found() || rewritten) {
+        manifest.update_timestamp();
+
+        let manifest_plain = manifest.encode(&checker.key)?;
+        let manifest_data = pack_data(&checker.key, &Compression::default(), &manifest_plain)?;
+
+        if let Objects::Repair(mut transaction) = checker.objects {
+            transaction.put(&MANIFEST_ID, &manifest_data)?;
+            transaction.commit()?;
+        }
+    }
+
+    if checker.problems.found() {
+        eprintln!("Archive consistency check complete, problems found.");
+    } else {
+        eprintln!("Archive consistency check complete, no problems found.");
+    }
+
+    Ok(repair || !checker.problems.found())
+}
+
+/// Where the archive check reads objects from. A repair writes to a
+/// transaction, so it reads from that transaction as well.
+enum Objects<'a> {
+    Committed {
+        repository: &'a Repository,
+        state: RepositoryState,
+    },
+    Repair(Transaction<'a>),
+}
+
+impl<'a> Objects<'a> {
+    fn index(&self) -> &NsIndex {
+        match self {
+            Self::Committed { state, .. } => &state.index,
+            Self::Repair(transaction) => transaction.index(),
+        }
+    }
+
+    fn contains(&self, id: &[u8; 32]) -> bool {
+        self.index().get(id).is_some()
+    }
+
+    fn get(&mut self, id: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        match self {
+            Self::Committed { repository, state } => match state.index.get(id) {
+                Some((segment, offset)) => repository.read_put(segment, offset, id).map(Some),
+                None => Ok(None),
+            },
+            Self::Repair(transaction) => transaction.get(id),
+        }
+    }
+
+    /// The plain contents of an object, `None` if it does not exist and an
+    /// error if it is damaged
+    fn load(&mut self, key: &Key, id: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        self.get(id)?
+            .map(|data| unpack_data(key, &data))
+            .transpose()
+    }
+
+    /// The transaction a repair writes to
+    fn transaction(&mut self) -> &mut Transaction<'a> {
+        match self {
+            Self::Repair(transaction) => transaction,
+            Self::Committed { .. } => unreachable!("only a repair writes to the repository"),
+        }
+    }
+}
+
+/// Checks the archives of a repository and, when repairing, fixes them, the
+/// way borg's `ArchiveChecker` does
+struct ArchiveChecker<'a> {
+    key: Key,
+    objects: Objects<'a>,
+    repair: bool,
+
+    /// The keys an item may have
+    item_keys: HashSet<String>,
+
+    /// Every object the manifest and the archives reference
+    referenced: HashSet<[u8; 32]>,
+
+    problems: Problems,
+}
+
+impl ArchiveChecker<'_> {
+    /// Build a manifest listing every archive object in the repository
+    fn rebuild_manifest(&mut self, options: &Options) -> Result<Manifest> {
+        eprintln!("Rebuilding missing manifest, this might take some time...");
+
+        let ids: Vec<_> = self.objects.index().iter().map(|(id, _)| id).collect();
+        let objects = &mut self.objects;
+        let archives = find_archives(&self.key, &ids, |id| objects.get(id), options.tam.archives);
+        let manifest = rebuild_manifest(&self.key, archives);
+
+        eprintln!("Manifest rebuild complete.");
+
+        Ok(manifest)
+    }
+
+    /// Check one archive. Returns the ID it has after a repair, or `None` if
+    /// it is lost.
+    fn check_archive(
+        &mut self,
+        name: &str,
+        id: &Bytes,
+        options: &Options,
+    ) -> Result<Option<[u8; 32]>> {
+        let Ok(id) = <[u8; 32]>::try_from(id.0.as_slice()) else {
+            self.problems
+                .report(format!("{name}: Manifest lists an invalid archive ID."));
+            return Ok(None);
+        };
+
+        let archive = self.objects.load(&self.key, &id).and_then(|plain| {
+            plain
+                .map(|plain| Archive::decode(&self.key, &plain, options.tam.archives))
+                .transpose()
+        });
+
+        let mut archive = match archive {
+            Ok(Some(archive)) => archive,
+            Ok(None) => {
+                self.problems.report(format!(
+                    "Archive metadata block {} is missing!",
+                    hex_lower(&id)
+                ));
+                return Ok(None);
+            }
+            Err(e) => {
+                self.problems
+                    .report(format!("{name}: Archive metadata is damaged: {e:#}"));
+                return Ok(None);
+            }
+        };
+
+        let (mut items, mut changed) = self.read_items(name, &archive);
+
+        for item in &mut items {
+            changed |= self.check_item_chunks(name, item)?;
+        }
+
+        if !(self.repair && changed) {
+            self.referenced.insert(id);
+            self.referenced.extend(
+                archive
+                    .items
+                    .iter()
+                    .filter_map(|item_id| <[u8; 32]>::try_from(item_id.0.as_slice()).ok()),
+            );
+
+            return Ok(Some(id));
+        }
+
+        // the repaired items are stored as new item metadata objects, which
+        // a new archive object references
+        let mut item_ids = Vec::new();
+        let mut buffer = Vec::new();
+        for item in &items {
+            item.encode(&mut buffer)?;
+
+            if buffer.len() >= ITEMS_BUFFER_SIZE {
+                item_ids.push(self.store(&buffer)?.0);
+                buffer.clear();
+            }
+        }
+
+        if !buffer.is_empty() {
+            item_ids.push(self.store(&buffer)?.0);
+        }
+
+        archive.items = item_ids.iter().map(|id| Bytes(id.to_vec())).collect();
+
+        let (id, _) = self.store(&archive.encode(&self.key)?)?;
+
+        Ok(Some(id))
+    }
+
+    /// Decode the items of an archive, skipping the ones that are damaged.
+    /// Also returns whether anything was.
+    fn read_items(&mut self, name: &str, archive: &Archive) -> (Vec<RawItem>, bool) {
+        let mut damaged = false;
+
+        // lost item metadata objects are reported, never given up on
+        let stream = item_stream(
+            &archive.items,
+            |item_id| {
+                self.objects
+                    .load(&self.key, item_id)
+                    .wrap_err_with(|| {
+                        format!("Item metadata chunk {} is damaged", hex_lower(item_id))
+                    })?
+                    .ok_or_else(|| eyre!("Item metadata chunk {} is missing.", hex_lower(item_id)))
+            },
+            |e| {
+                self.problems.report(format!("{name}: {e:#}"));
+                damaged = true;
+                Ok(())
+            },
+        )
+        .unwrap_or_default();
+
+        let mut items = Vec::new();
+        let mut position = 0;
+        while position < stream.len() {
+            match RawItem::decode(&stream[position..], &self.item_keys) {
+                Ok((item, len)) => {
+                    items.push(item);
+                    position += len;
+                }
+                Err(e) => {
+                    self.problems.report(format!(
+                        "{name}: Item metadata is damaged at byte {position}: {e:#}"
+                    ));
+                    damaged = true;
+
+                    // like borg, continue with the next byte that starts
+                    // something that looks like an item
+                    position = (position + 1..stream.len())
+                        .find(|&start| RawItem::decode(&stream[start..], &self.item_keys).is_ok())
+                        .unwrap_or(stream.len());
+                }
+            }
+        }
+
+        (items, damaged)
+    }
+
+    /// Check that the chunks of an item exist. A repair replaces missing
+    /// chunks with zero-filled ones of the same size and keeps the original
+    /// list as `chunks_healthy`, so the file heals if the chunks turn up
+    /// again. Returns whether the item changed.
+    fn check_item_chunks(&mut self, name: &str, item: &mut RawItem) -> Result<bool> {
+        let Some(current) = item.chunks.clone() else {
+            return Ok(false);
+        };
+
+        let path = String::from_utf8_lossy(&item.path).into_owned();
+        let mut changed = false;
+
+        if item
+            .chunks_healthy
+            .as_ref()
+            .is_some_and(|healthy| healthy.len() != current.len())
+        {
+            self.problems.report(format!(
+                "{name}: {path}: chunks_healthy list is not of the same length as chunks, discarding it."
+            ));
+            item.chunks_healthy = None;
+            changed = true;
+        }
+
+        let healthy = item
+            .chunks_healthy
+            .clone()
+            .unwrap_or_else(|| current.clone());
+
+        let mut chunks = Vec::with_capacity(current.len());
+        let mut replaced = false;
+        let mut offset = 0;
+
+        for (&current_entry, &healthy_entry) in current.iter().zip(&healthy) {
+            let (healthy_id, size, _) = healthy_entry;
+            let range = format!("Byte {offset}-{}", offset + size as u64);
+
+            let entry = if self.objects.contains(&healthy_id) {
+                if current_entry != healthy_entry {
+                    eprintln!(
+                        "{name}: {path}: Healed previously missing file chunk! ({range}, Chunk {}).",
+                        hex_lower(&healthy_id)
+                    );
+                }
+
+                healthy_entry
+            } else if current_entry == healthy_entry {
+                let message = format!(
+                    "{name}: {path}: Missing file chunk detected ({range}, Chunk {}).",
+                    hex_lower(&healthy_id)
+                );
+
+                if self.repair {
+                    self.problems
+                        .report(format!("{message} Replacing with all-zero chunk."));
+                    replaced = true;
+                    self.replacement_chunk(size)?
+                } else {
+                    self.problems.report(message);
+                    current_entry
+                }
+            } else if self.objects.contains(&current_entry.0) {
+                eprintln!(
+                    "{name}: {path}: Previously missing file chunk is still missing ({range}, Chunk {}). It has an all-zero replacement chunk already.",
+                    hex_lower(&healthy_id)
+                );
+
+                current_entry
+            } else {
+                let message = format!(
+                    "{name}: {path}: Missing all-zero replacement chunk detected ({range}, Chunk {}).",
+                    hex_lower(&current_entry.0)
+                );
+
+                if self.repair {
+                    self.problems
+                        .report(format!("{message} Generating new replacement chunk."));
+                    replaced = true;
+                    self.replacement_chunk(size)?
+                } else {
+                    self.problems.report(message);
+                    current_entry
+                }
+            };
+
+            self.referenced.insert(entry.0);
+            chunks.push(entry);
+            offset += size as u64;
+        }
+
+        if replaced && item.chunks_healthy.is_none() {
+            item.chunks_healthy = Some(current.clone());
+            changed = true;
+        }
+
+        if item.chunks_healthy.as_ref() == Some(&chunks) {
+            eprintln!("{name}: {path}: Completely healed previously damaged file!");
+            item.chunks_healthy = None;
+            changed = true;
+        }
+
+        changed |= chunks != current;
+        item.chunks = Some(chunks);
+
+        Ok(changed)
+    }
+
+    /// A chunk of `size` zero bytes standing in for a missing one
+    fn replacement_chunk(&mut self, size: u32) -> Result<ChunkEntry> {
+        let (id, csize) = self.store(&vec![0; size as usize])?;
+
+        Ok((id, size, csize))
+    }
+
+    /// Store an object unless an intact one with the same ID exists,
+    /// returning its ID and stored size
+    fn store(&mut self, data: &[u8]) -> Result<([u8; 32], u32)> {
+        let id = self.key.id_hash(data)?;
+        self.referenced.insert(id);
+
+        if let Ok(Some(existing)) = self.objects.get(&id) {
+            return Ok((id, existing.len() as u32));
+        }
+
+        let packed = pack_data(&self.key, &Compression::default(), data)?;
+        self.objects.transaction().put(&id, &packed)?;
+
+        Ok((id, packed.len() as u32))
+    }
+
+    /// Report the objects neither the manifest nor any archive references.
+    /// A repair deletes them.
+    fn check_orphans(&mut self) -> Result<()> {
+        let orphans: Vec<_> = self
+            .objects
+            .index()
+            .iter()
+            .map(|(id, _)| id)
+            .filter(|id| !self.referenced.contains(id))
+            .collect();
+
+        if orphans.is_empty() {
+            return Ok(());
+        }
+
+        self.problems
+            .report(format!("{} orphaned objects found!", orphans.len()));
+
+        if self.repair {
+            for id in &orphans {
+                self.objects.transaction().delete(id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An item decoded only as far as the archive check needs it. All other
+/// keys are kept the way they were encoded, so a repaired item loses
+/// nothing.
+#[derive(Debug)]
+struct RawItem {
+    path: Vec<u8>,
+    chunks: Option<Vec<ChunkEntry>>,
+    chunks_healthy: Option<Vec<ChunkEntry>>,
+
+    /// The encoded values of the other keys, the path included
+    values: BTreeMap<String, Vec<u8>>,
+}
+
+impl RawItem {
+    /// Decode the item at the start of `data`, returning it along with its
+    /// encoded length. Like borg, only a map with a path and no keys besides
+    /// `item_keys` is taken for an item.
+    fn decode(data: &[u8], item_keys: &HashSet<String>) -> Result<(Self, usize)> {
+        let mut cursor = Cursor::new(data);
+
+        let len = rmp::decode::read_map_len(&mut cursor).map_err(|_| eyre!("item is not a map"))?;
+
+        let mut path = None;
+        let mut item = Self {
+            path: Vec::new(),
+            chunks: None,
+            chunks_healthy: None,
+            values: BTreeMap::new(),
+        };
+
+        for _ in 0..len {
+            let key: Bytes = rmp_serde::from_read(&mut cursor).wrap_err("decode item key")?;
+            let key = String::from_utf8(key.0)
+                .ok()
+                .filter(|key| item_keys.contains(key))
+                .ok_or_else(|| eyre!("unknown item key"))?;
+
+            let start = cursor.position() as usize;
+            let IgnoredAny =
+                rmp_serde::from_read(&mut cursor).wrap_err_with(|| format!("decode item {key}"))?;
+            let value = &data[start..cursor.position() as usize];
+
+            match key.as_str() {
+                "chunks" => item.chunks = Some(decode_chunks(value)?),
+                "chunks_healthy" => item.chunks_healthy = Some(decode_chunks(value)?),
+                _ => {
+                    if key == "path" {
+                        let value: Bytes =
+                            rmp_serde::from_slice(value).wrap_err("decode item path")?;
+                        path = Some(value.0);
+                    }
+
+                    item.values.insert(key, value.to_vec());
+                }
+            }
+        }
+
+        item.path = path.ok_or_else(|| eyre!("item has no path"))?;
+
+        Ok((item, cursor.position() as usize))
+    }
+
+    /// Append the item to an item metadata stream, with the keys sorted the
+    /// way borg writes them
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        let mut values = self.values.clone();
+
+        for (key, chunks) in [
+            ("chunks", &self.chunks),
+            ("chunks_healthy", &self.chunks_healthy),
+        ] {
+            if let Some(chunks) = chunks {
+                values.insert(key.to_string(), encode_chunks(chunks)?);
+            }
+        }
+
+        rmp::encode::write_map_len(out, values.len() as u32)?;
+        for (key, value) in values {
+            rmp::encode::write_str(out, &key)?;
+            out.extend(value);
+        }
+
+        Ok(())
+    }
+}
+
+fn decode_chunks(value: &[u8]) -> Result<Vec<ChunkEntry>> {
+    let chunks: Vec<(Bytes, u32, u32)> =
+        rmp_serde::from_slice(value).wrap_err("decode item chunks")?;
+
+    chunks
+        .into_iter()
+        .map(|(id, size, csize)| {
+            let id =
+                id.0.try_into()
+                    .map_err(|_| eyre!("item lists an invalid chunk ID"))?;
+
+            Ok((id, size, csize))
+        })
+        .collect()
+}
+
+/// Encode a chunk list the way `create` writes it
+fn encode_chunks(chunks: &[ChunkEntry]) -> Result<Vec<u8>> {
+    use rmp::encode::*;
+
+    let mut out = Vec::new();
+
+    write_array_len(&mut out, chunks.len() as u32)?;
+    for (id, size, csize) in chunks {
+        write_array_len(&mut out, 3)?;
+        write_bin(&mut out, id)?;
+        write_uint(&mut out, *size as u64)?;
+        write_uint(&mut out, *csize as u64)?;
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,482 @@
+use std::io::Read;
+
+use eyre::{bail, eyre, Context, Result};
+
+use crate::{msgpack::PythonValue, segment_writer::MAX_DATA_SIZE};
+
+/// Borg's default chunker parameters: buzhash with chunks between 512 KiB and
+/// 8 MiB, 2 MiB on average, and a 4095 byte rolling hash window
+pub const DEFAULT_CHUNKER_PARAMS: ChunkerParams = ChunkerParams::Buzhash {
+    min_exp: 19,
+    max_exp: 23,
+    mask_bits: 21,
+    window_size: 4095,
+};
+
+/// The random values buzhash mixes in for each byte value, before they are
+/// XORed with the seed. This is borg's `table_base`, which every borg
+/// version uses, so chunks are cut where borg cuts them.
+#[rustfmt::skip]
+const TABLE_BASE: [u32; 256] = [
+    0xe7f831ec, 0xf4026465, 0xafb50cae, 0x6d553c7a, 0xd639efe3, 0x19a7b895, 0x9aba5b21, 0x5417d6d4,
+    0x35fd2b84, 0xd1f6a159, 0x3f8e323f, 0xb419551c, 0xf444cebf, 0x21dc3b80, 0xde8d1e36, 0x84a32436,
+    0xbeb35a9d, 0xa36f24aa, 0xa4e60186, 0x98d18ffe, 0x3f042f9e, 0xdb228bcd, 0x096474b7, 0x5c20c2f7,
+    0xf9eec872, 0xe8625275, 0xb9d38f80, 0xd48eb716, 0x22a950b4, 0x3cbaaeaa, 0xc37cddd3, 0x8fea6f6a,
+    0x1d55d526, 0x7fd6d3b3, 0xdaa072ee, 0x4345ac40, 0xa077c642, 0x8f2bd45b, 0x28509110, 0x55557613,
+    0xffc17311, 0xd961ffef, 0xe532c287, 0xaab95937, 0x46d38365, 0xb065c703, 0xf2d91d0f, 0x92cd4bb0,
+    0x4007c712, 0xf35509dd, 0x505b2f69, 0x557ead81, 0x310f4563, 0xbddc5be8, 0x9760f38c, 0x701e0205,
+    0x00157244, 0x14912826, 0xdc4ca32b, 0x67b196de, 0x5db292e8, 0x8c1b406b, 0x01f34075, 0xfa2520f7,
+    0x73bc37ab, 0x1e18bc30, 0xfe2c6cb3, 0x20c522d0, 0x5639e3db, 0x942bda35, 0x899af9d1, 0xced44035,
+    0x98cc025b, 0x255f5771, 0x70fefa24, 0xe928fa4d, 0x2c030405, 0xb9325590, 0x20cb63bd, 0xa166305d,
+    0x80e52c0a, 0xa8fafe2f, 0x1ad13f7d, 0xcfaf3685, 0x6c83a199, 0x7d26718a, 0xde5dfcd9, 0x79cf7355,
+    0x8979d7fb, 0xebf8c55e, 0xebe408e4, 0xcd2affba, 0xe483be6e, 0xe239d6de, 0x5dc1e9e0, 0x0473931f,
+    0x851b097c, 0xac5db249, 0x09c0f9f2, 0xd8d2f134, 0xe6f38e41, 0xb1c71bf1, 0x52b6e4db, 0x07224424,
+    0x6cf73e85, 0x4f25d89c, 0x782a7d74, 0x10a68dcd, 0x3a868189, 0xd570d2dc, 0x69630745, 0x9542ed86,
+    0x331cd6b2, 0xa84b5b28, 0x07879c9d, 0x38372f64, 0x7185db11, 0x25ba7c83, 0x01061523, 0xe6792f9f,
+    0xe5df07d1, 0x4321b47f, 0x7d2469d8, 0x1a3a4f90, 0x48be29a3, 0x669071af, 0x8ec8dd31, 0x0810bfbf,
+    0x813a06b4, 0x68538345, 0x65865ddc, 0x43a71b8e, 0x78619a56, 0x5a34451d, 0x5bdaa3ed, 0x71edc7e9,
+    0x17ac9a20, 0x78d10bfa, 0x6c1e7f35, 0xd51839d9, 0x240cbc51, 0x33513cc1, 0xd2b4f795, 0xccaa8186,
+    0x0babe682, 0xa33cf164, 0x18c643ea, 0xc1ca105f, 0x9959147a, 0x6d3d94de, 0x0b654fbe, 0xed902ca0,
+    0x7d835cb5, 0x99ba1509, 0x6445c922, 0x495e76c2, 0xf07194bc, 0xa1631d7e, 0x677076a5, 0x89fffe35,
+    0x1a49bcf3, 0x8e6c948a, 0x0144c917, 0x8d93aea1, 0x16f87ddf, 0xc8f25d49, 0x1fb11297, 0x27e750cd,
+    0x2f422da1, 0xdee89a77, 0x1534c643, 0x457b7b8b, 0xaf172f7a, 0x6b9b09d6, 0x33573f7f, 0xf14e15c4,
+    0x526467d5, 0xaf488241, 0x87c3ee0d, 0x33be490c, 0x95aa6e52, 0x43ec242e, 0xd77de99b, 0xd018334f,
+    0x5b78d407, 0x498eb66b, 0xb1279fa8, 0xb38b0ea6, 0x90718376, 0xe325dee2, 0x8e2f2cba, 0xcaa5bdec,
+    0x9d652c56, 0xad68f5cb, 0xa77591af, 0x88e37ee8, 0xf8faa221, 0xfcbbbe47, 0x4f407786, 0xaf393889,
+    0xf444a1d9, 0x15ae1a2f, 0x40aa7097, 0x6f9486ac, 0x29d232a3, 0xe47609e9, 0xe8b631ff, 0xba8565f4,
+    0x11288749, 0x46c9a838, 0xeb1b7cd8, 0xf516bbb1, 0xfb74fda0, 0x010996e6, 0x4c994653, 0x1d889512,
+    0x53dcd9a3, 0xdd074697, 0x1e78e17c, 0x637c98bf, 0x930bb219, 0xcf7f75b0, 0xcb9355fb, 0x9e623009,
+    0xe466d82c, 0x28f968d3, 0xfeb385d9, 0x238e026c, 0xb8ed0560, 0x0c6a027a, 0x3d6fec4b, 0xbb4b2ec2,
+    0xe715031c, 0xeded011d, 0xcdc4d3b9, 0xc456fc96, 0xdd0eea20, 0xb3df8ec9, 0x12351993, 0xd9cbb01c,
+    0x603147a2, 0xcf37d17d, 0xf7fcd9dc, 0xd8556fa3, 0x104c8131, 0x13152774, 0xb4715811, 0x6a72c2c9,
+    0xc5ae37bb, 0xa76ce12a, 0x8150d8f3, 0x2ec29218, 0xa35f0984, 0x48c0647e, 0x0b5ff98c, 0x71893f7b,
+];
+
+/// How files are cut into chunks, as given to borg's `--chunker-params`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkerParams {
+    /// Content defined chunking with a rolling buzhash. Chunks are between
+    /// `2^min_exp` and `2^max_exp` bytes and a chunk ends wherever the lowest
+    /// `mask_bits` bits of the hash of the preceding window are zero.
+    Buzhash {
+        min_exp: u32,
+        max_exp: u32,
+        mask_bits: u32,
+        window_size: u32,
+    },
+
+    /// Chunks of `block_size` bytes, after an optional first chunk of
+    /// `header_size` bytes
+    Fixed { block_size: u32, header_size: u32 },
+}
+
+impl Default for ChunkerParams {
+    fn default() -> Self {
+        DEFAULT_CHUNKER_PARAMS
+    }
+}
+
+impl ChunkerParams {
+    /// Parse chunker params with the same syntax and limits as borg:
+    /// `buzhash,MIN_EXP,MAX_EXP,MASK_BITS,WINDOW_SIZE`, the same without the
+    /// `buzhash` prefix, `fixed,BLOCK_SIZE[,HEADER_SIZE]` or `default`
+    pub fn parse(s: &str) -> Result<Self> {
+        let params: Vec<_> = s.trim().split(',').map(str::trim).collect();
+        let algorithm = params[0].to_lowercase();
+
+        let int = |value: &str| -> Result<u32> {
+            value
+                .parse()
+                .wrap_err_with(|| format!("invalid chunker parameter {value:?}"))
+        };
+
+        match (algorithm.as_str(), params.len()) {
+            ("default", 1) => Ok(DEFAULT_CHUNKER_PARAMS),
+            ("fixed", 2 | 3) => {
+                let block_size = int(params[1])?;
+                let header_size = params.get(2).map_or(Ok(0), |value| int(value))?;
+
+                if block_size < 64 {
+                    bail!("block_size must not be less than 64 bytes");
+                }
+
+                if block_size as usize > MAX_DATA_SIZE || header_size as usize > MAX_DATA_SIZE {
+                    bail!("block_size and header_size must not exceed {MAX_DATA_SIZE} bytes");
+                }
+
+                Ok(Self::Fixed {
+                    block_size,
+                    header_size,
+                })
+            }
+            ("buzhash", 5) | (_, 4) => {
+                let values = params[params.len() - 4..]
+                    .iter()
+                    .map(|value| int(value))
+                    .collect::<Result<Vec<_>>>()?;
+                let [min_exp, max_exp, mask_bits, window_size] = values[..] else {
+                    unreachable!("exactly four parameters were taken")
+                };
+
+                if !(min_exp <= mask_bits && mask_bits <= max_exp) {
+                    bail!("required: chunk_min <= chunk_mask <= chunk_max");
+                }
+
+                if min_exp < 6 {
+                    bail!("min. chunk size exponent must not be less than 6 (2^6 = 64B min. chunk size)");
+                }
+
+                if max_exp > 23 {
+                    bail!("max. chunk size exponent must not be more than 23 (2^23 = 8MiB max. chunk size)");
+                }
+
+                // like borg, which also rules out an empty window
+                if window_size % 2 == 0 {
+                    bail!("window_size must be an uneven (odd) number");
+                }
+
+                if window_size as u64 + (1 << min_exp) + 1 > 1 << max_exp {
+                    bail!("the hash window does not fit between the min. and max. chunk size");
+                }
+
+                Ok(Self::Buzhash {
+                    min_exp,
+                    max_exp,
+                    mask_bits,
+                    window_size,
+                })
+            }
+            _ => Err(eyre!("invalid chunker params {s:?}")),
+        }
+    }
+
+    /// Cut the data read from `reader` into chunks. The seed only affects
+    /// buzhash, borg uses the `chunk_seed` of the repository key.
+    pub fn chunker<R: Read>(&self, reader: R, seed: u32) -> Chunker<R> {
+        match *self {
+            Self::Buzhash {
+                min_exp,
+                max_exp,
+                mask_bits,
+                window_size,
+            } => Chunker::Buzhash(BuzhashChunker::new(
+                reader,
+                seed,
+                1 << min_exp,
+                1 << max_exp,
+                (1 << mask_bits) - 1,
+                window_size as usize,
+            )),
+            Self::Fixed {
+                block_size,
+                header_size,
+            } => Chunker::Fixed(FixedChunker {
+                reader,
+                block_size: block_size as usize,
+                header_size: header_size as usize,
+                started: false,
+                done: false,
+            }),
+        }
+    }
+}
+
+impl ChunkerParams {
+    /// The tuple borg records in the metadata of archives created with these
+    /// params
+    pub fn to_python(self) -> PythonValue {
+        let values = match self {
+            Self::Buzhash {
+                min_exp,
+                max_exp,
+                mask_bits,
+                window_size,
+            } => vec![
+                PythonValue::String("buzhash".to_string()),
+                PythonValue::U32(min_exp),
+                PythonValue::U32(max_exp),
+                PythonValue::U32(mask_bits),
+                PythonValue::U32(window_size),
+            ],
+            Self::Fixed {
+                block_size,
+                header_size,
+            } => vec![
+                PythonValue::String("fixed".to_string()),
+                PythonValue::U32(block_size),
+                PythonValue::U32(header_size),
+            ],
+        };
+
+        PythonValue::Sequence(values)
+    }
+}
+
+impl std::fmt::Display for ChunkerParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Buzhash {
+                min_exp,
+                max_exp,
+                mask_bits,
+                window_size,
+            } => write!(f, "buzhash,{min_exp},{max_exp},{mask_bits},{window_size}"),
+            Self::Fixed {
+                block_size,
+                header_size: 0,
+            } => write!(f, "fixed,{block_size}"),
+            Self::Fixed {
+                block_size,
+                header_size,
+            } => write!(f, "fixed,{block_size},{header_size}"),
+        }
+    }
+}
+
+/// Yields the chunks of a stream, each as an owned buffer
+pub enum Chunker<R> {
+    Buzhash(BuzhashChunker<R>),
+    Fixed(FixedChunker<R>),
+}
+
+impl<R: Read> Iterator for Chunker<R> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Buzhash(chunker) => chunker.next_chunk().transpose(),
+            Self::Fixed(chunker) => chunker.next_chunk().transpose(),
+        }
+    }
+}
+
+/// The table for a seed, which borg XORs into every value
+fn seeded_table(seed: u32) -> [u32; 256] {
+    TABLE_BASE.map(|value| value ^ seed)
+}
+
+/// The buzhash of a whole window
+pub fn buzhash(data: &[u8], seed: u32) -> u32 {
+    buzhash_with(&seeded_table(seed), data)
+}
+
+/// Roll the buzhash of a window one byte forward, removing `remove` at its
+/// start and adding `add` at its end
+pub fn buzhash_update(sum: u32, remove: u8, add: u8, window_size: usize, seed: u32) -> u32 {
+    buzhash_update_with(&seeded_table(seed), sum, remove, add, window_size)
+}
+
+fn buzhash_with(table: &[u32; 256], data: &[u8]) -> u32 {
+    let (last, rest) = data.split_last().expect("buzhash window is not empty");
+
+    rest.iter()
+        .enumerate()
+        .fold(table[*last as usize], |sum, (i, &byte)| {
+            sum ^ table[byte as usize].rotate_left((data.len() - 1 - i) as u32 & 0x1f)
+        })
+}
+
+fn buzhash_update_with(
+    table: &[u32; 256],
+    sum: u32,
+    remove: u8,
+    add: u8,
+    window_size: usize,
+) -> u32 {
+    sum.rotate_left(1)
+        ^ table[remove as usize].rotate_left(window_size as u32 & 0x1f)
+        ^ table[add as usize]
+}
+
+/// Borg's content defined chunker. This follows borg's C implementation
+/// step for step, including how it buffers, since where the buffer ends
+/// decides where chunks that hit the maximum size end.
+pub struct BuzhashChunker<R> {
+    reader: R,
+    table: Box<[u32; 256]>,
+    window_size: usize,
+    chunk_mask: u32,
+    min_size: usize,
+
+    /// Chunks never grow beyond the buffer, so its size is the max chunk size
+    data: Vec<u8>,
+
+    /// Start of the chunk being cut
+    last: usize,
+
+    /// Where the hash window currently starts
+    position: usize,
+
+    /// Bytes in the buffer after `position`
+    remaining: usize,
+
+    eof: bool,
+    done: bool,
+}
+
+impl<R: Read> BuzhashChunker<R> {
+    fn new(
+        reader: R,
+        seed: u32,
+        min_size: usize,
+        max_size: usize,
+        chunk_mask: u32,
+        window_size: usize,
+    ) -> Self {
+        Self {
+            reader,
+            table: Box::new(seeded_table(seed)),
+            window_size,
+            chunk_mask,
+            min_size,
+            data: vec![0; max_size],
+            last: 0,
+            position: 0,
+            remaining: 0,
+            eof: false,
+            done: false,
+        }
+    }
+
+    /// Move the unconsumed data to the start of the buffer and fill up the
+    /// rest
+    fn fill(&mut self) -> Result<()> {
+        self.data
+            .copy_within(self.last..self.position + self.remaining, 0);
+        self.position -= self.last;
+        self.last = 0;
+
+        let start = self.position + self.remaining;
+        if self.eof || start == self.data.len() {
+            return Ok(());
+        }
+
+        let read = read_full(&mut self.reader, &mut self.data[start..])?;
+
+        if read == 0 {
+            self.eof = true;
+        } else {
+            self.remaining += read;
+        }
+
+        Ok(())
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        while self.remaining < self.min_size + self.window_size + 1 && !self.eof {
+            self.fill()?;
+        }
+
+        if self.eof {
+            self.done = true;
+
+            if self.remaining == 0 {
+                return Ok(None);
+            }
+
+            let chunk = self.data[self.position..self.position + self.remaining].to_vec();
+            self.remaining = 0;
+
+            return Ok(Some(chunk));
+        }
+
+        // chunks are never cut before min_size, so the first window that
+        // matters starts there
+        self.position += self.min_size;
+        self.remaining -= self.min_size;
+
+        let mut sum = buzhash_with(
+            &self.table,
+            &self.data[self.position..self.position + self.window_size],
+        );
+
+        while self.remaining > self.window_size && sum & self.chunk_mask != 0 {
+            let stop_at = self.position + self.remaining - self.window_size;
+            let mut p = self.position;
+
+            while p < stop_at && sum & self.chunk_mask != 0 {
+                sum = buzhash_update_with(
+                    &self.table,
+                    sum,
+                    self.data[p],
+                    self.data[p + self.window_size],
+                    self.window_size,
+                );
+                p += 1;
+            }
+
+            self.remaining -= p - self.position;
+            self.position = p;
+
+            if self.remaining <= self.window_size {
+                self.fill()?;
+            }
+        }
+
+        if self.remaining <= self.window_size {
+            self.position += self.remaining;
+            self.remaining = 0;
+        }
+
+        let chunk = self.data[self.last..self.position].to_vec();
+        self.last = self.position;
+
+        Ok(Some(chunk))
+    }
+}
+
+/// Cuts a stream into blocks of the same size, which suits block devices and
+/// disk images better than content defined chunking
+pub struct FixedChunker<R> {
+    reader: R,
+    block_size: usize,
+    header_size: usize,
+    started: bool,
+    done: bool,
+}
+
+impl<R: Read> FixedChunker<R> {
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let size = if !self.started && self.header_size > 0 {
+            self.header_size
+        } else {
+            self.block_size
+        };
+        self.started = true;
+
+        let mut chunk = vec![0; size];
+        let read = read_full(&mut self.reader, &mut chunk)?;
+        chunk.truncate(read);
+
+        if read < size {
+            self.done = true;
+        }
+
+        Ok((read > 0).then_some(chunk))
+    }
+}
+
+/// Read until `buf` is full or the reader is exhausted, returning how much
+/// was read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).wrap_err("read data to chunk"),
+        }
+    }
+
+    Ok(read)
+}
@@ -0,0 +1,23 @@
+use std::path::PathBuf;
+
+use eyre::Result;
+
+use crate::{lock::LockMode, transaction::Transaction, Options, Repository};
+
+/// Free the space taken by deleted and superseded objects. Only segments in
+/// which more than `threshold_percent` of the bytes can be freed are
+/// rewritten, as the hints of the last transaction tell.
+pub fn compact(path: PathBuf, threshold_percent: f64, options: &Options) -> Result<()> {
+    let repository = Repository::open(path, LockMode::Exclusive, options.lock_wait())?;
+
+    if repository.config.append_only {
+        eprintln!("Compaction is not done in append-only mode.");
+        return Ok(());
+    }
+
+    let freed = Transaction::begin(&repository)?.commit_compacted(threshold_percent / 100.0)?;
+
+    eprintln!("Compaction freed about {freed} bytes of repository space.");
+
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use std::io::{ErrorKind, Read, Write};
+
+use eyre::{bail, eyre, Context, Result};
+
+use crate::segment_writer::MAX_DATA_SIZE;
+
+const NONE_ID: [u8; 2] = [0x00, 0x00];
+const LZ4_ID: [u8; 2] = [0x01, 0x00];
+const LZMA_ID: [u8; 2] = [0x02, 0x00];
+const ZSTD_ID: [u8; 2] = [0x03, 0x00];
+const OBFUSCATE_ID: [u8; 2] = [0x04, 0x00];
+
+/// Obfuscated sizes are drawn from a reciprocal distribution, this keeps the
+/// padding from growing without bounds
+const OBFUSCATE_MIN_RANDOM: f64 = 0.0001;
+
+/// How objects are compressed, as given to borg's `--compression`. Borg 1.x
+/// prefixes the compressed data with a two byte type ID, except for zlib,
+/// whose data is recognized by the zlib header itself. The level is not
+/// stored, decompression does not need it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Compression {
+    None,
+
+    /// Borg's default
+    #[default]
+    Lz4,
+
+    /// Level 1 to 22
+    Zstd(i32),
+
+    /// Level 0 to 9
+    Zlib(u32),
+
+    /// Level 0 to 9
+    Lzma(u32),
+
+    /// Trial compress with lz4 and only use the given, slower compression if
+    /// lz4 did not leave the data incompressible
+    Auto(Box<Compression>),
+
+    /// Pad the compressed data with zeros so the object size gives less away
+    /// about the contents. Levels 1 to 6 add a random amount relative to the
+    /// size, levels 110 to 123 up to `2^(level - 100)` bytes.
+    Obfuscate {
+        level: u32,
+        inner: Box<Compression>,
+    },
+}
+
+impl Compression {
+    /// Parse a compression spec with the same syntax and limits as borg:
+    /// `none`, `lz4`, `zstd[,L]`, `zlib[,L]`, `lzma[,L]`, `auto,SPEC` or
+    /// `obfuscate,LEVEL,SPEC`
+    pub fn parse(s: &str) -> Result<Self> {
+        let values: Vec<_> = s.split(',').collect();
+
+        let level = |default: u32, range: std::ops::RangeInclusive<u32>| -> Result<u32> {
+            let level = match values[1..] {
+                [] => default,
+                [level] => level
+                    .parse()
+                    .wrap_err_with(|| format!("invalid compression level {level:?}"))?,
+                _ => bail!("invalid compression spec {s:?}"),
+            };
+
+            if !range.contains(&level) {
+                bail!(
+                    "{} compression level must be between {} and {}",
+                    values[0],
+                    range.start(),
+                    range.end()
+                );
+            }
+
+            Ok(level)
+        };
+
+        match values[0] {
+            "none" | "lz4" if values.len() > 1 => bail!("invalid compression spec {s:?}"),
+            "none" => Ok(Self::None),
+            "lz4" => Ok(Self::Lz4),
+            "zstd" => Ok(Self::Zstd(level(3, 1..=22)? as i32)),
+            "zlib" => Ok(Self::Zlib(level(6, 0..=9)?)),
+            "lzma" => Ok(Self::Lzma(level(6, 0..=9)?)),
+            "auto" => {
+                let inner = Self::parse(&values[1..].join(","))?;
+
+                match inner {
+                    Self::Lz4 | Self::Zstd(_) | Self::Zlib(_) | Self::Lzma(_) => {
+                        Ok(Self::Auto(Box::new(inner)))
+                    }
+                    _ => bail!("auto compression needs lz4, zstd, zlib or lzma, not {inner}"),
+                }
+            }
+            "obfuscate" => {
+                let Some(level) = values.get(1) else {
+                    bail!("obfuscate needs a level and a compression spec");
+                };
+                let level: u32 = level
+                    .parse()
+                    .wrap_err_with(|| format!("invalid obfuscation level {level:?}"))?;
+
+                if !((1..=6).contains(&level) || (110..=123).contains(&level)) {
+                    bail!("obfuscation level must be between 1 and 6 or 110 and 123");
+                }
+
+                let inner = Self::parse(&values[2..].join(","))?;
+                if matches!(inner, Self::Obfuscate { .. }) {
+                    bail!("obfuscate cannot be nested");
+                }
+
+                Ok(Self::Obfuscate {
+                    level,
+                    inner: Box::new(inner),
+                })
+            }
+            "" => bail!("no compression given"),
+            other => bail!("unknown compression {other:?}"),
+        }
+    }
+
+    /// Compress an object, prefixed with the header that tells borg how
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match self {
+            Self::None => with_id(NONE_ID, data.to_vec()),
+            Self::Lz4 => with_id(
+                LZ4_ID,
+                lz4::block::compress(data, None, false).wrap_err("lz4 compress")?,
+            ),
+            Self::Zstd(level) => with_id(
+                ZSTD_ID,
+                zstd::bulk::compress(data, *level).wrap_err("zstd compress")?,
+            ),
+            Self::Zlib(level) => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::new(*level));
+                encoder.write_all(data)?;
+                encoder.finish().wrap_err("zlib compress")?
+            }
+            Self::Lzma(level) => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), *level);
+                encoder.write_all(data)?;
+                with_id(LZMA_ID, encoder.finish().wrap_err("lzma compress")?)
+            }
+            Self::Auto(inner) => auto_compress(inner, data)?,
+            Self::Obfuscate { level, inner } => {
+                let compressed = inner.compress(data)?;
+                let size = compressed.len();
+
+                let padding = if *level <= 6 {
+                    let factor = 0.001 * 10f64.powi(*level as i32);
+                    let random = rand::random::<f64>().max(OBFUSCATE_MIN_RANDOM);
+
+                    (size as f64 * factor / random) as usize
+                } else {
+                    ((1u64 << (level - 100)) as f64 * rand::random::<f64>()) as usize
+                };
+
+                // the padding must not push the object over the size limit
+                let padding = padding.min((MAX_DATA_SIZE - 1024).saturating_sub(size + 4));
+
+                let mut obfuscated = Vec::with_capacity(2 + 4 + size + padding);
+                obfuscated.extend_from_slice(&OBFUSCATE_ID);
+                obfuscated.extend_from_slice(&(size as u32).to_be_bytes());
+                obfuscated.extend_from_slice(&compressed);
+                obfuscated.resize(obfuscated.len() + padding, 0);
+
+                obfuscated
+            }
+        };
+
+        Ok(compressed)
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Lz4 => write!(f, "lz4"),
+            Self::Zstd(level) => write!(f, "zstd,{level}"),
+            Self::Zlib(level) => write!(f, "zlib,{level}"),
+            Self::Lzma(level) => write!(f, "lzma,{level}"),
+            Self::Auto(inner) => write!(f, "auto,{inner}"),
+            Self::Obfuscate { level, inner } => write!(f, "obfuscate,{level},{inner}"),
+        }
+    }
+}
+
+fn with_id(id: [u8; 2], data: Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 2);
+    out.extend_from_slice(&id);
+    out.extend_from_slice(&data);
+
+    out
+}
+
+/// Borg's `auto` heuristic: data that lz4 cannot shrink below 97% is stored
+/// with lz4 or uncompressed, anything else with `inner` unless that is not
+/// at least 1% smaller than what lz4 made of it
+fn auto_compress(inner: &Compression, data: &[u8]) -> Result<Vec<u8>> {
+    let lz4 = Compression::Lz4.compress(data)?;
+
+    if data.is_empty() {
+        return Compression::None.compress(data);
+    }
+
+    let ratio = lz4.len() as f64 / data.len() as f64;
+    if ratio >= 1.0 {
+        return Compression::None.compress(data);
+    }
+
+    if ratio >= 0.97 || *inner == Compression::Lz4 {
+        return Ok(lz4);
+    }
+
+    let compressed = inner.compress(data)?;
+    if (compressed.len() as f64 / lz4.len() as f64) < 0.99 {
+        Ok(compressed)
+    } else {
+        Ok(lz4)
+    }
+}
+
+/// Removes the compression layer from a decrypted object payload, telling
+/// the compression from the header like borg does
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < 2 {
+        bail!("compressed data is too short");
+    }
+
+    let (id, payload) = data.split_at(2);
+
+    match [id[0], id[1]] {
+        LZ4_ID => lz4_decompress(payload),
+        ZSTD_ID => zstd::stream::decode_all(payload).wrap_err("zstd decompress"),
+        NONE_ID => Ok(payload.to_vec()),
+        [cmf, flg] if cmf & 0x0f == 8 && (u16::from(cmf) << 8 | u16::from(flg)) % 31 == 0 => {
+            let mut out = Vec::new();
+            flate2::read::ZlibDecoder::new(data)
+                .read_to_end(&mut out)
+                .wrap_err("zlib decompress")?;
+
+            Ok(out)
+        }
+        LZMA_ID => {
+            let mut out = Vec::new();
+            xz2::read::XzDecoder::new(payload)
+                .read_to_end(&mut out)
+                .wrap_err("lzma decompress")?;
+
+            Ok(out)
+        }
+        OBFUSCATE_ID => {
+            let size = payload
+                .get(..4)
+                .ok_or_else(|| eyre!("obfuscated data is too short"))?;
+            let size = u32::from_be_bytes(size.try_into().unwrap()) as usize;
+            let inner = payload
+                .get(4..4 + size)
+                .ok_or_else(|| eyre!("obfuscated data is shorter than its header says"))?;
+
+            decompress(inner)
+        }
+        id => bail!("unknown compression type {id:02x?}"),
+    }
+}
+
+/// Name the compression of a decrypted object payload from its header, the
+/// same way `decompress` tells them apart. Obfuscated payloads name their
+/// inner compression too.
+pub fn describe(data: &[u8]) -> String {
+    let Some((id, payload)) = data.split_first_chunk::<2>() else {
+        return "truncated".to_string();
+    };
+
+    match *id {
+        NONE_ID => "none".to_string(),
+        LZ4_ID => "lz4".to_string(),
+        ZSTD_ID => "zstd".to_string(),
+        LZMA_ID => "lzma".to_string(),
+        [cmf, flg] if cmf & 0x0f == 8 && (u16::from(cmf) << 8 | u16::from(flg)) % 31 == 0 => {
+            "zlib".to_string()
+        }
+        OBFUSCATE_ID => {
+            let inner = payload
+                .split_first_chunk::<4>()
+                .and_then(|(size, inner)| inner.get(..u32::from_be_bytes(*size) as usize));
+
+            match inner {
+                Some(inner) => format!("obfuscate({})", describe(inner)),
+                None => "obfuscate(truncated)".to_string(),
+            }
+        }
+        [a, b] => format!("unknown {a:02x} {b:02x}"),
+    }
+}
+
+/// Borg's lz4 data does not record the uncompressed size, so this guesses
+/// and tries again with a larger buffer when the guess is too small, which
+/// lz4 reports the same as invalid data
+fn lz4_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut size = data.len() * 3;
+    loop {
+        let mut buffer = vec![0; size];
+        match lz4::block::decompress_to_buffer(data, Some(size as i32), &mut buffer) {
+            Ok(bytes) => {
+                buffer.resize(bytes, 0);
+                return Ok(buffer);
+            }
+            Err(e) => {
+                if matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::InvalidData) {
+                    if size > 2usize.pow(27) {
+                        return Err(e).wrap_err("lz4 decompress");
+                    }
+
+                    size = (size as f64 * 1.5) as usize + 1;
+                } else {
+                    return Err(e).wrap_err("lz4 decompress");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+
+use configparser::ini::Ini;
+use eyre::{bail, eyre, Context, Result};
+
+/// The largest object borg will write into a segment, including its header
+pub const MAX_OBJECT_SIZE: u64 = 20 * 1024 * 1024;
+
+/// Segment offsets are stored as u32, so segments must stay below this size
+pub const MAX_SEGMENT_SIZE_LIMIT: u64 = (1 << 32) - MAX_OBJECT_SIZE;
+
+pub const DEFAULT_SEGMENTS_PER_DIR: u32 = 1000;
+pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 500 * 1024 * 1024;
+
+/// The contents of a repository's `config` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepositoryConfig {
+    pub version: u32,
+    pub segments_per_dir: u32,
+    pub max_segment_size: u64,
+    pub append_only: bool,
+    pub storage_quota: u64,
+    pub additional_free_space: u64,
+    pub id: String,
+
+    /// The base64 key blob for repokey repositories, empty otherwise
+    pub key: String,
+
+    /// Keys of the repository section that we do not know about, kept so that
+    /// writing the config back does not lose them
+    pub extra: BTreeMap<String, String>,
+}
+
+impl RepositoryConfig {
+    /// A config for a new repository with borg's defaults
+    pub fn new(id: String) -> Self {
+        Self {
+            version: 1,
+            segments_per_dir: DEFAULT_SEGMENTS_PER_DIR,
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            append_only: false,
+            storage_quota: 0,
+            additional_free_space: 0,
+            id,
+            key: String::new(),
+            extra: BTreeMap::new(),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut ini = Ini::new();
+        ini.set_multiline(true);
+
+        ini.read(s.to_string())
+            .map_err(|e| eyre!(e))
+            .wrap_err("parse config ini")?;
+
+        let mut section = ini
+            .get_map()
+            .and_then(|mut map| map.remove("repository"))
+            .ok_or_else(|| eyre!("config file has no repository section"))?;
+
+        let mut take = |name: &str| section.remove(name).flatten();
+
+        let version = take("version").ok_or_else(|| eyre!("config file missing version key"))?;
+        let version = version
+            .parse()
+            .wrap_err_with(|| format!("invalid repository version {version:?}"))?;
+
+        let segments_per_dir = match take("segments_per_dir") {
+            Some(value) => value
+                .parse()
+                .wrap_err_with(|| format!("invalid segments_per_dir {value:?}"))?,
+            None => DEFAULT_SEGMENTS_PER_DIR,
+        };
+
+        let max_segment_size = match take("max_segment_size") {
+            Some(value) => parse_size(&value).wrap_err("invalid max_segment_size")?,
+            None => DEFAULT_MAX_SEGMENT_SIZE,
+        };
+
+        let append_only = match take("append_only") {
+            Some(value) => parse_bool(&value).wrap_err("invalid append_only")?,
+            None => false,
+        };
+
+        let storage_quota = match take("storage_quota") {
+            Some(value) => parse_size(&value).wrap_err("invalid storage_quota")?,
+            None => 0,
+        };
+
+        let additional_free_space = match take("additional_free_space") {
+            Some(value) => parse_size(&value).wrap_err("invalid additional_free_space")?,
+            None => 0,
+        };
+
+        let id = take("id").ok_or_else(|| eyre!("config file missing ID key"))?;
+        let key = take("key").unwrap_or_default();
+
+        let extra = section
+            .into_iter()
+            .map(|(name, value)| (name, value.unwrap_or_default()))
+            .collect();
+
+        let config = Self {
+            version,
+            segments_per_dir,
+            max_segment_size,
+            append_only,
+            storage_quota,
+            additional_free_space,
+            id,
+            key,
+            extra,
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Check that the config describes a repository we know how to handle
+    pub fn validate(&self) -> Result<()> {
+        if self.version != 1 {
+            bail!(
+                "unsupported repository version {}, only version 1 repositories are supported",
+                self.version
+            );
+        }
+
+        if self.segments_per_dir == 0 {
+            bail!("segments_per_dir must be greater than 0");
+        }
+
+        if self.max_segment_size >= MAX_SEGMENT_SIZE_LIMIT {
+            bail!("max_segment_size must be less than {MAX_SEGMENT_SIZE_LIMIT}");
+        }
+
+        if self.id.len() != 64 || !self.id.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("repository id {:?} is not 32 bytes of hex", self.id);
+        }
+
+        Ok(())
+    }
+
+    /// Render the config in the same layout borg writes it in
+    pub fn write(&self) -> String {
+        let mut out = String::from("[repository]\n");
+
+        let mut set = |name: &str, value: &str| {
+            let mut lines = value.lines();
+
+            out.push_str(&format!("{name} = {}\n", lines.next().unwrap_or_default()));
+
+            for line in lines {
+                out.push_str(&format!("\t{line}\n"));
+            }
+        };
+
+        set("version", &self.version.to_string());
+        set("segments_per_dir", &self.segments_per_dir.to_string());
+        set("max_segment_size", &self.max_segment_size.to_string());
+        set("append_only", if self.append_only { "1" } else { "0" });
+        set("storage_quota", &self.storage_quota.to_string());
+        set(
+            "additional_free_space",
+            &self.additional_free_space.to_string(),
+        );
+        set("id", &self.id);
+        set("key", &self.key);
+
+        for (name, value) in &self.extra {
+            set(name, value);
+        }
+
+        out.push('\n');
+
+        out
+    }
+}
+
+fn parse_bool(s: &str) -> Result<bool> {
+    match s.trim().to_lowercase().as_str() {
+        "1" | "yes" | "true" | "on" => Ok(true),
+        "0" | "no" | "false" | "off" => Ok(false),
+        _ => bail!("{s:?} is not a boolean"),
+    }
+}
+
+/// Parse a size the way borg does, as a number with an optional decimal
+/// suffix like `500M` or `2G`
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+
+    let (number, multiplier) = match s.chars().last() {
+        Some('K') => (&s[..s.len() - 1], 1_000u64),
+        Some('M') => (&s[..s.len() - 1], 1_000_000),
+        Some('G') => (&s[..s.len() - 1], 1_000_000_000),
+        Some('T') => (&s[..s.len() - 1], 1_000_000_000_000),
+        Some('P') => (&s[..s.len() - 1], 1_000_000_000_000_000),
+        _ => (s, 1),
+    };
+
+    let number: f64 = number
+        .parse()
+        .wrap_err_with(|| format!("{s:?} is not a size"))?;
+
+    Ok((number * multiplier as f64) as u64)
+}
@@ -0,0 +1,641 @@
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    fmt::Write as _,
+    fs::{File, Metadata},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Component, Path, PathBuf},
+};
+
+use eyre::{bail, eyre, Context, Result};
+
+use crate::{
+    cache::{Cache, FilesCacheMode},
+    chunker::ChunkerParams,
+    compression::Compression,
+    key::Key,
+    lock::{hostname, LockMode},
+    msgpack::Bytes,
+    pack_data, parse_location,
+    transaction::Transaction,
+    unpack_data, Archive, Manifest, ManifestArchive, Options, Repository, ISO_FORMAT, MANIFEST_ID,
+};
+
+/// How large the buffered item metadata stream may grow before it is stored
+/// as an object. Borg cuts this stream with a buzhash chunker averaging 128
+/// KiB, so items may span objects. Bork cuts it between items instead, which
+/// borg reads just the same.
+pub const ITEMS_BUFFER_SIZE: usize = 1 << 17;
+
+/// The format `{now}` and `{utcnow}` use without an explicit one
+const PLACEHOLDER_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// A chunk as listed in an item: its ID, size and stored size
+pub type ChunkEntry = ([u8; 32], u32, u32);
+
+/// Back up `paths` into a new archive. `location` is `REPOSITORY::NAME`, the
+/// name may contain placeholders.
+pub fn create(
+    location: &str,
+    paths: &[PathBuf],
+    chunker_params: ChunkerParams,
+    compression: &Compression,
+    files_cache_mode: FilesCacheMode,
+    comment: String,
+    options: &Options,
+) -> Result<()> {
+    let (path, name) = parse_location(location)?;
+    let name = replace_placeholders(
+        &name.ok_or_else(|| eyre!("no archive name given, use REPOSITORY::NAME"))?,
+    )?;
+
+    if name.is_empty() || name.contains('/') {
+        bail!("invalid archive name {name:?}");
+    }
+
+    let start = chrono::Utc::now();
+
+    let repository = Repository::open(path, LockMode::Exclusive, options.lock_wait())?;
+    let mut transaction = Transaction::begin(&repository)?;
+
+    let manifest_data = transaction
+        .get(&MANIFEST_ID)?
+        .ok_or_else(|| eyre!("repository has no manifest"))?;
+    let mut key = Key::load(&repository, &manifest_data)?;
+    key.track_ivs(&repository)?;
+
+    let manifest_plain = unpack_data(&key, &manifest_data)?;
+    let mut manifest = Manifest::decode(&key, &manifest_plain, options.tam.manifest)?;
+
+    if manifest.archives.contains_key(&name) {
+        bail!("archive {name} already exists");
+    }
+
+    let mut cache = Cache::open(&repository, files_cache_mode, options.lock_wait())?;
+    cache.sync(
+        &key,
+        &key.id_hash(&manifest_plain)?,
+        &manifest,
+        &mut transaction,
+    )?;
+
+    let mut writer = ArchiveWriter::new(
+        &key,
+        &mut transaction,
+        &mut cache,
+        chunker_params,
+        compression,
+    )?;
+    for path in paths {
+        writer.add_path(path)?;
+    }
+    writer.flush_items()?;
+
+    let mut archive = Archive {
+        version: 1,
+        name: name.clone(),
+        items: writer.items.iter().map(|id| Bytes(id.to_vec())).collect(),
+        cmdline: std::env::args().collect(),
+        hostname: hostname(),
+        username: username(),
+        time: start.format(ISO_FORMAT).to_string(),
+        time_end: chrono::Utc::now().format(ISO_FORMAT).to_string(),
+        comment,
+        chunker_params: Some(chunker_params.to_python()),
+        size: Some(writer.stats.size),
+        csize: Some(writer.stats.csize),
+        nfiles: Some(writer.stats.nfiles),
+        tam: None,
+    };
+
+    let (id, _) = writer.store(&archive.encode(&key)?)?;
+
+    manifest.archives.insert(
+        name,
+        ManifestArchive {
+            id: Bytes(id.to_vec()),
+            time: archive.time,
+        },
+    );
+    manifest.update_timestamp();
+
+    let manifest_plain = manifest.encode(&key)?;
+    transaction.put(
+        &MANIFEST_ID,
+        &pack_data(&key, compression, &manifest_plain)?,
+    )?;
+    transaction.commit()?;
+
+    cache.commit(
+        &repository,
+        &key,
+        &key.id_hash(&manifest_plain)?,
+        &manifest.timestamp,
+    )?;
+
+    Ok(())
+}
+
+/// Replace the placeholders borg supports in archive names: `{now}` and
+/// `{utcnow}` with an optional strftime format as in `{now:%Y-%m-%d}`,
+/// `{hostname}`, `{user}` and `{pid}`. `{{` and `}}` stand for literal
+/// braces.
+pub fn replace_placeholders(text: &str) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        if let Some(after) = rest.strip_prefix("{{") {
+            out.push('{');
+            rest = after;
+            continue;
+        }
+
+        if let Some(after) = rest.strip_prefix("}}") {
+            out.push('}');
+            rest = after;
+            continue;
+        }
+
+        if rest.starts_with('}') {
+            bail!("single '}}' in {text:?}");
+        }
+
+        let end = rest
+            .find('}')
+            .ok_or_else(|| eyre!("unclosed placeholder in {text:?}"))?;
+        let (name, format) = match rest[1..end].split_once(':') {
+            Some((name, format)) => (name, Some(format)),
+            None => (&rest[1..end], None),
+        };
+        let time_format = format.unwrap_or(PLACEHOLDER_TIME_FORMAT);
+
+        let written = match name {
+            "now" => write!(out, "{}", chrono::Local::now().format(time_format)),
+            "utcnow" => write!(out, "{}", chrono::Utc::now().format(time_format)),
+            "hostname" => write!(out, "{}", hostname()),
+            "user" => write!(out, "{}", username()),
+            "pid" => write!(out, "{}", std::process::id()),
+            _ => bail!("unknown placeholder {{{name}}} in {text:?}"),
+        };
+        written.map_err(|_| eyre!("invalid time format in {text:?}"))?;
+
+        rest = &rest[end + 1..];
+    }
+
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    /// Size of all file contents
+    size: u64,
+
+    /// Stored size of all file contents, counting deduplicated chunks too
+    csize: u64,
+
+    nfiles: u64,
+}
+
+/// Writes the chunks and the item metadata stream of a new archive
+struct ArchiveWriter<'a, 'r> {
+    key: &'a Key,
+    transaction: &'a mut Transaction<'r>,
+    cache: &'a mut Cache,
+    chunker_params: ChunkerParams,
+    compression: &'a Compression,
+
+    /// The files cache is keyed by absolute paths, joined the way borg does
+    /// it without normalizing them
+    cwd: PathBuf,
+
+    /// Encoded items not yet stored
+    buffer: Vec<u8>,
+
+    /// IDs of the stored item metadata objects
+    items: Vec<[u8; 32]>,
+
+    users: HashMap<u32, Option<String>>,
+    groups: HashMap<u32, Option<String>>,
+    stats: Stats,
+}
+
+impl<'a, 'r> ArchiveWriter<'a, 'r> {
+    fn new(
+        key: &'a Key,
+        transaction: &'a mut Transaction<'r>,
+        cache: &'a mut Cache,
+        chunker_params: ChunkerParams,
+        compression: &'a Compression,
+    ) -> Result<Self> {
+        Ok(Self {
+            key,
+            transaction,
+            cache,
+            chunker_params,
+            compression,
+            cwd: std::env::current_dir().wrap_err("get current directory")?,
+            buffer: Vec::new(),
+            items: Vec::new(),
+            users: HashMap::new(),
+            groups: HashMap::new(),
+            stats: Stats::default(),
+        })
+    }
+
+    /// Add a file system entry and, for directories, everything below it.
+    /// Entries that cannot be read are skipped with a warning, like borg does.
+    fn add_path(&mut self, path: &Path) -> Result<()> {
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                eprintln!("warning: {}: {e}", path.display());
+                return Ok(());
+            }
+        };
+
+        let mut item = self.item(path, &metadata);
+        let file_type = metadata.file_type();
+
+        if file_type.is_dir() {
+            let entries = match std::fs::read_dir(path).and_then(|entries| {
+                entries
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect::<std::io::Result<Vec<_>>>()
+            }) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    eprintln!("warning: {}: {e}", path.display());
+                    return Ok(());
+                }
+            };
+
+            // the directory comes first so its children can be restored into it
+            self.add_item(&item)?;
+
+            let mut entries = entries;
+            entries.sort();
+
+            for entry in entries {
+                self.add_path(&entry)?;
+            }
+
+            return Ok(());
+        }
+
+        if file_type.is_file() {
+            let path_hash = self
+                .key
+                .id_hash(self.cwd.join(path).as_os_str().as_bytes())?;
+
+            let chunks = match self.known_chunks(&path_hash, &metadata)? {
+                Some(chunks) => chunks,
+                None => {
+                    let file = match File::open(path) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            eprintln!("warning: {}: {e}", path.display());
+                            return Ok(());
+                        }
+                    };
+
+                    let chunks = self
+                        .add_file(file)
+                        .wrap_err_with(|| format!("back up {}", path.display()))?;
+
+                    if let Some(files) = &mut self.cache.files {
+                        files.memorize(
+                            path_hash,
+                            &metadata,
+                            chunks.iter().map(|&(id, _, _)| id).collect(),
+                        );
+                    }
+
+                    chunks
+                }
+            };
+
+            item.size = Some(chunks.iter().map(|&(_, size, _)| size as u64).sum());
+            item.chunks = Some(chunks);
+            self.stats.nfiles += 1;
+        } else if file_type.is_symlink() {
+            match std::fs::read_link(path) {
+                Ok(target) => item.source = Some(target.as_os_str().as_bytes().to_vec()),
+                Err(e) => {
+                    eprintln!("warning: {}: {e}", path.display());
+                    return Ok(());
+                }
+            }
+        } else if file_type.is_block_device() || file_type.is_char_device() {
+            item.rdev = Some(metadata.rdev());
+        } else if !file_type.is_fifo() {
+            // sockets cannot be restored, borg skips them too
+            return Ok(());
+        }
+
+        self.add_item(&item)
+    }
+
+    /// The metadata every item has, whatever its type
+    fn item(&mut self, path: &Path, metadata: &Metadata) -> Item {
+        let uid = metadata.uid();
+        let gid = metadata.gid();
+
+        Item {
+            path: item_path(path),
+            mode: metadata.mode(),
+            uid,
+            gid,
+            user: self
+                .users
+                .entry(uid)
+                .or_insert_with(|| user_name(uid))
+                .clone(),
+            group: self
+                .groups
+                .entry(gid)
+                .or_insert_with(|| group_name(gid))
+                .clone(),
+            mtime: metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+            ctime: metadata.ctime() * 1_000_000_000 + metadata.ctime_nsec(),
+            source: None,
+            rdev: None,
+            size: None,
+            chunks: None,
+        }
+    }
+
+    /// The chunks of a file the files cache knows to be unchanged, as long as
+    /// the chunks cache still has all of them
+    fn known_chunks(
+        &mut self,
+        path_hash: &[u8; 32],
+        metadata: &Metadata,
+    ) -> Result<Option<Vec<ChunkEntry>>> {
+        let Some(ids) = self
+            .cache
+            .files
+            .as_mut()
+            .and_then(|files| files.lookup(path_hash, metadata))
+        else {
+            return Ok(None);
+        };
+
+        if !ids.iter().all(|id| self.cache.chunks.get(id).is_some()) {
+            return Ok(None);
+        }
+
+        let mut chunks = Vec::with_capacity(ids.len());
+        for id in ids {
+            let entry = self.cache.chunks.incref(&id).unwrap();
+
+            self.stats.size += entry.size as u64;
+            self.stats.csize += entry.csize as u64;
+
+            chunks.push((id, entry.size, entry.csize));
+        }
+
+        Ok(Some(chunks))
+    }
+
+    /// Chunk a file and store the chunks the repository does not have yet
+    fn add_file(&mut self, file: File) -> Result<Vec<ChunkEntry>> {
+        let mut chunks = Vec::new();
+
+        for chunk in self.chunker_params.chunker(file, self.key.chunk_seed()) {
+            let chunk = chunk?;
+            let (id, csize) = self.store(&chunk)?;
+
+            self.stats.size += chunk.len() as u64;
+            self.stats.csize += csize as u64;
+
+            chunks.push((id, chunk.len() as u32, csize));
+        }
+
+        Ok(chunks)
+    }
+
+    /// Store an object unless the chunks cache knows it already, returning
+    /// its ID and stored size
+    fn store(&mut self, data: &[u8]) -> Result<([u8; 32], u32)> {
+        let id = self.key.id_hash(data)?;
+
+        if let Some(entry) = self.cache.chunks.incref(&id) {
+            return Ok((id, entry.csize));
+        }
+
+        let packed = pack_data(self.key, self.compression, data)?;
+        self.transaction.put(&id, &packed)?;
+        self.cache
+            .chunks
+            .add(&id, 1, data.len() as u32, packed.len() as u32);
+
+        Ok((id, packed.len() as u32))
+    }
+
+    fn add_item(&mut self, item: &Item) -> Result<()> {
+        item.encode(&mut self.buffer)?;
+
+        if self.buffer.len() >= ITEMS_BUFFER_SIZE {
+            self.flush_items()?;
+        }
+
+        Ok(())
+    }
+
+    /// Store the buffered items
+    fn flush_items(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let buffer = std::mem::take(&mut self.buffer);
+        let (id, _) = self.store(&buffer)?;
+        self.items.push(id);
+
+        Ok(())
+    }
+}
+
+/// The metadata of one file system entry, as borg's `Item` stores it
+#[derive(Debug)]
+struct Item {
+    path: Vec<u8>,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    user: Option<String>,
+    group: Option<String>,
+
+    /// Nanoseconds since the epoch
+    mtime: i64,
+    ctime: i64,
+
+    /// Target of a symlink
+    source: Option<Vec<u8>>,
+
+    /// Device number of a block or character device
+    rdev: Option<u64>,
+
+    size: Option<u64>,
+    chunks: Option<Vec<ChunkEntry>>,
+}
+
+impl Item {
+    /// Append the item to an item metadata stream. Borg writes items as maps
+    /// with sorted keys, paths and names as msgpack raw strings of whatever
+    /// bytes the file system uses, and chunks as `[id, size, csize]` arrays.
+    fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        use rmp::encode::*;
+
+        let optional = [
+            self.chunks.is_some(),
+            self.rdev.is_some(),
+            self.size.is_some(),
+            self.source.is_some(),
+        ];
+        write_map_len(out, 8 + optional.iter().filter(|&&x| x).count() as u32)?;
+
+        if let Some(chunks) = &self.chunks {
+            write_str(out, "chunks")?;
+            write_array_len(out, chunks.len() as u32)?;
+            for (id, size, csize) in chunks {
+                write_array_len(out, 3)?;
+                write_bin(out, id)?;
+                write_uint(out, *size as u64)?;
+                write_uint(out, *csize as u64)?;
+            }
+        }
+
+        write_str(out, "ctime")?;
+        write_sint(out, self.ctime)?;
+
+        write_str(out, "gid")?;
+        write_uint(out, self.gid as u64)?;
+
+        write_str(out, "group")?;
+        write_name(out, self.group.as_deref())?;
+
+        write_str(out, "mode")?;
+        write_uint(out, self.mode as u64)?;
+
+        write_str(out, "mtime")?;
+        write_sint(out, self.mtime)?;
+
+        write_str(out, "path")?;
+        write_raw(out, &self.path)?;
+
+        if let Some(rdev) = self.rdev {
+            write_str(out, "rdev")?;
+            write_uint(out, rdev)?;
+        }
+
+        if let Some(size) = self.size {
+            write_str(out, "size")?;
+            write_uint(out, size)?;
+        }
+
+        if let Some(source) = &self.source {
+            write_str(out, "source")?;
+            write_raw(out, source)?;
+        }
+
+        write_str(out, "uid")?;
+        write_uint(out, self.uid as u64)?;
+
+        write_str(out, "user")?;
+        write_name(out, self.user.as_deref())?;
+
+        Ok(())
+    }
+}
+
+/// Write bytes as a msgpack raw string, which is what borg 1.x writes for
+/// every string, valid utf-8 or not
+fn write_raw(out: &mut Vec<u8>, data: &[u8]) -> Result<()> {
+    rmp::encode::write_str_len(out, data.len() as u32)?;
+    out.extend_from_slice(data);
+
+    Ok(())
+}
+
+/// Write a user or group name, or nil if the ID has no name
+fn write_name(out: &mut Vec<u8>, name: Option<&str>) -> Result<()> {
+    match name {
+        Some(name) => rmp::encode::write_str(out, name)?,
+        None => rmp::encode::write_nil(out)?,
+    }
+
+    Ok(())
+}
+
+/// The path an entry is stored under: normalized, relative and without
+/// leading `..`, the same as borg's `make_path_safe`
+fn item_path(path: &Path) -> Vec<u8> {
+    let mut parts = Vec::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.as_bytes()),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+
+    if parts.is_empty() {
+        return b".".to_vec();
+    }
+
+    parts.join(&b'/')
+}
+
+/// The name of the user running bork, found the same way python's
+/// `getpass.getuser` does
+fn username() -> String {
+    ["LOGNAME", "USER", "LNAME", "USERNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok().filter(|user| !user.is_empty()))
+        .or_else(|| user_name(unsafe { libc::getuid() }))
+        .unwrap_or_default()
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    // SAFETY: getpwuid returns null or a pointer to a static entry, which is
+    // copied before anything else can overwrite it
+    unsafe {
+        let entry = libc::getpwuid(uid);
+        if entry.is_null() {
+            return None;
+        }
+
+        CStr::from_ptr((*entry).pw_name)
+            .to_str()
+            .ok()
+            .map(str::to_string)
+    }
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    // SAFETY: as for getpwuid above
+    unsafe {
+        let entry = libc::getgrgid(gid);
+        if entry.is_null() {
+            return None;
+        }
+
+        CStr::from_ptr((*entry).gr_name)
+            .to_str()
+            .ok()
+            .map(str::to_string)
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind, Read, Write},
+    path::PathBuf,
+};
+
+use eyre::{bail, eyre, Context, Result};
+
+use crate::{
+    compression::{decompress, describe},
+    item_stream,
+    key::{hex_decode, hex_lower, Key, KeyType},
+    lock::LockMode,
+    msgpack, parse_location,
+    transaction::{RepositoryState, Transaction},
+    unpack_data, Archive, LogEntry, Manifest, Options, Repository, ENTRY_HEADER_SIZE,
+    ENTRY_KEY_HEADER_SIZE, MANIFEST_ID, SEGMENT_MAGIC, TAG_COMMIT, TAG_DELETE, TAG_PUT,
+};
+
+/// Bytes per line of a hex dump
+const HEX_DUMP_WIDTH: usize = 16;
+
+/// The kinds of segment entries, to pick which ones a dump shows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EntryTag {
+    Put,
+    Delete,
+    Commit,
+}
+
+/// How the payload of PUT entries is shown in a dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PayloadDump {
+    /// The payload as stored, compressed and encrypted
+    Hex,
+
+    /// The plain payload decoded as msgpack, shown as JSON
+    Msgpack,
+}
+
+/// Print every entry of a segment with its offset and whether its CRC is
+/// intact. PUT entries also show how their payload is encrypted and
+/// compressed, which needs the key in encrypted repositories. Only entries
+/// with one of `tags`, if any are given, and whose key starts with
+/// `key_prefix` are shown. Unlike the other readers, a CRC mismatch does
+/// not stop the dump, only damaged framing does.
+pub fn dump_segment(
+    path: PathBuf,
+    segment: u32,
+    tags: &[EntryTag],
+    key_prefix: Option<&str>,
+    payload: Option<PayloadDump>,
+    out: &mut impl Write,
+    options: &Options,
+) -> Result<()> {
+    let key_prefix = key_prefix.map(str::to_lowercase);
+    if let Some(prefix) = &key_prefix {
+        if !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("key prefix {prefix:?} is not hex");
+        }
+    }
+
+    let repository = Repository::open(path, LockMode::Shared, options.lock_wait())?;
+
+    let segment_path = repository
+        .segments()?
+        .into_iter()
+        .find(|candidate| candidate.id == segment)
+        .ok_or_else(|| eyre!("segment {segment} not found"))?
+        .path;
+
+    let file =
+        File::open(&segment_path).wrap_err_with(|| format!("open {}", segment_path.display()))?;
+    let length = file.metadata()?.len();
+    let mut data = BufReader::new(file);
+
+    let mut magic = [0; 8];
+    data.read_exact(&mut magic)
+        .ok()
+        .filter(|_| &magic == SEGMENT_MAGIC)
+        .ok_or_else(|| eyre!("invalid segment magic"))?;
+
+    // the key is loaded for the first PUT shown, so dumping only DELETEs
+    // and COMMITs needs no passphrase
+    let mut key = None;
+
+    let mut offset = SEGMENT_MAGIC.len() as u64;
+    loop {
+        let mut header = [0; ENTRY_HEADER_SIZE as usize];
+        match data.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && offset == length => break,
+            Err(_) => {
+                writeln!(out, "{offset:>10} truncated entry header, end of dump")?;
+                break;
+            }
+        }
+
+        let crc = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let size = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let tag = header[8];
+
+        if size < ENTRY_HEADER_SIZE || offset + size as u64 > length {
+            writeln!(out, "{offset:>10} invalid entry size {size}, end of dump")?;
+            break;
+        }
+
+        let mut body = vec![0; (size - ENTRY_HEADER_SIZE) as usize];
+        data.read_exact(&mut body)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(&body);
+        let computed = hasher.finalize();
+
+        let crc_status = if computed == crc {
+            "crc ok".to_string()
+        } else {
+            format!("crc MISMATCH (stored {crc:08x}, computed {computed:08x})")
+        };
+
+        let entry_offset = offset;
+        offset += size as u64;
+
+        let (entry_tag, entry) = match tag {
+            TAG_PUT if size >= ENTRY_KEY_HEADER_SIZE => (
+                EntryTag::Put,
+                LogEntry::Put {
+                    key: body[..32].try_into().unwrap(),
+                    data: body[32..].to_vec(),
+                },
+            ),
+            TAG_DELETE if size == ENTRY_KEY_HEADER_SIZE => (
+                EntryTag::Delete,
+                LogEntry::Delete {
+                    key: body[..32].try_into().unwrap(),
+                },
+            ),
+            TAG_COMMIT if size == ENTRY_HEADER_SIZE => (EntryTag::Commit, LogEntry::Commit),
+            _ => {
+                writeln!(
+                    out,
+                    "{entry_offset:>10} {crc_status} invalid entry with tag {tag} and size {size}"
+                )?;
+                continue;
+            }
+        };
+
+        if !tags.is_empty() && !tags.contains(&entry_tag) {
+            continue;
+        }
+
+        if let Some(prefix) = &key_prefix {
+            let matches = match &entry {
+                LogEntry::Put { key, .. } | LogEntry::Delete { key } => {
+                    hex_lower(key).starts_with(prefix.as_str())
+                }
+                LogEntry::Commit => false,
+            };
+
+            if !matches {
+                continue;
+            }
+        }
+
+        writeln!(out, "{entry_offset:>10} {crc_status} {entry:?}")?;
+
+        if let LogEntry::Put { data, .. } = &entry {
+            dump_payload(&repository, &mut key, data, payload, out)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the encryption and compression header of a PUT payload, and the
+/// payload itself if asked to
+fn dump_payload(
+    repository: &Repository,
+    key: &mut Option<Key>,
+    data: &[u8],
+    payload: Option<PayloadDump>,
+    out: &mut impl Write,
+) -> Result<()> {
+    let key_type = match data.first().map(|byte| KeyType::from_byte(*byte)) {
+        Some(Ok(key_type)) => key_type,
+        Some(Err(e)) => {
+            writeln!(out, "           {e:#}")?;
+            return Ok(());
+        }
+        None => {
+            writeln!(out, "           empty payload")?;
+            return Ok(());
+        }
+    };
+
+    let mut header = format!("{key_type:?}");
+    if matches!(key_type, KeyType::Keyfile | KeyType::Repokey) && data.len() >= 41 {
+        header.push_str(&format!(
+            ", mac {}, iv {}",
+            hex_lower(&data[1..33]),
+            u64::from_be_bytes(data[33..41].try_into().unwrap())
+        ));
+    }
+
+    if key.is_none() {
+        *key = Some(Key::load(repository, data)?);
+    }
+
+    let compressed = key.as_ref().unwrap().decrypt(data);
+    match &compressed {
+        Ok(compressed) => header.push_str(&format!(", {}", describe(compressed))),
+        Err(e) => header.push_str(&format!(", {e:#}")),
+    }
+
+    writeln!(out, "           {header}")?;
+
+    match payload {
+        Some(PayloadDump::Hex) => hex_dump(data, out)?,
+        Some(PayloadDump::Msgpack) => {
+            let plain = compressed.and_then(|compressed| decompress(&compressed));
+
+            match plain.and_then(|plain| decode_msgpack(&plain)) {
+                Ok(values) => {
+                    for value in values {
+                        for line in json_text(&value, b"  ")?.lines() {
+                            writeln!(out, "           {line}")?;
+                        }
+                    }
+                }
+                Err(e) => writeln!(out, "           payload is not msgpack: {e:#}")?,
+            }
+        }
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Every msgpack value in `data`, which holds several of them for item
+/// metadata
+fn decode_msgpack(mut data: &[u8]) -> Result<Vec<serde_json::Value>> {
+    let mut values = Vec::new();
+    while !data.is_empty() {
+        values.push(msgpack::to_json(&mut data)?);
+    }
+
+    Ok(values)
+}
+
+/// The classic hex dump layout, offsets on the left and printable ASCII on
+/// the right
+fn hex_dump(data: &[u8], out: &mut impl Write) -> Result<()> {
+    for (line, bytes) in data.chunks(HEX_DUMP_WIDTH).enumerate() {
+        let hex: Vec<_> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        let ascii: String = bytes
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+
+        writeln!(
+            out,
+            "           {:08x}  {:<width$}  |{ascii}|",
+            line * HEX_DUMP_WIDTH,
+            hex.join(" "),
+            width = HEX_DUMP_WIDTH * 3 - 1
+        )?;
+    }
+
+    Ok(())
+}
+
+/// An object ID given as 64 hex digits
+fn parse_id(id: &str) -> Result<[u8; 32]> {
+    hex_decode(id)
+        .and_then(|id| id.try_into().ok())
+        .ok_or_else(|| eyre!("object ID {id:?} is not 64 hex digits"))
+}
+
+/// Write the object with the given ID to `out`, either as stored or with
+/// its encryption and compression removed
+pub fn get_obj(
+    path: PathBuf,
+    id: &str,
+    unpack: bool,
+    out: &mut impl Write,
+    options: &Options,
+) -> Result<()> {
+    let id = parse_id(id)?;
+
+    let repository = Repository::open(path, LockMode::Shared, options.lock_wait())?;
+    let state = RepositoryState::load(&repository)?;
+
+    let mut data = state
+        .get(&repository, &id)?
+        .ok_or_else(|| eyre!("object {} not found", hex_lower(&id)))?;
+
+    if unpack {
+        let key = Key::load(&repository, &data)?;
+        data = unpack_data(&key, &data)?;
+    }
+
+    out.write_all(&data)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Store `data` as is under the given ID, replacing any object with that
+/// ID. Nothing checks that the data is a valid object or matches the ID.
+pub fn put_obj(path: PathBuf, id: &str, data: &[u8], options: &Options) -> Result<()> {
+    let id = parse_id(id)?;
+
+    let repository = Repository::open(path, LockMode::Exclusive, options.lock_wait())?;
+
+    let mut transaction = Transaction::begin(&repository)?;
+    transaction.put(&id, data)?;
+    transaction.commit()?;
+
+    eprintln!("object {} put.", hex_lower(&id));
+
+    Ok(())
+}
+
+/// Delete the objects with the given IDs. IDs that are not in the
+/// repository are reported and skipped, the rest are deleted in one
+/// transaction.
+pub fn delete_obj(path: PathBuf, ids: &[String], options: &Options) -> Result<()> {
+    let ids = ids
+        .iter()
+        .map(|id| parse_id(id))
+        .collect::<Result<Vec<_>>>()?;
+
+    let repository = Repository::open(path, LockMode::Exclusive, options.lock_wait())?;
+
+    let mut transaction = Transaction::begin(&repository)?;
+    let mut deleted = false;
+
+    for id in ids {
+        if !transaction.contains(&id) {
+            eprintln!("object {} not found.", hex_lower(&id));
+            continue;
+        }
+
+        transaction.delete(&id)?;
+        deleted = true;
+
+        eprintln!("object {} deleted.", hex_lower(&id));
+    }
+
+    if deleted {
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+/// The repository key with the unpacked manifest, whose TAM is checked
+fn load_manifest(
+    repository: &Repository,
+    state: &RepositoryState,
+    options: &Options,
+) -> Result<(Key, Manifest, Vec<u8>)> {
+    let data = state
+        .get(repository, &MANIFEST_ID)?
+        .ok_or_else(|| eyre!("repository has no manifest"))?;
+
+    let key = Key::load(repository, &data)?;
+    let plain = unpack_data(&key, &data)?;
+    let manifest = Manifest::decode(&key, &plain, options.tam.manifest)?;
+
+    Ok((key, manifest, plain))
+}
+
+/// `value` as indented JSON, with DEL escaped like Python's `json` does,
+/// as it marks hex values
+fn json_text(value: &serde_json::Value, indent: &[u8]) -> Result<String> {
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(indent);
+    let mut text = Vec::new();
+    let mut serializer = serde_json::Serializer::with_formatter(&mut text, formatter);
+
+    serde::Serialize::serialize(value, &mut serializer)?;
+
+    Ok(String::from_utf8(text)?.replace('\x7f', "\\u007f"))
+}
+
+/// Write `value` as JSON indented by four spaces, the way borg dumps it
+fn write_json(value: &serde_json::Value, out: &mut impl Write) -> Result<()> {
+    writeln!(out, "{}", json_text(value, b"    ")?)?;
+
+    Ok(())
+}
+
+/// Write the manifest as JSON, with every key it holds rather than only the
+/// ones bork knows about
+pub fn dump_manifest(path: PathBuf, out: &mut impl Write, options: &Options) -> Result<()> {
+    let repository = Repository::open(path, LockMode::Shared, options.lock_wait())?;
+    let state = RepositoryState::load(&repository)?;
+
+    let (_, _, plain) = load_manifest(&repository, &state, options)?;
+
+    write_json(&msgpack::to_json(&mut &plain[..])?, out)
+}
+
+/// Write an archive as JSON in the layout of `borg debug dump-archive`: its
+/// name, its entry in the manifest, its metadata and all of its items, each
+/// with every key it holds
+pub fn dump_archive(location: &str, out: &mut impl Write, options: &Options) -> Result<()> {
+    let (path, Some(name)) = parse_location(location)? else {
+        bail!("no archive given in {location:?}");
+    };
+
+    let repository = Repository::open(path, LockMode::Shared, options.lock_wait())?;
+    let state = RepositoryState::load(&repository)?;
+
+    let (key, manifest, manifest_plain) = load_manifest(&repository, &state, options)?;
+
+    let id: [u8; 32] = manifest
+        .archives
+        .get(&name)
+        .ok_or_else(|| eyre!("archive {name} not found"))?
+        .id
+        .0
+        .as_slice()
+        .try_into()
+        .map_err(|_| eyre!("manifest lists an invalid ID for archive {name}"))?;
+
+    let manifest_entry = msgpack::to_json(&mut &manifest_plain[..])?
+        .get("archives")
+        .and_then(|archives| archives.get(&name))
+        .cloned()
+        .unwrap_or_default();
+
+    let data = state
+        .get(&repository, &id)?
+        .ok_or_else(|| eyre!("archive object {} not found", hex_lower(&id)))?;
+    let plain = unpack_data(&key, &data)?;
+    let archive = Archive::decode(&key, &plain, options.tam.archives)?;
+
+    let stream = item_stream(
+        &archive.items,
+        |item_id| {
+            let data = state
+                .get(&repository, item_id)?
+                .ok_or_else(|| eyre!("item metadata object {} not found", hex_lower(item_id)))?;
+            unpack_data(&key, &data)
+        },
+        Err,
+    )?;
+
+    let items = decode_msgpack(&stream).wrap_err("decode items")?;
+
+    let dump = serde_json::json!({
+        "_name": name,
+        "_manifest_entry": manifest_entry,
+        "_meta": msgpack::to_json(&mut &plain[..])?,
+        "_items": items,
+    });
+
+    write_json(&dump, out)
+}
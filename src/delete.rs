@@ -0,0 +1,264 @@
+use std::io::{BufRead, Write};
+
+use eyre::{bail, eyre, Context, Result};
+
+use crate::{
+    cache::{self, archive_chunk_index, Cache, FilesCacheMode},
+    compression::Compression,
+    glob::glob_match,
+    key::{destroy_security_info, Key},
+    lock::LockMode,
+    pack_data, parse_location,
+    transaction::{RepositoryState, Transaction},
+    unpack_data, Manifest, Options, Repository, MANIFEST_ID,
+};
+
+/// Lets scripts confirm deleting a whole repository without a prompt
+const DELETE_CONFIRMATION_ENV: &str = "BORG_DELETE_I_KNOW_WHAT_I_AM_DOING";
+
+/// Delete archives, or the whole repository if `location` names no archive
+/// and no archives are selected otherwise. Archives are selected by name,
+/// in the location or in `archives`, and by matching `glob_archives`. With
+/// `keep_security_info`, what is known about a deleted repository is kept
+/// so it is not taken for an unknown one should it ever come back.
+pub fn delete(
+    location: &str,
+    archives: &[String],
+    glob_archives: Option<&str>,
+    dry_run: bool,
+    keep_security_info: bool,
+    options: &Options,
+) -> Result<()> {
+    let (path, name) = parse_location(location)?;
+
+    let mut names: Vec<_> = name.into_iter().collect();
+    names.extend(archives.iter().cloned());
+
+    let repository = Repository::open(path, LockMode::Exclusive, options.lock_wait())?;
+
+    if names.is_empty() && glob_archives.is_none() {
+        return delete_repository(repository, dry_run, keep_security_info, options);
+    }
+
+    delete_archives(&repository, &names, glob_archives, dry_run, options)
+}
+
+fn delete_archives(
+    repository: &Repository,
+    names: &[String],
+    glob_archives: Option<&str>,
+    dry_run: bool,
+    options: &Options,
+) -> Result<()> {
+    let mut transaction = Transaction::begin(repository)?;
+
+    let manifest_data = transaction
+        .get(&MANIFEST_ID)?
+        .ok_or_else(|| eyre!("repository has no manifest"))?;
+    let mut key = Key::load(repository, &manifest_data)?;
+    key.track_ivs(repository)?;
+
+    let manifest_plain = unpack_data(&key, &manifest_data)?;
+    let mut manifest = Manifest::decode(&key, &manifest_plain, options.tam.manifest)?;
+
+    let mut selected = Vec::new();
+    for name in names {
+        if manifest.archives.contains_key(name) {
+            selected.push(name.clone());
+        } else {
+            eprintln!("Archive {name} not found.");
+        }
+    }
+
+    if let Some(pattern) = glob_archives {
+        let mut matching: Vec<_> = manifest
+            .archives
+            .iter()
+            .filter(|(name, _)| glob_match(pattern, name) && !selected.contains(name))
+            .collect();
+        matching.sort_by(|(_, a), (_, b)| a.time.cmp(&b.time));
+
+        selected.extend(matching.into_iter().map(|(name, _)| name.clone()));
+    }
+
+    if selected.is_empty() {
+        bail!("no archives to delete");
+    }
+
+    let mut cache = Cache::open(repository, FilesCacheMode::disabled(), options.lock_wait())?;
+    cache.sync(
+        &key,
+        &key.id_hash(&manifest_plain)?,
+        &manifest,
+        &mut transaction,
+    )?;
+
+    let count = selected.len();
+    for (i, name) in selected.iter().enumerate() {
+        let archive = &manifest.archives[name];
+
+        if dry_run {
+            eprintln!(
+                "Would delete archive: {} ({}/{count})",
+                archive.format(name),
+                i + 1
+            );
+            continue;
+        }
+
+        eprintln!(
+            "Deleting archive: {} ({}/{count})",
+            archive.format(name),
+            i + 1
+        );
+
+        delete_archive(&key, &mut transaction, &mut cache, &mut manifest, name)?;
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+
+    manifest.update_timestamp();
+
+    let manifest_plain = manifest.encode(&key)?;
+    transaction.put(
+        &MANIFEST_ID,
+        &pack_data(&key, &Compression::default(), &manifest_plain)?,
+    )?;
+    transaction.commit()?;
+
+    cache.commit(
+        repository,
+        &key,
+        &key.id_hash(&manifest_plain)?,
+        &manifest.timestamp,
+    )?;
+
+    Ok(())
+}
+
+/// Remove an archive from the manifest along with every reference it holds,
+/// deleting the objects nothing else references. The manifest is not
+/// written.
+pub fn delete_archive(
+    key: &Key,
+    transaction: &mut Transaction,
+    cache: &mut Cache,
+    manifest: &mut Manifest,
+    name: &str,
+) -> Result<()> {
+    let archive = manifest
+        .archives
+        .remove(name)
+        .ok_or_else(|| eyre!("archive {name} not found"))?;
+
+    let id: [u8; 32] = archive
+        .id
+        .0
+        .as_slice()
+        .try_into()
+        .map_err(|_| eyre!("manifest entry for archive {name} has an invalid ID"))?;
+
+    let references = archive_chunk_index(key, transaction, &id)
+        .wrap_err_with(|| format!("read archive {name}"))?;
+
+    for (chunk_id, entry) in references.iter() {
+        if cache.chunks.decref(&chunk_id, entry.refcount) == Some(0) {
+            transaction.delete(&chunk_id)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete the repository, its cache and, unless asked to keep it, the
+/// security info, after listing what would be lost and asking for a `YES`
+fn delete_repository(
+    mut repository: Repository,
+    dry_run: bool,
+    keep_security_info: bool,
+    options: &Options,
+) -> Result<()> {
+    let mut message = Vec::new();
+
+    // a repository too damaged to read its manifest can still be deleted
+    let manifest = RepositoryState::load(&repository)
+        .and_then(|state| state.get(&repository, &MANIFEST_ID))
+        .ok()
+        .flatten()
+        .and_then(|manifest_data| {
+            let key = Key::load(&repository, &manifest_data).ok()?;
+            let plain = unpack_data(&key, &manifest_data).ok()?;
+
+            Manifest::decode(&key, &plain, options.tam.manifest).ok()
+        });
+
+    match manifest {
+        Some(manifest) => {
+            message.push("You requested to completely DELETE the repository *including* all archives it contains:".to_string());
+
+            let mut archives: Vec<_> = manifest.archives.iter().collect();
+            archives.sort_by(|(_, a), (_, b)| a.time.cmp(&b.time));
+
+            for (name, archive) in archives {
+                message.push(archive.format(name));
+            }
+        }
+        None => {
+            message.push("You requested to completely DELETE the repository *including* all archives it may contain.".to_string());
+            message.push(
+                "This repository seems to have no manifest, so we can't tell anything about its contents.".to_string(),
+            );
+        }
+    }
+
+    message.push("Type 'YES' if you understand this and want to continue: ".to_string());
+
+    if !confirm(&message.join("\n"), DELETE_CONFIRMATION_ENV)? {
+        bail!("Aborting.");
+    }
+
+    if dry_run {
+        eprintln!("Would delete repository.");
+        eprintln!(
+            "Would {} security info.",
+            if keep_security_info { "keep" } else { "delete" }
+        );
+
+        return Ok(());
+    }
+
+    repository.destroy()?;
+    eprintln!("Repository deleted.");
+
+    if !keep_security_info {
+        destroy_security_info(&repository)?;
+    }
+
+    cache::destroy(&repository)?;
+    eprintln!("Cache deleted.");
+
+    Ok(())
+}
+
+/// Ask for a `YES` on stdin, unless the environment variable `env` already
+/// gives one the way borg allows it
+pub fn confirm(message: &str, env: &str) -> Result<bool> {
+    if let Ok(answer) = std::env::var(env) {
+        eprintln!("{message}{answer} (from {env})");
+
+        return Ok(answer == "YES");
+    }
+
+    eprint!("{message}");
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .wrap_err("read answer")?;
+
+    Ok(answer.trim_end_matches(['\r', '\n']) == "YES")
+}
@@ -0,0 +1,70 @@
+/// Whether `name` matches a shell pattern as borg's `--glob-archives` takes
+/// them: `*` matches any run of characters, `?` any single character and
+/// `[...]` any character of a set, negated by a leading `!`. Everything else
+/// matches itself, including a `[` that is never closed. The pattern must
+/// match the whole name.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    matches(&pattern, &name)
+}
+
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    let Some((&first, rest)) = pattern.split_first() else {
+        return name.is_empty();
+    };
+
+    match first {
+        '*' => (0..=name.len()).any(|skip| matches(rest, &name[skip..])),
+        '?' => !name.is_empty() && matches(rest, &name[1..]),
+        '[' => match character_set(rest) {
+            Some((set, negated, rest)) => match name.split_first() {
+                Some((c, name)) => set_contains(set, *c) != negated && matches(rest, name),
+                None => false,
+            },
+            None => name.first() == Some(&'[') && matches(rest, &name[1..]),
+        },
+        c => name.first() == Some(&c) && matches(rest, &name[1..]),
+    }
+}
+
+/// Split off the set of a `[...]` pattern, whose opening bracket was already
+/// taken. A `]` right at the start is part of the set, like in python's
+/// `fnmatch`. Returns `None` if the set is never closed.
+fn character_set(pattern: &[char]) -> Option<(&[char], bool, &[char])> {
+    let (negated, pattern) = match pattern.split_first() {
+        Some(('!', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+
+    let end = pattern
+        .iter()
+        .skip(1)
+        .position(|&c| c == ']')
+        .map(|position| position + 1)?;
+
+    Some((&pattern[..end], negated, &pattern[end + 1..]))
+}
+
+fn set_contains(set: &[char], c: char) -> bool {
+    let mut i = 0;
+
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            if (set[i]..=set[i + 2]).contains(&c) {
+                return true;
+            }
+
+            i += 3;
+        } else {
+            if set[i] == c {
+                return true;
+            }
+
+            i += 1;
+        }
+    }
+
+    false
+}
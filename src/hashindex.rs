@@ -0,0 +1,605 @@
+use std::io::{Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use eyre::{bail, Context, Result};
+
+/// Magic number at the start of every borg 1.x hashindex file
+pub const MAGIC: &[u8; 8] = b"BORG_IDX";
+
+/// Size of the magic, entry count, bucket count, key size and value size
+pub const HEADER_SIZE: usize = 18;
+
+/// The first four bytes of a bucket's value mark it as empty or deleted. Real
+/// values never start with these, segment numbers and refcounts stay below.
+const EMPTY: u32 = 0xffff_ffff;
+const DELETED: u32 = 0xffff_fffe;
+
+/// Bucket counts borg grows its hash tables through
+const HASH_SIZES: &[usize] = &[
+    1031, 2053, 4099, 8209, 16411, 32771, 65537, 131101, 262147, 445649, 757607, 1287917, 2189459,
+    3065243, 4291319, 6007867, 8410991, 11775359, 16485527, 23079703, 32311591, 45236231, 63330739,
+    88663063, 124128287, 173779601, 243291419, 340608053, 476851277, 667591807, 934628527,
+    1308479939, 1831871927, 2147483647,
+];
+
+/// How full the table may get before it is grown
+const MAX_LOAD_FACTOR: f64 = 0.75;
+
+/// How few empty buckets may be left before the table is rebuilt to get rid
+/// of deleted ones, which would otherwise make lookups of missing keys slow
+const MIN_EMPTY_FACTOR: f64 = 0.1;
+
+/// A hash table in borg's on-disk hashindex format: an open addressing table
+/// with linear probing whose buckets are stored back to back. Keys are object
+/// IDs, so their first four bytes are already uniformly distributed and are
+/// used as the hash directly.
+#[derive(Clone)]
+pub struct HashIndex {
+    key_size: usize,
+    value_size: usize,
+    num_entries: usize,
+    num_deleted: usize,
+    num_buckets: usize,
+    buckets: Vec<u8>,
+}
+
+impl std::fmt::Debug for HashIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HashIndex")
+            .field("key_size", &self.key_size)
+            .field("value_size", &self.value_size)
+            .field("num_entries", &self.num_entries)
+            .field("num_buckets", &self.num_buckets)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HashIndex {
+    pub fn new(key_size: usize, value_size: usize) -> Self {
+        Self::with_buckets(key_size, value_size, HASH_SIZES[0])
+    }
+
+    fn with_buckets(key_size: usize, value_size: usize, num_buckets: usize) -> Self {
+        let bucket_size = key_size + value_size;
+        let mut buckets = vec![0; num_buckets * bucket_size];
+
+        for bucket in buckets.chunks_exact_mut(bucket_size) {
+            bucket[key_size..key_size + 4].copy_from_slice(&EMPTY.to_le_bytes());
+        }
+
+        Self {
+            key_size,
+            value_size,
+            num_entries: 0,
+            num_deleted: 0,
+            num_buckets,
+            buckets,
+        }
+    }
+
+    /// Read a hashindex, checking that its key and value sizes are the
+    /// expected ones
+    pub fn read(r: &mut impl Read, key_size: usize, value_size: usize) -> Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic).wrap_err("read hashindex magic")?;
+
+        if &magic != MAGIC {
+            bail!("unknown hashindex magic number {magic:?}");
+        }
+
+        let num_entries = r.read_i32::<LittleEndian>()?;
+        let num_buckets = r.read_i32::<LittleEndian>()?;
+        let file_key_size = r.read_i8()?;
+        let file_value_size = r.read_i8()?;
+
+        if num_entries < 0 || num_buckets <= 0 {
+            bail!("invalid hashindex header");
+        }
+
+        if file_key_size as usize != key_size || file_value_size as usize != value_size {
+            bail!(
+                "hashindex has key size {file_key_size} and value size {file_value_size}, expected {key_size} and {value_size}"
+            );
+        }
+
+        let num_buckets = num_buckets as usize;
+        let buckets = read_buckets(r, num_buckets, key_size + value_size)?;
+
+        let mut index = Self {
+            key_size,
+            value_size,
+            num_entries: 0,
+            num_deleted: 0,
+            num_buckets,
+            buckets,
+        };
+
+        for bucket in 0..num_buckets {
+            match index.marker(bucket) {
+                EMPTY => {}
+                DELETED => index.num_deleted += 1,
+                _ => index.num_entries += 1,
+            }
+        }
+
+        if index.num_entries != num_entries as usize {
+            bail!(
+                "hashindex header claims {num_entries} entries but contains {}",
+                index.num_entries
+            );
+        }
+
+        // lookups of missing keys stop at the first empty bucket
+        if index.num_entries + index.num_deleted == num_buckets {
+            bail!("hashindex has no empty buckets");
+        }
+
+        Ok(index)
+    }
+
+    /// Read a hashindex in the compact form, whose buckets are only the
+    /// entries back to back, and spread them over a regular table
+    pub fn read_compact(r: &mut impl Read, key_size: usize, value_size: usize) -> Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic).wrap_err("read hashindex magic")?;
+
+        if &magic != MAGIC {
+            bail!("unknown hashindex magic number {magic:?}");
+        }
+
+        let num_entries = r.read_i32::<LittleEndian>()?;
+        let num_buckets = r.read_i32::<LittleEndian>()?;
+        let file_key_size = r.read_i8()?;
+        let file_value_size = r.read_i8()?;
+
+        if num_entries < 0 || num_buckets != num_entries {
+            bail!("hashindex is not compact");
+        }
+
+        if file_key_size as usize != key_size || file_value_size as usize != value_size {
+            bail!(
+                "hashindex has key size {file_key_size} and value size {file_value_size}, expected {key_size} and {value_size}"
+            );
+        }
+
+        let buckets = read_buckets(r, num_entries as usize, key_size + value_size)?;
+
+        let mut index = Self::new(key_size, value_size);
+        for bucket in buckets.chunks_exact(key_size + value_size) {
+            let (key, value) = bucket.split_at(key_size);
+            index.insert(key, value);
+        }
+
+        Ok(index)
+    }
+
+    /// The header borg writes before the buckets
+    pub fn header(&self) -> Vec<u8> {
+        self.header_with_buckets(self.num_buckets)
+    }
+
+    /// The header of the compact form, see `write_compact`
+    pub fn compact_header(&self) -> Vec<u8> {
+        self.header_with_buckets(self.num_entries)
+    }
+
+    fn header_with_buckets(&self, num_buckets: usize) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&(self.num_entries as i32).to_le_bytes());
+        header.extend_from_slice(&(num_buckets as i32).to_le_bytes());
+        header.push(self.key_size as u8);
+        header.push(self.value_size as u8);
+
+        header
+    }
+
+    /// The raw buckets, which follow the header on disk
+    pub fn buckets(&self) -> &[u8] {
+        &self.buckets
+    }
+
+    pub fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(&self.header())?;
+        w.write_all(&self.buckets)?;
+
+        Ok(())
+    }
+
+    /// Write the compact form borg uses for indices that are only ever
+    /// iterated: the entries without empty buckets in between
+    pub fn write_compact(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(&self.compact_header())?;
+
+        for (key, value) in self.iter() {
+            w.write_all(key)?;
+            w.write_all(value)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_entries == 0
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.lookup(key).ok().map(|bucket| self.value(bucket))
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.lookup(key).is_ok()
+    }
+
+    /// Insert or replace the value for a key
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        assert_eq!(key.len(), self.key_size, "wrong hashindex key size");
+        assert_eq!(value.len(), self.value_size, "wrong hashindex value size");
+
+        let bucket = match self.lookup(key) {
+            Ok(bucket) => bucket,
+            Err(bucket) => {
+                if self.marker(bucket) == DELETED {
+                    self.num_deleted -= 1;
+                }
+                self.num_entries += 1;
+
+                bucket
+            }
+        };
+
+        let offset = bucket * self.bucket_size();
+        let (key_size, bucket_size) = (self.key_size, self.bucket_size());
+        self.buckets[offset..offset + key_size].copy_from_slice(key);
+        self.buckets[offset + key_size..offset + bucket_size].copy_from_slice(value);
+
+        self.maybe_resize();
+    }
+
+    /// Remove a key, returning its value if it was present
+    pub fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let bucket = self.lookup(key).ok()?;
+        let value = self.value(bucket).to_vec();
+
+        let offset = bucket * self.bucket_size() + self.key_size;
+        self.buckets[offset..offset + 4].copy_from_slice(&DELETED.to_le_bytes());
+
+        self.num_entries -= 1;
+        self.num_deleted += 1;
+
+        self.maybe_resize();
+
+        Some(value)
+    }
+
+    /// Iterate over every key and value, in bucket order
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.buckets
+            .chunks_exact(self.bucket_size())
+            .filter(|bucket| {
+                let marker = marker(&bucket[self.key_size..]);
+                marker != EMPTY && marker != DELETED
+            })
+            .map(|bucket| bucket.split_at(self.key_size))
+    }
+
+    fn bucket_size(&self) -> usize {
+        self.key_size + self.value_size
+    }
+
+    fn value(&self, bucket: usize) -> &[u8] {
+        let offset = bucket * self.bucket_size() + self.key_size;
+        &self.buckets[offset..offset + self.value_size]
+    }
+
+    fn marker(&self, bucket: usize) -> u32 {
+        marker(self.value(bucket))
+    }
+
+    /// Find the bucket holding `key`. If it is missing, returns the bucket it
+    /// should be inserted into instead, reusing the first deleted bucket on the
+    /// way like borg does.
+    fn lookup(&self, key: &[u8]) -> std::result::Result<usize, usize> {
+        let start = u32::from_le_bytes(key[..4].try_into().unwrap()) as usize % self.num_buckets;
+        let mut bucket = start;
+        let mut first_deleted = None;
+
+        loop {
+            match self.marker(bucket) {
+                EMPTY => return Err(first_deleted.unwrap_or(bucket)),
+                DELETED => {
+                    first_deleted.get_or_insert(bucket);
+                }
+                _ => {
+                    let offset = bucket * self.bucket_size();
+                    if &self.buckets[offset..offset + self.key_size] == key {
+                        return Ok(bucket);
+                    }
+                }
+            }
+
+            bucket = (bucket + 1) % self.num_buckets;
+
+            if bucket == start {
+                // only reachable if there are no empty buckets left, which
+                // `maybe_resize` prevents
+                return Err(first_deleted.expect("hashindex is full"));
+            }
+        }
+    }
+
+    fn maybe_resize(&mut self) {
+        let num_buckets = self.num_buckets as f64;
+        let num_empty = self.num_buckets - self.num_entries - self.num_deleted;
+
+        if self.num_entries as f64 > num_buckets * MAX_LOAD_FACTOR {
+            let size = HASH_SIZES
+                .iter()
+                .copied()
+                .find(|&size| size > self.num_buckets)
+                .unwrap_or(self.num_buckets * 2);
+
+            self.resize(size);
+        } else if (num_empty as f64) < num_buckets * MIN_EMPTY_FACTOR {
+            self.resize(self.num_buckets);
+        }
+    }
+
+    fn resize(&mut self, num_buckets: usize) {
+        let mut resized = Self::with_buckets(self.key_size, self.value_size, num_buckets);
+
+        for (key, value) in self.iter() {
+            let bucket = resized
+                .lookup(key)
+                .expect_err("keys in a hashindex are unique");
+            let bucket_size = resized.bucket_size();
+            let offset = bucket * bucket_size;
+            resized.buckets[offset..offset + bucket_size].copy_from_slice(&[key, value].concat());
+            resized.num_entries += 1;
+        }
+
+        *self = resized;
+    }
+}
+
+/// Read the buckets following the header. The header is untrusted, so its
+/// bucket count is checked against the data that is actually there instead of
+/// being allocated up front.
+fn read_buckets(r: &mut impl Read, num_buckets: usize, bucket_size: usize) -> Result<Vec<u8>> {
+    let mut buckets = Vec::new();
+    r.read_to_end(&mut buckets)
+        .wrap_err("read hashindex buckets")?;
+
+    if num_buckets.checked_mul(bucket_size) != Some(buckets.len()) {
+        bail!(
+            "hashindex header claims {num_buckets} buckets but the file holds {} bytes of them",
+            buckets.len()
+        );
+    }
+
+    Ok(buckets)
+}
+
+fn marker(value: &[u8]) -> u32 {
+    u32::from_le_bytes(value[..4].try_into().unwrap())
+}
+
+/// The repository index, mapping object IDs to the segment and offset of the
+/// PUT entry holding their current contents
+#[derive(Debug, Clone)]
+pub struct NsIndex(HashIndex);
+
+impl Default for NsIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NsIndex {
+    pub fn new() -> Self {
+        Self(HashIndex::new(32, 8))
+    }
+
+    pub fn read(r: &mut impl Read) -> Result<Self> {
+        Ok(Self(HashIndex::read(r, 32, 8)?))
+    }
+
+    pub fn inner(&self) -> &HashIndex {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, id: &[u8; 32]) -> Option<(u32, u32)> {
+        self.0.get(id).map(split_u32s)
+    }
+
+    pub fn insert(&mut self, id: &[u8; 32], segment: u32, offset: u32) {
+        self.0.insert(id, &join_u32s(segment, offset));
+    }
+
+    pub fn remove(&mut self, id: &[u8; 32]) -> Option<(u32, u32)> {
+        self.0.remove(id).as_deref().map(split_u32s)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ([u8; 32], (u32, u32))> + '_ {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.try_into().unwrap(), split_u32s(value)))
+    }
+}
+
+/// The largest refcount borg stores, higher ones stay at this value because
+/// the count is no longer exact
+pub const MAX_REFCOUNT: u32 = 0xffff_fbff;
+
+/// The chunks cache, mapping object IDs to their refcount, size and stored
+/// size
+#[derive(Debug, Clone)]
+pub struct ChunkIndex(HashIndex);
+
+impl Default for ChunkIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkIndex {
+    pub fn new() -> Self {
+        Self(HashIndex::new(32, 12))
+    }
+
+    pub fn read(r: &mut impl Read) -> Result<Self> {
+        Ok(Self(HashIndex::read(r, 32, 12)?))
+    }
+
+    pub fn read_compact(r: &mut impl Read) -> Result<Self> {
+        Ok(Self(HashIndex::read_compact(r, 32, 12)?))
+    }
+
+    pub fn inner(&self) -> &HashIndex {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, id: &[u8; 32]) -> Option<ChunkIndexEntry> {
+        self.0.get(id).map(ChunkIndexEntry::from_value)
+    }
+
+    pub fn insert(&mut self, id: &[u8; 32], entry: ChunkIndexEntry) {
+        self.0.insert(id, &entry.to_value());
+    }
+
+    pub fn remove(&mut self, id: &[u8; 32]) -> Option<ChunkIndexEntry> {
+        self.0
+            .remove(id)
+            .as_deref()
+            .map(ChunkIndexEntry::from_value)
+    }
+
+    /// Add `refcount` references to a chunk, adding the chunk if it is new
+    pub fn add(&mut self, id: &[u8; 32], refcount: u32, size: u32, csize: u32) {
+        let refcount = match self.get(id) {
+            Some(entry) => add_refcounts(entry.refcount, refcount),
+            None => refcount,
+        };
+
+        self.insert(
+            id,
+            ChunkIndexEntry {
+                refcount,
+                size,
+                csize,
+            },
+        );
+    }
+
+    /// Add one reference to a chunk that is already known, returning it
+    pub fn incref(&mut self, id: &[u8; 32]) -> Option<ChunkIndexEntry> {
+        let mut entry = self.get(id)?;
+        entry.refcount = add_refcounts(entry.refcount, 1);
+        self.insert(id, entry);
+
+        Some(entry)
+    }
+
+    /// Remove `refcount` references from a chunk, returning how many are left.
+    /// A chunk without references is removed. Saturated refcounts are no
+    /// longer exact, so they are never decremented.
+    pub fn decref(&mut self, id: &[u8; 32], refcount: u32) -> Option<u32> {
+        let mut entry = self.get(id)?;
+
+        if entry.refcount == MAX_REFCOUNT {
+            return Some(MAX_REFCOUNT);
+        }
+
+        entry.refcount = entry.refcount.saturating_sub(refcount);
+        if entry.refcount == 0 {
+            self.remove(id);
+        } else {
+            self.insert(id, entry);
+        }
+
+        Some(entry.refcount)
+    }
+
+    /// Add the references of another index to this one
+    pub fn merge(&mut self, other: &ChunkIndex) {
+        for (id, entry) in other.iter() {
+            self.add(&id, entry.refcount, entry.size, entry.csize);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ([u8; 32], ChunkIndexEntry)> + '_ {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.try_into().unwrap(), ChunkIndexEntry::from_value(value)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkIndexEntry {
+    pub refcount: u32,
+    pub size: u32,
+    pub csize: u32,
+}
+
+impl ChunkIndexEntry {
+    fn from_value(value: &[u8]) -> Self {
+        let (refcount, size) = split_u32s(&value[..8]);
+        let csize = u32::from_le_bytes(value[8..12].try_into().unwrap());
+
+        Self {
+            refcount,
+            size,
+            csize,
+        }
+    }
+
+    fn to_value(self) -> Vec<u8> {
+        let mut value = join_u32s(self.refcount, self.size);
+        value.extend_from_slice(&self.csize.to_le_bytes());
+
+        value
+    }
+}
+
+/// Refcounts saturate at `MAX_REFCOUNT` instead of overflowing into the
+/// markers for empty and deleted buckets
+fn add_refcounts(a: u32, b: u32) -> u32 {
+    a.saturating_add(b).min(MAX_REFCOUNT)
+}
+
+fn split_u32s(value: &[u8]) -> (u32, u32) {
+    let mut value = value;
+
+    (
+        value.read_u32::<LittleEndian>().unwrap(),
+        value.read_u32::<LittleEndian>().unwrap(),
+    )
+}
+
+fn join_u32s(a: u32, b: u32) -> Vec<u8> {
+    let mut value = Vec::with_capacity(8);
+    value.write_u32::<LittleEndian>(a).unwrap();
+    value.write_u32::<LittleEndian>(b).unwrap();
+
+    value
+}
@@ -0,0 +1,147 @@
+use std::collections::{BTreeMap, HashMap};
+
+use eyre::{bail, eyre, Context, Result};
+use serde::Deserialize;
+
+use crate::msgpack::Bytes;
+
+/// The hints version bork writes
+const HINTS_VERSION: u8 = 2;
+
+/// Bookkeeping borg keeps next to the index to decide which segments are
+/// worth compacting
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hints {
+    /// Number of live objects in each segment
+    pub segments: BTreeMap<u32, u32>,
+
+    /// Number of bytes that compacting each segment would free
+    pub compact: BTreeMap<u32, u64>,
+
+    pub storage_quota_use: u64,
+
+    /// Segments holding PUTs that were deleted, per object ID. Compaction must
+    /// keep the DELETE entries shadowing them until these segments are gone.
+    pub shadow_index: BTreeMap<[u8; 32], Vec<u32>>,
+}
+
+/// The contents of a `hints.N` file in one of the versions borg writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HintsFile {
+    /// Written by borg before 1.1, which only knew which segments were
+    /// worth compacting, not how much compacting them frees
+    V1 {
+        segments: BTreeMap<u32, u32>,
+        compact: Vec<u32>,
+    },
+
+    V2(Hints),
+}
+
+#[derive(Deserialize)]
+struct Version {
+    version: u8,
+}
+
+#[derive(Deserialize)]
+struct HintsV1 {
+    segments: BTreeMap<u32, u32>,
+    compact: Vec<u32>,
+}
+
+#[derive(Deserialize)]
+struct HintsV2 {
+    segments: BTreeMap<u32, u32>,
+    compact: BTreeMap<u32, u64>,
+
+    // both were added during borg 1.1 development
+    #[serde(default)]
+    storage_quota_use: u64,
+    #[serde(default)]
+    shadow_index: HashMap<Bytes, Vec<u32>>,
+}
+
+impl HintsFile {
+    /// Decode a hints file. Borg 1.1 and older write the keys as bytes, later
+    /// versions as strings, both are accepted.
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let Version { version } = rmp_serde::from_slice(data).wrap_err("decode hints version")?;
+
+        match version {
+            1 => {
+                let HintsV1 { segments, compact } =
+                    rmp_serde::from_slice(data).wrap_err("decode version 1 hints")?;
+
+                Ok(Self::V1 { segments, compact })
+            }
+            2 => {
+                let hints: HintsV2 =
+                    rmp_serde::from_slice(data).wrap_err("decode version 2 hints")?;
+
+                let mut shadow_index = BTreeMap::new();
+                for (id, segments) in hints.shadow_index {
+                    let id = id.0.try_into().map_err(|id: Vec<u8>| {
+                        eyre!(
+                            "object ID in the hints shadow index is {} bytes, not 32",
+                            id.len()
+                        )
+                    })?;
+
+                    shadow_index.insert(id, segments);
+                }
+
+                Ok(Self::V2(Hints {
+                    segments: hints.segments,
+                    compact: hints.compact,
+                    storage_quota_use: hints.storage_quota_use,
+                    shadow_index,
+                }))
+            }
+            version => bail!("unknown hints file version {version}"),
+        }
+    }
+}
+
+impl Hints {
+    /// Encode the hints in the msgpack layout borg 1.2 writes, which borg
+    /// 1.1 reads as well
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        use rmp::encode::*;
+
+        let mut out = Vec::new();
+
+        write_map_len(&mut out, 5)?;
+
+        write_str(&mut out, "version")?;
+        write_uint(&mut out, HINTS_VERSION as u64)?;
+
+        write_str(&mut out, "segments")?;
+        write_map_len(&mut out, self.segments.len() as u32)?;
+        for (&segment, &count) in &self.segments {
+            write_uint(&mut out, segment as u64)?;
+            write_uint(&mut out, count as u64)?;
+        }
+
+        write_str(&mut out, "compact")?;
+        write_map_len(&mut out, self.compact.len() as u32)?;
+        for (&segment, &size) in &self.compact {
+            write_uint(&mut out, segment as u64)?;
+            write_uint(&mut out, size)?;
+        }
+
+        write_str(&mut out, "storage_quota_use")?;
+        write_uint(&mut out, self.storage_quota_use)?;
+
+        write_str(&mut out, "shadow_index")?;
+        write_map_len(&mut out, self.shadow_index.len() as u32)?;
+        for (id, segments) in &self.shadow_index {
+            write_bin(&mut out, id)?;
+            write_array_len(&mut out, segments.len() as u32)?;
+            for &segment in segments {
+                write_uint(&mut out, segment as u64)?;
+            }
+        }
+
+        Ok(out)
+    }
+}
@@ -0,0 +1,96 @@
+use std::{path::Path, time::Duration};
+
+use eyre::{bail, Context, Result};
+
+use crate::{
+    compression::Compression,
+    config::RepositoryConfig,
+    key::{hex_lower, new_passphrase, save_security_info, Key, KeyType},
+    lock::LockMode,
+    pack_data,
+    transaction::Transaction,
+    Manifest, Repository, MANIFEST_ID,
+};
+
+/// Borg writes this into every repository so people know what they found
+const README: &str = "This is a Borg Backup repository.\nSee https://borgbackup.readthedocs.io/\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EncryptionMode {
+    /// No encryption and no authentication
+    None,
+
+    /// No encryption, but objects are authenticated with a key stored in the
+    /// repository
+    Authenticated,
+
+    /// Objects are encrypted with a key stored in the repository
+    Repokey,
+}
+
+impl EncryptionMode {
+    fn key_type(self) -> KeyType {
+        match self {
+            Self::None => KeyType::Plaintext,
+            Self::Authenticated => KeyType::Authenticated,
+            Self::Repokey => KeyType::Repokey,
+        }
+    }
+}
+
+/// Create a new repository at `path`, which must not exist or be an empty
+/// directory. The result is the same as what `borg init` creates: a config
+/// with a random ID, an empty data directory and a first transaction that
+/// stores an empty manifest.
+pub fn init(path: &Path, mode: EncryptionMode) -> Result<()> {
+    if path.join("config").exists() {
+        bail!("a repository already exists at {}", path.display());
+    }
+
+    if path.exists() {
+        let mut entries = std::fs::read_dir(path)
+            .wrap_err_with(|| format!("{} is not a directory", path.display()))?;
+
+        if entries.next().is_some() {
+            bail!("{} exists and is not empty", path.display());
+        }
+    }
+
+    let id: [u8; 32] = rand::random();
+    let key = Key::generate(mode.key_type(), &id)?;
+
+    let mut config = RepositoryConfig::new(hex_lower(&id));
+    if key.key_type != KeyType::Plaintext {
+        config.key = key.material()?.encrypt(&new_passphrase()?)?;
+    }
+
+    std::fs::create_dir_all(path.join("data"))
+        .wrap_err_with(|| format!("create repository directory {}", path.display()))?;
+    std::fs::write(path.join("README"), README).wrap_err("write README")?;
+    std::fs::write(path.join("config"), config.write()).wrap_err("write config")?;
+
+    let repository = Repository::open(path.to_path_buf(), LockMode::Exclusive, Duration::ZERO)?;
+
+    let mut manifest = Manifest::new(&key);
+    let data = pack_data(&key, &Compression::default(), &manifest.encode(&key)?)?;
+
+    let mut transaction = Transaction::begin(&repository)?;
+    transaction.put(&MANIFEST_ID, &data)?;
+    transaction.commit()?;
+
+    if key.key_type == KeyType::Repokey {
+        // the next IV borg may use, so it never reuses ours
+        std::fs::write(path.join("nonce"), format!("{:016x}", key.next_iv()))
+            .wrap_err("write nonce")?;
+    }
+
+    save_security_info(&repository, &key, &manifest.timestamp)?;
+
+    if key.key_type == KeyType::Repokey {
+        eprintln!(
+            "The key is stored in the repository config. Export it with `bork key export` and keep it somewhere safe, the repository is lost if the config is."
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+
+use eyre::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh64::Xxh64;
+
+/// The only algorithm borg uses for file integrity digests
+const ALGORITHM: &str = "XXH64";
+
+/// Version of the integrity file, which borg keeps equal to the hints version
+pub const INTEGRITY_VERSION: u8 = 2;
+
+/// The contents of `integrity.N`, holding the JSON encoded digests of the
+/// index and hints files of transaction N
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IntegrityFile {
+    pub version: u8,
+    pub hints: String,
+    pub index: String,
+}
+
+impl IntegrityFile {
+    pub fn new(hints: &IntegrityData, index: &IntegrityData) -> Result<Self> {
+        Ok(Self {
+            version: INTEGRITY_VERSION,
+            hints: serde_json::to_string(hints)?,
+            index: serde_json::to_string(index)?,
+        })
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let file: Self = rmp_serde::from_slice(data).wrap_err("decode integrity file msgpack")?;
+
+        if file.version != INTEGRITY_VERSION {
+            bail!("unsupported integrity file version {}", file.version);
+        }
+
+        Ok(file)
+    }
+
+    pub fn hints(&self) -> Result<IntegrityData> {
+        serde_json::from_str(&self.hints).wrap_err("decode hints integrity data")
+    }
+
+    pub fn index(&self) -> Result<IntegrityData> {
+        serde_json::from_str(&self.index).wrap_err("decode index integrity data")
+    }
+}
+
+/// The digests of one file, as stored in the integrity file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IntegrityData {
+    pub algorithm: String,
+    pub digests: BTreeMap<String, String>,
+}
+
+impl IntegrityData {
+    /// Check that the digests computed while reading a file match these
+    pub fn verify(&self, computed: &IntegrityData, name: &str) -> Result<()> {
+        if self.algorithm != ALGORITHM {
+            bail!(
+                "{name} uses unsupported integrity algorithm {}",
+                self.algorithm
+            );
+        }
+
+        for (part, digest) in &self.digests {
+            if computed.digests.get(part) != Some(digest) {
+                bail!("{name} is corrupted, its {part} digest does not match");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Computes digests of a file the same way borg's `IntegrityCheckedFile`
+/// does. The file name (without its directory) is hashed first, since it
+/// carries the context, then the contents. Named parts snapshot the running
+/// digest together with the current length, the last part is always `final`.
+pub struct IntegrityHasher {
+    hasher: Xxh64,
+    length: u64,
+    digests: BTreeMap<String, String>,
+}
+
+impl IntegrityHasher {
+    pub fn new(filename: &str) -> Self {
+        let mut hasher = Self {
+            hasher: Xxh64::new(0),
+            length: 0,
+            digests: BTreeMap::new(),
+        };
+
+        hasher.hash_name(filename);
+
+        hasher
+    }
+
+    /// Hash part of the file's contents
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.length += data.len() as u64;
+    }
+
+    pub fn hash_part(&mut self, part: &str) {
+        self.hash_name(part);
+        self.hasher.update(self.length.to_string().as_bytes());
+
+        self.digests
+            .insert(part.to_string(), format!("{:016x}", self.hasher.digest()));
+    }
+
+    pub fn finish(mut self) -> IntegrityData {
+        self.hash_part("final");
+
+        IntegrityData {
+            algorithm: ALGORITHM.to_string(),
+            digests: self.digests,
+        }
+    }
+
+    fn hash_name(&mut self, name: &str) {
+        self.hasher.update(format!("{:10}", name.len()).as_bytes());
+        self.hasher.update(name.as_bytes());
+    }
+}
+
+/// Digests of a hashindex file, which borg hashes as a `HashHeader` part
+/// for the header followed by the buckets
+pub fn hashindex_digests(name: &str, data: &[u8]) -> IntegrityData {
+    let header_size = crate::hashindex::HEADER_SIZE.min(data.len());
+
+    let mut hasher = IntegrityHasher::new(name);
+    hasher.update(&data[..header_size]);
+    hasher.hash_part("HashHeader");
+    hasher.update(&data[header_size..]);
+
+    hasher.finish()
+}
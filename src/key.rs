@@ -0,0 +1,914 @@
+use std::{
+    cell::Cell,
+    path::{Component, Path, PathBuf},
+};
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use base64::Engine;
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    ChaCha20Poly1305, KeyInit,
+};
+use eyre::{bail, eyre, Context, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::{msgpack::Bytes, Repository};
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+/// Borg's default parameters for protecting a key with a passphrase
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const ARGON2_TIME_COST: u32 = 3;
+const ARGON2_MEMORY_COST: u32 = 1 << 16;
+const ARGON2_PARALLELISM: u32 = 4;
+
+/// How many IVs are reserved at a time. The reservation is recorded before
+/// any of them is used, so a crash can never lead to reusing one.
+const NONCE_RESERVATION: u64 = 1 << 28;
+
+/// Width that borg wraps base64 key blobs at
+const BLOB_WIDTH: usize = 70;
+
+/// The first byte of every object stored in a repository, which tells us which
+/// kind of key was used to encrypt and authenticate it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    Keyfile,
+    Plaintext,
+    Repokey,
+    Blake2Keyfile,
+    Blake2Repokey,
+    Authenticated,
+    Blake2Authenticated,
+}
+
+impl KeyType {
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        Ok(match byte {
+            0x00 => Self::Keyfile,
+            0x02 => Self::Plaintext,
+            0x03 => Self::Repokey,
+            0x04 => Self::Blake2Keyfile,
+            0x05 => Self::Blake2Repokey,
+            0x06 => Self::Authenticated,
+            0x07 => Self::Blake2Authenticated,
+            _ => bail!("unknown key type {byte:#04x}"),
+        })
+    }
+
+    pub fn byte(self) -> u8 {
+        match self {
+            Self::Keyfile => 0x00,
+            Self::Plaintext => 0x02,
+            Self::Repokey => 0x03,
+            Self::Blake2Keyfile => 0x04,
+            Self::Blake2Repokey => 0x05,
+            Self::Authenticated => 0x06,
+            Self::Blake2Authenticated => 0x07,
+        }
+    }
+
+    /// Keys of these types are stored in a file in the user's borg keys
+    /// directory rather than in the repository config
+    pub fn is_keyfile(self) -> bool {
+        matches!(self, Self::Keyfile | Self::Blake2Keyfile)
+    }
+}
+
+/// The decrypted contents of a borg key blob
+#[derive(Deserialize, Serialize)]
+pub struct KeyMaterial {
+    pub version: u8,
+    pub repository_id: Bytes,
+    pub enc_key: Bytes,
+    pub enc_hmac_key: Bytes,
+    pub id_key: Bytes,
+    pub chunk_seed: i32,
+
+    #[serde(default)]
+    pub tam_required: Option<bool>,
+}
+
+impl KeyMaterial {
+    /// Fresh random key material for a new repository
+    pub fn generate(repository_id: &[u8]) -> Self {
+        let random: [u8; 100] = {
+            let mut random = [0; 100];
+            rand::Rng::fill(&mut rand::thread_rng(), &mut random[..]);
+            random
+        };
+
+        Self {
+            version: 1,
+            repository_id: Bytes(repository_id.to_vec()),
+            enc_key: Bytes(random[0..32].to_vec()),
+            enc_hmac_key: Bytes(random[32..64].to_vec()),
+            id_key: Bytes(random[64..96].to_vec()),
+            chunk_seed: i32::from_le_bytes(random[96..100].try_into().unwrap()),
+            tam_required: Some(true),
+        }
+    }
+
+    /// Encrypt the key material with a passphrase, returning the base64 blob
+    /// that goes into the repository config or a keyfile
+    pub fn encrypt(&self, passphrase: &str) -> Result<String> {
+        let plaintext = rmp_serde::to_vec_named(self).wrap_err("encode key msgpack")?;
+
+        EncryptedKey::encrypt(&plaintext, passphrase, KeyAlgorithm::Pbkdf2)?.encode()
+    }
+}
+
+/// A key blob as it is stored in the repository config or a keyfile, still
+/// encrypted with the user's passphrase. Borg 1.x keys use PBKDF2 and
+/// AES-CTR with an HMAC, newer keys use Argon2 and ChaCha20-Poly1305.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct EncryptedKey {
+    pub version: u8,
+    pub salt: Bytes,
+    pub algorithm: String,
+    pub data: Bytes,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iterations: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Bytes>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub argon2_time_cost: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub argon2_memory_cost: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub argon2_parallelism: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub argon2_type: Option<String>,
+}
+
+/// How a key blob is protected by the passphrase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    Pbkdf2,
+    Argon2,
+}
+
+impl KeyAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Pbkdf2 => "sha256",
+            Self::Argon2 => "argon2 chacha20-poly1305",
+        }
+    }
+}
+
+impl EncryptedKey {
+    /// Decode a base64 key blob as found in the config or a keyfile
+    pub fn decode(blob: &str) -> Result<Self> {
+        let stripped: String = blob.chars().filter(|c| !c.is_whitespace()).collect();
+
+        let raw = base64::engine::general_purpose::STANDARD
+            .decode(stripped)
+            .wrap_err("decode key base64")?;
+
+        rmp_serde::from_slice(&raw).wrap_err("decode encrypted key msgpack")
+    }
+
+    /// Encode as a base64 key blob, wrapped the same way borg does it
+    pub fn encode(&self) -> Result<String> {
+        let raw = rmp_serde::to_vec_named(self).wrap_err("encode encrypted key msgpack")?;
+
+        Ok(encode_blob(&raw))
+    }
+
+    pub fn algorithm(&self) -> Result<KeyAlgorithm> {
+        match self.algorithm.as_str() {
+            "sha256" => Ok(KeyAlgorithm::Pbkdf2),
+            "argon2 chacha20-poly1305" => Ok(KeyAlgorithm::Argon2),
+            other => bail!("unsupported key algorithm {other}"),
+        }
+    }
+
+    /// Decrypt the key with the given passphrase, returning the msgpack encoded
+    /// key material
+    pub fn decrypt(&self, passphrase: &str) -> Result<Vec<u8>> {
+        if self.version != 1 {
+            bail!("unsupported key version {}", self.version);
+        }
+
+        match self.algorithm()? {
+            KeyAlgorithm::Pbkdf2 => {
+                let iterations = self
+                    .iterations
+                    .ok_or_else(|| eyre!("key is missing its iteration count"))?;
+                let hash = self
+                    .hash
+                    .as_ref()
+                    .ok_or_else(|| eyre!("key is missing its hash"))?;
+
+                let mut kek = [0; 32];
+                pbkdf2::pbkdf2_hmac::<Sha256>(
+                    passphrase.as_bytes(),
+                    &self.salt.0,
+                    iterations,
+                    &mut kek,
+                );
+
+                let mut data = self.data.0.clone();
+                Aes256Ctr::new(&kek.into(), &[0; 16].into()).apply_keystream(&mut data);
+
+                let mut mac = <HmacSha256 as Mac>::new_from_slice(&kek)?;
+                mac.update(&data);
+                mac.verify_slice(&hash.0)
+                    .map_err(|_| eyre!("incorrect passphrase"))?;
+
+                Ok(data)
+            }
+            KeyAlgorithm::Argon2 => {
+                let kek = self.argon2_kek(passphrase)?;
+
+                // layout is poly1305 tag, 12 byte nonce, ciphertext. The nonce
+                // is authenticated as associated data.
+                if self.data.0.len() < 28 {
+                    bail!("encrypted key is too short");
+                }
+
+                let (tag, rest) = self.data.0.split_at(16);
+                let (nonce, ciphertext) = rest.split_at(12);
+
+                let mut sealed = ciphertext.to_vec();
+                sealed.extend_from_slice(tag);
+
+                ChaCha20Poly1305::new(&kek.into())
+                    .decrypt(
+                        nonce.into(),
+                        Payload {
+                            msg: &sealed,
+                            aad: nonce,
+                        },
+                    )
+                    .map_err(|_| eyre!("incorrect passphrase"))
+            }
+        }
+    }
+
+    /// Encrypt msgpack encoded key material with a passphrase
+    pub fn encrypt(plaintext: &[u8], passphrase: &str, algorithm: KeyAlgorithm) -> Result<Self> {
+        match algorithm {
+            KeyAlgorithm::Pbkdf2 => {
+                let salt: [u8; 32] = rand::random();
+
+                let mut kek = [0; 32];
+                pbkdf2::pbkdf2_hmac::<Sha256>(
+                    passphrase.as_bytes(),
+                    &salt,
+                    PBKDF2_ITERATIONS,
+                    &mut kek,
+                );
+
+                let mut mac = <HmacSha256 as Mac>::new_from_slice(&kek)?;
+                mac.update(plaintext);
+                let hash = mac.finalize().into_bytes().to_vec();
+
+                let mut data = plaintext.to_vec();
+                Aes256Ctr::new(&kek.into(), &[0; 16].into()).apply_keystream(&mut data);
+
+                Ok(Self {
+                    version: 1,
+                    salt: Bytes(salt.to_vec()),
+                    algorithm: algorithm.name().to_string(),
+                    data: Bytes(data),
+                    iterations: Some(PBKDF2_ITERATIONS),
+                    hash: Some(Bytes(hash)),
+                    argon2_time_cost: None,
+                    argon2_memory_cost: None,
+                    argon2_parallelism: None,
+                    argon2_type: None,
+                })
+            }
+            KeyAlgorithm::Argon2 => {
+                let salt: [u8; 16] = rand::random();
+
+                let mut key = Self {
+                    version: 1,
+                    salt: Bytes(salt.to_vec()),
+                    algorithm: algorithm.name().to_string(),
+                    data: Bytes(Vec::new()),
+                    iterations: None,
+                    hash: None,
+                    argon2_time_cost: Some(ARGON2_TIME_COST),
+                    argon2_memory_cost: Some(ARGON2_MEMORY_COST),
+                    argon2_parallelism: Some(ARGON2_PARALLELISM),
+                    argon2_type: Some("id".to_string()),
+                };
+
+                let kek = key.argon2_kek(passphrase)?;
+                let nonce = [0; 12];
+
+                let sealed = ChaCha20Poly1305::new(&kek.into())
+                    .encrypt(
+                        &nonce.into(),
+                        Payload {
+                            msg: plaintext,
+                            aad: &nonce,
+                        },
+                    )
+                    .map_err(|_| eyre!("encrypt key"))?;
+
+                let (ciphertext, tag) = sealed.split_at(sealed.len() - 16);
+
+                let mut data = tag.to_vec();
+                data.extend_from_slice(&nonce);
+                data.extend_from_slice(ciphertext);
+                key.data = Bytes(data);
+
+                Ok(key)
+            }
+        }
+    }
+
+    fn argon2_kek(&self, passphrase: &str) -> Result<[u8; 32]> {
+        let (Some(time_cost), Some(memory_cost), Some(parallelism), Some(tipe)) = (
+            self.argon2_time_cost,
+            self.argon2_memory_cost,
+            self.argon2_parallelism,
+            self.argon2_type.as_deref(),
+        ) else {
+            bail!("key is missing its argon2 parameters");
+        };
+
+        let variant = match tipe {
+            "id" => argon2::Algorithm::Argon2id,
+            "i" => argon2::Algorithm::Argon2i,
+            "d" => argon2::Algorithm::Argon2d,
+            other => bail!("unsupported argon2 type {other}"),
+        };
+
+        let params = argon2::Params::new(memory_cost, time_cost, parallelism, Some(32))
+            .map_err(|e| eyre!("invalid argon2 parameters: {e}"))?;
+
+        let mut kek = [0; 32];
+        argon2::Argon2::new(variant, argon2::Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt.0, &mut kek)
+            .map_err(|e| eyre!("derive key from passphrase: {e}"))?;
+
+        Ok(kek)
+    }
+}
+
+pub struct Key {
+    pub key_type: KeyType,
+    material: Option<KeyMaterial>,
+
+    /// The counter to use for the next encrypted object. AES-CTR must never
+    /// reuse a counter value with the same key.
+    next_iv: Cell<u64>,
+
+    /// Where IV reservations are recorded, once `reserve_ivs` was called
+    reservation: Option<IvReservation>,
+}
+
+/// IVs reserved the same way borg's `NonceManager` does it: the first unused
+/// IV is written both into the repository and into the local security
+/// directory, and every client starts counting from the highest of the two
+#[derive(Debug)]
+struct IvReservation {
+    repository_file: PathBuf,
+    security_file: PathBuf,
+    end: Cell<Option<u64>>,
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Key")
+            .field("key_type", &self.key_type)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Key {
+    pub fn plaintext() -> Self {
+        Self {
+            key_type: KeyType::Plaintext,
+            material: None,
+            next_iv: Cell::new(0),
+            reservation: None,
+        }
+    }
+
+    /// Create a key of the given type with fresh key material for a new
+    /// repository
+    pub fn generate(key_type: KeyType, repository_id: &[u8]) -> Result<Self> {
+        match key_type {
+            KeyType::Plaintext => Ok(Self::plaintext()),
+            KeyType::Keyfile | KeyType::Repokey | KeyType::Authenticated => Ok(Self {
+                key_type,
+                material: Some(KeyMaterial::generate(repository_id)),
+                next_iv: Cell::new(0),
+                reservation: None,
+            }),
+            _ => bail!("creating {key_type:?} keys is not supported"),
+        }
+    }
+
+    /// Load the key used by a repository. The key type is taken from the first
+    /// byte of the manifest object, the same way borg does it.
+    pub fn load(repository: &Repository, manifest_data: &[u8]) -> Result<Self> {
+        let key_type = KeyType::from_byte(
+            *manifest_data
+                .first()
+                .ok_or_else(|| eyre!("manifest object is empty"))?,
+        )?;
+
+        if key_type == KeyType::Plaintext {
+            return Ok(Self::plaintext());
+        }
+
+        let blob = if key_type.is_keyfile() {
+            find_keyfile(&repository.id)?
+                .ok_or_else(|| eyre!("no key file found for repository {}", repository.id))?
+                .1
+        } else if repository.config.key.trim().is_empty() {
+            bail!("repository config does not contain a key");
+        } else {
+            repository.config.key.clone()
+        };
+
+        let material = decrypt_key_blob(&blob, &passphrase()?)?;
+
+        if hex_lower(&material.repository_id.0) != repository.id.to_lowercase() {
+            bail!("key does not belong to repository {}", repository.id);
+        }
+
+        // borg continues counting from the IV after the manifest's, which is
+        // the last object written
+        let next_iv = match key_type {
+            KeyType::Keyfile | KeyType::Repokey if manifest_data.len() >= 41 => {
+                u64::from_be_bytes(manifest_data[33..41].try_into().unwrap())
+                    + cipher_blocks(manifest_data.len() - 41)
+            }
+            _ => 0,
+        };
+
+        Ok(Self {
+            key_type,
+            material: Some(material),
+            next_iv: Cell::new(next_iv),
+            reservation: None,
+        })
+    }
+
+    /// Whether manifests and archives written with this key must carry a TAM.
+    /// Only plaintext repositories get away without one.
+    pub fn tam_required(&self) -> bool {
+        self.key_type != KeyType::Plaintext
+    }
+
+    /// Derive the key used for the HMAC of a TAM. The context is either
+    /// `manifest` or `archive`.
+    pub fn tam_key(&self, salt: &[u8], context: &[u8]) -> Result<Vec<u8>> {
+        let Some(material) = &self.material else {
+            // plaintext repositories have no secret to derive from, borg uses
+            // the salt and context directly
+            let mut key = salt.to_vec();
+            key.extend_from_slice(context);
+
+            return Ok(key);
+        };
+
+        let mut ikm = Vec::with_capacity(96);
+        ikm.extend_from_slice(&material.id_key.0);
+        ikm.extend_from_slice(&material.enc_key.0);
+        ikm.extend_from_slice(&material.enc_hmac_key.0);
+
+        let mut info = b"borg-metadata-authentication-".to_vec();
+        info.extend_from_slice(context);
+
+        let mut out = vec![0; 64];
+        hkdf::Hkdf::<Sha512>::new(Some(salt), &ikm)
+            .expand(&info, &mut out)
+            .map_err(|e| eyre!("derive TAM key: {e}"))?;
+
+        Ok(out)
+    }
+
+    /// Remove the encryption layer from a repository object, returning the
+    /// still-compressed payload
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let key_type = KeyType::from_byte(*data.first().ok_or_else(|| eyre!("empty object"))?)?;
+
+        if key_type != self.key_type {
+            bail!(
+                "object was written with a {key_type:?} key but the repository uses {:?}",
+                self.key_type
+            );
+        }
+
+        match key_type {
+            KeyType::Plaintext | KeyType::Authenticated | KeyType::Blake2Authenticated => {
+                Ok(data[1..].to_vec())
+            }
+            KeyType::Keyfile | KeyType::Repokey => {
+                let material = self.material()?;
+
+                if data.len() < 41 {
+                    bail!("encrypted object is too short");
+                }
+
+                let mut mac = <HmacSha256 as Mac>::new_from_slice(&material.enc_hmac_key.0)?;
+                mac.update(&data[33..]);
+                mac.verify_slice(&data[1..33])
+                    .map_err(|_| eyre!("object MAC verification failed"))?;
+
+                let mut iv = [0; 16];
+                iv[8..].copy_from_slice(&data[33..41]);
+
+                let mut payload = data[41..].to_vec();
+                Aes256Ctr::new_from_slices(&material.enc_key.0, &iv)
+                    .map_err(|e| eyre!("init cipher: {e}"))?
+                    .apply_keystream(&mut payload);
+
+                Ok(payload)
+            }
+            KeyType::Blake2Keyfile | KeyType::Blake2Repokey => {
+                bail!("blake2 encrypted repositories are not supported")
+            }
+        }
+    }
+
+    /// Add the encryption layer to a compressed payload, the inverse of
+    /// `decrypt`
+    pub fn encrypt(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        let mut data = vec![self.key_type.byte()];
+
+        match self.key_type {
+            KeyType::Plaintext | KeyType::Authenticated => data.extend_from_slice(payload),
+            KeyType::Keyfile | KeyType::Repokey => {
+                let material = self.material()?;
+
+                let counter = self.reserve_ivs(cipher_blocks(payload.len()))?;
+                self.next_iv.set(counter + cipher_blocks(payload.len()));
+
+                let mut iv = [0; 16];
+                iv[8..].copy_from_slice(&counter.to_be_bytes());
+
+                let mut ciphertext = payload.to_vec();
+                Aes256Ctr::new_from_slices(&material.enc_key.0, &iv)
+                    .map_err(|e| eyre!("init cipher: {e}"))?
+                    .apply_keystream(&mut ciphertext);
+
+                let mut mac = <HmacSha256 as Mac>::new_from_slice(&material.enc_hmac_key.0)?;
+                mac.update(&iv[8..]);
+                mac.update(&ciphertext);
+
+                data.extend_from_slice(&mac.finalize().into_bytes());
+                data.extend_from_slice(&iv[8..]);
+                data.extend_from_slice(&ciphertext);
+            }
+            key_type => bail!("encrypting with {key_type:?} keys is not supported"),
+        }
+
+        Ok(data)
+    }
+
+    /// The ID of an object with the given plain contents. Keyed types use an
+    /// HMAC so the IDs do not reveal the contents.
+    pub fn id_hash(&self, data: &[u8]) -> Result<[u8; 32]> {
+        match self.key_type {
+            KeyType::Plaintext => Ok(Sha256::digest(data).into()),
+            KeyType::Keyfile | KeyType::Repokey | KeyType::Authenticated => {
+                let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.material()?.id_key.0)?;
+                mac.update(data);
+
+                Ok(mac.finalize().into_bytes().into())
+            }
+            key_type => bail!("hashing with {key_type:?} keys is not supported"),
+        }
+    }
+
+    /// Record IV reservations in `repository` before encrypting anything for
+    /// it. Keys that do not encrypt do not need any.
+    pub fn track_ivs(&mut self, repository: &Repository) -> Result<()> {
+        if !matches!(self.key_type, KeyType::Keyfile | KeyType::Repokey) {
+            return Ok(());
+        }
+
+        self.reservation = Some(IvReservation {
+            repository_file: repository.path.join("nonce"),
+            security_file: security_dir()?.join(&repository.id).join("nonce"),
+            end: Cell::new(None),
+        });
+
+        Ok(())
+    }
+
+    /// Make sure the next `blocks` IVs are reserved and return the first of
+    /// them. The first reservation may skip ahead to IVs that other clients
+    /// have not reserved.
+    fn reserve_ivs(&self, blocks: u64) -> Result<u64> {
+        let Some(reservation) = &self.reservation else {
+            return Ok(self.next_iv.get());
+        };
+
+        let next_iv = self.next_iv.get();
+        if let Some(end) = reservation.end.get() {
+            if next_iv + blocks <= end {
+                return Ok(next_iv);
+            }
+        }
+
+        let free = read_iv(&reservation.repository_file)?
+            .max(read_iv(&reservation.security_file)?)
+            .max(next_iv);
+        let end = free + blocks + NONCE_RESERVATION;
+
+        write_iv(&reservation.repository_file, end)?;
+        if let Some(dir) = reservation.security_file.parent() {
+            std::fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("create security directory {}", dir.display()))?;
+        }
+        write_iv(&reservation.security_file, end)?;
+
+        reservation.end.set(Some(end));
+        self.next_iv.set(free);
+
+        Ok(free)
+    }
+
+    /// The seed for the buzhash chunker. Plaintext keys have no key material
+    /// and use 0.
+    pub fn chunk_seed(&self) -> u32 {
+        self.material
+            .as_ref()
+            .map_or(0, |material| material.chunk_seed as u32)
+    }
+
+    /// The counter the next encrypted object will use
+    pub fn next_iv(&self) -> u64 {
+        self.next_iv.get()
+    }
+
+    /// Continue counting from `iv` if it is ahead of our own counter, for
+    /// example because the repository reserved IVs up to it
+    pub fn skip_iv_to(&self, iv: u64) {
+        if iv > self.next_iv.get() {
+            self.next_iv.set(iv);
+        }
+    }
+
+    pub fn material(&self) -> Result<&KeyMaterial> {
+        self.material
+            .as_ref()
+            .ok_or_else(|| eyre!("{:?} key has no key material", self.key_type))
+    }
+}
+
+/// Decode a base64 key blob and decrypt it with the given passphrase
+pub fn decrypt_key_blob(blob: &str, passphrase: &str) -> Result<KeyMaterial> {
+    let data = EncryptedKey::decode(blob)?.decrypt(passphrase)?;
+
+    rmp_serde::from_slice(&data).wrap_err("decode key msgpack")
+}
+
+/// Base64 encode a binary key blob and wrap it the same way borg does
+pub fn encode_blob(binary: &[u8]) -> String {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(binary);
+
+    let mut out = encoded
+        .as_bytes()
+        .chunks(BLOB_WIDTH)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect::<Vec<_>>()
+        .join("\n");
+    out.push('\n');
+
+    out
+}
+
+/// The directory borg stores keyfiles in
+pub fn keys_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("BORG_KEYS_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+
+    if let Some(dir) = std::env::var_os("BORG_CONFIG_DIR") {
+        return Ok(PathBuf::from(dir).join("keys"));
+    }
+
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        return Ok(PathBuf::from(dir).join("borg").join("keys"));
+    }
+
+    let home = std::env::var_os("HOME").ok_or_else(|| eyre!("HOME is not set"))?;
+
+    Ok(PathBuf::from(home).join(".config/borg/keys"))
+}
+
+/// Find the keyfile belonging to the repository with the given ID and return
+/// its path and base64 key blob
+pub fn find_keyfile(repository_id: &str) -> Result<Option<(PathBuf, String)>> {
+    if let Some(path) = std::env::var_os("BORG_KEY_FILE") {
+        let contents = std::fs::read_to_string(&path).wrap_err("read BORG_KEY_FILE")?;
+
+        let blob = keyfile_blob(&contents, repository_id)
+            .ok_or_else(|| eyre!("BORG_KEY_FILE does not belong to this repository"))?;
+
+        return Ok(Some((PathBuf::from(path), blob)));
+    }
+
+    let dir = keys_dir()?;
+
+    if !dir.exists() {
+        return Ok(None);
+    }
+
+    for result in std::fs::read_dir(&dir)
+        .wrap_err_with(|| format!("read keys directory {}", dir.display()))?
+    {
+        let dir_entry = result?;
+
+        if let Ok(contents) = std::fs::read_to_string(dir_entry.path()) {
+            if let Some(blob) = keyfile_blob(&contents, repository_id) {
+                return Ok(Some((dir_entry.path(), blob)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns the key blob from the contents of a keyfile if it belongs to the
+/// given repository
+pub fn keyfile_blob(contents: &str, repository_id: &str) -> Option<String> {
+    let (header, blob) = contents.split_once('\n')?;
+    let id = header.strip_prefix("BORG_KEY ")?;
+
+    if id.trim().eq_ignore_ascii_case(repository_id) {
+        Some(blob.to_string())
+    } else {
+        None
+    }
+}
+
+/// Get the repository passphrase from the environment or by prompting the user
+pub fn passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var("BORG_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+
+    if let Ok(command) = std::env::var("BORG_PASSCOMMAND") {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .output()
+            .wrap_err("run BORG_PASSCOMMAND")?;
+
+        if !output.status.success() {
+            bail!("BORG_PASSCOMMAND exited with {}", output.status);
+        }
+
+        let passphrase = String::from_utf8(output.stdout).wrap_err("passphrase is not utf-8")?;
+
+        return Ok(passphrase.trim_end_matches('\n').to_string());
+    }
+
+    rpassword::prompt_password("Enter passphrase for key: ").wrap_err("read passphrase")
+}
+
+/// Get a new passphrase from the environment or by prompting the user twice
+pub fn new_passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var("BORG_NEW_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+
+    let passphrase =
+        rpassword::prompt_password("Enter new passphrase: ").wrap_err("read new passphrase")?;
+    let again = rpassword::prompt_password("Enter same passphrase again: ")
+        .wrap_err("read new passphrase")?;
+
+    if passphrase != again {
+        bail!("passphrases do not match");
+    }
+
+    Ok(passphrase)
+}
+
+/// Read the first unreserved IV from a nonce file, which holds it as 16 hex
+/// characters. A missing file has not reserved anything yet.
+fn read_iv(path: &Path) -> Result<u64> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => u64::from_str_radix(contents.trim(), 16)
+            .wrap_err_with(|| format!("{} does not contain a valid IV", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).wrap_err_with(|| format!("read {}", path.display())),
+    }
+}
+
+/// Replace a nonce file, going through a temporary file so it is never left
+/// half written
+fn write_iv(path: &Path, iv: u64) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+
+    std::fs::write(&tmp, format!("{iv:016x}"))
+        .wrap_err_with(|| format!("write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).wrap_err_with(|| format!("replace {}", path.display()))
+}
+
+/// The directory borg keeps per-repository security information in
+pub fn security_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("BORG_SECURITY_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+
+    if let Some(dir) = std::env::var_os("BORG_CONFIG_DIR") {
+        return Ok(PathBuf::from(dir).join("security"));
+    }
+
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        return Ok(PathBuf::from(dir).join("borg").join("security"));
+    }
+
+    let home = std::env::var_os("HOME").ok_or_else(|| eyre!("HOME is not set"))?;
+
+    Ok(PathBuf::from(home).join(".config/borg/security"))
+}
+
+/// Record what we know about a repository the way borg does whenever it
+/// writes a manifest, so that borg does not warn about an unknown repository
+/// or refuse a manifest it thinks was rolled back
+pub fn save_security_info(repository: &Repository, key: &Key, timestamp: &str) -> Result<()> {
+    let dir = security_dir()?.join(&repository.id);
+
+    std::fs::create_dir_all(&dir)
+        .wrap_err_with(|| format!("create security directory {}", dir.display()))?;
+
+    std::fs::write(dir.join("key-type"), key.key_type.byte().to_string())
+        .wrap_err("write key-type")?;
+    std::fs::write(
+        dir.join("location"),
+        absolute_path(&repository.path)?
+            .to_string_lossy()
+            .as_bytes(),
+    )
+    .wrap_err("write location")?;
+    std::fs::write(dir.join("manifest-timestamp"), timestamp)
+        .wrap_err("write manifest-timestamp")?;
+
+    Ok(())
+}
+
+/// Forget everything recorded about a repository, including its reserved
+/// IVs, once the repository is gone
+pub fn destroy_security_info(repository: &Repository) -> Result<()> {
+    let dir = security_dir()?.join(&repository.id);
+
+    match std::fs::remove_dir_all(&dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).wrap_err_with(|| format!("remove {}", dir.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// An absolute path with `.` and `..` removed lexically, like python's
+/// `os.path.abspath` which borg uses for repository locations
+pub fn absolute_path(path: &Path) -> Result<PathBuf> {
+    let path = std::path::absolute(path).wrap_err("make repository path absolute")?;
+
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            component => normalized.push(component),
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// Number of AES blocks needed to encrypt `length` bytes
+fn cipher_blocks(length: usize) -> u64 {
+    length.div_ceil(16) as u64
+}
+
+pub fn hex_lower(x: &[u8]) -> String {
+    x.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
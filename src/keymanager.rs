@@ -0,0 +1,409 @@
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use base64::Engine;
+use eyre::{bail, eyre, Context, Result};
+use qrcodegen::{QrCode, QrCodeEcc};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    key::{self, encode_blob, hex_decode, EncryptedKey, KeyType},
+    transaction::RepositoryState,
+    write_atomic, Repository, MANIFEST_ID,
+};
+
+/// The number of key bytes on each line of a paper key
+const PAPER_LINE_BYTES: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// The same format as the files in borg's keys directory
+    Keyfile,
+
+    /// Hex lines with checksums, meant to be printed and typed back in
+    Paper,
+
+    /// A printable HTML page with the keyfile as text and as a QR code
+    Html,
+}
+
+/// Export the key of a repository to `path`, or to stdout if no path is given
+pub fn export(repository: &Repository, path: Option<&Path>, format: ExportFormat) -> Result<()> {
+    let (_, blob) = load_keyblob(repository)?;
+
+    let output = match format {
+        ExportFormat::Keyfile => keyfile_data(&repository.id, &blob),
+        ExportFormat::Paper => paper_key(&repository.id, &blob)?,
+        ExportFormat::Html => html_key(&repository.id, &blob)?,
+    };
+
+    match path {
+        // an interrupted export must not leave a truncated key behind
+        Some(path) if path != Path::new("-") => write_atomic(path, output.as_bytes()),
+        _ => std::io::stdout()
+            .write_all(output.as_bytes())
+            .wrap_err("write key to stdout"),
+    }
+}
+
+/// Import a key previously written by `export` (or `borg key export`) from
+/// `path`, or from stdin if no path is given. The key is stored wherever the
+/// repository expects it: in the config for repokey repositories or in the
+/// keys directory for keyfile repositories.
+pub fn import(repository: &mut Repository, path: Option<&Path>, paper: bool) -> Result<()> {
+    let mut input = String::new();
+
+    match path {
+        Some(path) if path != Path::new("-") => {
+            input = std::fs::read_to_string(path)
+                .wrap_err_with(|| format!("read {}", path.display()))?;
+        }
+        _ => {
+            std::io::stdin()
+                .read_to_string(&mut input)
+                .wrap_err("read key from stdin")?;
+        }
+    }
+
+    let blob = if paper {
+        parse_paper_key(&repository.id, &input)?
+    } else {
+        parse_keyfile_data(&repository.id, &input)?
+    };
+
+    // make sure we are not about to store garbage as the repository key
+    EncryptedKey::decode(&blob).wrap_err("imported key is not a valid borg key")?;
+
+    store_keyblob(repository, &blob)
+}
+
+/// Re-encrypt the repository key with a new passphrase. The key keeps the
+/// algorithm it was protected with, so every borg version that could unlock it
+/// before can still unlock it afterwards.
+pub fn change_passphrase(
+    repository: &mut Repository,
+    passphrase: &str,
+    new_passphrase: &str,
+) -> Result<()> {
+    let (_, blob) = load_keyblob(repository)?;
+
+    let encrypted = EncryptedKey::decode(&blob)?;
+    let plaintext = encrypted.decrypt(passphrase)?;
+
+    let reencrypted = EncryptedKey::encrypt(&plaintext, new_passphrase, encrypted.algorithm()?)?;
+
+    store_keyblob(repository, &reencrypted.encode()?)
+}
+
+/// Determine where the repository keeps its key and load the base64 key blob
+/// from there
+pub fn load_keyblob(repository: &Repository) -> Result<(KeyType, String)> {
+    let manifest_data = RepositoryState::load(repository)?
+        .get(repository, &MANIFEST_ID)?
+        .ok_or_else(|| eyre!("repository does not contain a manifest"))?;
+
+    let key_type = KeyType::from_byte(
+        *manifest_data
+            .first()
+            .ok_or_else(|| eyre!("manifest object is empty"))?,
+    )?;
+
+    let blob = match key_type {
+        KeyType::Plaintext => bail!("repository is not encrypted, it does not have a key"),
+        KeyType::Keyfile | KeyType::Blake2Keyfile => {
+            key::find_keyfile(&repository.id)?
+                .ok_or_else(|| eyre!("no key file found for repository {}", repository.id))?
+                .1
+        }
+        _ => {
+            if repository.config.key.trim().is_empty() {
+                bail!("repository config does not contain a key");
+            }
+
+            repository.config.key.clone()
+        }
+    };
+
+    Ok((key_type, blob))
+}
+
+/// Store a key blob in the config or keys directory depending on the type of
+/// key the repository uses
+pub fn store_keyblob(repository: &mut Repository, blob: &str) -> Result<()> {
+    let key_type = match RepositoryState::load(repository)?.get(repository, &MANIFEST_ID)? {
+        Some(data) => KeyType::from_byte(*data.first().unwrap_or(&0xff))?,
+        None => bail!("repository does not contain a manifest"),
+    };
+
+    if key_type.is_keyfile() {
+        let target = match key::find_keyfile(&repository.id)? {
+            Some((path, _)) => path,
+            None => new_keyfile_path(repository)?,
+        };
+
+        write_atomic(&target, keyfile_data(&repository.id, blob).as_bytes())
+    } else {
+        repository.config.key = blob.trim_end().to_string();
+
+        repository.save_config()
+    }
+}
+
+/// Pick a file name in the keys directory for a repository that does not have
+/// a keyfile yet, based on the repository's directory name
+fn new_keyfile_path(repository: &Repository) -> Result<PathBuf> {
+    let dir = key::keys_dir()?;
+    std::fs::create_dir_all(&dir).wrap_err("create keys directory")?;
+
+    let name: String = repository
+        .path
+        .canonicalize()
+        .unwrap_or_else(|_| repository.path.clone())
+        .to_string_lossy()
+        .trim_start_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    let mut path = dir.join(&name);
+    let mut i = 1;
+    while path.exists() {
+        path = dir.join(format!("{name}.{i}"));
+        i += 1;
+    }
+
+    Ok(path)
+}
+
+/// Format a key blob the way borg stores it in a keyfile
+pub fn keyfile_data(repository_id: &str, blob: &str) -> String {
+    let mut data = format!("BORG_KEY {repository_id}\n{blob}");
+
+    if !data.ends_with('\n') {
+        data.push('\n');
+    }
+
+    data
+}
+
+fn parse_keyfile_data(repository_id: &str, input: &str) -> Result<String> {
+    if !input.starts_with("BORG_KEY ") {
+        bail!("input is not a borg key file");
+    }
+
+    key::keyfile_blob(input, repository_id)
+        .ok_or_else(|| eyre!("key file belongs to a different repository"))
+}
+
+/// Format a key blob as a paper key. Every line carries a short checksum so
+/// typos can be caught line by line when the key is typed back in.
+pub fn paper_key(repository_id: &str, blob: &str) -> Result<String> {
+    let binary = decode_blob(blob)?;
+
+    let lines = binary.len().div_ceil(PAPER_LINE_BYTES);
+    let short_id = &repository_id.to_lowercase()[..18];
+    let checksum = sha256_truncated(&binary, 12);
+    let id_checksum = sha256_truncated(format!("{lines}/{short_id}/{checksum}").as_bytes(), 2);
+
+    let mut out = String::from("To restore key use bork key import --paper /path/to/repo\n\n");
+    out.push_str("BORG PAPER KEY v1\n");
+    out.push_str(&format!(
+        "id: {lines} / {} / {} - {id_checksum}\n",
+        grouped(short_id),
+        grouped(&checksum)
+    ));
+
+    let width = lines.to_string().len();
+
+    for (i, line) in binary.chunks(PAPER_LINE_BYTES).enumerate() {
+        let index = i + 1;
+
+        out.push_str(&format!(
+            "{index:width$}: {} - {}\n",
+            grouped(&key::hex_lower(line)),
+            line_checksum(index, line)
+        ));
+    }
+
+    Ok(out)
+}
+
+/// Parse a paper key produced by `paper_key`, verifying every checksum, and
+/// return the key blob
+pub fn parse_paper_key(repository_id: &str, input: &str) -> Result<String> {
+    let mut lines = input
+        .lines()
+        .map(|line| line.trim())
+        .skip_while(|line| !line.starts_with("id:"));
+
+    let id_line = lines
+        .next()
+        .ok_or_else(|| eyre!("paper key does not contain an id line"))?;
+
+    let (id_data, id_checksum) =
+        split_checksum(&id_line[3..]).ok_or_else(|| eyre!("id line is missing its checksum"))?;
+
+    let id_parts: Vec<String> = id_data.split('/').map(ungrouped).collect();
+    let [line_count, short_id, checksum] = id_parts.as_slice() else {
+        bail!("id line is malformed");
+    };
+
+    if sha256_truncated(format!("{line_count}/{short_id}/{checksum}").as_bytes(), 2) != id_checksum
+    {
+        bail!("id line checksum mismatch, check the id line for typos");
+    }
+
+    if !repository_id.to_lowercase().starts_with(short_id.as_str()) {
+        bail!("paper key belongs to a different repository");
+    }
+
+    let line_count: usize = line_count
+        .parse()
+        .wrap_err("id line has an invalid line count")?;
+
+    let mut binary = Vec::new();
+
+    for index in 1..=line_count {
+        let line = lines
+            .next()
+            .ok_or_else(|| eyre!("paper key ends before line {index}"))?;
+
+        let (number, rest) = line
+            .split_once(':')
+            .ok_or_else(|| eyre!("line {index} is malformed"))?;
+
+        if number.trim().parse::<usize>().ok() != Some(index) {
+            bail!("expected line {index} but found line {}", number.trim());
+        }
+
+        let (data, checksum) =
+            split_checksum(rest).ok_or_else(|| eyre!("line {index} is missing its checksum"))?;
+
+        let data = hex_decode(&ungrouped(data))
+            .ok_or_else(|| eyre!("line {index} contains invalid hex"))?;
+
+        if line_checksum(index, &data) != checksum {
+            bail!("line {index} checksum mismatch, check the line for typos");
+        }
+
+        binary.extend_from_slice(&data);
+    }
+
+    if &sha256_truncated(&binary, 12) != checksum {
+        bail!("key checksum mismatch, the paper key is incomplete or corrupt");
+    }
+
+    Ok(encode_blob(&binary))
+}
+
+/// Format a key blob as a printable HTML page, with the keyfile also as a
+/// QR code like borg's `--qr-html`
+pub fn html_key(repository_id: &str, blob: &str) -> Result<String> {
+    let keyfile = keyfile_data(repository_id, blob);
+    let paper = paper_key(repository_id, blob)?;
+    let qr = qr_svg(&keyfile)?;
+
+    Ok(format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>BorgBackup key for repository {repository_id}</title>
+<style>
+body {{ font-family: sans-serif; }}
+pre, textarea {{ font-family: monospace; font-size: 10pt; }}
+textarea {{ width: 100%; border: none; resize: none; }}
+</style>
+</head>
+<body>
+<h1>BorgBackup key</h1>
+<p>Repository {repository_id}</p>
+<h2>Keyfile</h2>
+<p>Import with <code>bork key import /path/to/repo keyfile</code> after scanning the code or copying the text.</p>
+{qr}
+<textarea id="key" rows="{rows}" readonly>{keyfile}</textarea>
+<h2>Paper key</h2>
+<pre>{paper}</pre>
+</body>
+</html>
+"#,
+        rows = keyfile.lines().count(),
+    ))
+}
+
+/// Render `text` as a QR code in an SVG image, one unit per module and with
+/// the quiet zone of four modules scanners expect
+fn qr_svg(text: &str) -> Result<String> {
+    const QUIET_ZONE: i32 = 4;
+
+    let qr = QrCode::encode_text(text, QrCodeEcc::Low)
+        .map_err(|_| eyre!("the key is too long for a QR code"))?;
+
+    let size = qr.size() + 2 * QUIET_ZONE;
+    let mut path = String::new();
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get_module(x, y) {
+                path.push_str(&format!("M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE));
+            }
+        }
+    }
+
+    Ok(format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {size} {size}" width="{width}" height="{width}" shape-rendering="crispEdges"><rect width="100%" height="100%" fill="white"/><path d="{path}" fill="black"/></svg>"#,
+        width = size * 4,
+    ))
+}
+
+/// Split `data - checksum` into its parts
+fn split_checksum(s: &str) -> Option<(&str, String)> {
+    let (data, checksum) = s.rsplit_once('-')?;
+
+    Some((data.trim(), checksum.trim().to_lowercase()))
+}
+
+fn line_checksum(index: usize, data: &[u8]) -> String {
+    let mut input = (index as u16).to_be_bytes().to_vec();
+    input.extend_from_slice(data);
+
+    sha256_truncated(&input, 2)
+}
+
+fn sha256_truncated(data: &[u8], len: usize) -> String {
+    let mut hex = key::hex_lower(&Sha256::digest(data));
+    hex.truncate(len);
+
+    hex
+}
+
+/// Insert a space every 6 characters to make long hex strings easier to copy
+fn grouped(s: &str) -> String {
+    let mut out = String::new();
+
+    for (i, c) in s.chars().enumerate() {
+        if i != 0 && i % 6 == 0 {
+            out.push(' ');
+        }
+
+        out.push(c);
+    }
+
+    out
+}
+
+fn ungrouped(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn decode_blob(blob: &str) -> Result<Vec<u8>> {
+    let stripped: String = blob.chars().filter(|c| !c.is_whitespace()).collect();
+
+    base64::engine::general_purpose::STANDARD
+        .decode(stripped)
+        .wrap_err("decode key base64")
+}
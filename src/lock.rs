@@ -0,0 +1,586 @@
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use eyre::{bail, Context, Result};
+
+/// How long to sleep between attempts to take a lock
+const LOCK_SLEEP: Duration = Duration::from_millis(200);
+
+const SHARED: &str = "shared";
+const EXCLUSIVE: &str = "exclusive";
+
+/// Identifies a single process on a single host the same way borg does, so
+/// that borg and bork respect each other's locks
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ProcessId {
+    pub host: String,
+    pub pid: u32,
+    pub thread: u32,
+}
+
+impl ProcessId {
+    pub fn current() -> Self {
+        Self {
+            host: host_id(),
+            pid: std::process::id(),
+            thread: 0,
+        }
+    }
+
+    /// The name of the file that marks this process as the owner of an
+    /// exclusive lock directory, `host.pid-thread` with the thread in hex
+    fn unique_name(&self) -> String {
+        format!("{}.{}-{:x}", self.host, self.pid, self.thread)
+    }
+
+    fn parse_unique_name(name: &str) -> Option<Self> {
+        let (host_pid, thread) = name.rsplit_once('-')?;
+        let (host, pid) = host_pid.rsplit_once('.')?;
+
+        Some(Self {
+            host: host.to_string(),
+            pid: pid.parse().ok()?,
+            thread: u32::from_str_radix(thread, 16).ok()?,
+        })
+    }
+
+    /// Whether the process could still be holding a lock. Processes on other
+    /// hosts are assumed to be alive since we have no way to check.
+    fn is_alive(&self) -> bool {
+        if self.host != host_id() {
+            return true;
+        }
+
+        // SAFETY: signal 0 performs no action, it only checks that the process
+        // exists
+        let result = unsafe { libc::kill(self.pid as libc::pid_t, 0) };
+
+        result == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+    }
+}
+
+/// Borg identifies hosts as `fqdn@node`, where node is the MAC address of
+/// the machine as an integer. `BORG_HOST_ID` overrides this.
+fn host_id() -> String {
+    if let Ok(id) = std::env::var("BORG_HOST_ID") {
+        return id;
+    }
+
+    format!("{}@{}", fqdn(), node())
+}
+
+/// The name of this machine, as python's `platform.node()` reports it
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "localhost".to_string())
+}
+
+/// The fully qualified name of this machine, as python's `socket.getfqdn()`
+/// finds it: the first name its address resolves back to that contains a
+/// dot, else the canonical one. Without a reverse lookup it is the host
+/// name.
+fn fqdn() -> String {
+    let name = hostname();
+
+    match reverse_lookup(&name) {
+        Some(names) => names
+            .iter()
+            .find(|name| name.contains('.'))
+            .unwrap_or(&names[0])
+            .clone(),
+        None => name,
+    }
+}
+
+extern "C" {
+    fn gethostbyaddr_r(
+        addr: *const libc::c_void,
+        len: libc::socklen_t,
+        family: libc::c_int,
+        ret: *mut libc::hostent,
+        buf: *mut libc::c_char,
+        buflen: libc::size_t,
+        result: *mut *mut libc::hostent,
+        h_errnop: *mut libc::c_int,
+    ) -> libc::c_int;
+}
+
+/// The canonical name and the aliases of the first address `name` resolves
+/// to, like python's `socket.gethostbyaddr()`
+fn reverse_lookup(name: &str) -> Option<Vec<String>> {
+    let name = CString::new(name).ok()?;
+
+    // SAFETY: all zeros is a valid addrinfo, asking for any family
+    let hints = libc::addrinfo {
+        ai_family: libc::AF_UNSPEC,
+        ..unsafe { std::mem::zeroed() }
+    };
+
+    let mut info = std::ptr::null_mut();
+    // SAFETY: the name is NUL terminated and the results are freed below
+    if unsafe { libc::getaddrinfo(name.as_ptr(), std::ptr::null(), &hints, &mut info) } != 0 {
+        return None;
+    }
+
+    // SAFETY: a successful getaddrinfo returns at least one result, whose
+    // address matches its family
+    let (family, address) = unsafe {
+        let address = (*info).ai_addr;
+        let result = match (*address).sa_family as libc::c_int {
+            libc::AF_INET => {
+                let address = &*(address as *const libc::sockaddr_in);
+                Some((
+                    libc::AF_INET,
+                    address.sin_addr.s_addr.to_ne_bytes().to_vec(),
+                ))
+            }
+            libc::AF_INET6 => {
+                let address = &*(address as *const libc::sockaddr_in6);
+                Some((libc::AF_INET6, address.sin6_addr.s6_addr.to_vec()))
+            }
+            _ => None,
+        };
+        libc::freeaddrinfo(info);
+
+        result?
+    };
+
+    // python's buffer size
+    let mut buffer = vec![0 as libc::c_char; 16384];
+    // SAFETY: all zeros is a valid hostent, filled in below
+    let mut entry: libc::hostent = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut error = 0;
+
+    // SAFETY: the buffers outlive the call and their sizes are passed along
+    let status = unsafe {
+        gethostbyaddr_r(
+            address.as_ptr().cast(),
+            address.len() as libc::socklen_t,
+            family,
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+            &mut error,
+        )
+    };
+    if status != 0 || result.is_null() {
+        return None;
+    }
+
+    // SAFETY: on success the entry holds a NUL terminated name and a NULL
+    // terminated list of aliases, all pointing into the buffer
+    unsafe {
+        let mut names = vec![CStr::from_ptr(entry.h_name).to_string_lossy().into_owned()];
+
+        let mut alias = entry.h_aliases;
+        while !alias.is_null() && !(*alias).is_null() {
+            names.push(CStr::from_ptr(*alias).to_string_lossy().into_owned());
+            alias = alias.add(1);
+        }
+
+        Some(names)
+    }
+}
+
+/// The node of this machine as python's `uuid.getnode()` gets it from
+/// libuuid: the first hardware address that is not all zeros among the
+/// interfaces `SIOCGIFCONF` lists. Without one it is a random number with
+/// the multicast bit set, which no hardware address has, picked once per
+/// process.
+fn node() -> u64 {
+    static NODE: OnceLock<u64> = OnceLock::new();
+
+    *NODE.get_or_init(|| {
+        hardware_address().unwrap_or_else(|| rand::random::<u64>() & 0xffff_ffff_ffff | 1 << 40)
+    })
+}
+
+fn hardware_address() -> Option<u64> {
+    // SAFETY: a plain socket, closed below
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if socket < 0 {
+        return None;
+    }
+
+    // SAFETY: all zeros is a valid ifreq
+    let mut requests: Vec<libc::ifreq> = (0..64).map(|_| unsafe { std::mem::zeroed() }).collect();
+    let mut conf = libc::ifconf {
+        ifc_len: (requests.len() * std::mem::size_of::<libc::ifreq>()) as libc::c_int,
+        ifc_ifcu: libc::__c_anonymous_ifc_ifcu {
+            ifcu_req: requests.as_mut_ptr(),
+        },
+    };
+
+    // SAFETY: the buffer holds as many requests as `ifc_len` says
+    let address = if unsafe { libc::ioctl(socket, libc::SIOCGIFCONF, &mut conf) } < 0 {
+        None
+    } else {
+        let count = conf.ifc_len as usize / std::mem::size_of::<libc::ifreq>();
+
+        requests[..count].iter_mut().find_map(|request| {
+            // SAFETY: the request names an interface and has room for the
+            // address, which is read from the field the kernel fills in
+            let data = unsafe {
+                if libc::ioctl(socket, libc::SIOCGIFHWADDR, request as *mut libc::ifreq) < 0 {
+                    return None;
+                }
+
+                request.ifr_ifru.ifru_hwaddr.sa_data
+            };
+
+            let node = data[..6]
+                .iter()
+                .fold(0, |node, &byte| node << 8 | byte as u8 as u64);
+
+            (node != 0).then_some(node)
+        })
+    };
+
+    // SAFETY: the socket was opened above
+    unsafe { libc::close(socket) };
+
+    address
+}
+
+/// A lock only one process can hold, implemented as a directory that contains
+/// a file naming its owner. The directory is created under a temporary name
+/// and renamed into place, so it is never observed without an owner.
+#[derive(Debug)]
+struct ExclusiveLock {
+    path: PathBuf,
+    id: ProcessId,
+    timeout: Duration,
+}
+
+impl ExclusiveLock {
+    fn new(path: PathBuf, id: ProcessId, timeout: Duration) -> Self {
+        Self { path, id, timeout }
+    }
+
+    fn acquire(&self) -> Result<()> {
+        let parent = self.path.parent().unwrap_or_else(|| Path::new("."));
+        let base_name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("lock.exclusive");
+
+        let temp_path = parent.join(format!(
+            "{base_name}.{}-{}.tmp",
+            self.id.pid,
+            rand::random::<u32>()
+        ));
+
+        std::fs::create_dir(&temp_path)
+            .wrap_err_with(|| format!("create {}", temp_path.display()))?;
+        std::fs::write(temp_path.join(self.id.unique_name()), b"").wrap_err("write lock owner")?;
+
+        let start = Instant::now();
+
+        let result = loop {
+            if std::fs::rename(&temp_path, &self.path).is_ok() {
+                break Ok(());
+            }
+
+            if self.by_me() {
+                break Ok(());
+            }
+
+            if self.kill_stale_lock() {
+                continue;
+            }
+
+            if start.elapsed() >= self.timeout {
+                break Err(eyre::eyre!(
+                    "failed to create/acquire the lock {} (timeout)",
+                    self.path.display()
+                ));
+            }
+
+            std::thread::sleep(LOCK_SLEEP);
+        };
+
+        // only still exists if the rename never succeeded
+        _ = std::fs::remove_file(temp_path.join(self.id.unique_name()));
+        _ = std::fs::remove_dir(&temp_path);
+
+        result
+    }
+
+    fn release(&self) -> Result<()> {
+        let owner = self.path.join(self.id.unique_name());
+
+        if !owner.exists() {
+            bail!("lock {} is not held by us", self.path.display());
+        }
+
+        std::fs::remove_file(&owner).wrap_err("remove lock owner")?;
+        std::fs::remove_dir(&self.path).wrap_err("remove lock directory")?;
+
+        Ok(())
+    }
+
+    fn by_me(&self) -> bool {
+        self.path.join(self.id.unique_name()).exists()
+    }
+
+    /// Remove the lock if it belongs to a process on this host that no longer
+    /// exists. Returns whether the lock was removed.
+    fn kill_stale_lock(&self) -> bool {
+        let Ok(entries) = std::fs::read_dir(&self.path) else {
+            return false;
+        };
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name();
+            let Some(owner) = name.to_str().and_then(ProcessId::parse_unique_name) else {
+                continue;
+            };
+
+            if !owner.is_alive() {
+                eprintln!(
+                    "warning: removing stale exclusive lock of host {} pid {} thread {}",
+                    owner.host, owner.pid, owner.thread
+                );
+                _ = std::fs::remove_file(entry.path());
+            }
+        }
+
+        std::fs::remove_dir(&self.path).is_ok()
+    }
+
+    fn break_lock(&self) -> Result<()> {
+        if self.path.exists() {
+            std::fs::remove_dir_all(&self.path)
+                .wrap_err_with(|| format!("remove {}", self.path.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A JSON file listing which processes hold shared and exclusive locks
+#[derive(Debug)]
+struct LockRoster {
+    path: PathBuf,
+    id: ProcessId,
+}
+
+type Roster = HashMap<String, Vec<(String, u32, u32)>>;
+
+impl LockRoster {
+    fn load(&self) -> Roster {
+        let Ok(data) = std::fs::read(&self.path) else {
+            return Roster::new();
+        };
+
+        // a corrupt or empty roster is treated like a missing one, like borg
+        let mut roster: Roster = serde_json::from_slice(&data).unwrap_or_default();
+
+        // drop entries of processes that have died without releasing
+        for (kind, entries) in roster.iter_mut() {
+            entries.retain(|(host, pid, thread)| {
+                let alive = ProcessId {
+                    host: host.clone(),
+                    pid: *pid,
+                    thread: *thread,
+                }
+                .is_alive();
+
+                if !alive {
+                    eprintln!(
+                        "warning: removing stale {kind} roster lock for host {host} pid {pid} thread {thread}"
+                    );
+                }
+
+                alive
+            });
+        }
+
+        roster
+    }
+
+    fn save(&self, roster: &Roster) -> Result<()> {
+        std::fs::write(&self.path, serde_json::to_vec(roster)?)
+            .wrap_err_with(|| format!("write {}", self.path.display()))
+    }
+
+    fn get(&self, kind: &str) -> Vec<(String, u32, u32)> {
+        self.load().remove(kind).unwrap_or_default()
+    }
+
+    fn empty(&self) -> bool {
+        self.load().values().all(|entries| entries.is_empty())
+    }
+
+    fn modify(&self, kind: &str, add: bool) -> Result<()> {
+        let mut roster = self.load();
+        let entries = roster.entry(kind.to_string()).or_default();
+        let me = (self.id.host.clone(), self.id.pid, self.id.thread);
+
+        if add {
+            if !entries.contains(&me) {
+                entries.push(me);
+            }
+        } else {
+            entries.retain(|entry| entry != &me);
+        }
+
+        self.save(&roster)
+    }
+
+    fn remove(&self) -> Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).wrap_err_with(|| format!("remove {}", self.path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Any number of readers may hold a shared lock at the same time
+    Shared,
+
+    /// Writers hold an exclusive lock, which excludes every other lock
+    Exclusive,
+}
+
+/// A repository lock compatible with borg's. Shared locks are registered in the
+/// roster while briefly holding the exclusive lock directory; an exclusive lock
+/// holds the directory for as long as it lives, after waiting for all shared
+/// lock holders to leave. The lock is released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    mode: LockMode,
+    exclusive: ExclusiveLock,
+    roster: LockRoster,
+}
+
+impl Lock {
+    /// Acquire the lock at `path` (usually `<repository>/lock`), waiting at
+    /// most `timeout` for other processes to release it
+    pub fn acquire(path: &Path, mode: LockMode, timeout: Duration) -> Result<Self> {
+        Self::acquire_as(path, mode, timeout, ProcessId::current())
+    }
+
+    /// Acquire the lock on behalf of a specific process identity
+    pub fn acquire_as(
+        path: &Path,
+        mode: LockMode,
+        timeout: Duration,
+        id: ProcessId,
+    ) -> Result<Self> {
+        let lock = Self {
+            mode,
+            exclusive: ExclusiveLock::new(with_suffix(path, ".exclusive"), id.clone(), timeout),
+            roster: LockRoster {
+                path: with_suffix(path, ".roster"),
+                id,
+            },
+        };
+
+        match mode {
+            LockMode::Shared => {
+                lock.exclusive.acquire()?;
+                let result = lock.roster.modify(SHARED, true);
+                lock.exclusive.release()?;
+                result?;
+            }
+            LockMode::Exclusive => {
+                lock.wait_for_readers(timeout)?;
+                lock.roster.modify(EXCLUSIVE, true)?;
+            }
+        }
+
+        Ok(lock)
+    }
+
+    /// Take the exclusive lock directory and keep it once no shared lock
+    /// holders are left
+    fn wait_for_readers(&self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+
+        loop {
+            self.exclusive.acquire()?;
+
+            if self.roster.get(SHARED).is_empty() {
+                return Ok(());
+            }
+
+            self.exclusive.release()?;
+
+            if start.elapsed() >= timeout {
+                bail!(
+                    "failed to acquire the exclusive lock {}, other processes are reading the repository (timeout)",
+                    self.exclusive.path.display()
+                );
+            }
+
+            std::thread::sleep(LOCK_SLEEP);
+        }
+    }
+
+    fn release(&self) -> Result<()> {
+        match self.mode {
+            LockMode::Shared => {
+                self.exclusive.acquire()?;
+                let result = self.roster.modify(SHARED, false).and_then(|_| {
+                    if self.roster.empty() {
+                        self.roster.remove()?;
+                    }
+
+                    Ok(())
+                });
+                self.exclusive.release()?;
+                result
+            }
+            LockMode::Exclusive => {
+                self.roster.modify(EXCLUSIVE, false)?;
+                if self.roster.empty() {
+                    self.roster.remove()?;
+                }
+                self.exclusive.release()
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            eprintln!("warning: failed to release lock: {e:#}");
+        }
+    }
+}
+
+/// Forcefully remove a lock regardless of who holds it. Only safe when no
+/// borg or bork process is using the repository.
+pub fn break_lock(path: &Path) -> Result<()> {
+    let id = ProcessId::current();
+
+    LockRoster {
+        path: with_suffix(path, ".roster"),
+        id: id.clone(),
+    }
+    .remove()?;
+
+    ExclusiveLock::new(with_suffix(path, ".exclusive"), id, Duration::ZERO).break_lock()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+
+    PathBuf::from(path)
+}
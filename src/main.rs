@@ -5,33 +5,585 @@ use std::{
     ffi::OsStr,
     fmt::Debug,
     fs::File,
-    io::{BufReader, ErrorKind, Read},
-    path::PathBuf,
+    io::{BufReader, Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use configparser::ini::Ini;
+use clap::{Parser, Subcommand};
+use compression::{decompress, Compression};
+use config::RepositoryConfig;
 use eyre::{bail, eyre, Context, Result};
+use hints::HintsFile;
+use key::Key;
+use keymanager::ExportFormat;
+use lock::{Lock, LockMode};
 use msgpack::{Bytes, PythonValue};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use tam::TamContext;
 
 #[cfg(test)]
 mod tests;
 
+mod cache;
+mod check;
+mod chunker;
+mod compact;
+mod compression;
+mod config;
+mod create;
+mod debug;
+mod delete;
+mod glob;
+mod hashindex;
+mod hints;
+mod init;
+mod integrity;
+mod key;
+mod keymanager;
+mod lock;
 mod msgpack;
+mod prune;
+mod recover;
+mod salvage;
+mod segment_writer;
+mod tam;
+mod transaction;
 
 const MANIFEST_ID: [u8; 32] = [0; 32];
 
-fn main() -> Result<()> {
-    let path = PathBuf::from(std::env::args().nth(1).unwrap());
+/// Every key an item in an archive may have, which borg records in the
+/// manifest
+const ITEM_KEYS: &[&str] = &[
+    "acl_access",
+    "acl_default",
+    "acl_extended",
+    "acl_nfs4",
+    "atime",
+    "birthtime",
+    "bsdflags",
+    "chunks",
+    "chunks_healthy",
+    "ctime",
+    "gid",
+    "group",
+    "hardlink_master",
+    "mode",
+    "mtime",
+    "part",
+    "path",
+    "rdev",
+    "size",
+    "source",
+    "uid",
+    "user",
+    "xattrs",
+];
+
+/// The timestamp format borg uses in manifests and archives, always in UTC
+const ISO_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f";
+
+/// Experimental tools for working with BorgBackup repositories
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    options: Options,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Options that apply to every command
+#[derive(clap::Args, Debug, Clone, Copy)]
+struct Options {
+    #[command(flatten)]
+    tam: TamOptions,
+
+    /// How many seconds to wait for another process to release the
+    /// repository lock
+    #[arg(long, global = true, default_value_t = 1)]
+    lock_wait: u64,
+}
+
+impl Options {
+    fn lock_wait(&self) -> Duration {
+        Duration::from_secs(self.lock_wait)
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            tam: TamOptions::default(),
+            lock_wait: 1,
+        }
+    }
+}
+
+/// Which metadata objects may be read without a valid TAM even if the
+/// repository requires one
+#[derive(clap::Args, Debug, Default, Clone, Copy)]
+struct TamOptions {
+    /// Accept a manifest without a valid TAM even if the repository requires
+    /// one. Only use this if you know why the manifest is unauthenticated.
+    #[arg(long = "allow-unauthenticated-manifest", global = true)]
+    manifest: bool,
+
+    /// Accept archives without a TAM even if the repository requires one.
+    /// Archives written before borg 1.2.5 do not have a TAM.
+    #[arg(long = "allow-unauthenticated-archives", global = true)]
+    archives: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a new, empty repository
+    Init {
+        repository: PathBuf,
+
+        /// How the repository is encrypted and authenticated
+        #[arg(short, long, value_enum)]
+        encryption: init::EncryptionMode,
+    },
+
+    /// Back up files and directories into a new archive
+    Create {
+        /// Where to create the archive, as REPOSITORY::NAME. The name may
+        /// contain placeholders such as {now} and {hostname}.
+        location: String,
+
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// How files are cut into chunks, in borg's syntax
+        #[arg(long, default_value = "default", value_parser = chunker::ChunkerParams::parse)]
+        chunker_params: chunker::ChunkerParams,
+
+        /// How objects are compressed, in borg's syntax
+        #[arg(long, short = 'C', default_value = "lz4", value_parser = Compression::parse)]
+        compression: Compression,
+
+        /// Which file properties must be unchanged for a file to be taken
+        /// from the files cache instead of being read again, or `disabled`
+        #[arg(long, default_value = "ctime,size,inode", value_parser = cache::FilesCacheMode::parse)]
+        files_cache: cache::FilesCacheMode,
+
+        /// Comment stored with the archive
+        #[arg(long, default_value = "")]
+        comment: String,
+    },
+
+    /// Delete archives, or the whole repository if no archive is given
+    Delete {
+        /// The repository, or REPOSITORY::NAME for a single archive
+        location: String,
+
+        /// More archives to delete
+        archives: Vec<String>,
+
+        /// Delete the archives whose names match this shell pattern
+        #[arg(short = 'a', long)]
+        glob_archives: Option<String>,
+
+        /// Only show what would be deleted
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Keep the local security info when deleting a repository
+        #[arg(long)]
+        keep_security_info: bool,
+    },
+
+    /// Delete the archives that no retention rule keeps
+    Prune {
+        repository: PathBuf,
+
+        #[command(flatten)]
+        rules: prune::KeepRules,
+
+        /// Only consider archives whose names match this shell pattern
+        #[arg(short = 'a', long)]
+        glob_archives: Option<String>,
+
+        /// Only show what would be pruned
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// List every archive with what happens to it
+        #[arg(long)]
+        list: bool,
+    },
+
+    /// Check the repository for damage. Exits with status 1 if any problem
+    /// was found.
+    Check {
+        repository: PathBuf,
+
+        /// Only check the segments and the index, not the archives
+        #[arg(long, conflicts_with = "archives_only")]
+        repository_only: bool,
+
+        /// Only check the archives, not the segments and the index
+        #[arg(long)]
+        archives_only: bool,
+
+        /// Fix the problems found as far as possible. Damaged segments are
+        /// recovered, missing file chunks are replaced with zeros and a lost
+        /// manifest is rebuilt.
+        #[arg(long)]
+        repair: bool,
+    },
+
+    /// Free the space of deleted objects by rewriting the segments they are
+    /// in
+    Compact {
+        repository: PathBuf,
+
+        /// Only compact segments in which more than this percentage of the
+        /// space can be freed
+        #[arg(long, default_value_t = 10.0)]
+        threshold: f64,
+    },
+
+    /// Rebuild a lost or damaged manifest from the archives in the
+    /// repository
+    RecoverManifest {
+        repository: PathBuf,
+
+        /// Only list the archives the rebuilt manifest would hold
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    /// Find deleted archives whose objects are still in the segments, as a
+    /// last resort after an accidental delete
+    Salvage {
+        #[command(subcommand)]
+        command: SalvageCommand,
+    },
+
+    /// Extract the files of every archive into example/extracted
+    Extract { repository: PathBuf },
+
+    /// Forcefully remove the repository lock. Only use this if no borg or
+    /// bork process is using the repository.
+    BreakLock { repository: PathBuf },
+
+    /// Manage the repository key
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+
+    /// Tools for investigating repositories by hand
+    Debug {
+        #[command(subcommand)]
+        command: DebugCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum SalvageCommand {
+    /// List the deleted archives that can still be salvaged
+    List { repository: PathBuf },
+
+    /// Extract a deleted archive into a directory
+    Extract {
+        repository: PathBuf,
+
+        /// The name of the archive, or a prefix of its ID if several
+        /// deleted archives have that name
+        archive: String,
+
+        destination: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Export the repository key for safekeeping
+    Export {
+        repository: PathBuf,
+
+        /// Where to write the key, defaults to stdout
+        path: Option<PathBuf>,
+
+        /// Export a human readable paper key with checksummed lines
+        #[arg(long, conflicts_with = "qr_html")]
+        paper: bool,
+
+        /// Export a printable HTML page containing the key
+        #[arg(long)]
+        qr_html: bool,
+    },
+
+    /// Restore the repository key from a previous export
+    Import {
+        repository: PathBuf,
+
+        /// Where to read the key from, defaults to stdin
+        path: Option<PathBuf>,
+
+        /// Import a paper key written by `key export --paper`
+        #[arg(long)]
+        paper: bool,
+    },
+
+    /// Re-encrypt the repository key with a new passphrase
+    ChangePassphrase { repository: PathBuf },
+}
+
+#[derive(Subcommand)]
+enum DebugCommand {
+    /// Print the entries of a segment with their offsets and CRC status
+    DumpSegment {
+        repository: PathBuf,
+
+        segment: u32,
+
+        /// Only show entries with this tag, may be given several times
+        #[arg(long, value_enum)]
+        tag: Vec<debug::EntryTag>,
+
+        /// Only show entries whose key starts with these hex digits
+        #[arg(long)]
+        key_prefix: Option<String>,
+
+        /// Also show the payload of PUT entries
+        #[arg(long, value_enum)]
+        payload: Option<debug::PayloadDump>,
+    },
+
+    /// Fetch an object by its ID
+    GetObj {
+        repository: PathBuf,
+
+        /// The object ID as 64 hex digits
+        id: String,
+
+        /// Where to write the object, defaults to stdout
+        path: Option<PathBuf>,
+
+        /// Remove the encryption and compression instead of writing the
+        /// object as stored
+        #[arg(long)]
+        unpack: bool,
+    },
+
+    /// Store the contents of a file as the object with the given ID, as is
+    PutObj {
+        repository: PathBuf,
+
+        /// The object ID as 64 hex digits
+        id: String,
 
-    extract(path)?;
+        path: PathBuf,
+    },
+
+    /// Delete objects by their IDs
+    DeleteObj {
+        repository: PathBuf,
+
+        /// The object IDs as 64 hex digits
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
+
+    /// Print the manifest as JSON
+    DumpManifest { repository: PathBuf },
+
+    /// Print the metadata and items of an archive as JSON
+    DumpArchive {
+        /// The archive, as REPOSITORY::NAME
+        location: String,
+    },
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let options = args.options;
+
+    match args.command {
+        Command::Init {
+            repository,
+            encryption,
+        } => init::init(&repository, encryption)?,
+        Command::Create {
+            location,
+            paths,
+            chunker_params,
+            compression,
+            files_cache,
+            comment,
+        } => create::create(
+            &location,
+            &paths,
+            chunker_params,
+            &compression,
+            files_cache,
+            comment,
+            &options,
+        )?,
+        Command::Delete {
+            location,
+            archives,
+            glob_archives,
+            dry_run,
+            keep_security_info,
+        } => delete::delete(
+            &location,
+            &archives,
+            glob_archives.as_deref(),
+            dry_run,
+            keep_security_info,
+            &options,
+        )?,
+        Command::Prune {
+            repository,
+            rules,
+            glob_archives,
+            dry_run,
+            list,
+        } => prune::prune(
+            repository,
+            &rules,
+            glob_archives.as_deref(),
+            dry_run,
+            list,
+            &options,
+        )?,
+        Command::Check {
+            repository,
+            repository_only,
+            archives_only,
+            repair,
+        } => {
+            // like borg, problems found exit with 1, failing to check with 2
+            match check::check(repository, repository_only, archives_only, repair, &options) {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("Error: {e:?}");
+                    std::process::exit(2);
+                }
+            }
+        }
+        Command::Compact {
+            repository,
+            threshold,
+        } => compact::compact(repository, threshold, &options)?,
+        Command::RecoverManifest {
+            repository,
+            dry_run,
+        } => recover::recover_manifest(repository, dry_run, &options)?,
+        Command::Salvage { command } => match command {
+            SalvageCommand::List { repository } => salvage::list(repository, &options)?,
+            SalvageCommand::Extract {
+                repository,
+                archive,
+                destination,
+            } => salvage::extract(repository, &archive, &destination, &options)?,
+        },
+        Command::Extract { repository } => extract(repository, &options)?,
+        Command::BreakLock { repository } => Repository::break_lock(&repository)?,
+        Command::Key { command } => match command {
+            KeyCommand::Export {
+                repository,
+                path,
+                paper,
+                qr_html,
+            } => {
+                let format = if paper {
+                    ExportFormat::Paper
+                } else if qr_html {
+                    ExportFormat::Html
+                } else {
+                    ExportFormat::Keyfile
+                };
+
+                keymanager::export(
+                    &Repository::open(repository, LockMode::Shared, options.lock_wait())?,
+                    path.as_deref(),
+                    format,
+                )?
+            }
+            KeyCommand::Import {
+                repository,
+                path,
+                paper,
+            } => keymanager::import(
+                &mut Repository::open(repository, LockMode::Exclusive, options.lock_wait())?,
+                path.as_deref(),
+                paper,
+            )?,
+            KeyCommand::ChangePassphrase { repository } => keymanager::change_passphrase(
+                &mut Repository::open(repository, LockMode::Exclusive, options.lock_wait())?,
+                &key::passphrase()?,
+                &key::new_passphrase()?,
+            )?,
+        },
+        Command::Debug { command } => match command {
+            DebugCommand::DumpSegment {
+                repository,
+                segment,
+                tag,
+                key_prefix,
+                payload,
+            } => debug::dump_segment(
+                repository,
+                segment,
+                &tag,
+                key_prefix.as_deref(),
+                payload,
+                &mut std::io::stdout().lock(),
+                &options,
+            )?,
+            DebugCommand::GetObj {
+                repository,
+                id,
+                path,
+                unpack,
+            } => match path {
+                Some(path) => {
+                    let mut file = File::create(&path)
+                        .wrap_err_with(|| format!("create {}", path.display()))?;
+                    debug::get_obj(repository, &id, unpack, &mut file, &options)?
+                }
+                None => debug::get_obj(
+                    repository,
+                    &id,
+                    unpack,
+                    &mut std::io::stdout().lock(),
+                    &options,
+                )?,
+            },
+            DebugCommand::PutObj {
+                repository,
+                id,
+                path,
+            } => {
+                let data =
+                    std::fs::read(&path).wrap_err_with(|| format!("read {}", path.display()))?;
+                debug::put_obj(repository, &id, &data, &options)?
+            }
+            DebugCommand::DeleteObj { repository, ids } => {
+                debug::delete_obj(repository, &ids, &options)?
+            }
+            DebugCommand::DumpManifest { repository } => {
+                debug::dump_manifest(repository, &mut std::io::stdout().lock(), &options)?
+            }
+            DebugCommand::DumpArchive { location } => {
+                debug::dump_archive(&location, &mut std::io::stdout().lock(), &options)?
+            }
+        },
+    }
 
     Ok(())
 }
 
-fn extract(path: PathBuf) -> Result<()> {
-    let repository = Repository::load(path)?;
+fn extract(path: PathBuf, options: &Options) -> Result<()> {
+    let repository = Repository::open(path, LockMode::Shared, options.lock_wait())?;
 
     let mut items = HashMap::<Vec<u8>, Vec<u8>>::new();
 
@@ -53,43 +605,39 @@ fn extract(path: PathBuf) -> Result<()> {
         println!();
     }
 
-    if let Some(manifest_data) = items.get(&Vec::from(MANIFEST_ID)) {
-        let data = unpack_data(&manifest_data)?;
+    let Some(manifest_data) = items.get(&Vec::from(MANIFEST_ID)) else {
+        bail!("repository has no manifest, `bork recover-manifest` can rebuild it");
+    };
 
-        let manifest =
-            rmp_serde::decode::from_slice::<Manifest>(&data).wrap_err("decode manifest msgpack")?;
-        dbg!(&manifest);
+    let key = Key::load(&repository, manifest_data)?;
+    let data = unpack_data(&key, manifest_data)?;
+
+    let manifest = Manifest::decode(&key, &data, options.tam.manifest)?;
 
-        for (_, manifest_archive) in manifest.archives {
-            if let Some(archive_data) = items.get(&manifest_archive.id.0) {
-                let data = unpack_data(&archive_data)?;
+    for (_, manifest_archive) in manifest.archives {
+        if let Some(archive_data) = items.get(&manifest_archive.id.0) {
+            let data = unpack_data(&key, archive_data)?;
 
-                let archive = rmp_serde::from_slice::<Archive>(&data)?;
-                dbg!(&archive);
+            let archive = Archive::decode(&key, &data, options.tam.archives)?;
 
-                for item_id in &archive.items {
-                    if let Some(item_data) = items.get(&item_id.0) {
-                        let data = unpack_data(&item_data)?;
+            for item_id in &archive.items {
+                if let Some(item_data) = items.get(&item_id.0) {
+                    let data = unpack_data(&key, item_data)?;
 
-                        let mut cursor = std::io::Cursor::new(data);
+                    let mut cursor = std::io::Cursor::new(data);
 
-                        while cursor_has_data(&cursor) {
-                            let item_metadata =
-                                rmp_serde::from_read::<_, ItemMetadata>(&mut cursor)?;
+                    while cursor_has_data(&cursor) {
+                        let item_metadata = rmp_serde::from_read::<_, ItemMetadata>(&mut cursor)?;
 
-                            println!("{}", item_metadata.path);
+                        println!("{}", item_metadata.path);
 
-                            let subbed_path = item_metadata.path.replace("/", "__");
+                        let subbed_path = item_metadata.path.replace('/', "__");
 
-                            for (id, _, _) in &item_metadata.chunks {
-                                if let Some(chunk) = items.get(&id.0) {
-                                    let data = unpack_data(&chunk)?;
+                        for (id, _, _) in &item_metadata.chunks {
+                            if let Some(chunk) = items.get(&id.0) {
+                                let data = unpack_data(&key, chunk)?;
 
-                                    std::fs::write(
-                                        format!("example/extracted/{subbed_path}"),
-                                        data,
-                                    )?;
-                                }
+                                std::fs::write(format!("example/extracted/{subbed_path}"), data)?;
                             }
                         }
                     }
@@ -117,51 +665,50 @@ fn cursor_has_data(data: &std::io::Cursor<Vec<u8>>) -> bool {
     (data.position() as usize) < len - 1
 }
 
+/// Write a file by writing to a temporary file next to it and renaming that
+/// over the target
+fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file =
+        File::create(&tmp_path).wrap_err_with(|| format!("create {}", tmp_path.display()))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    std::fs::rename(&tmp_path, path).wrap_err_with(|| format!("replace {}", path.display()))
+}
+
 /// Reads the data segment from a PUT log entry and removes the encryption and
 /// compression layers from it, returning a plain view of the data
-fn unpack_data(data: &[u8]) -> Result<Vec<u8>> {
-    let mut data = std::io::Cursor::new(data);
+fn unpack_data(key: &Key, data: &[u8]) -> Result<Vec<u8>> {
+    let payload = key.decrypt(data).wrap_err("decrypt object")?;
 
-    if data.read_u8().wrap_err("read encryption")? != 0x02 {
-        bail!("only plaintext data is supported");
-    }
+    decompress(&payload)
+}
 
-    let compression_tag = data
-        .read_u16::<LittleEndian>()
-        .wrap_err("read compression")?;
+/// Adds the compression and encryption layers to the plain data of an
+/// object, the inverse of `unpack_data`
+fn pack_data(key: &Key, compression: &Compression, data: &[u8]) -> Result<Vec<u8>> {
+    let payload = compression.compress(data)?;
 
-    let position = data.position() as usize;
-    let sliced_data = &data.into_inner()[position..];
+    key.encrypt(&payload).wrap_err("encrypt object")
+}
 
-    if compression_tag == 0x00_00 {
-        return Ok(sliced_data.to_vec());
-    }
+/// Split a `REPOSITORY::ARCHIVE` location into the repository path and the
+/// archive name, if there is one
+fn parse_location(location: &str) -> Result<(PathBuf, Option<String>)> {
+    let (repository, archive) = match location.split_once("::") {
+        Some((repository, archive)) => (repository, Some(archive.to_string())),
+        None => (location, None),
+    };
 
-    if compression_tag != 0x00_01 {
-        bail!("only lz4 compression is supported");
+    if repository.is_empty() {
+        bail!("no repository given in {location:?}");
     }
 
-    let mut size = sliced_data.len() * 3;
-    loop {
-        let mut buffer = vec![0; size];
-        match lz4::block::decompress_to_buffer(sliced_data, Some(size as i32), &mut buffer) {
-            Ok(bytes) => {
-                buffer.resize(bytes, 0);
-                return Ok(buffer);
-            }
-            Err(e) => {
-                if e.kind() == ErrorKind::InvalidInput {
-                    if size > 2usize.pow(27) {
-                        bail!("lz4 decompress failed");
-                    }
-
-                    size = (size as f64 * 1.5) as usize;
-                } else {
-                    return Err(e).wrap_err("lz4 decompress");
-                }
-            }
-        }
-    }
+    Ok((PathBuf::from(repository), archive))
 }
 
 fn number(o: &OsStr) -> Option<u32> {
@@ -174,27 +721,90 @@ fn number(o: &OsStr) -> Option<u32> {
 
 #[derive(Debug)]
 struct Hint {
-    data: HintData,
+    data: HintsFile,
     id: u32,
 }
 
-#[derive(Deserialize, Debug)]
-struct HintData {
-    version: u8,
-    segments: HashMap<PythonValue, PythonValue>,
-    compact: HashMap<PythonValue, PythonValue>,
-    storage_quota_use: PythonValue,
-    shadow_index: HashMap<PythonValue, PythonValue>,
-}
-
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 struct Manifest {
     version: u8,
     timestamp: String,
     item_keys: Vec<String>,
-    config: HashMap<String, String>,
+    config: HashMap<String, PythonValue>,
     archives: HashMap<String, ManifestArchive>,
-    tam: Tam,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tam: Option<Tam>,
+}
+
+impl Manifest {
+    /// An empty manifest for a new repository
+    fn new(key: &Key) -> Self {
+        let mut config = HashMap::new();
+        if key.tam_required() {
+            config.insert("tam_required".to_string(), PythonValue::Bool(true));
+        }
+
+        Self {
+            version: 1,
+            timestamp: chrono::Utc::now().format(ISO_FORMAT).to_string(),
+            item_keys: ITEM_KEYS.iter().map(|key| key.to_string()).collect(),
+            config,
+            archives: HashMap::new(),
+            tam: None,
+        }
+    }
+
+    /// Encode the manifest with a fresh TAM, ready to be packed and stored
+    /// under `MANIFEST_ID`
+    fn encode(&mut self, key: &Key) -> Result<Vec<u8>> {
+        tam::pack(
+            key,
+            self,
+            |manifest| &mut manifest.tam,
+            TamContext::Manifest,
+        )
+    }
+
+    /// Move the timestamp forward before writing the manifest again. Borg
+    /// refuses manifests older than the last one it saw, so the timestamp
+    /// must increase even if the clock went backwards.
+    fn update_timestamp(&mut self) {
+        let now = chrono::Utc::now().naive_utc();
+        let next = chrono::NaiveDateTime::parse_from_str(&self.timestamp, ISO_FORMAT)
+            .map(|previous| (previous + chrono::Duration::microseconds(1)).max(now))
+            .unwrap_or(now);
+
+        self.timestamp = next.format(ISO_FORMAT).to_string();
+    }
+
+    /// Decode an unpacked manifest object and verify its TAM. Manifests
+    /// without a valid TAM are rejected if the key requires one, unless
+    /// `allow_unauthenticated` is set.
+    fn decode(key: &Key, data: &[u8], allow_unauthenticated: bool) -> Result<Self> {
+        if data.starts_with(&[0xc1; 4]) {
+            bail!("manifest was written by a newer, unsupported version of borg");
+        }
+
+        let manifest =
+            rmp_serde::decode::from_slice::<Manifest>(data).wrap_err("decode manifest msgpack")?;
+
+        let mut required = key.tam_required();
+        if allow_unauthenticated && required {
+            eprintln!("warning: manifest authentication DISABLED");
+            required = false;
+        }
+
+        tam::verify(
+            key,
+            data,
+            manifest.tam.as_ref(),
+            TamContext::Manifest,
+            required,
+        )?;
+
+        Ok(manifest)
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -206,7 +816,7 @@ struct ItemMetadata {
     chunks: Vec<(Bytes, PythonValue, PythonValue)>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 struct Tam {
     #[serde(rename = "type")]
     tipe: String,
@@ -215,12 +825,37 @@ struct Tam {
     data: HashMap<String, Bytes>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct ManifestArchive {
     id: Bytes,
     time: String,
 }
 
+impl ManifestArchive {
+    /// When the archive was created. Borg writes the time in UTC, leaving out
+    /// the fraction if it is zero.
+    fn timestamp(&self) -> Result<chrono::DateTime<chrono::Utc>> {
+        chrono::NaiveDateTime::parse_from_str(&self.time, "%Y-%m-%dT%H:%M:%S%.f")
+            .map(|time| time.and_utc())
+            .wrap_err_with(|| format!("invalid archive time {:?}", self.time))
+    }
+
+    /// One line about an archive the way borg lists it: the name, the local
+    /// time it was created and its ID
+    fn format(&self, name: &str) -> String {
+        let time = self
+            .timestamp()
+            .map(|time| {
+                time.with_timezone(&chrono::Local)
+                    .format("%a, %Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|_| self.time.clone());
+
+        format!("{name:<36} {time} [{}]", key::hex_lower(&self.id.0))
+    }
+}
+
 impl Debug for ManifestArchive {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ManifestArchive")
@@ -233,11 +868,15 @@ impl Debug for ManifestArchive {
 #[derive(Debug)]
 struct Repository {
     path: PathBuf,
-    config: Ini,
+    config: RepositoryConfig,
     id: String,
+
+    /// Held for as long as the repository is open, if it was opened with
+    /// `Repository::open`
+    lock: Option<Lock>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 struct Archive {
     version: u8,
     name: String,
@@ -248,6 +887,75 @@ struct Archive {
     time: String,
     time_end: String,
     comment: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunker_params: Option<PythonValue>,
+
+    /// Statistics borg 1.2 records when an archive is created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    csize: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nfiles: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tam: Option<Tam>,
+}
+
+impl Archive {
+    /// Encode the archive metadata with a fresh TAM, ready to be packed and
+    /// stored under its ID
+    fn encode(&mut self, key: &Key) -> Result<Vec<u8>> {
+        tam::pack(key, self, |archive| &mut archive.tam, TamContext::Archive)
+    }
+
+    /// Decode an unpacked archive metadata object and verify its TAM. Borg
+    /// 1.2.5 and later authenticate every archive, so an archive without a TAM
+    /// is rejected if the key requires one, unless `allow_unauthenticated` is
+    /// set.
+    fn decode(key: &Key, data: &[u8], allow_unauthenticated: bool) -> Result<Self> {
+        let archive = rmp_serde::from_slice::<Archive>(data).wrap_err("decode archive msgpack")?;
+
+        let required = key.tam_required() && !allow_unauthenticated;
+
+        tam::verify(
+            key,
+            data,
+            archive.tam.as_ref(),
+            TamContext::Archive,
+            required,
+        )
+        .wrap_err_with(|| format!("verify TAM of archive {:?}", archive.name))?;
+
+        Ok(archive)
+    }
+}
+
+/// The item metadata objects of an archive as one stream. Items may span
+/// item metadata objects, so they can only be decoded from the whole
+/// stream. `get` gives the plain contents of an object. An object that is
+/// listed with an invalid ID or that `get` fails on is passed to `lost`,
+/// which either leaves it out or gives up by returning the error.
+fn item_stream(
+    items: &[Bytes],
+    mut get: impl FnMut(&[u8; 32]) -> Result<Vec<u8>>,
+    mut lost: impl FnMut(eyre::Report) -> Result<()>,
+) -> Result<Vec<u8>> {
+    let mut stream = Vec::new();
+
+    for item_id in items {
+        let plain = <[u8; 32]>::try_from(item_id.0.as_slice())
+            .map_err(|_| eyre!("archive lists an invalid item metadata ID"))
+            .and_then(|item_id| get(&item_id));
+
+        match plain {
+            Ok(plain) => stream.extend(plain),
+            Err(e) => lost(e)?,
+        }
+    }
+
+    Ok(stream)
 }
 
 #[derive(Debug)]
@@ -259,6 +967,24 @@ struct Segment {
 #[derive(Debug)]
 struct OpenSegment {
     data: BufReader<File>,
+
+    /// Offset of the next entry in the segment file
+    offset: u32,
+}
+
+/// Iterates over the entries of a segment along with their offsets
+struct OffsetEntries(OpenSegment);
+
+impl Iterator for OffsetEntries {
+    type Item = Result<(u32, LogEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.0.offset;
+
+        self.0
+            .next()
+            .map(|entry| entry.map(|entry| (offset, entry)))
+    }
 }
 
 #[derive(Debug)]
@@ -279,6 +1005,19 @@ enum IndexVariant {
     V2,
 }
 
+/// Magic number at the start of every segment file
+const SEGMENT_MAGIC: &[u8; 8] = b"BORG_SEG";
+
+const TAG_PUT: u8 = 0;
+const TAG_DELETE: u8 = 1;
+const TAG_COMMIT: u8 = 2;
+
+/// Size of the CRC, size and tag fields that start every log entry
+const ENTRY_HEADER_SIZE: u32 = 9;
+
+/// Size of a log entry header followed by a key
+const ENTRY_KEY_HEADER_SIZE: u32 = ENTRY_HEADER_SIZE + 32;
+
 enum LogEntry {
     Put { key: [u8; 32], data: Vec<u8> },
     Delete { key: [u8; 32] },
@@ -322,6 +1061,10 @@ impl Iterator for OpenSegment {
 }
 
 impl OpenSegment {
+    fn with_offsets(self) -> OffsetEntries {
+        OffsetEntries(self)
+    }
+
     fn next_log_entry(&mut self) -> Result<Option<LogEntry>> {
         // TODO: actually use the CRC?
         let _crc = match self.data.read_u32::<LittleEndian>() {
@@ -337,27 +1080,31 @@ impl OpenSegment {
         let size = self.data.read_u32::<LittleEndian>()?;
         let tag = self.data.read_u8()?;
 
-        match tag {
-            0 => {
+        let entry = match tag {
+            TAG_PUT => {
                 let mut key = [0; 32];
                 self.data.read_exact(&mut key)?;
 
-                let data_len = (size - 41) as usize;
+                let data_len = (size - ENTRY_KEY_HEADER_SIZE) as usize;
 
                 let mut data = vec![0; data_len];
                 self.data.read_exact(&mut data)?;
 
                 Ok(Some(LogEntry::Put { key, data }))
             }
-            1 => {
+            TAG_DELETE => {
                 let mut key = [0; 32];
                 self.data.read_exact(&mut key)?;
 
                 Ok(Some(LogEntry::Delete { key }))
             }
-            2 => Ok(Some(LogEntry::Commit)),
+            TAG_COMMIT => Ok(Some(LogEntry::Commit)),
             _ => bail!("unknown log entry tag {tag}"),
-        }
+        };
+
+        self.offset += size;
+
+        entry
     }
 }
 
@@ -368,11 +1115,14 @@ impl Segment {
         let mut buf = [0; 8];
         data.read_exact(&mut buf).wrap_err("failed 8 byte read")?;
 
-        if &buf != b"BORG_SEG" {
+        if &buf != SEGMENT_MAGIC {
             bail!("segment does not contain BORG_SEG magic number");
         }
 
-        Ok(OpenSegment { data })
+        Ok(OpenSegment {
+            data,
+            offset: SEGMENT_MAGIC.len() as u32,
+        })
     }
 
     fn variant(r: &mut impl Read) -> Result<IndexVariant> {
@@ -420,22 +1170,70 @@ impl Index {
 }
 
 impl Repository {
+    /// Open a repository, taking a lock before anything is read from it. Use
+    /// `LockMode::Exclusive` for anything that modifies the repository.
+    fn open(path: PathBuf, mode: LockMode, lock_wait: Duration) -> Result<Self> {
+        if !path.join("config").is_file() {
+            bail!("{} is not a valid repository", path.display());
+        }
+
+        let lock = Lock::acquire(&path.join("lock"), mode, lock_wait)?;
+
+        let mut repository = Self::load(path)?;
+        repository.lock = Some(lock);
+
+        Ok(repository)
+    }
+
+    /// Remove the lock of a repository, no matter who holds it
+    fn break_lock(path: &Path) -> Result<()> {
+        if !path.join("config").is_file() {
+            bail!("{} is not a valid repository", path.display());
+        }
+
+        lock::break_lock(&path.join("lock"))
+    }
+
+    /// Remove the repository with everything in it. Like borg, the config
+    /// goes first so that what is left is no longer taken for a repository.
+    fn destroy(&mut self) -> Result<()> {
+        if self.config.append_only {
+            bail!("{} is in append-only mode", self.path.display());
+        }
+
+        drop(self.lock.take());
+
+        std::fs::remove_file(self.path.join("config")).wrap_err("remove repository config")?;
+        std::fs::remove_dir_all(&self.path)
+            .wrap_err_with(|| format!("remove {}", self.path.display()))
+    }
+
     fn load(path: PathBuf) -> Result<Self> {
         let config_str =
             std::fs::read_to_string(path.join("config")).wrap_err("read config file")?;
 
-        let mut config = configparser::ini::Ini::new();
+        let config = RepositoryConfig::parse(&config_str)
+            .wrap_err_with(|| format!("invalid repository config in {}", path.display()))?;
 
-        config
-            .read(config_str)
-            .map_err(|e| eyre!(e))
-            .wrap_err("parse config ini")?;
+        let id = config.id.clone();
 
-        let id = config
-            .get("repository", "id")
-            .ok_or_else(|| eyre!("config file missing ID key"))?;
+        Ok(Self {
+            config,
+            path,
+            id,
+            lock: None,
+        })
+    }
+
+    /// Write the config back to disk, atomically so a crash never leaves a
+    /// half-written config behind
+    fn save_config(&self) -> Result<()> {
+        write_atomic(&self.path.join("config"), self.config.write().as_bytes())
+    }
 
-        Ok(Self { config, path, id })
+    /// The ID the next segment written to this repository should get
+    fn next_segment_id(&self) -> Result<u32> {
+        Ok(self.segments()?.last().map_or(0, |segment| segment.id + 1))
     }
 
     fn hints(&self) -> Result<Vec<Hint>> {
@@ -445,15 +1243,14 @@ impl Repository {
             let dir_entry = result?;
 
             if let Some(s) = dir_entry.file_name().to_str() {
-                if s.starts_with("hints.") {
-                    if let Ok(id) = s[6..].parse() {
+                if let Some(id) = s.strip_prefix("hints.") {
+                    if let Ok(id) = id.parse() {
                         hints.push(Hint {
                             id,
-                            data: rmp_serde::from_read(
-                                File::open(dir_entry.path())
+                            data: HintsFile::decode(
+                                &std::fs::read(dir_entry.path())
                                     .wrap_err("failed to read hint file")?,
-                            )
-                            .wrap_err("failed to parse hint file as msgpack")?,
+                            )?,
                         });
                     }
                 }
@@ -470,8 +1267,8 @@ impl Repository {
             let dir_entry = result?;
 
             if let Some(s) = dir_entry.file_name().to_str() {
-                if s.starts_with("index.") {
-                    if let Ok(id) = s[6..].parse() {
+                if let Some(id) = s.strip_prefix("index.") {
+                    if let Ok(id) = id.parse() {
                         indices.push(Index {
                             transaction_id: id,
                             path: dir_entry.path(),
@@ -481,7 +1278,7 @@ impl Repository {
             }
         }
 
-        indices.sort_by(|i1, i2| i1.transaction_id.cmp(&i2.transaction_id));
+        indices.sort_by_key(|i| i.transaction_id);
 
         Ok(indices)
     }
@@ -501,7 +1298,7 @@ impl Repository {
             }
         }
 
-        dirs.sort_by(|d1, d2| d1.0.cmp(&d2.0));
+        dirs.sort_by_key(|d| d.0);
 
         let mut segments = Vec::new();
 
@@ -518,7 +1315,7 @@ impl Repository {
             }
         }
 
-        segments.sort_by(|s1, s2| s1.id.cmp(&s2.id));
+        segments.sort_by_key(|s| s.id);
 
         Ok(segments)
     }
@@ -1,6 +1,6 @@
 use std::fmt::Debug;
 
-use serde::{de::Visitor, Deserialize};
+use serde::{de::Visitor, Deserialize, Serialize};
 
 #[derive(Hash, Clone, Eq, PartialEq)]
 pub enum PythonValue {
@@ -17,6 +17,7 @@ pub enum PythonValue {
     I64(i64),
     I128(i128),
     Sequence(Vec<PythonValue>),
+    Bool(bool),
 }
 
 impl Debug for PythonValue {
@@ -35,6 +36,31 @@ impl Debug for PythonValue {
             Self::String(x) => write!(f, "{x:?}"),
             Self::Bytes(x) => write!(f, "{x:?}"),
             Self::Sequence(x) => write!(f, "{x:?}"),
+            Self::Bool(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+impl Serialize for PythonValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Self::U8(x) => serializer.serialize_u8(*x),
+            Self::U16(x) => serializer.serialize_u16(*x),
+            Self::U32(x) => serializer.serialize_u32(*x),
+            Self::U64(x) => serializer.serialize_u64(*x),
+            Self::U128(x) => serializer.serialize_u128(*x),
+            Self::I8(x) => serializer.serialize_i8(*x),
+            Self::I16(x) => serializer.serialize_i16(*x),
+            Self::I32(x) => serializer.serialize_i32(*x),
+            Self::I64(x) => serializer.serialize_i64(*x),
+            Self::I128(x) => serializer.serialize_i128(*x),
+            Self::String(x) => serializer.serialize_str(x),
+            Self::Bytes(x) => serializer.serialize_bytes(x),
+            Self::Sequence(x) => x.serialize(serializer),
+            Self::Bool(x) => serializer.serialize_bool(*x),
         }
     }
 }
@@ -54,7 +80,7 @@ impl<'de> Visitor<'de> for ValueVisitor {
     type Value = PythonValue;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(formatter, "a number, string, or byte buffer")
+        write!(formatter, "a number, boolean, string, or byte buffer")
     }
 
     fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
@@ -75,7 +101,7 @@ impl<'de> Visitor<'de> for ValueVisitor {
     where
         E: serde::de::Error,
     {
-        Ok(PythonValue::String(v.into()))
+        Ok(PythonValue::String(v))
     }
 
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
@@ -92,6 +118,13 @@ impl<'de> Visitor<'de> for ValueVisitor {
         Ok(PythonValue::Bytes(v.into()))
     }
 
+    fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(PythonValue::Bool(v))
+    }
+
     fn visit_i8<E>(self, v: i8) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
@@ -174,6 +207,7 @@ impl<'de> Visitor<'de> for ValueVisitor {
     }
 }
 
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Bytes(pub Vec<u8>);
 
 impl Debug for Bytes {
@@ -182,6 +216,15 @@ impl Debug for Bytes {
     }
 }
 
+impl Serialize for Bytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
 impl<'de> Deserialize<'de> for Bytes {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -220,4 +263,148 @@ impl<'de> Visitor<'de> for BytesVisitor {
     {
         Ok(Bytes(v.into()))
     }
+
+    // borg 1.x writes bytes with the legacy msgpack raw type, which is
+    // indistinguishable from a string when the bytes happen to be valid utf-8
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(Bytes(v.as_bytes().into()))
+    }
+}
+
+/// Decode one msgpack value into JSON the way `borg debug dump-archive`
+/// shows it: strings and binary values that are UTF-8 become strings, all
+/// others become `\x7f` followed by lowercase hex. A value that starts
+/// with `\x7f` is shown in hex as well, so the two cannot be confused.
+/// Map keys are rendered the same way, as JSON only allows string keys.
+pub fn to_json(data: &mut &[u8]) -> eyre::Result<serde_json::Value> {
+    use byteorder::{BigEndian, ReadBytesExt};
+    use rmp::Marker;
+    use serde_json::Value;
+
+    fn bytes<'a>(data: &mut &'a [u8], len: usize) -> eyre::Result<&'a [u8]> {
+        if data.len() < len {
+            eyre::bail!("msgpack data ends in the middle of a value");
+        }
+
+        let (value, rest) = data.split_at(len);
+        *data = rest;
+
+        Ok(value)
+    }
+
+    fn string(value: &[u8]) -> Value {
+        match std::str::from_utf8(value) {
+            Ok(s) if !s.starts_with('\x7f') => Value::String(s.to_string()),
+            _ => Value::String(format!("\x7f{}", crate::key::hex_lower(value))),
+        }
+    }
+
+    fn key(value: Value) -> String {
+        match value {
+            Value::String(s) => s,
+            other => other.to_string(),
+        }
+    }
+
+    let marker = rmp::decode::read_marker(data)
+        .map_err(|_| eyre::eyre!("msgpack data ends before a value"))?;
+
+    let value = match marker {
+        Marker::Null => Value::Null,
+        Marker::True => Value::Bool(true),
+        Marker::False => Value::Bool(false),
+        Marker::FixPos(n) => n.into(),
+        Marker::FixNeg(n) => n.into(),
+        Marker::U8 => data.read_u8()?.into(),
+        Marker::U16 => data.read_u16::<BigEndian>()?.into(),
+        Marker::U32 => data.read_u32::<BigEndian>()?.into(),
+        Marker::U64 => data.read_u64::<BigEndian>()?.into(),
+        Marker::I8 => data.read_i8()?.into(),
+        Marker::I16 => data.read_i16::<BigEndian>()?.into(),
+        Marker::I32 => data.read_i32::<BigEndian>()?.into(),
+        Marker::I64 => data.read_i64::<BigEndian>()?.into(),
+        Marker::F32 => data.read_f32::<BigEndian>()?.into(),
+        Marker::F64 => data.read_f64::<BigEndian>()?.into(),
+        Marker::FixStr(len) => string(bytes(data, len as usize)?),
+        Marker::Str8 => {
+            let len = data.read_u8()? as usize;
+            string(bytes(data, len)?)
+        }
+        Marker::Str16 => {
+            let len = data.read_u16::<BigEndian>()? as usize;
+            string(bytes(data, len)?)
+        }
+        Marker::Str32 => {
+            let len = data.read_u32::<BigEndian>()? as usize;
+            string(bytes(data, len)?)
+        }
+        Marker::Bin8 | Marker::Bin16 | Marker::Bin32 => {
+            let len = match marker {
+                Marker::Bin8 => data.read_u8()? as usize,
+                Marker::Bin16 => data.read_u16::<BigEndian>()? as usize,
+                _ => data.read_u32::<BigEndian>()? as usize,
+            };
+
+            string(bytes(data, len)?)
+        }
+        Marker::FixArray(_) | Marker::Array16 | Marker::Array32 => {
+            let len = match marker {
+                Marker::FixArray(len) => len as usize,
+                Marker::Array16 => data.read_u16::<BigEndian>()? as usize,
+                _ => data.read_u32::<BigEndian>()? as usize,
+            };
+
+            Value::Array(
+                (0..len)
+                    .map(|_| to_json(data))
+                    .collect::<eyre::Result<_>>()?,
+            )
+        }
+        Marker::FixMap(_) | Marker::Map16 | Marker::Map32 => {
+            let len = match marker {
+                Marker::FixMap(len) => len as usize,
+                Marker::Map16 => data.read_u16::<BigEndian>()? as usize,
+                _ => data.read_u32::<BigEndian>()? as usize,
+            };
+
+            let mut map = serde_json::Map::new();
+            for _ in 0..len {
+                let key = key(to_json(data)?);
+                map.insert(key, to_json(data)?);
+            }
+
+            Value::Object(map)
+        }
+        Marker::FixExt1
+        | Marker::FixExt2
+        | Marker::FixExt4
+        | Marker::FixExt8
+        | Marker::FixExt16
+        | Marker::Ext8
+        | Marker::Ext16
+        | Marker::Ext32 => {
+            let len = match marker {
+                Marker::FixExt1 => 1,
+                Marker::FixExt2 => 2,
+                Marker::FixExt4 => 4,
+                Marker::FixExt8 => 8,
+                Marker::FixExt16 => 16,
+                Marker::Ext8 => data.read_u8()? as usize,
+                Marker::Ext16 => data.read_u16::<BigEndian>()? as usize,
+                _ => data.read_u32::<BigEndian>()? as usize,
+            };
+            let ext_type = data.read_i8()?;
+
+            serde_json::json!({
+                "ext": ext_type,
+                "data": crate::key::hex_lower(bytes(data, len)?),
+            })
+        }
+        Marker::Reserved => eyre::bail!("invalid msgpack marker 0xc1"),
+    };
+
+    Ok(value)
 }
@@ -0,0 +1,357 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use eyre::{bail, eyre, Context, Result};
+
+use crate::{
+    cache::{Cache, FilesCacheMode},
+    compression::Compression,
+    delete::delete_archive,
+    glob::glob_match,
+    key::Key,
+    lock::LockMode,
+    pack_data,
+    transaction::Transaction,
+    unpack_data, Manifest, Options, Repository, MANIFEST_ID,
+};
+
+/// The time periods archives are kept for, with the strftime format that
+/// tells which period an archive falls into, in the order borg applies them
+const PRUNING_PATTERNS: [(&str, &str); 7] = [
+    ("secondly", "%Y-%m-%d %H:%M:%S"),
+    ("minutely", "%Y-%m-%d %H:%M"),
+    ("hourly", "%Y-%m-%d %H"),
+    ("daily", "%Y-%m-%d"),
+    ("weekly", "%G-%V"),
+    ("monthly", "%Y-%m"),
+    ("yearly", "%Y"),
+];
+
+/// Which archives `prune` keeps, as given to borg. Each count keeps the
+/// newest archive of that many periods, -1 of every period.
+#[derive(clap::Args, Debug, Default, Clone, Copy)]
+pub struct KeepRules {
+    /// Keep all archives within this time interval, such as 7d
+    #[arg(long = "keep-within", value_parser = parse_interval)]
+    pub within: Option<u64>,
+
+    /// Number of secondly archives to keep
+    #[arg(long = "keep-last", visible_alias = "keep-secondly")]
+    pub secondly: Option<i64>,
+
+    /// Number of minutely archives to keep
+    #[arg(long = "keep-minutely")]
+    pub minutely: Option<i64>,
+
+    /// Number of hourly archives to keep
+    #[arg(short = 'H', long = "keep-hourly")]
+    pub hourly: Option<i64>,
+
+    /// Number of daily archives to keep
+    #[arg(short = 'd', long = "keep-daily")]
+    pub daily: Option<i64>,
+
+    /// Number of weekly archives to keep
+    #[arg(short = 'w', long = "keep-weekly")]
+    pub weekly: Option<i64>,
+
+    /// Number of monthly archives to keep
+    #[arg(short = 'm', long = "keep-monthly")]
+    pub monthly: Option<i64>,
+
+    /// Number of yearly archives to keep
+    #[arg(short = 'y', long = "keep-yearly")]
+    pub yearly: Option<i64>,
+}
+
+impl KeepRules {
+    fn count(&self, rule: &str) -> Option<i64> {
+        match rule {
+            "secondly" => self.secondly,
+            "minutely" => self.minutely,
+            "hourly" => self.hourly,
+            "daily" => self.daily,
+            "weekly" => self.weekly,
+            "monthly" => self.monthly,
+            "yearly" => self.yearly,
+            _ => None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.within.is_none()
+            && PRUNING_PATTERNS
+                .iter()
+                .all(|(rule, _)| self.count(rule).is_none())
+    }
+}
+
+/// Parse an interval like borg does, a positive number followed by `H` for
+/// hours, `d` for days, `w` for weeks, `m` for months of 31 days or `y` for
+/// years of 365 days. Returns the number of hours.
+pub fn parse_interval(s: &str) -> Result<u64> {
+    let Some(unit) = s.chars().last() else {
+        bail!("interval is empty");
+    };
+
+    let hours = match unit {
+        'H' => 1,
+        'd' => 24,
+        'w' => 24 * 7,
+        'm' => 24 * 31,
+        'y' => 24 * 365,
+        _ => bail!("unexpected interval time unit {unit:?}, known units: H, d, w, m, y"),
+    };
+
+    let number: u64 = s[..s.len() - 1]
+        .parse()
+        .wrap_err_with(|| format!("unexpected interval number {:?}", &s[..s.len() - 1]))?;
+
+    if number == 0 {
+        bail!("interval must be greater than zero");
+    }
+
+    number
+        .checked_mul(hours)
+        .ok_or_else(|| eyre!("interval {s:?} is too long"))
+}
+
+/// Why an archive is kept: the rule, and how many archives the rule kept
+/// up to and including this one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepReason {
+    pub rule: String,
+    pub number: usize,
+}
+
+/// Decide which archives to keep, using borg's algorithm so that both keep
+/// the same ones. `archives` must be sorted newest first and not contain
+/// checkpoints. Every rule keeps the newest archive of each of its periods,
+/// skipping archives an earlier rule already keeps. A rule that runs out of
+/// archives before reaching its count keeps the oldest archive as well.
+pub fn keep_archives(
+    archives: &[(&str, DateTime<Utc>)],
+    rules: &KeepRules,
+    now: DateTime<Utc>,
+) -> HashMap<String, KeepReason> {
+    let mut kept = HashMap::new();
+
+    if let Some(hours) = rules.within {
+        // an interval reaching back before the earliest representable time
+        // keeps everything
+        let target = i64::try_from(hours)
+            .ok()
+            .and_then(chrono::Duration::try_hours)
+            .and_then(|within| now.checked_sub_signed(within))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+
+        let mut number = 0;
+        for &(name, time) in archives {
+            if time > target {
+                number += 1;
+                kept.insert(
+                    name.to_string(),
+                    KeepReason {
+                        rule: "within".to_string(),
+                        number,
+                    },
+                );
+            }
+        }
+    }
+
+    for (rule, pattern) in PRUNING_PATTERNS {
+        let Some(count) = rules.count(rule) else {
+            continue;
+        };
+
+        if count == 0 {
+            continue;
+        }
+
+        let mut last = None;
+        let mut number = 0;
+        let mut oldest = None;
+
+        for &(name, time) in archives {
+            oldest = Some(name);
+
+            let period = time
+                .with_timezone(&chrono::Local)
+                .format(pattern)
+                .to_string();
+
+            if last.as_ref() == Some(&period) {
+                continue;
+            }
+
+            last = Some(period);
+
+            if !kept.contains_key(name) {
+                number += 1;
+                kept.insert(
+                    name.to_string(),
+                    KeepReason {
+                        rule: rule.to_string(),
+                        number,
+                    },
+                );
+
+                if number as i64 == count {
+                    break;
+                }
+            }
+        }
+
+        if let Some(oldest) = oldest {
+            if (number as i64) < count && !kept.contains_key(oldest) {
+                kept.insert(
+                    oldest.to_string(),
+                    KeepReason {
+                        rule: format!("{rule}[oldest]"),
+                        number: number + 1,
+                    },
+                );
+            }
+        }
+    }
+
+    kept
+}
+
+/// If `name` is that of a checkpoint, which borg writes while a backup is
+/// still running, the name of the archive it belongs to. Checkpoints are
+/// named like the archive with `.checkpoint` or `.checkpoint.N` appended.
+pub fn strip_checkpoint(name: &str) -> Option<&str> {
+    let (base, suffix) = name.rsplit_once(".checkpoint")?;
+
+    match suffix.strip_prefix('.') {
+        None if suffix.is_empty() => Some(base),
+        Some(number) if !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()) => {
+            Some(base)
+        }
+        _ => None,
+    }
+}
+
+/// Delete the archives that none of the `rules` keeps. Only archives whose
+/// names match `glob_archives` are considered.
+/// The newest checkpoint is kept unless a complete archive is newer, other
+/// checkpoints are always deleted.
+pub fn prune(
+    path: PathBuf,
+    rules: &KeepRules,
+    glob_archives: Option<&str>,
+    dry_run: bool,
+    list: bool,
+    options: &Options,
+) -> Result<()> {
+    if rules.is_empty() {
+        bail!(
+            "At least one of the \"keep-within\", \"keep-last\", \"keep-secondly\", \"keep-minutely\", \"keep-hourly\", \"keep-daily\", \"keep-weekly\", \"keep-monthly\" or \"keep-yearly\" settings must be specified."
+        );
+    }
+
+    let repository = Repository::open(path, LockMode::Exclusive, options.lock_wait())?;
+    let mut transaction = Transaction::begin(&repository)?;
+
+    let manifest_data = transaction
+        .get(&MANIFEST_ID)?
+        .ok_or_else(|| eyre!("repository has no manifest"))?;
+    let mut key = Key::load(&repository, &manifest_data)?;
+    key.track_ivs(&repository)?;
+
+    let manifest_plain = unpack_data(&key, &manifest_data)?;
+    let mut manifest = Manifest::decode(&key, &manifest_plain, options.tam.manifest)?;
+
+    let mut archives = Vec::new();
+    for (name, archive) in &manifest.archives {
+        // like borg, a pattern is matched against the whole name, so a
+        // checkpoint is only considered if the pattern covers its suffix
+        if glob_archives.is_none_or(|pattern| glob_match(pattern, name)) {
+            archives.push((name.clone(), archive.timestamp()?));
+        }
+    }
+    archives.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+    let is_checkpoint = |name: &str| strip_checkpoint(name).is_some();
+
+    // a checkpoint is the best there is until a later backup completes
+    let keep_checkpoint = archives
+        .first()
+        .filter(|(name, _)| is_checkpoint(name))
+        .map(|(name, _)| name.clone());
+
+    let complete: Vec<_> = archives
+        .iter()
+        .filter(|(name, _)| !is_checkpoint(name))
+        .map(|(name, time)| (name.as_str(), *time))
+        .collect();
+    let kept = keep_archives(&complete, rules, Utc::now());
+
+    let to_delete = archives
+        .iter()
+        .filter(|(name, _)| !kept.contains_key(name) && keep_checkpoint.as_ref() != Some(name))
+        .count();
+
+    let mut cache = if dry_run || to_delete == 0 {
+        None
+    } else {
+        let mut cache = Cache::open(&repository, FilesCacheMode::disabled(), options.lock_wait())?;
+        cache.sync(
+            &key,
+            &key.id_hash(&manifest_plain)?,
+            &manifest,
+            &mut transaction,
+        )?;
+
+        Some(cache)
+    };
+
+    let mut deleted = 0;
+    for (name, _) in &archives {
+        let formatted = manifest.archives[name].format(name);
+
+        let message = if let Some(reason) = kept.get(name) {
+            format!(
+                "Keeping archive (rule: {} #{}):",
+                reason.rule, reason.number
+            )
+        } else if keep_checkpoint.as_ref() == Some(name) {
+            "Keeping checkpoint archive:".to_string()
+        } else if let Some(cache) = &mut cache {
+            deleted += 1;
+            delete_archive(&key, &mut transaction, cache, &mut manifest, name)?;
+
+            format!("Pruning archive ({deleted}/{to_delete}):")
+        } else {
+            "Would prune:".to_string()
+        };
+
+        if list {
+            eprintln!("{message:<40} {formatted}");
+        }
+    }
+
+    let Some(cache) = cache else {
+        return Ok(());
+    };
+
+    manifest.update_timestamp();
+
+    let manifest_plain = manifest.encode(&key)?;
+    transaction.put(
+        &MANIFEST_ID,
+        &pack_data(&key, &Compression::default(), &manifest_plain)?,
+    )?;
+    transaction.commit()?;
+
+    cache.commit(
+        &repository,
+        &key,
+        &key.id_hash(&manifest_plain)?,
+        &manifest.timestamp,
+    )?;
+
+    Ok(())
+}
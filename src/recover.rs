@@ -0,0 +1,183 @@
+use std::path::PathBuf;
+
+use eyre::{bail, eyre, Result};
+
+use crate::{
+    compression::Compression,
+    delete::confirm,
+    key::{hex_lower, Key},
+    lock::LockMode,
+    msgpack::Bytes,
+    pack_data,
+    transaction::{RepositoryState, Transaction},
+    unpack_data, Archive, Manifest, ManifestArchive, Options, Repository, MANIFEST_ID,
+};
+
+/// Answers the question whether to write a rebuilt manifest
+const RECOVER_CONFIRMATION_ENV: &str = "BORK_RECOVER_MANIFEST_I_KNOW_WHAT_I_AM_DOING";
+
+/// Rebuild a lost or damaged manifest from the archive objects in the
+/// repository, list the archives it would hold and, after asking, write it
+pub fn recover_manifest(path: PathBuf, dry_run: bool, options: &Options) -> Result<()> {
+    let repository = Repository::open(path, LockMode::Exclusive, options.lock_wait())?;
+    let state = RepositoryState::load(&repository)?;
+
+    let get = |id: &[u8; 32]| -> Result<Option<Vec<u8>>> {
+        match state.index.get(id) {
+            Some((segment, offset)) => repository.read_put(segment, offset, id).map(Some),
+            None => Ok(None),
+        }
+    };
+
+    let ids: Vec<_> = state.index.iter().map(|(id, _)| id).collect();
+
+    let manifest_data = get(&MANIFEST_ID).ok().flatten();
+
+    let mut key = load_key(&repository, manifest_data.as_deref(), &ids, get)?;
+
+    match manifest_data {
+        Some(data) => {
+            match unpack_data(&key, &data)
+                .and_then(|plain| Manifest::decode(&key, &plain, options.tam.manifest))
+            {
+                Ok(_) => bail!("the repository manifest is intact, there is nothing to recover"),
+                Err(e) => eprintln!("Repository manifest is damaged: {e:#}"),
+            }
+        }
+        None => eprintln!("Repository manifest not found!"),
+    }
+
+    eprintln!("Scanning {} objects for archives...", ids.len());
+
+    let archives = find_archives(&key, &ids, get, options.tam.archives);
+    let mut manifest = rebuild_manifest(&key, archives);
+
+    let mut archives: Vec<_> = manifest.archives.iter().collect();
+    archives.sort_by(|(_, a), (_, b)| a.time.cmp(&b.time));
+
+    eprintln!("The rebuilt manifest lists {} archives:", archives.len());
+    for (name, archive) in archives {
+        eprintln!("{}", archive.format(name));
+    }
+
+    if dry_run {
+        return Ok(());
+    }
+
+    if !confirm(
+        "Type 'YES' if you want to write this manifest to the repository: ",
+        RECOVER_CONFIRMATION_ENV,
+    )? {
+        bail!("Aborting.");
+    }
+
+    key.track_ivs(&repository)?;
+
+    let mut transaction = Transaction::begin(&repository)?;
+
+    manifest.update_timestamp();
+    let manifest_plain = manifest.encode(&key)?;
+    transaction.put(
+        &MANIFEST_ID,
+        &pack_data(&key, &Compression::default(), &manifest_plain)?,
+    )?;
+    transaction.commit()?;
+
+    eprintln!("Manifest written.");
+
+    Ok(())
+}
+
+/// Load the key from the manifest in `manifest_data`. Every object tells
+/// the key type, so if the manifest is gone any intact object among `ids`
+/// will do.
+pub fn load_key(
+    repository: &Repository,
+    manifest_data: Option<&[u8]>,
+    ids: &[[u8; 32]],
+    mut get: impl FnMut(&[u8; 32]) -> Result<Option<Vec<u8>>>,
+) -> Result<Key> {
+    match manifest_data {
+        Some(data) => Key::load(repository, data),
+        None => {
+            let data = ids
+                .iter()
+                .find_map(|id| get(id).ok().flatten())
+                .ok_or_else(|| eyre!("the repository has no intact objects"))?;
+
+            Key::load(repository, &data)
+        }
+    }
+}
+
+/// Find the objects among `ids` that are archive metadata. Objects that are
+/// missing or damaged are skipped, as are archives without a valid TAM
+/// unless `allow_unauthenticated` is set.
+pub fn find_archives(
+    key: &Key,
+    ids: &[[u8; 32]],
+    mut get: impl FnMut(&[u8; 32]) -> Result<Option<Vec<u8>>>,
+    allow_unauthenticated: bool,
+) -> Vec<([u8; 32], Archive)> {
+    let mut found = Vec::new();
+
+    for id in ids {
+        if id == &MANIFEST_ID {
+            continue;
+        }
+
+        let Ok(Some(data)) = get(id) else {
+            continue;
+        };
+
+        let Ok(plain) = unpack_data(key, &data) else {
+            continue;
+        };
+
+        // most objects are file chunks or item metadata, which do not
+        // decode as archive metadata
+        if rmp_serde::from_slice::<Archive>(&plain).is_err() {
+            continue;
+        }
+
+        match Archive::decode(key, &plain, allow_unauthenticated) {
+            Ok(archive) => found.push((*id, archive)),
+            Err(e) => eprintln!("Skipping archive object {}: {e:#}", hex_lower(id)),
+        }
+    }
+
+    found
+}
+
+/// A new manifest listing `archives`, the way borg's check rebuilds a lost
+/// one. Archives with the same name are told apart by a numeric suffix, the
+/// older one keeps the name.
+pub fn rebuild_manifest(key: &Key, mut archives: Vec<([u8; 32], Archive)>) -> Manifest {
+    archives.sort_by(|(_, a), (_, b)| a.time.cmp(&b.time));
+
+    let mut manifest = Manifest::new(key);
+    for (id, archive) in archives {
+        eprintln!("Found archive {}", archive.name);
+
+        let mut name = archive.name.clone();
+        let mut suffix = 1;
+        while manifest.archives.contains_key(&name) {
+            name = format!("{}.{suffix}", archive.name);
+            suffix += 1;
+        }
+
+        if name != archive.name {
+            eprintln!("Duplicate archive name {}, storing as {name}", archive.name);
+        }
+
+        manifest.archives.insert(
+            name,
+            ManifestArchive {
+                id: Bytes(id.to_vec()),
+                time: archive.time,
+            },
+        );
+    }
+
+    manifest
+}
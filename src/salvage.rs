@@ -0,0 +1,361 @@
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::Write,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use eyre::{bail, eyre, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    check::{CheckedEntry, CheckedSegment},
+    hashindex::NsIndex,
+    item_stream,
+    key::{hex_lower, Key},
+    lock::LockMode,
+    msgpack::Bytes,
+    recover::{find_archives, load_key},
+    transaction::RepositoryState,
+    unpack_data, Archive, Manifest, ManifestArchive, Options, Repository, MANIFEST_ID,
+};
+
+/// The file type bits of a mode, as in `stat.h`
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+/// The objects any segment still holds, including the ones that were
+/// deleted but not compacted away yet
+struct Salvage {
+    repository: Repository,
+    index: NsIndex,
+    key: Key,
+}
+
+impl Salvage {
+    /// Replay every segment, committed or not, ignoring DELETEs. Of several
+    /// PUTs of an object the last one wins. Damaged segments are read up
+    /// to the damage.
+    fn open(path: PathBuf, options: &Options) -> Result<Self> {
+        let repository = Repository::open(path, LockMode::Shared, options.lock_wait())?;
+
+        let mut index = NsIndex::new();
+        for segment in repository.segments()? {
+            let mut reader = match CheckedSegment::open(&segment.path) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    eprintln!("warning: skipping segment {}: {e:#}", segment.id);
+                    continue;
+                }
+            };
+
+            loop {
+                match reader.next_entry() {
+                    Ok(Some((offset, CheckedEntry::Put { key, .. }))) => {
+                        index.insert(&key, segment.id, offset)
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => break,
+                    Err(e) => {
+                        eprintln!(
+                            "warning: skipping the rest of segment {}: {e:#}",
+                            segment.id
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+
+        let get = |id: &[u8; 32]| match index.get(id) {
+            Some((segment, offset)) => repository.read_put(segment, offset, id).map(Some),
+            None => Ok(None),
+        };
+
+        let ids: Vec<_> = index.iter().map(|(id, _)| id).collect();
+        let manifest_data = get(&MANIFEST_ID).ok().flatten();
+        let key = load_key(&repository, manifest_data.as_deref(), &ids, get)?;
+
+        Ok(Self {
+            repository,
+            index,
+            key,
+        })
+    }
+
+    fn get(&self, id: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(id) {
+            Some((segment, offset)) => self.repository.read_put(segment, offset, id).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The archives whose metadata is still in the segments but which the
+    /// current manifest does not list, oldest first
+    fn deleted_archives(&self, options: &Options) -> Result<Vec<([u8; 32], Archive)>> {
+        let state = RepositoryState::load(&self.repository)?;
+
+        let manifest = state
+            .index
+            .get(&MANIFEST_ID)
+            .ok_or_else(|| eyre!("repository has no manifest"))
+            .and_then(|(segment, offset)| self.repository.read_put(segment, offset, &MANIFEST_ID))
+            .and_then(|data| unpack_data(&self.key, &data))
+            .and_then(|plain| Manifest::decode(&self.key, &plain, options.tam.manifest));
+
+        let listed: Vec<_> = match manifest {
+            Ok(manifest) => manifest
+                .archives
+                .into_values()
+                .map(|archive| archive.id.0)
+                .collect(),
+            Err(e) => {
+                eprintln!("warning: {e:#}, taking every archive for deleted");
+                Vec::new()
+            }
+        };
+
+        let ids: Vec<_> = self.index.iter().map(|(id, _)| id).collect();
+
+        let mut archives: Vec<_> =
+            find_archives(&self.key, &ids, |id| self.get(id), options.tam.archives)
+                .into_iter()
+                .filter(|(id, _)| !listed.contains(&id.to_vec()))
+                .collect();
+        archives.sort_by(|(_, a), (_, b)| a.time.cmp(&b.time));
+
+        Ok(archives)
+    }
+}
+
+/// List the deleted archives that can still be salvaged
+pub fn list(path: PathBuf, options: &Options) -> Result<()> {
+    let salvage = Salvage::open(path, options)?;
+    let archives = salvage.deleted_archives(options)?;
+
+    if archives.is_empty() {
+        eprintln!("No deleted archives found.");
+    }
+
+    for (id, archive) in archives {
+        let entry = ManifestArchive {
+            id: Bytes(id.to_vec()),
+            time: archive.time,
+        };
+
+        println!("{}", entry.format(&archive.name));
+    }
+
+    Ok(())
+}
+
+/// The parts of an item needed to restore it
+#[derive(Deserialize)]
+struct SalvagedItem {
+    path: Bytes,
+    mode: u32,
+
+    /// Nanoseconds since the epoch
+    #[serde(default)]
+    mtime: Option<i64>,
+
+    /// Target of a symlink, or the file a hard link points to
+    #[serde(default)]
+    source: Option<Bytes>,
+
+    #[serde(default)]
+    chunks: Vec<(Bytes, u32, u32)>,
+}
+
+/// Extract a deleted archive into `destination`. The archive is given by
+/// its name, if that is unique among the deleted archives, or by a prefix
+/// of its ID. Files, directories and symlinks are restored, chunks that
+/// are gone are filled with zeros.
+pub fn extract(path: PathBuf, archive: &str, destination: &Path, options: &Options) -> Result<()> {
+    let salvage = Salvage::open(path, options)?;
+
+    let mut matching: Vec<_> = salvage
+        .deleted_archives(options)?
+        .into_iter()
+        .filter(|(id, candidate)| {
+            candidate.name == archive || hex_lower(id).starts_with(&archive.to_lowercase())
+        })
+        .collect();
+
+    let (id, archive) = match matching.len() {
+        0 => bail!("no deleted archive {archive} found"),
+        1 => matching.remove(0),
+        _ => bail!("{archive} matches several deleted archives, give its ID instead"),
+    };
+
+    eprintln!("Extracting archive {} [{}]", archive.name, hex_lower(&id));
+
+    let stream = item_stream(
+        &archive.items,
+        |item_id| {
+            let data = salvage
+                .get(item_id)?
+                .ok_or_else(|| eyre!("item metadata object is gone"))?;
+            unpack_data(&salvage.key, &data)
+        },
+        |e| {
+            eprintln!("warning: {e:#}, some items are lost");
+            Ok(())
+        },
+    )?;
+
+    std::fs::create_dir_all(destination)
+        .wrap_err_with(|| format!("create {}", destination.display()))?;
+
+    // a directory's mode may forbid writing into it, so like borg the
+    // modes of directories are only applied once everything is in place
+    let mut directories = Vec::new();
+
+    let mut cursor = std::io::Cursor::new(&stream[..]);
+    while (cursor.position() as usize) < stream.len() {
+        let item: SalvagedItem = match rmp_serde::from_read(&mut cursor) {
+            Ok(item) => item,
+            Err(e) => {
+                eprintln!("warning: item metadata is damaged, the remaining items are lost: {e}");
+                break;
+            }
+        };
+
+        let display_path = String::from_utf8_lossy(&item.path.0).into_owned();
+        let Some(target) = safe_join(destination, &item.path.0) else {
+            eprintln!("warning: {display_path}: unsafe path, skipped");
+            continue;
+        };
+
+        if let Err(e) = extract_item(&salvage, &item, &target, destination) {
+            eprintln!("warning: {display_path}: {e:#}");
+            continue;
+        }
+
+        if item.mode & S_IFMT == S_IFDIR {
+            directories.push((target, item.mode));
+        }
+
+        println!("{display_path}");
+    }
+
+    // children before their parents, in case a parent is not searchable
+    for (target, mode) in directories.into_iter().rev() {
+        let permissions = std::fs::Permissions::from_mode(mode & 0o7777);
+        if let Err(e) = std::fs::set_permissions(&target, permissions) {
+            eprintln!("warning: {}: {e}", target.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn extract_item(
+    salvage: &Salvage,
+    item: &SalvagedItem,
+    target: &Path,
+    destination: &Path,
+) -> Result<()> {
+    // a symlink extracted before must not redirect anything outside of
+    // the destination
+    if let Some(link) = symlink_below(destination, target) {
+        bail!(
+            "{} is a symlink, refusing to extract through it",
+            link.display()
+        );
+    }
+
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    match item.mode & S_IFMT {
+        S_IFDIR => {
+            std::fs::create_dir_all(target)?;
+
+            return Ok(());
+        }
+        S_IFLNK => {
+            let source = item
+                .source
+                .as_ref()
+                .ok_or_else(|| eyre!("symlink without a target"))?;
+            std::os::unix::fs::symlink(OsStr::from_bytes(&source.0), target)?;
+
+            return Ok(());
+        }
+        S_IFREG if item.chunks.is_empty() && item.source.is_some() => {
+            // a hard link to a file extracted before
+            let source = safe_join(destination, &item.source.as_ref().unwrap().0)
+                .ok_or_else(|| eyre!("hard link with an unsafe source"))?;
+            if let Some(link) = symlink_below(destination, &source) {
+                bail!(
+                    "{} is a symlink, refusing to link through it",
+                    link.display()
+                );
+            }
+            std::fs::hard_link(source, target)?;
+
+            return Ok(());
+        }
+        S_IFREG => {
+            let mut file = File::create(target)?;
+
+            for (chunk_id, size, _) in &item.chunks {
+                let data = <[u8; 32]>::try_from(chunk_id.0.as_slice())
+                    .ok()
+                    .and_then(|chunk_id| salvage.get(&chunk_id).ok().flatten())
+                    .and_then(|data| unpack_data(&salvage.key, &data).ok());
+
+                match data {
+                    Some(data) => file.write_all(&data)?,
+                    None => {
+                        eprintln!(
+                            "warning: {}: chunk {} is gone, filled with zeros",
+                            target.display(),
+                            hex_lower(&chunk_id.0)
+                        );
+                        file.write_all(&vec![0; *size as usize])?;
+                    }
+                }
+            }
+
+            if let Some(mtime) = item.mtime {
+                let mtime = SystemTime::UNIX_EPOCH + Duration::from_nanos(mtime.max(0) as u64);
+                file.set_modified(mtime)?;
+            }
+        }
+        _ => bail!("special files are not extracted"),
+    }
+
+    std::fs::set_permissions(target, std::fs::Permissions::from_mode(item.mode & 0o7777))?;
+
+    Ok(())
+}
+
+/// The first of `target` and its ancestors below `destination` that exists
+/// as a symlink
+fn symlink_below(destination: &Path, target: &Path) -> Option<PathBuf> {
+    let relative = target.strip_prefix(destination).ok()?;
+
+    let mut path = destination.to_path_buf();
+    relative.components().find_map(|component| {
+        path.push(component);
+        std::fs::symlink_metadata(&path)
+            .is_ok_and(|metadata| metadata.file_type().is_symlink())
+            .then(|| path.clone())
+    })
+}
+
+/// `path` below `destination`, or `None` if it would end up elsewhere
+fn safe_join(destination: &Path, path: &[u8]) -> Option<PathBuf> {
+    let path = Path::new(OsStr::from_bytes(path));
+
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        .then(|| destination.join(path))
+}
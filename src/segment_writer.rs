@@ -0,0 +1,212 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use eyre::{bail, Context, Result};
+
+use crate::{
+    config::{RepositoryConfig, MAX_OBJECT_SIZE},
+    ENTRY_HEADER_SIZE, ENTRY_KEY_HEADER_SIZE, SEGMENT_MAGIC, TAG_COMMIT, TAG_DELETE, TAG_PUT,
+};
+
+/// The largest payload a PUT entry can carry
+pub const MAX_DATA_SIZE: usize = (MAX_OBJECT_SIZE - ENTRY_KEY_HEADER_SIZE as u64) as usize;
+
+/// Appends log entries to segment files in borg's on-disk format. Segments are
+/// rotated once they grow past `max_segment_size` and every
+/// `segments_per_dir` segments go into a new `data/N` directory.
+#[derive(Debug)]
+pub struct SegmentWriter {
+    data_dir: PathBuf,
+    segments_per_dir: u32,
+    max_segment_size: u64,
+
+    /// The segment currently being written, or the next one to create
+    segment: u32,
+    file: Option<BufWriter<File>>,
+    offset: u64,
+}
+
+impl SegmentWriter {
+    /// Create a writer for the repository at `path` that starts writing at
+    /// segment `next_segment`, which must not exist yet
+    pub fn new(path: &Path, config: &RepositoryConfig, next_segment: u32) -> Self {
+        Self {
+            data_dir: path.join("data"),
+            segments_per_dir: config.segments_per_dir,
+            max_segment_size: config.max_segment_size,
+            segment: next_segment,
+            file: None,
+            offset: 0,
+        }
+    }
+
+    /// Append a PUT entry, returning the segment and offset it was written at
+    pub fn put(&mut self, key: &[u8; 32], data: &[u8]) -> Result<(u32, u32)> {
+        if data.len() > MAX_DATA_SIZE {
+            bail!(
+                "object of {} bytes exceeds the maximum of {MAX_DATA_SIZE} bytes",
+                data.len()
+            );
+        }
+
+        let size = ENTRY_KEY_HEADER_SIZE + data.len() as u32;
+
+        let mut body = Vec::with_capacity(size as usize - 4);
+        body.write_u32::<LittleEndian>(size)?;
+        body.push(TAG_PUT);
+        body.extend_from_slice(key);
+        body.extend_from_slice(data);
+
+        self.write_entry(&body)
+    }
+
+    /// Append a DELETE entry, returning the segment and offset it was written
+    /// at
+    pub fn delete(&mut self, key: &[u8; 32]) -> Result<(u32, u32)> {
+        let mut body = Vec::with_capacity(ENTRY_KEY_HEADER_SIZE as usize - 4);
+        body.write_u32::<LittleEndian>(ENTRY_KEY_HEADER_SIZE)?;
+        body.push(TAG_DELETE);
+        body.extend_from_slice(key);
+
+        self.write_entry(&body)
+    }
+
+    /// Commit everything written so far. Like borg, the COMMIT entry goes into
+    /// a segment of its own, which is synced to disk before returning. Returns
+    /// the ID of that segment, which is the ID of the transaction.
+    pub fn commit(&mut self) -> Result<u32> {
+        self.close_segment()?;
+
+        self.commit_intermediate()
+    }
+
+    /// Commit everything written so far with a COMMIT entry at the end of the
+    /// current segment, which is then synced and closed. Borg does this while
+    /// compacting, where the commit only makes the segments written so far
+    /// safe to rely on. Returns the ID of the segment.
+    pub fn commit_intermediate(&mut self) -> Result<u32> {
+        let mut body = Vec::with_capacity(ENTRY_HEADER_SIZE as usize - 4);
+        body.write_u32::<LittleEndian>(ENTRY_HEADER_SIZE)?;
+        body.push(TAG_COMMIT);
+
+        let (segment, _) = self.append_entry(&body)?;
+
+        self.close_segment()?;
+
+        Ok(segment)
+    }
+
+    /// Whether the current segment has grown past `max_segment_size`, so that
+    /// the next entry would start a new one
+    pub fn is_full(&self) -> bool {
+        self.file.is_some() && self.offset > self.max_segment_size
+    }
+
+    /// The ID of the segment the next entry will be written to, if it fits
+    pub fn segment(&self) -> u32 {
+        self.segment
+    }
+
+    /// Flush buffered entries so they can be read back from the segment file
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.flush().wrap_err("flush segment")?;
+        }
+
+        Ok(())
+    }
+
+    /// Sync and close the current segment, the next entry will start a new
+    /// one
+    pub fn close_segment(&mut self) -> Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush().wrap_err("flush segment")?;
+            file.get_ref().sync_all().wrap_err("sync segment")?;
+
+            self.segment += 1;
+            self.offset = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Path of the segment file with the given ID
+    pub fn segment_path(&self, segment: u32) -> PathBuf {
+        self.data_dir
+            .join((segment / self.segments_per_dir).to_string())
+            .join(segment.to_string())
+    }
+
+    /// Write an entry given everything after its CRC, rotating segments if
+    /// the current one is full
+    fn write_entry(&mut self, body: &[u8]) -> Result<(u32, u32)> {
+        if self.is_full() {
+            self.close_segment()?;
+        }
+
+        self.append_entry(body)
+    }
+
+    /// Write an entry to the current segment, no matter its size
+    fn append_entry(&mut self, body: &[u8]) -> Result<(u32, u32)> {
+        if self.file.is_none() {
+            self.open_segment()?;
+        }
+
+        let file = self.file.as_mut().expect("segment was just opened");
+        let offset = self.offset;
+
+        file.write_u32::<LittleEndian>(crc32fast::hash(body))?;
+        file.write_all(body).wrap_err("write log entry")?;
+
+        self.offset += 4 + body.len() as u64;
+
+        Ok((self.segment, offset as u32))
+    }
+
+    fn open_segment(&mut self) -> Result<()> {
+        let path = self.segment_path(self.segment);
+        let dir = path.parent().expect("segment path has a parent");
+
+        if !dir.exists() {
+            std::fs::create_dir_all(dir)
+                .wrap_err_with(|| format!("create segment directory {}", dir.display()))?;
+            sync_dir(&self.data_dir)?;
+        }
+
+        if path.exists() {
+            bail!("segment {} already exists", path.display());
+        }
+
+        let mut file = BufWriter::new(
+            File::create(&path).wrap_err_with(|| format!("create segment {}", path.display()))?,
+        );
+        file.write_all(SEGMENT_MAGIC)?;
+
+        sync_dir(dir)?;
+
+        self.file = Some(file);
+        self.offset = SEGMENT_MAGIC.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl Drop for SegmentWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.close_segment() {
+            eprintln!("warning: failed to close segment: {e:#}");
+        }
+    }
+}
+
+/// Sync a directory so that files created in it survive a crash
+pub fn sync_dir(path: &Path) -> Result<()> {
+    File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .wrap_err_with(|| format!("sync directory {}", path.display()))
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use eyre::{bail, eyre, Context, Result};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha512;
+
+use crate::{key::Key, msgpack::Bytes, Tam};
+
+/// The only TAM suite borg has ever used
+const TAM_TYPE: &str = "HKDF_HMAC_SHA512";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TamContext {
+    Manifest,
+    Archive,
+}
+
+impl TamContext {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            Self::Manifest => b"manifest",
+            Self::Archive => b"archive",
+        }
+    }
+}
+
+impl std::fmt::Display for TamContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Manifest => write!(f, "manifest"),
+            Self::Archive => write!(f, "archive"),
+        }
+    }
+}
+
+/// Verify the tertiary authentication mechanism (TAM) of a metadata object.
+///
+/// `data` is the complete msgpack encoding of the object, including the TAM
+/// itself. Borg computes the HMAC over this encoding with the HMAC bytes
+/// zeroed, so we do the same. Returns whether the object was authenticated; an
+/// unauthenticated object is only accepted when `required` is false.
+pub fn verify(
+    key: &Key,
+    data: &[u8],
+    tam: Option<&Tam>,
+    context: TamContext,
+    required: bool,
+) -> Result<bool> {
+    let Some(tam) = tam else {
+        if required {
+            bail!("{context} is not authenticated, but this repository requires it. This is either an attack or the {context} was written by a borg version without TAM support.");
+        }
+
+        return Ok(false);
+    };
+
+    if tam.tipe != TAM_TYPE {
+        if required {
+            bail!("{context} TAM uses unsupported suite {:?}", tam.tipe);
+        }
+
+        return Ok(false);
+    }
+
+    let (Some(tam_hmac), Some(salt)) = (tam.data.get("hmac"), tam.data.get("salt")) else {
+        bail!("{context} TAM is missing its hmac or salt");
+    };
+
+    if tam_hmac.0.len() != 64 {
+        bail!("{context} TAM hmac has the wrong length");
+    }
+
+    let offset = data
+        .windows(64)
+        .position(|window| window == tam_hmac.0)
+        .ok_or_else(|| eyre!("{context} TAM hmac not found in {context} data"))?;
+
+    let mut zeroed = data.to_vec();
+    zeroed[offset..offset + 64].fill(0);
+
+    mac(key, &salt.0, context)?
+        .chain_update(&zeroed)
+        .verify_slice(&tam_hmac.0)
+        .map_err(|_| {
+            eyre!("{context} TAM is invalid, the {context} may have been tampered with")
+        })?;
+
+    Ok(true)
+}
+
+/// Pack a metadata object with a fresh TAM. The object is packed once with
+/// the HMAC zeroed to compute it, then again with the HMAC filled in, which
+/// keeps the encoding the same length.
+pub fn pack<T: Serialize>(
+    key: &Key,
+    object: &mut T,
+    tam: impl Fn(&mut T) -> &mut Option<Tam>,
+    context: TamContext,
+) -> Result<Vec<u8>> {
+    let salt: [u8; 64] = {
+        let mut salt = [0; 64];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut salt[..]);
+        salt
+    };
+
+    *tam(object) = Some(Tam {
+        tipe: TAM_TYPE.to_string(),
+        data: HashMap::from([
+            ("hmac".to_string(), Bytes(vec![0; 64])),
+            ("salt".to_string(), Bytes(salt.to_vec())),
+        ]),
+    });
+
+    let zeroed = rmp_serde::to_vec_named(object).wrap_err_with(|| format!("encode {context}"))?;
+    let hmac = mac(key, &salt, context)?
+        .chain_update(&zeroed)
+        .finalize()
+        .into_bytes();
+
+    tam(object)
+        .as_mut()
+        .expect("TAM was just set")
+        .data
+        .insert("hmac".to_string(), Bytes(hmac.to_vec()));
+
+    rmp_serde::to_vec_named(object).wrap_err_with(|| format!("encode {context}"))
+}
+
+fn mac(key: &Key, salt: &[u8], context: TamContext) -> Result<Hmac<Sha512>> {
+    Ok(Hmac::<Sha512>::new_from_slice(
+        &key.tam_key(salt, context.as_bytes())?,
+    )?)
+}
@@ -0,0 +1,250 @@
+use std::{collections::HashMap, os::unix::ffi::OsStrExt, time::Duration};
+
+use crate::{
+    cache::{cache_dir, files_cache_name, Cache, CacheConfig, FilesCache, FilesCacheMode},
+    hashindex::{ChunkIndex, ChunkIndexEntry},
+    init::{init, EncryptionMode},
+    key::hex_lower,
+    lock::LockMode,
+    transaction::Transaction,
+    Repository,
+};
+
+use super::{backup, backup_with_files_cache, init::test_environment, read_manifest};
+
+#[test]
+fn test_files_cache_mode_parse() {
+    assert_eq!(
+        FilesCacheMode::parse("ctime,size,inode").unwrap(),
+        FilesCacheMode::default()
+    );
+
+    for valid in [
+        "mtime,size,inode",
+        "ctime,size",
+        "mtime,size",
+        "rechunk,ctime",
+        "rechunk,mtime",
+        "size",
+        "disabled",
+    ] {
+        assert!(FilesCacheMode::parse(valid).is_ok(), "{valid} is valid");
+    }
+
+    for invalid in [
+        "ctime,mtime,size",
+        "inode",
+        "disabled,size",
+        "ctime",
+        "atime",
+    ] {
+        assert!(
+            FilesCacheMode::parse(invalid).is_err(),
+            "{invalid} is invalid"
+        );
+    }
+}
+
+#[test]
+fn test_cache_config_roundtrip() {
+    let config = CacheConfig::parse(
+        "[cache]\nversion = 1\nrepository = abc\nmanifest = \ntimestamp = 2023-01-01T00:00:00.000000\n\n[integrity]\nmanifest = \nfiles = {\"algorithm\": \"XXH64\", \"digests\": {\"final\": \"0123456789abcdef\"}}\n",
+    )
+    .unwrap();
+
+    assert_eq!(config.repository, "abc");
+    assert_eq!(config.extra["timestamp"], "2023-01-01T00:00:00.000000");
+    assert!(config.integrity("files").is_some());
+    assert_eq!(CacheConfig::parse(&config.write()).unwrap(), config);
+
+    // digests written for another manifest cannot be trusted
+    let mut stale = config;
+    stale
+        .integrity
+        .insert("manifest".to_string(), "00".repeat(32));
+    assert!(stale.integrity("files").is_none());
+}
+
+#[test]
+fn test_create_maintains_files_cache() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join("old.txt"), "old").unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    std::fs::write(source.join("new.txt"), "new").unwrap();
+
+    init(&repository, EncryptionMode::Repokey).unwrap();
+    backup(&repository, "first", &source);
+
+    let repo = Repository::open(repository.clone(), LockMode::Shared, Duration::ZERO).unwrap();
+    let (key, _, _) = read_manifest(&repository).unwrap();
+    let path_hash = |name: &str| {
+        key.id_hash(source.join(name).as_os_str().as_bytes())
+            .unwrap()
+    };
+
+    let cache_path = cache_dir().unwrap().join(&repo.id);
+    let load = || {
+        let config =
+            CacheConfig::parse(&std::fs::read_to_string(cache_path.join("config")).unwrap())
+                .unwrap();
+
+        FilesCache::load(
+            &cache_path.join(files_cache_name()),
+            config.integrity(&files_cache_name()),
+            FilesCacheMode::default(),
+        )
+    };
+
+    // the newest file is left out, its ctime might still change within the
+    // timestamp granularity
+    let files = load();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files.get(&path_hash("old.txt")).unwrap().age, 1);
+    assert!(!cache_path.join("txn.active").exists());
+
+    std::thread::sleep(Duration::from_millis(50));
+    std::fs::write(source.join("newer.txt"), "newer").unwrap();
+    drop(repo);
+
+    backup(&repository, "second", &source);
+
+    let files = load();
+    assert_eq!(files.len(), 2);
+    assert!(files.get(&path_hash("new.txt")).is_some());
+    assert!(files.get(&path_hash("newer.txt")).is_none());
+
+    // a disabled cache is neither read nor written
+    backup_with_files_cache(
+        &repository,
+        "third",
+        &source,
+        FilesCacheMode::parse("disabled").unwrap(),
+    );
+    assert_eq!(load().len(), 2);
+}
+
+#[test]
+fn test_files_cache_detects_changes() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("file");
+    std::fs::write(&path, "contents").unwrap();
+    let metadata = std::fs::metadata(&path).unwrap();
+
+    let mut files = FilesCache::new(FilesCacheMode::default());
+    files.memorize([1; 32], &metadata, vec![[2; 32]]);
+    assert_eq!(files.lookup(&[1; 32], &metadata), Some(vec![[2; 32]]));
+    assert_eq!(files.lookup(&[3; 32], &metadata), None);
+
+    std::thread::sleep(Duration::from_millis(50));
+    std::fs::write(&path, "changed contents").unwrap();
+    let changed = std::fs::metadata(&path).unwrap();
+    assert_eq!(files.lookup(&[1; 32], &changed), None);
+
+    let mut rechunk = FilesCache::new(FilesCacheMode::parse("rechunk,ctime").unwrap());
+    rechunk.memorize([1; 32], &metadata, vec![[2; 32]]);
+    assert_eq!(rechunk.lookup(&[1; 32], &metadata), None);
+}
+
+fn entries(chunks: &ChunkIndex) -> HashMap<[u8; 32], ChunkIndexEntry> {
+    chunks.iter().collect()
+}
+
+#[test]
+fn test_chunks_cache_sync() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join("a.txt"), "same contents").unwrap();
+    std::fs::write(source.join("b.txt"), "same contents").unwrap();
+
+    init(&repository, EncryptionMode::Repokey).unwrap();
+    backup(&repository, "first", &source);
+    backup(&repository, "second", &source);
+
+    let (key, manifest, manifest_id) = read_manifest(&repository).unwrap();
+
+    let repo = Repository::open(repository.clone(), LockMode::Exclusive, Duration::ZERO).unwrap();
+    let mut transaction = Transaction::begin(&repo).unwrap();
+
+    let mut cache = Cache::open(&repo, FilesCacheMode::default(), Duration::ZERO).unwrap();
+    assert_eq!(cache.config.manifest, hex_lower(&manifest_id));
+
+    // both files share a chunk, referenced twice by each archive
+    let content_id = key.id_hash(b"same contents").unwrap();
+    assert_eq!(cache.chunks.get(&content_id).unwrap().refcount, 4);
+
+    let maintained = entries(&cache.chunks);
+    let archive_ids: Vec<[u8; 32]> = manifest
+        .archives
+        .values()
+        .map(|archive| archive.id.0.clone().try_into().unwrap())
+        .collect();
+    for id in &archive_ids {
+        assert_eq!(maintained[id].refcount, 1);
+    }
+
+    // an up to date cache is left alone
+    cache
+        .sync(&key, &manifest_id, &manifest, &mut transaction)
+        .unwrap();
+    assert_eq!(entries(&cache.chunks), maintained);
+
+    // rebuilding it from the archives gives the same result, writing an
+    // index for each archive and dropping those of deleted archives
+    let archive_dir = cache_dir().unwrap().join(&repo.id).join("chunks.archive.d");
+    std::fs::create_dir_all(&archive_dir).unwrap();
+    std::fs::write(
+        archive_dir.join(format!("{}.compact", "00".repeat(32))),
+        "gone",
+    )
+    .unwrap();
+
+    cache.config.manifest.clear();
+    cache
+        .sync(&key, &manifest_id, &manifest, &mut transaction)
+        .unwrap();
+    assert_eq!(entries(&cache.chunks), maintained);
+
+    let mut names: Vec<_> = std::fs::read_dir(&archive_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    let mut expected: Vec<_> = archive_ids
+        .iter()
+        .map(|id| hex_lower(id))
+        .flat_map(|id| [format!("{id}.compact"), format!("{id}.compact.integrity")])
+        .collect();
+    expected.sort();
+    assert_eq!(names, expected);
+
+    // an archive index that does not match its digests is built again
+    let second = hex_lower(&manifest.archives["second"].id.0);
+    std::fs::write(
+        archive_dir.join(format!("{second}.compact.integrity")),
+        "{\"algorithm\": \"XXH64\", \"digests\": {\"final\": \"0000000000000000\", \"HashHeader\": \"0000000000000000\"}}",
+    )
+    .unwrap();
+    cache.config.manifest.clear();
+    cache
+        .sync(&key, &manifest_id, &manifest, &mut transaction)
+        .unwrap();
+    assert_eq!(entries(&cache.chunks), maintained);
+    assert!(
+        !std::fs::read_to_string(archive_dir.join(format!("{second}.compact.integrity")))
+            .unwrap()
+            .contains("0000000000000000")
+    );
+}
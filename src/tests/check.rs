@@ -0,0 +1,410 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::FilesCacheMode,
+    check::{check, CheckedEntry, CheckedSegment},
+    chunker::ChunkerParams,
+    compression::Compression,
+    create::create,
+    init::{init, EncryptionMode},
+    key::Key,
+    lock::LockMode,
+    msgpack::Bytes,
+    pack_data,
+    transaction::{RepositoryState, Transaction},
+    unpack_data, Archive, Manifest, Options, Repository, MANIFEST_ID,
+};
+
+use super::init::test_environment;
+
+/// A repository with a few objects, one of them deleted, in segments 2
+/// to 5
+fn repository(path: &Path) -> Repository {
+    test_environment();
+
+    init(path, EncryptionMode::None).unwrap();
+    let repository =
+        Repository::open(path.to_path_buf(), LockMode::Exclusive, Duration::ZERO).unwrap();
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    for i in 1..=3 {
+        transaction.put(&[i; 32], &[i; 100]).unwrap();
+    }
+    transaction.commit().unwrap();
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    transaction.delete(&[2; 32]).unwrap();
+    transaction.commit().unwrap();
+
+    repository
+}
+
+fn check_ok(path: &Path) -> bool {
+    check(path.to_path_buf(), true, false, false, &Options::default()).unwrap()
+}
+
+#[test]
+fn test_check_clean() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    drop(repository(&path));
+
+    assert!(check_ok(&path));
+}
+
+#[test]
+fn test_checked_segment() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = repository(&path);
+
+    let mut reader = CheckedSegment::open(&repository.segment_path(2)).unwrap();
+    for (i, offset) in [(1, 8), (2, 149), (3, 290)] {
+        assert_eq!(
+            reader.next_entry().unwrap(),
+            Some((
+                offset,
+                CheckedEntry::Put {
+                    key: [i; 32],
+                    size: 141
+                }
+            ))
+        );
+    }
+    assert_eq!(reader.next_entry().unwrap(), None);
+
+    let mut reader = CheckedSegment::open(&repository.segment_path(4)).unwrap();
+    assert_eq!(
+        reader.next_entry().unwrap(),
+        Some((8, CheckedEntry::Delete { key: [2; 32] }))
+    );
+    assert_eq!(reader.next_entry().unwrap(), None);
+
+    let mut reader = CheckedSegment::open(&repository.segment_path(5)).unwrap();
+    assert_eq!(
+        reader.next_entry().unwrap(),
+        Some((8, CheckedEntry::Commit))
+    );
+    assert_eq!(reader.next_entry().unwrap(), None);
+}
+
+#[test]
+fn test_check_bad_crc() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = repository(&path);
+
+    let segment = repository.segment_path(2);
+    let mut data = std::fs::read(&segment).unwrap();
+    data[200] ^= 1;
+    std::fs::write(&segment, data).unwrap();
+
+    let mut reader = CheckedSegment::open(&segment).unwrap();
+    assert!(reader.next_entry().unwrap().is_some());
+    assert!(reader.next_entry().is_err());
+
+    drop(repository);
+    assert!(!check_ok(&path));
+}
+
+#[test]
+fn test_check_truncated_segment() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = repository(&path);
+
+    let segment = repository.segment_path(2);
+    let data = std::fs::read(&segment).unwrap();
+    std::fs::write(&segment, &data[..data.len() - 10]).unwrap();
+
+    let mut reader = CheckedSegment::open(&segment).unwrap();
+    assert!(reader.next_entry().unwrap().is_some());
+    assert!(reader.next_entry().unwrap().is_some());
+    assert!(reader.next_entry().is_err());
+
+    drop(repository);
+    assert!(!check_ok(&path));
+}
+
+#[test]
+fn test_check_index_mismatch() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = repository(&path);
+
+    // one object missing, one that should not exist and one in the wrong
+    // place
+    let mut state = RepositoryState::load(&repository).unwrap();
+    state.index.remove(&[1; 32]);
+    state.index.insert(&[2; 32], 2, 149);
+    state.index.insert(&[3; 32], 2, 8);
+    state.write(&path).unwrap();
+
+    drop(repository);
+    assert!(!check_ok(&path));
+}
+
+#[test]
+fn test_check_stale_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = repository(&path);
+
+    // an index of the previous transaction is fine as long as it matches
+    // the segments as of that transaction
+    let state = RepositoryState::replay(&repository, 3).unwrap();
+    state.write(&path).unwrap();
+
+    drop(repository);
+    assert!(check_ok(&path));
+}
+
+/// A repository with an archive of a file of three chunks, the second of
+/// which has the returned ID
+fn archive_repository(dir: &Path) -> (PathBuf, [u8; 32]) {
+    test_environment();
+
+    let path = dir.join("repo");
+    let source = dir.join("source");
+    std::fs::create_dir(&source).unwrap();
+
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(source.join("file"), &data).unwrap();
+
+    init(&path, EncryptionMode::None).unwrap();
+    create(
+        &format!("{}::archive", path.display()),
+        &[source],
+        ChunkerParams::parse("fixed,4096").unwrap(),
+        &Compression::default(),
+        FilesCacheMode::default(),
+        String::new(),
+        &Options::default(),
+    )
+    .unwrap();
+
+    (path, Sha256::digest(&data[4096..8192]).into())
+}
+
+fn delete_object(path: &Path, id: &[u8; 32]) {
+    let repository =
+        Repository::open(path.to_path_buf(), LockMode::Exclusive, Duration::ZERO).unwrap();
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    transaction.delete(id).unwrap();
+    transaction.commit().unwrap();
+}
+
+fn check_archives_ok(path: &Path) -> bool {
+    check(path.to_path_buf(), false, true, false, &Options::default()).unwrap()
+}
+
+#[test]
+fn test_check_archives() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = archive_repository(dir.path());
+
+    assert!(check_archives_ok(&path));
+    assert!(check(path, false, false, false, &Options::default()).unwrap());
+}
+
+#[test]
+fn test_check_archives_missing_chunk() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, chunk_id) = archive_repository(dir.path());
+
+    delete_object(&path, &chunk_id);
+
+    // the repository itself is consistent, only the archive is damaged
+    assert!(check_ok(&path));
+    assert!(!check_archives_ok(&path));
+}
+
+#[test]
+fn test_check_archives_missing_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = archive_repository(dir.path());
+
+    delete_object(&path, &MANIFEST_ID);
+
+    assert!(!check_archives_ok(&path));
+}
+
+fn repair(path: &Path, repository_only: bool) -> bool {
+    std::env::set_var("BORG_CHECK_I_KNOW_WHAT_I_AM_DOING", "YES");
+
+    check(
+        path.to_path_buf(),
+        repository_only,
+        false,
+        true,
+        &Options::default(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_check_repair_damaged_entry() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = repository(&path);
+
+    // damage the first of the three objects in segment 2
+    let segment = repository.segment_path(2);
+    let mut data = std::fs::read(&segment).unwrap();
+    data[50] ^= 1;
+    std::fs::write(&segment, data).unwrap();
+    drop(repository);
+
+    assert!(repair(&path, true));
+    assert!(check_ok(&path));
+
+    let mut backup = segment.clone().into_os_string();
+    backup.push(".beforerecover");
+    assert!(Path::new(&backup).exists());
+
+    let repository = Repository::open(path, LockMode::Exclusive, Duration::ZERO).unwrap();
+    let state = RepositoryState::load(&repository).unwrap();
+    assert_eq!(state.index.get(&[1; 32]), None);
+    assert_eq!(state.index.get(&[2; 32]), None);
+
+    let (segment, offset) = state.index.get(&[3; 32]).unwrap();
+    assert_eq!(
+        repository.read_put(segment, offset, &[3; 32]).unwrap(),
+        [3; 100]
+    );
+}
+
+#[test]
+fn test_check_repair_truncated_segment() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = repository(&path);
+
+    // the COMMIT of the last transaction is lost with the end of the segment
+    let segment = repository.segment_path(5);
+    let data = std::fs::read(&segment).unwrap();
+    std::fs::write(&segment, &data[..data.len() - 3]).unwrap();
+    drop(repository);
+
+    assert!(!check_ok(&path));
+    assert!(repair(&path, true));
+    assert!(check_ok(&path));
+}
+
+#[test]
+fn test_check_repair_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = repository(&path);
+
+    let mut state = RepositoryState::load(&repository).unwrap();
+    state.index.remove(&[1; 32]);
+    state.write(&path).unwrap();
+    drop(repository);
+
+    assert!(repair(&path, true));
+    assert!(check_ok(&path));
+}
+
+/// An item with the lists of chunks it has and had before a repair
+#[derive(Deserialize)]
+struct ChunkLists {
+    chunks: Vec<(Bytes, u32, u32)>,
+    chunks_healthy: Option<Vec<(Bytes, u32, u32)>>,
+}
+
+/// The chunk lists of the one file in the archive of `archive_repository`
+fn file_chunks(path: &Path) -> ChunkLists {
+    let repository =
+        Repository::open(path.to_path_buf(), LockMode::Shared, Duration::ZERO).unwrap();
+
+    let state = RepositoryState::load(&repository).unwrap();
+    let get = |id: &[u8; 32]| state.get(&repository, id).unwrap().unwrap();
+
+    let manifest_data = get(&MANIFEST_ID);
+    let key = Key::load(&repository, &manifest_data).unwrap();
+    let manifest =
+        Manifest::decode(&key, &unpack_data(&key, &manifest_data).unwrap(), false).unwrap();
+
+    let id: [u8; 32] = manifest.archives["archive"]
+        .id
+        .0
+        .clone()
+        .try_into()
+        .unwrap();
+    let archive_data = unpack_data(&key, &get(&id)).unwrap();
+    let archive = Archive::decode(&key, &archive_data, false).unwrap();
+
+    let mut stream = Vec::new();
+    for item_id in &archive.items {
+        let id: [u8; 32] = item_id.0.clone().try_into().unwrap();
+        stream.extend(unpack_data(&key, &get(&id)).unwrap());
+    }
+
+    // the source directory comes first, then the file
+    let mut cursor = std::io::Cursor::new(&stream[..]);
+    let _: serde::de::IgnoredAny = rmp_serde::from_read(&mut cursor).unwrap();
+    rmp_serde::from_read(&mut cursor).unwrap()
+}
+
+#[test]
+fn test_check_repair_missing_chunk() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, chunk_id) = archive_repository(dir.path());
+
+    let before = file_chunks(&path);
+    assert!(before.chunks_healthy.is_none());
+
+    delete_object(&path, &chunk_id);
+    assert!(repair(&path, false));
+
+    // a missing chunk that was replaced is no problem any more
+    assert!(check(path.clone(), false, false, false, &Options::default()).unwrap());
+
+    let repaired = file_chunks(&path);
+    let zeros: [u8; 32] = Sha256::digest([0; 4096]).into();
+    assert_eq!(repaired.chunks[1].0 .0, zeros);
+    assert_eq!(repaired.chunks[1].1, 4096);
+    assert_eq!(repaired.chunks[0], before.chunks[0]);
+    assert_eq!(repaired.chunks_healthy.as_ref(), Some(&before.chunks));
+
+    // once the chunk is back, the next repair heals the file
+    let data: Vec<u8> = (4096..8192u32).map(|i| (i % 251) as u8).collect();
+    let repository = Repository::open(path.clone(), LockMode::Exclusive, Duration::ZERO).unwrap();
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    transaction
+        .put(
+            &chunk_id,
+            &pack_data(&Key::plaintext(), &Compression::default(), &data).unwrap(),
+        )
+        .unwrap();
+    transaction.commit().unwrap();
+    drop(repository);
+
+    assert!(repair(&path, false));
+    assert!(check_archives_ok(&path));
+
+    let healed = file_chunks(&path);
+    assert_eq!(healed.chunks, before.chunks);
+    assert!(healed.chunks_healthy.is_none());
+}
+
+#[test]
+fn test_check_repair_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = archive_repository(dir.path());
+
+    delete_object(&path, &MANIFEST_ID);
+    assert!(repair(&path, false));
+    assert!(check_archives_ok(&path));
+
+    let chunks = file_chunks(&path);
+    assert_eq!(chunks.chunks.len(), 3);
+}
@@ -0,0 +1,313 @@
+use blake2::{digest::consts::U32, Blake2b, Digest};
+
+use crate::{
+    chunker::{buzhash, buzhash_update, ChunkerParams, DEFAULT_CHUNKER_PARAMS},
+    key::hex_lower,
+};
+
+/// Deterministic pseudo random test data
+fn data(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn chunks(params: ChunkerParams, data: &[u8], seed: u32) -> Vec<Vec<u8>> {
+    params
+        .chunker(data, seed)
+        .collect::<eyre::Result<_>>()
+        .unwrap()
+}
+
+#[test]
+fn test_chunker_params_parse() {
+    assert_eq!(
+        ChunkerParams::parse("default").unwrap(),
+        DEFAULT_CHUNKER_PARAMS
+    );
+    assert_eq!(
+        ChunkerParams::parse("buzhash,19,23,21,4095").unwrap(),
+        DEFAULT_CHUNKER_PARAMS
+    );
+    assert_eq!(
+        ChunkerParams::parse("10,23,16,4095").unwrap(),
+        ChunkerParams::Buzhash {
+            min_exp: 10,
+            max_exp: 23,
+            mask_bits: 16,
+            window_size: 4095
+        }
+    );
+    assert_eq!(
+        ChunkerParams::parse("fixed,4194304,512").unwrap(),
+        ChunkerParams::Fixed {
+            block_size: 4194304,
+            header_size: 512
+        }
+    );
+
+    for invalid in [
+        "",
+        "buzhash,19,23,21",
+        "19,23,24,4095",
+        "5,23,16,4095",
+        "19,24,21,4095",
+        "fixed,63",
+        "fixed,4194304,a",
+        "rabin,1,2,3,4",
+        "buzhash,10,23,16,0",
+        "10,23,16,4096",
+    ] {
+        assert!(ChunkerParams::parse(invalid).is_err(), "{invalid:?}");
+    }
+
+    for params in ["buzhash,19,23,21,4095", "fixed,4096", "fixed,4096,512"] {
+        assert_eq!(ChunkerParams::parse(params).unwrap().to_string(), params);
+    }
+}
+
+#[test]
+fn test_fixed_chunker() {
+    let data = data(10_000);
+    let params = ChunkerParams::parse("fixed,4096,100").unwrap();
+
+    let cut = chunks(params, &data, 0);
+    let sizes: Vec<_> = cut.iter().map(Vec::len).collect();
+
+    assert_eq!(sizes, [100, 4096, 4096, 1708]);
+    assert_eq!(cut.concat(), data);
+
+    assert!(chunks(params, &[], 0).is_empty());
+}
+
+#[test]
+fn test_buzhash_rolls() {
+    let data = data(100);
+
+    for seed in [0, 1, 0xdead_beef] {
+        let mut sum = buzhash(&data[..40], seed);
+
+        for i in 0..60 {
+            sum = buzhash_update(sum, data[i], data[i + 40], 40, seed);
+            assert_eq!(sum, buzhash(&data[i + 1..i + 41], seed));
+        }
+    }
+
+    // the seed is XORed into every table value, which flips the same bits of
+    // a 16 byte window's hash no matter what the table holds
+    let window = b"abcdefghijklmnop";
+    assert_eq!(buzhash(window, 0) ^ buzhash(window, 1), 0xffff);
+}
+
+#[test]
+fn test_buzhash_chunker() {
+    let data = data(3_000_000);
+    let params = ChunkerParams::parse("buzhash,10,16,12,63").unwrap();
+
+    let cut = chunks(params, &data, 7);
+    assert_eq!(cut.concat(), data);
+
+    let (last, rest) = cut.split_last().unwrap();
+    assert!(!last.is_empty() && last.len() <= 1 << 16);
+    for chunk in rest {
+        assert!(chunk.len() >= 1 << 10 && chunk.len() <= 1 << 16);
+    }
+
+    // around 4 KiB on average
+    assert!((300..1500).contains(&cut.len()), "{}", cut.len());
+
+    // a different seed cuts different chunks
+    assert_ne!(chunks(params, &data, 8), cut);
+}
+
+#[test]
+fn test_buzhash_chunker_resynchronizes() {
+    let data = data(1_000_000);
+    let params = ChunkerParams::parse("buzhash,10,16,12,63").unwrap();
+
+    let mut shifted = b"some bytes inserted at the start".to_vec();
+    shifted.extend_from_slice(&data);
+
+    let original = chunks(params, &data, 0);
+    let shifted = chunks(params, &shifted, 0);
+
+    // after the first few chunks, inserting data no longer matters
+    assert_eq!(original[5..], shifted[shifted.len() - original.len() + 5..]);
+}
+
+#[test]
+fn test_buzhash_chunker_short_input() {
+    let params = ChunkerParams::parse("buzhash,10,16,12,63").unwrap();
+
+    assert!(chunks(params, &[], 0).is_empty());
+    assert_eq!(chunks(params, b"tiny", 0), [b"tiny".to_vec()]);
+}
+
+/// Test vectors from borg's test suite, computed by borg's buzhash
+#[test]
+fn test_buzhash_borg_vectors() {
+    assert_eq!(buzhash(b"abcdefghijklmnop", 0), 3795437769);
+    assert_eq!(buzhash(b"abcdefghijklmnop", 1), 3795400502);
+    assert_eq!(
+        buzhash(&b"abcdefghijklmnopqrstuvwxyz".repeat(2), 0),
+        566521248
+    );
+}
+
+fn buzhash_params(min_exp: u32, max_exp: u32, mask_bits: u32, window_size: u32) -> ChunkerParams {
+    ChunkerParams::Buzhash {
+        min_exp,
+        max_exp,
+        mask_bits,
+        window_size,
+    }
+}
+
+/// The boundaries borg's own chunker finds, from `test_chunkify` in borg's
+/// test suite
+#[test]
+fn test_buzhash_chunker_borg_boundaries() {
+    let data = b"foobarboobaz".repeat(3);
+
+    let check = |seed, min_exp, mask_bits, window_size, expected: &[&[u8]]| {
+        let params = buzhash_params(min_exp, 23, mask_bits, window_size);
+
+        assert_eq!(
+            chunks(params, &data, seed),
+            expected,
+            "seed {seed}, min_exp {min_exp}, mask_bits {mask_bits}, window_size {window_size}"
+        );
+    };
+
+    check(
+        0,
+        1,
+        2,
+        2,
+        &[
+            b"fooba", b"rboobaz", b"fooba", b"rboobaz", b"fooba", b"rboobaz",
+        ],
+    );
+    check(
+        1,
+        1,
+        2,
+        2,
+        &[
+            b"fo", b"obarb", b"oob", b"azf", b"oobarb", b"oob", b"azf", b"oobarb", b"oobaz",
+        ],
+    );
+    check(
+        2,
+        1,
+        2,
+        2,
+        &[
+            b"foob",
+            b"ar",
+            b"boobazfoob",
+            b"ar",
+            b"boobazfoob",
+            b"ar",
+            b"boobaz",
+        ],
+    );
+    check(0, 2, 2, 3, &[&data]);
+    check(
+        1,
+        2,
+        2,
+        3,
+        &[
+            b"foobar",
+            b"boobazfo",
+            b"obar",
+            b"boobazfo",
+            b"obar",
+            b"boobaz",
+        ],
+    );
+    check(
+        2,
+        2,
+        2,
+        3,
+        &[
+            b"foob",
+            b"arboobaz",
+            b"foob",
+            b"arboobaz",
+            b"foob",
+            b"arboobaz",
+        ],
+    );
+    check(0, 3, 2, 3, &[&data]);
+    check(
+        1,
+        3,
+        2,
+        3,
+        &[b"foobarbo", b"obazfoobar", b"boobazfo", b"obarboobaz"],
+    );
+    check(
+        2,
+        3,
+        2,
+        3,
+        &[b"foobarboobaz", b"foobarboobaz", b"foobarboobaz"],
+    );
+
+    let data = [b"0".repeat(3 << 22), b"Y".to_vec()].concat();
+    let cut = chunks(buzhash_params(1, 23, 2, 2), &data, 0);
+    assert_eq!(cut.len(), 2);
+    assert_eq!(cut.concat(), data);
+}
+
+/// `test_chunkpoints_unchanged` from borg's test suite, which hashes the
+/// chunks of many parameter combinations with unkeyed BLAKE2b-256 and so
+/// covers every table value. The expected hash is borg's.
+#[test]
+fn test_buzhash_chunker_borg_chunkpoints() {
+    let mut x: u32 = 1;
+    let data: Vec<u8> = (0..100_000)
+        .map(|_| {
+            x = (x.wrapping_mul(1103515245).wrapping_add(12345)) & 0x7fff_ffff;
+            x as u8
+        })
+        .collect();
+
+    let mut runs = Vec::new();
+    for window_size in [65, 129, 4095, 7351] {
+        for min_exp in [4, 6, 7, 11, 12] {
+            for max_exp in [15, 17] {
+                if min_exp >= max_exp {
+                    continue;
+                }
+
+                for mask_bits in [4, 7, 10, 12] {
+                    for seed in [1849058162, 1234567653] {
+                        let params = buzhash_params(min_exp, max_exp, mask_bits, window_size);
+
+                        let hashes: Vec<u8> = chunks(params, &data, seed)
+                            .iter()
+                            .flat_map(Blake2b::<U32>::digest)
+                            .collect();
+                        runs.extend(Blake2b::<U32>::digest(&hashes));
+                    }
+                }
+            }
+        }
+    }
+
+    assert_eq!(
+        hex_lower(&Blake2b::<U32>::digest(&runs)),
+        "b559b0ac8df8daaa221201d018815114241ea5c6609d98913cd2246a702af4e3"
+    );
+}
@@ -0,0 +1,266 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use crate::{
+    compact::compact,
+    config::RepositoryConfig,
+    init::{init, EncryptionMode},
+    lock::LockMode,
+    transaction::{RepositoryState, Transaction},
+    LogEntry, Options, Repository, Segment, MANIFEST_ID,
+};
+
+use super::init::test_environment;
+
+/// Create a repository with small segments, so that a few objects fill
+/// several of them
+fn small_segment_repository(path: &Path) {
+    test_environment();
+
+    init(path, EncryptionMode::None).unwrap();
+
+    let config_path = path.join("config");
+    let mut config =
+        RepositoryConfig::parse(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+    config.max_segment_size = 300;
+    config.segments_per_dir = 4;
+    std::fs::write(&config_path, config.write()).unwrap();
+}
+
+fn open(path: &Path) -> Repository {
+    Repository::open(path.to_path_buf(), LockMode::Exclusive, Duration::ZERO).unwrap()
+}
+
+fn segment_ids(repository: &Repository) -> Vec<u32> {
+    repository
+        .segments()
+        .unwrap()
+        .iter()
+        .map(|segment| segment.id)
+        .collect()
+}
+
+/// Check that the index and hints written by compaction agree with what
+/// replaying the remaining segments gives, and return the objects besides
+/// the manifest
+fn check_state(repository: &Repository) -> HashMap<[u8; 32], Vec<u8>> {
+    let state = RepositoryState::load(repository).unwrap();
+    let transaction_id = state.transaction_id.unwrap();
+    assert_eq!(
+        repository.last_committed_segment().unwrap(),
+        Some(transaction_id)
+    );
+
+    let replayed = RepositoryState::replay(repository, transaction_id).unwrap();
+
+    let index: HashMap<_, _> = state.index.iter().collect();
+    assert_eq!(index, replayed.index.iter().collect::<HashMap<_, _>>());
+    assert_eq!(state.hints.segments, replayed.hints.segments);
+    assert_eq!(
+        state.hints.storage_quota_use,
+        replayed.hints.storage_quota_use
+    );
+
+    index
+        .into_iter()
+        .filter(|(id, _)| id != &MANIFEST_ID)
+        .map(|(id, (segment, offset))| (id, repository.read_put(segment, offset, &id).unwrap()))
+        .collect()
+}
+
+#[test]
+fn test_compact_segments() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    small_segment_repository(&path);
+
+    let repository = open(&path);
+
+    // three objects fit in a segment
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    for i in 1..=10 {
+        transaction.put(&[i; 32], &[i; 100]).unwrap();
+    }
+    transaction.commit().unwrap();
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    for i in [1, 3, 5, 7] {
+        transaction.delete(&[i; 32]).unwrap();
+    }
+    transaction.put(&[10; 32], b"replaced").unwrap();
+    transaction.commit().unwrap();
+
+    let quota_use = RepositoryState::load(&repository)
+        .unwrap()
+        .hints
+        .storage_quota_use;
+
+    let freed = Transaction::begin(&repository)
+        .unwrap()
+        .commit_compacted(0.1)
+        .unwrap();
+    assert_eq!(freed, 5 * (41 + 100));
+
+    let state = RepositoryState::load(&repository).unwrap();
+    assert_eq!(state.hints.storage_quota_use, quota_use - freed);
+    assert!(state.hints.shadow_index.values().all(Vec::is_empty));
+
+    let mut expected: HashMap<_, _> = [2, 4, 6, 8, 9]
+        .into_iter()
+        .map(|i| ([i; 32], vec![i; 100]))
+        .collect();
+    expected.insert([10; 32], b"replaced".to_vec());
+    assert_eq!(check_state(&repository), expected);
+
+    // only the segment with the manifest is left of the ones written before,
+    // the rewritten objects filled a segment, which got an intermediate
+    // commit before the next one was started
+    let segments = segment_ids(&repository);
+    assert_eq!(segments, [0, 10, 11, 12]);
+
+    let commits = segments
+        .iter()
+        .flat_map(|&id| {
+            Segment {
+                id,
+                path: repository.segment_path(id),
+            }
+            .open()
+            .unwrap()
+        })
+        .filter(|entry| matches!(entry, Ok(LogEntry::Commit)))
+        .count();
+    assert_eq!(commits, 2);
+
+    // emptied segment directories are removed
+    assert!(!path.join("data/1").exists());
+}
+
+#[test]
+fn test_compact_keeps_unstable_deletes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    small_segment_repository(&path);
+
+    let repository = open(&path);
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    transaction.put(&[1; 32], &[1; 200]).unwrap();
+    transaction.put(&[2; 32], &[2; 250]).unwrap();
+    transaction.commit().unwrap();
+
+    // the threshold leaves the segment with the PUT alone, so the DELETE
+    // shadowing it must survive the compaction of its own segment
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    transaction.delete(&[1; 32]).unwrap();
+    transaction.commit_compacted(0.5).unwrap();
+
+    let objects = check_state(&repository);
+    assert_eq!(objects.len(), 1);
+    assert!(!objects.contains_key(&[1; 32]));
+
+    let state = RepositoryState::load(&repository).unwrap();
+    assert_eq!(state.hints.shadow_index[&[1; 32]], vec![2]);
+
+    // once the PUT is gone, so is the DELETE
+    Transaction::begin(&repository)
+        .unwrap()
+        .commit_compacted(0.1)
+        .unwrap();
+
+    let objects = check_state(&repository);
+    assert_eq!(objects.len(), 1);
+
+    let state = RepositoryState::load(&repository).unwrap();
+    assert!(!state.hints.shadow_index.contains_key(&[1; 32]));
+}
+
+#[test]
+fn test_compact_append_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    small_segment_repository(&path);
+
+    {
+        let repository = open(&path);
+        let mut transaction = Transaction::begin(&repository).unwrap();
+        transaction.put(&[1; 32], b"data").unwrap();
+        transaction.commit().unwrap();
+
+        let mut transaction = Transaction::begin(&repository).unwrap();
+        transaction.delete(&[1; 32]).unwrap();
+        transaction.commit().unwrap();
+    }
+
+    let config_path = path.join("config");
+    let mut config =
+        RepositoryConfig::parse(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+    config.append_only = true;
+    std::fs::write(&config_path, config.write()).unwrap();
+
+    let before = segment_ids(&open(&path));
+    compact(path.clone(), 10.0, &Options::default()).unwrap();
+    assert_eq!(segment_ids(&open(&path)), before);
+}
+
+/// A repository with objects 1 to 3, then a DELETE of object 1 and a
+/// replaced object 2, so there are shadowed PUTs to compact away
+fn shadowed_repository(path: &Path) -> Repository {
+    small_segment_repository(path);
+    let repository = open(path);
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    for i in 1..=3 {
+        transaction.put(&[i; 32], &[i; 50]).unwrap();
+    }
+    transaction.commit().unwrap();
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    transaction.delete(&[1; 32]).unwrap();
+    transaction.put(&[2; 32], b"replaced").unwrap();
+    transaction.commit().unwrap();
+
+    repository
+}
+
+#[test]
+fn test_compact_hints_without_quota() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = shadowed_repository(&path);
+
+    // as loaded from hints of version 1, which do not track the quota
+    let mut state = RepositoryState::load(&repository).unwrap();
+    state.hints.storage_quota_use = 0;
+    state.write(&repository.path).unwrap();
+
+    Transaction::begin(&repository)
+        .unwrap()
+        .commit_compacted(0.1)
+        .unwrap();
+
+    let state = RepositoryState::load(&repository).unwrap();
+    assert_eq!(state.hints.storage_quota_use, 0);
+    assert!(state.index.get(&[1; 32]).is_none());
+    assert!(state.index.get(&[2; 32]).is_some());
+}
+
+#[test]
+fn test_compact_corrupted_reference_count() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = shadowed_repository(&path);
+
+    let mut state = RepositoryState::load(&repository).unwrap();
+    let (segment, _) = state.index.get(&[3; 32]).unwrap();
+    state.hints.segments.remove(&segment);
+    state.write(&repository.path).unwrap();
+
+    let error = Transaction::begin(&repository)
+        .unwrap()
+        .commit_compacted(0.1)
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        format!("corrupted segment reference count for segment {segment}, the index or hints are corrupted")
+    );
+}
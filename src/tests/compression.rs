@@ -0,0 +1,146 @@
+use crate::compression::{decompress, Compression};
+
+/// Compressible but not trivially so, to give each algorithm some work
+fn text() -> Vec<u8> {
+    (0..2000)
+        .flat_map(|n| format!("line {n} of a text that repeats itself\n").into_bytes())
+        .collect()
+}
+
+/// Pseudo random bytes, which do not compress
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn test_compression_parse() {
+    for (spec, expected) in [
+        ("none", Compression::None),
+        ("lz4", Compression::Lz4),
+        ("zstd", Compression::Zstd(3)),
+        ("zstd,22", Compression::Zstd(22)),
+        ("zlib", Compression::Zlib(6)),
+        ("zlib,0", Compression::Zlib(0)),
+        ("lzma,9", Compression::Lzma(9)),
+        (
+            "auto,zstd,10",
+            Compression::Auto(Box::new(Compression::Zstd(10))),
+        ),
+        (
+            "obfuscate,110,auto,lz4",
+            Compression::Obfuscate {
+                level: 110,
+                inner: Box::new(Compression::Auto(Box::new(Compression::Lz4))),
+            },
+        ),
+    ] {
+        let parsed = Compression::parse(spec).unwrap();
+        assert_eq!(parsed, expected, "{spec}");
+        assert_eq!(Compression::parse(&parsed.to_string()).unwrap(), expected);
+    }
+
+    for invalid in [
+        "",
+        "gzip",
+        "lz4,1",
+        "zstd,0",
+        "zstd,23",
+        "zlib,10",
+        "lzma,x",
+        "zlib,6,1",
+        "auto",
+        "auto,none",
+        "obfuscate,7,lz4",
+        "obfuscate,110",
+        "obfuscate,1,obfuscate,1,lz4",
+    ] {
+        assert!(Compression::parse(invalid).is_err(), "{invalid} is invalid");
+    }
+}
+
+#[test]
+fn test_compression_headers() {
+    let data = text();
+
+    // borg 1.x writes the type and a zero byte, zlib data goes without one
+    for (spec, header) in [
+        ("none", [0x00, 0x00]),
+        ("lz4", [0x01, 0x00]),
+        ("lzma", [0x02, 0x00]),
+        ("zstd", [0x03, 0x00]),
+        ("obfuscate,1,none", [0x04, 0x00]),
+        ("zlib", [0x78, 0x9c]),
+    ] {
+        let compressed = Compression::parse(spec).unwrap().compress(&data).unwrap();
+        assert_eq!(compressed[..2], header, "{spec}");
+    }
+
+    assert_eq!(
+        Compression::None.compress(b"abc").unwrap(),
+        b"\x00\x00abc".to_vec()
+    );
+}
+
+#[test]
+fn test_compression_roundtrip() {
+    for data in [Vec::new(), b"x".to_vec(), text(), noise(100_000)] {
+        for spec in [
+            "none",
+            "lz4",
+            "zstd,1",
+            "zstd,19",
+            "zlib,0",
+            "zlib,9",
+            "lzma,0",
+            "lzma,6",
+            "auto,lzma",
+            "obfuscate,3,zstd",
+            "obfuscate,123,auto,zlib",
+        ] {
+            let compressed = Compression::parse(spec).unwrap().compress(&data).unwrap();
+            assert_eq!(decompress(&compressed).unwrap(), data, "{spec}");
+        }
+    }
+}
+
+#[test]
+fn test_compression_auto() {
+    let auto = Compression::parse("auto,zlib,9").unwrap();
+
+    // incompressible data is stored as it is, anything else with the slower
+    // compression if that is worth it
+    assert_eq!(auto.compress(&noise(10_000)).unwrap()[..2], [0x00, 0x00]);
+    assert_eq!(auto.compress(&text()).unwrap()[..2], [0x78, 0xda]);
+
+    let auto_lz4 = Compression::parse("auto,lz4").unwrap();
+    assert_eq!(
+        auto_lz4.compress(&text()).unwrap(),
+        Compression::Lz4.compress(&text()).unwrap()
+    );
+}
+
+#[test]
+fn test_compression_obfuscate_pads() {
+    let data = text();
+    let plain = Compression::Zstd(3).compress(&data).unwrap();
+
+    let padded = Compression::parse("obfuscate,120,zstd,3").unwrap();
+    let sizes: Vec<_> = (0..20)
+        .map(|_| padded.compress(&data).unwrap().len())
+        .collect();
+
+    // the inner data with its size in front, followed by up to 1 MiB of zeros
+    assert!(sizes
+        .iter()
+        .all(|&size| size >= 2 + 4 + plain.len() && size < 2 + 4 + plain.len() + (1 << 20)));
+    assert!(sizes.iter().any(|&size| size != sizes[0]));
+}
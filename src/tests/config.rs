@@ -0,0 +1,42 @@
+use crate::config::RepositoryConfig;
+
+const CONFIG: &str = "[repository]
+version = 1
+segments_per_dir = 1000
+max_segment_size = 524288000
+append_only = 0
+storage_quota = 0
+additional_free_space = 0
+id = 4d2a6b5c2f1f4e0c9b3a8d7e6f5a4b3c2d1e0f9a8b7c6d5e4f3a2b1c0d9e8f7a
+key = hqlhbGdvcml0aG2mc2hhMjU2pGRhdGHaAN5SsRgYmUi8Kj
+\tnxvhGNvqoCTdS1Ifv5h04qXq0ATFN8BN1EGhNkd7MFC1z
+";
+
+#[test]
+fn test_config_roundtrip() {
+    let config = RepositoryConfig::parse(CONFIG).unwrap();
+
+    assert_eq!(config.max_segment_size, 524288000);
+    assert!(!config.append_only);
+    assert_eq!(config.key.lines().count(), 2);
+
+    assert_eq!(RepositoryConfig::parse(&config.write()).unwrap(), config);
+}
+
+#[test]
+fn test_config_unsupported_version() {
+    let config = CONFIG.replace("version = 1", "version = 2");
+
+    let err = RepositoryConfig::parse(&config).unwrap_err();
+    assert!(format!("{err:#}").contains("unsupported repository version 2"));
+}
+
+#[test]
+fn test_config_sizes() {
+    let config = CONFIG.replace("storage_quota = 0", "storage_quota = 2G");
+
+    assert_eq!(
+        RepositoryConfig::parse(&config).unwrap().storage_quota,
+        2_000_000_000
+    );
+}
@@ -0,0 +1,280 @@
+use std::{path::Path, time::Duration};
+
+use crate::{
+    cache::FilesCacheMode,
+    chunker::ChunkerParams,
+    compression::Compression,
+    create::{create, replace_placeholders},
+    init::{init, EncryptionMode},
+    key::Key,
+    lock::{hostname, LockMode},
+    transaction::RepositoryState,
+    unpack_data, Archive, ItemMetadata, Manifest, Options, Repository, MANIFEST_ID,
+};
+
+use super::init::test_environment;
+
+/// Deterministic pseudo random file contents, so chunks do not deduplicate
+fn data(len: usize) -> Vec<u8> {
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// A small tree with a directory, a multi-chunk file and a symlink
+fn source_tree(dir: &Path) -> Vec<u8> {
+    let large = data(300_000);
+
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("small.txt"), "hello from small.txt").unwrap();
+    std::fs::write(dir.join("sub/large.bin"), &large).unwrap();
+    std::os::unix::fs::symlink("../small.txt", dir.join("sub/link")).unwrap();
+
+    large
+}
+
+fn fixed_64k() -> ChunkerParams {
+    ChunkerParams::parse("fixed,65536").unwrap()
+}
+
+/// Read the items of an archive with the same code extract uses
+fn read_archive(repository: &Path, name: &str) -> (Repository, Key, Archive, Vec<ItemMetadata>) {
+    let repository =
+        Repository::open(repository.to_path_buf(), LockMode::Shared, Duration::ZERO).unwrap();
+
+    let state = RepositoryState::load(&repository).unwrap();
+    let get = |id: &[u8; 32]| state.get(&repository, id).unwrap().unwrap();
+
+    let manifest_data = get(&MANIFEST_ID);
+    let key = Key::load(&repository, &manifest_data).unwrap();
+    let manifest =
+        Manifest::decode(&key, &unpack_data(&key, &manifest_data).unwrap(), false).unwrap();
+
+    let id: [u8; 32] = manifest.archives[name].id.0.clone().try_into().unwrap();
+    let archive_data = unpack_data(&key, &get(&id)).unwrap();
+    let archive = Archive::decode(&key, &archive_data, false).unwrap();
+
+    let mut items = Vec::new();
+    for item_id in &archive.items {
+        let id: [u8; 32] = item_id.0.clone().try_into().unwrap();
+        let data = unpack_data(&key, &get(&id)).unwrap();
+
+        let mut cursor = std::io::Cursor::new(&data[..]);
+        while (cursor.position() as usize) < data.len() {
+            items.push(rmp_serde::from_read::<_, ItemMetadata>(&mut cursor).unwrap());
+        }
+    }
+
+    (repository, key, archive, items)
+}
+
+fn check_create(mode: EncryptionMode) {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+    let large = source_tree(&source);
+
+    init(&repository, mode).unwrap();
+    create(
+        &format!("{}::first", repository.display()),
+        std::slice::from_ref(&source),
+        fixed_64k(),
+        &Compression::default(),
+        FilesCacheMode::default(),
+        "a comment".to_string(),
+        &Options::default(),
+    )
+    .unwrap();
+
+    let (repo, key, archive, items) = read_archive(&repository, "first");
+
+    assert_eq!(archive.name, "first");
+    assert_eq!(archive.comment, "a comment");
+    assert_eq!(archive.nfiles, Some(2));
+    assert_eq!(archive.size, Some(large.len() as u64 + 20));
+
+    let paths: Vec<_> = items.iter().map(|item| item.path.as_str()).collect();
+    let base = source.to_str().unwrap().trim_start_matches('/');
+    assert_eq!(
+        paths,
+        [
+            base.to_string(),
+            format!("{base}/small.txt"),
+            format!("{base}/sub"),
+            format!("{base}/sub/large.bin"),
+            format!("{base}/sub/link"),
+        ]
+    );
+
+    let large_item = &items[3];
+    assert_eq!(large_item.chunks.len(), 5);
+
+    let state = RepositoryState::load(&repo).unwrap();
+    let mut restored = Vec::new();
+    for (id, _, _) in &large_item.chunks {
+        let id: [u8; 32] = id.0.clone().try_into().unwrap();
+        restored.extend(unpack_data(&key, &state.get(&repo, &id).unwrap().unwrap()).unwrap());
+    }
+    assert_eq!(restored, large);
+
+    // the written index and hints must be what replaying produces. Like
+    // borg, replacing the manifest does not add it to the shadow index, but
+    // replaying the DELETE that goes with it does.
+    let state = RepositoryState::load(&repo).unwrap();
+    let mut replayed = RepositoryState::replay(&repo, state.transaction_id.unwrap()).unwrap();
+    assert_eq!(state.index.len(), replayed.index.len());
+    assert_eq!(
+        replayed.hints.shadow_index.remove(&MANIFEST_ID),
+        Some(vec![0])
+    );
+    assert_eq!(state.hints, replayed.hints);
+}
+
+#[test]
+fn test_create_none() {
+    check_create(EncryptionMode::None);
+}
+
+#[test]
+fn test_create_authenticated() {
+    check_create(EncryptionMode::Authenticated);
+}
+
+#[test]
+fn test_create_repokey() {
+    check_create(EncryptionMode::Repokey);
+}
+
+#[test]
+fn test_create_compression() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+    let large = source_tree(&source);
+
+    init(&repository, EncryptionMode::Authenticated).unwrap();
+    create(
+        &format!("{}::first", repository.display()),
+        std::slice::from_ref(&source),
+        fixed_64k(),
+        &Compression::parse("obfuscate,110,auto,zstd,10").unwrap(),
+        FilesCacheMode::default(),
+        String::new(),
+        &Options::default(),
+    )
+    .unwrap();
+
+    let (repo, key, _, items) = read_archive(&repository, "first");
+
+    let state = RepositoryState::load(&repo).unwrap();
+    let mut restored = Vec::new();
+    for (id, _, _) in &items[3].chunks {
+        let id: [u8; 32] = id.0.clone().try_into().unwrap();
+        restored.extend(unpack_data(&key, &state.get(&repo, &id).unwrap().unwrap()).unwrap());
+    }
+    assert_eq!(restored, large);
+}
+
+#[test]
+fn test_create_deduplicates() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+    source_tree(&source);
+
+    init(&repository, EncryptionMode::None).unwrap();
+
+    let objects = || {
+        let repo = Repository::open(repository.clone(), LockMode::Shared, Duration::ZERO).unwrap();
+        RepositoryState::load(&repo).unwrap().index.len()
+    };
+
+    let location = format!("{}::first", repository.display());
+    let create_first = || {
+        create(
+            &location,
+            std::slice::from_ref(&source),
+            fixed_64k(),
+            &Compression::default(),
+            FilesCacheMode::default(),
+            String::new(),
+            &Options::default(),
+        )
+    };
+
+    create_first().unwrap();
+    let after_first = objects();
+
+    // unchanged files and items only add the new archive object
+    create(
+        &format!("{}::second", repository.display()),
+        std::slice::from_ref(&source),
+        fixed_64k(),
+        &Compression::default(),
+        FilesCacheMode::default(),
+        String::new(),
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(objects(), after_first + 1);
+
+    assert!(create_first().is_err());
+}
+
+#[test]
+fn test_create_reserves_ivs() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+    source_tree(&source);
+
+    init(&repository, EncryptionMode::Repokey).unwrap();
+    create(
+        &format!("{}::first", repository.display()),
+        &[source],
+        fixed_64k(),
+        &Compression::default(),
+        FilesCacheMode::default(),
+        String::new(),
+        &Options::default(),
+    )
+    .unwrap();
+
+    let reserved = u64::from_str_radix(
+        &std::fs::read_to_string(repository.join("nonce")).unwrap(),
+        16,
+    )
+    .unwrap();
+
+    let (_, key, _, _) = read_archive(&repository, "first");
+    assert!(reserved >= key.next_iv());
+}
+
+#[test]
+fn test_replace_placeholders() {
+    assert_eq!(
+        replace_placeholders("{hostname}-{{x}}").unwrap(),
+        format!("{}-{{x}}", hostname())
+    );
+    assert_eq!(
+        replace_placeholders("{utcnow:%Y}").unwrap(),
+        chrono::Utc::now().format("%Y").to_string()
+    );
+    assert!(replace_placeholders("{unknown}").is_err());
+    assert!(replace_placeholders("{now").is_err());
+}
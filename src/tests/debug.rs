@@ -0,0 +1,337 @@
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+use crate::{
+    compression::Compression,
+    debug::{
+        delete_obj, dump_archive, dump_manifest, dump_segment, get_obj, put_obj, EntryTag,
+        PayloadDump,
+    },
+    init::{init, EncryptionMode},
+    key::Key,
+    lock::LockMode,
+    msgpack::{self, Bytes},
+    pack_data,
+    transaction::Transaction,
+    Options, Repository,
+};
+
+use super::{backup_with_comment, init::test_environment};
+
+/// A plaintext repository with two msgpack objects in segment 2 and a
+/// delete of the first one in segment 4
+fn repository(path: &Path) -> Repository {
+    test_environment();
+
+    init(path, EncryptionMode::None).unwrap();
+    let repository =
+        Repository::open(path.to_path_buf(), LockMode::Exclusive, Duration::ZERO).unwrap();
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    for (i, compression) in [(1, Compression::None), (2, Compression::Lz4)] {
+        let value = BTreeMap::from([("id", Bytes(vec![i, 0xff]))]);
+        let plain = rmp_serde::to_vec(&value).unwrap();
+
+        transaction
+            .put(
+                &[i; 32],
+                &pack_data(&Key::plaintext(), &compression, &plain).unwrap(),
+            )
+            .unwrap();
+    }
+    transaction.commit().unwrap();
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    transaction.delete(&[1; 32]).unwrap();
+    transaction.commit().unwrap();
+
+    repository
+}
+
+fn dump(
+    path: &Path,
+    segment: u32,
+    tags: &[EntryTag],
+    key_prefix: Option<&str>,
+    payload: Option<PayloadDump>,
+) -> Vec<String> {
+    let mut out = Vec::new();
+    dump_segment(
+        path.to_path_buf(),
+        segment,
+        tags,
+        key_prefix,
+        payload,
+        &mut out,
+        &Options::default(),
+    )
+    .unwrap();
+
+    String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn test_dump_segment() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    drop(repository(&path));
+
+    let lines = dump(&path, 2, &[], None, None);
+
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("         8 crc ok PUT    01 01 01"));
+    assert!(lines[0].ends_with(" - 11 bytes"));
+    assert_eq!(lines[1].trim(), "Plaintext, none");
+    assert!(lines[2].contains(" crc ok PUT    02 02 02"));
+    assert_eq!(lines[3].trim(), "Plaintext, lz4");
+
+    assert_eq!(
+        dump(&path, 3, &[], None, None),
+        ["         8 crc ok COMMIT"]
+    );
+}
+
+#[test]
+fn test_dump_segment_filters() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    drop(repository(&path));
+
+    let lines = dump(&path, 2, &[], Some("0202"), None);
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("PUT    02 02 02"));
+
+    assert!(dump(&path, 2, &[EntryTag::Delete], None, None).is_empty());
+    assert!(dump(&path, 3, &[], Some("01"), None).is_empty());
+
+    let lines = dump(&path, 4, &[EntryTag::Delete, EntryTag::Commit], None, None);
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].contains("crc ok DELETE 01 01 01"));
+}
+
+#[test]
+fn test_dump_segment_payload() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    drop(repository(&path));
+
+    let lines = dump(&path, 2, &[], Some("02"), Some(PayloadDump::Msgpack));
+    let json: Vec<_> = lines[2..].iter().map(|line| line.trim()).collect();
+    assert_eq!(json, ["{", "\"id\": \"\\u007f02ff\"", "}"]);
+
+    let lines = dump(&path, 2, &[], Some("01"), Some(PayloadDump::Hex));
+    assert_eq!(lines.len(), 3);
+    assert_eq!(
+        lines[2].trim(),
+        "00000000  02 00 00 81 a2 69 64 c4 02 01 ff                 |.....id....|"
+    );
+}
+
+#[test]
+fn test_dump_segment_bad_crc() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    let repository = repository(&path);
+
+    let segment = repository.segment_path(2);
+    let mut data = std::fs::read(&segment).unwrap();
+    data[50] ^= 1;
+    std::fs::write(&segment, data).unwrap();
+    drop(repository);
+
+    // the dump carries on after the damaged entry
+    let lines = dump(&path, 2, &[EntryTag::Put], None, None);
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].contains(" crc MISMATCH (stored "));
+    assert!(lines[2].contains(" crc ok PUT"));
+}
+
+#[test]
+fn test_dump_segment_missing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    drop(repository(&path));
+
+    let error = dump_segment(
+        path,
+        42,
+        &[],
+        None,
+        None,
+        &mut Vec::new(),
+        &Options::default(),
+    )
+    .unwrap_err();
+
+    assert_eq!(error.to_string(), "segment 42 not found");
+}
+
+fn get(path: &Path, id: &str, unpack: bool) -> eyre::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    get_obj(
+        path.to_path_buf(),
+        id,
+        unpack,
+        &mut out,
+        &Options::default(),
+    )?;
+
+    Ok(out)
+}
+
+#[test]
+fn test_get_obj() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    drop(repository(&path));
+
+    let id = "02".repeat(32);
+    let plain = rmp_serde::to_vec(&BTreeMap::from([("id", Bytes(vec![2, 0xff]))])).unwrap();
+
+    let raw = get(&path, &id, false).unwrap();
+    assert_eq!(
+        raw,
+        pack_data(&Key::plaintext(), &Compression::Lz4, &plain).unwrap()
+    );
+    assert_eq!(get(&path, &id, true).unwrap(), plain);
+
+    // deleted in the last transaction
+    let error = get(&path, &"01".repeat(32), false).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        format!("object {} not found", "01".repeat(32))
+    );
+
+    let error = get(&path, "0202", false).unwrap_err();
+    assert_eq!(error.to_string(), "object ID \"0202\" is not 64 hex digits");
+}
+
+#[test]
+fn test_put_and_delete_obj() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    drop(repository(&path));
+
+    let id = "ab".repeat(32);
+    put_obj(path.clone(), &id, b"raw object", &Options::default()).unwrap();
+    assert_eq!(get(&path, &id, false).unwrap(), b"raw object");
+
+    put_obj(path.clone(), &id, b"replaced", &Options::default()).unwrap();
+    assert_eq!(get(&path, &id, false).unwrap(), b"replaced");
+
+    // the missing object is skipped, the other one is still deleted
+    delete_obj(
+        path.clone(),
+        &["01".repeat(32), id.clone()],
+        &Options::default(),
+    )
+    .unwrap();
+    assert!(get(&path, &id, false).is_err());
+    assert!(get(&path, &"02".repeat(32), false).is_ok());
+}
+
+/// A repokey repository with one archive of a single file
+fn archive_repository(dir: &Path) -> std::path::PathBuf {
+    test_environment();
+
+    let path = dir.join("repo");
+    let source = dir.join("source");
+    std::fs::create_dir(&source).unwrap();
+    std::fs::write(source.join("file"), "some contents").unwrap();
+
+    init(&path, EncryptionMode::Repokey).unwrap();
+    backup_with_comment(&path, "archive", &source, "a comment");
+
+    path
+}
+
+#[test]
+fn test_dump_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = archive_repository(dir.path());
+
+    let mut out = Vec::new();
+    dump_manifest(path, &mut out, &Options::default()).unwrap();
+    let manifest: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+    assert_eq!(manifest["version"], 1);
+    assert!(manifest["timestamp"].is_string());
+    assert!(manifest["tam"]["hmac"].is_string());
+
+    let entry = &manifest["archives"]["archive"];
+    assert!(is_hex_id(&entry["id"]));
+    assert!(entry["time"].is_string());
+
+    // borg's four space indentation
+    assert!(String::from_utf8(out)
+        .unwrap()
+        .contains("\n    \"archives\": {\n"));
+}
+
+#[test]
+fn test_dump_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = archive_repository(dir.path());
+
+    let mut out = Vec::new();
+    dump_archive(
+        &format!("{}::archive", path.display()),
+        &mut out,
+        &Options::default(),
+    )
+    .unwrap();
+    let archive: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+    assert_eq!(archive["_name"], "archive");
+    assert!(is_hex_id(&archive["_manifest_entry"]["id"]));
+    assert_eq!(archive["_meta"]["name"], "archive");
+    assert_eq!(archive["_meta"]["comment"], "a comment");
+    assert!(archive["_meta"]["tam"].is_object());
+
+    let items = archive["_items"].as_array().unwrap();
+    let file = items
+        .iter()
+        .find(|item| item["path"].as_str().unwrap().ends_with("source/file"))
+        .unwrap();
+    assert_eq!(file["size"], 13);
+    assert!(is_hex_id(&file["chunks"][0][0]));
+    assert_eq!(file["chunks"][0][1], 13);
+
+    let error = dump_archive(
+        &format!("{}::missing", path.display()),
+        &mut Vec::new(),
+        &Options::default(),
+    )
+    .unwrap_err();
+    assert_eq!(error.to_string(), "archive missing not found");
+}
+
+/// An ID as borg dumps it, marked as hex by a leading DEL
+fn is_hex_id(value: &serde_json::Value) -> bool {
+    value
+        .as_str()
+        .and_then(|id| id.strip_prefix('\x7f'))
+        .is_some_and(|id| id.len() == 64)
+}
+
+#[test]
+fn test_msgpack_to_json_marks_hex() {
+    let mut data = Vec::new();
+    rmp::encode::write_array_len(&mut data, 4).unwrap();
+    rmp::encode::write_str(&mut data, "cafe").unwrap();
+    rmp::encode::write_bin(&mut data, &[0xca, 0xfe]).unwrap();
+    rmp::encode::write_bin(&mut data, b"text").unwrap();
+    rmp::encode::write_str(&mut data, "\x7fcafe").unwrap();
+
+    let value = msgpack::to_json(&mut &data[..]).unwrap();
+
+    // like borg, bytes that decode are shown as text, and anything else,
+    // including text that starts with DEL itself, as DEL and hex
+    assert_eq!(
+        value,
+        serde_json::json!(["cafe", "\x7fcafe", "text", "\x7f7f63616665"])
+    );
+}
@@ -0,0 +1,141 @@
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use crate::{
+    cache::{cache_dir, Cache, FilesCacheMode},
+    delete::delete,
+    init::{init, EncryptionMode},
+    key::security_dir,
+    lock::LockMode,
+    transaction::{RepositoryState, Transaction},
+    Options, Repository,
+};
+
+use super::{backup, init::test_environment, read_manifest};
+
+fn delete_archives(repository: &Path, archives: &[&str], glob_archives: Option<&str>) {
+    let archives: Vec<_> = archives.iter().map(|name| name.to_string()).collect();
+
+    delete(
+        &repository.display().to_string(),
+        &archives,
+        glob_archives,
+        false,
+        false,
+        &Options::default(),
+    )
+    .unwrap();
+}
+
+/// The archive names in the manifest, and checks that the chunks cache
+/// still agrees with the archives and the repository index with its log
+fn check_repository(repository: &Path) -> Vec<String> {
+    let (key, manifest, manifest_id) = read_manifest(repository).unwrap();
+
+    let repo = Repository::open(
+        repository.to_path_buf(),
+        LockMode::Exclusive,
+        Duration::ZERO,
+    )
+    .unwrap();
+
+    let state = RepositoryState::load(&repo).unwrap();
+    let replayed = RepositoryState::replay(&repo, state.transaction_id.unwrap()).unwrap();
+    assert_eq!(state.index.len(), replayed.index.len());
+
+    let mut transaction = Transaction::begin(&repo).unwrap();
+
+    let mut cache = Cache::open(&repo, FilesCacheMode::disabled(), Duration::ZERO).unwrap();
+    let maintained: HashMap<_, _> = cache.chunks.iter().collect();
+
+    cache.config.manifest.clear();
+    cache
+        .sync(&key, &manifest_id, &manifest, &mut transaction)
+        .unwrap();
+    assert_eq!(cache.chunks.iter().collect::<HashMap<_, _>>(), maintained);
+
+    // everything but the manifest is referenced by an archive
+    assert_eq!(state.index.len(), maintained.len() + 1);
+
+    let mut names: Vec<_> = manifest.archives.into_keys().collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_delete_archives() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join("shared.txt"), "in every archive").unwrap();
+
+    init(&repository, EncryptionMode::Repokey).unwrap();
+    for name in ["a-1", "a-2", "b-1"] {
+        std::fs::write(source.join(format!("{name}.txt")), name).unwrap();
+        backup(&repository, name, &source);
+    }
+
+    let location = format!("{}::a-1", repository.display());
+    delete(&location, &[], None, false, false, &Options::default()).unwrap();
+    assert_eq!(check_repository(&repository), ["a-2", "b-1"]);
+
+    // a dry run changes nothing
+    delete(
+        &repository.display().to_string(),
+        &[],
+        Some("*"),
+        true,
+        false,
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(check_repository(&repository), ["a-2", "b-1"]);
+
+    delete_archives(&repository, &[], Some("a-*"));
+    assert_eq!(check_repository(&repository), ["b-1"]);
+
+    delete_archives(&repository, &["b-1"], None);
+    assert!(check_repository(&repository).is_empty());
+
+    assert!(delete(&location, &[], None, false, false, &Options::default()).is_err());
+}
+
+#[test]
+fn test_delete_repository() {
+    test_environment();
+    std::env::set_var("BORG_DELETE_I_KNOW_WHAT_I_AM_DOING", "YES");
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join("file.txt"), "contents").unwrap();
+
+    init(&repository, EncryptionMode::Repokey).unwrap();
+    backup(&repository, "archive", &source);
+
+    let id = Repository::open(repository.clone(), LockMode::Shared, Duration::ZERO)
+        .unwrap()
+        .id
+        .clone();
+    assert!(cache_dir().unwrap().join(&id).exists());
+    assert!(security_dir().unwrap().join(&id).exists());
+
+    delete(
+        &repository.display().to_string(),
+        &[],
+        None,
+        false,
+        false,
+        &Options::default(),
+    )
+    .unwrap();
+
+    assert!(!repository.exists());
+    assert!(!cache_dir().unwrap().join(&id).exists());
+    assert!(!security_dir().unwrap().join(&id).exists());
+}
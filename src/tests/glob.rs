@@ -0,0 +1,33 @@
+use crate::glob::glob_match;
+
+#[test]
+fn test_glob_match() {
+    for (pattern, name) in [
+        ("*", ""),
+        ("*", "host-2023-01-01"),
+        ("host-*", "host-2023-01-01"),
+        ("*-01", "host-2023-01-01"),
+        ("host-????-*", "host-2023-01-01"),
+        ("host-[0-9]*", "host-2023"),
+        ("host-[!a-z]*", "host-2023"),
+        ("[]]", "]"),
+        ("a[b", "a[b"),
+        ("*a*a*", "banana"),
+    ] {
+        assert!(glob_match(pattern, name), "{pattern} matches {name}");
+    }
+
+    for (pattern, name) in [
+        ("host", "host-2023"),
+        ("*-02", "host-2023-01-01"),
+        ("?", ""),
+        ("host-[a-z]*", "host-2023"),
+        ("host-[!0-9]*", "host-2023"),
+        ("a[b", "ab"),
+    ] {
+        assert!(
+            !glob_match(pattern, name),
+            "{pattern} does not match {name}"
+        );
+    }
+}
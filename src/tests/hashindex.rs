@@ -0,0 +1,162 @@
+use crate::hashindex::{
+    ChunkIndex, ChunkIndexEntry, HashIndex, NsIndex, HEADER_SIZE, MAX_REFCOUNT,
+};
+
+fn id(n: u32) -> [u8; 32] {
+    let mut id = [0; 32];
+    id[..4].copy_from_slice(&n.wrapping_mul(2_654_435_761).to_le_bytes());
+    id[4..8].copy_from_slice(&n.to_le_bytes());
+    id
+}
+
+#[test]
+fn test_hashindex_insert_get_remove() {
+    let mut index = NsIndex::new();
+
+    index.insert(&id(1), 3, 8);
+    index.insert(&id(2), 4, 100);
+    index.insert(&id(1), 5, 16);
+
+    assert_eq!(index.len(), 2);
+    assert_eq!(index.get(&id(1)), Some((5, 16)));
+    assert_eq!(index.remove(&id(2)), Some((4, 100)));
+    assert_eq!(index.get(&id(2)), None);
+    assert_eq!(index.remove(&id(2)), None);
+    assert_eq!(index.len(), 1);
+}
+
+#[test]
+fn test_hashindex_grows_and_roundtrips() {
+    let mut index = NsIndex::new();
+
+    for n in 0..5000 {
+        index.insert(&id(n), n, n * 2);
+    }
+    for n in (0..5000).step_by(3) {
+        index.remove(&id(n));
+    }
+
+    let mut data = Vec::new();
+    index.inner().write(&mut data).unwrap();
+
+    assert_eq!(&data[..8], b"BORG_IDX");
+    let num_buckets = i32::from_le_bytes(data[12..16].try_into().unwrap()) as usize;
+    assert_eq!(data.len(), HEADER_SIZE + num_buckets * 40);
+
+    let read = NsIndex::read(&mut data.as_slice()).unwrap();
+    assert_eq!(read.len(), 5000 - 1667);
+
+    for n in 0..5000 {
+        let expected = (n % 3 != 0).then_some((n, n * 2));
+        assert_eq!(read.get(&id(n)), expected);
+    }
+}
+
+#[test]
+fn test_hashindex_rejects_damaged_files() {
+    let mut data = Vec::new();
+    NsIndex::new().inner().write(&mut data).unwrap();
+    assert!(NsIndex::read(&mut data.as_slice()).is_ok());
+
+    // truncated or with trailing garbage
+    assert!(NsIndex::read(&mut &data[..data.len() - 1]).is_err());
+    let mut longer = data.clone();
+    longer.push(0);
+    assert!(NsIndex::read(&mut longer.as_slice()).is_err());
+
+    // a header claiming far more buckets than the file holds is caught before
+    // anything is allocated for them
+    let mut huge = data[..HEADER_SIZE].to_vec();
+    huge[12..16].copy_from_slice(&i32::MAX.to_le_bytes());
+    assert!(NsIndex::read(&mut huge.as_slice()).is_err());
+}
+
+#[test]
+fn test_hashindex_rejects_table_without_empty_buckets() {
+    // two buckets, both in use, so looking up a missing key would never end
+    let mut data = b"BORG_IDX".to_vec();
+    data.extend_from_slice(&2i32.to_le_bytes());
+    data.extend_from_slice(&2i32.to_le_bytes());
+    data.extend_from_slice(&[32, 8]);
+    for n in 0..2 {
+        data.extend_from_slice(&id(n));
+        data.extend_from_slice(&[0; 8]);
+    }
+
+    let err = NsIndex::read(&mut data.as_slice()).unwrap_err();
+    assert!(err.to_string().contains("no empty buckets"), "{err}");
+}
+
+#[test]
+fn test_hashindex_reuses_deleted_buckets() {
+    let mut index = HashIndex::new(32, 8);
+
+    // far more inserts and removes than there are buckets, which would fill
+    // every bucket with a deletion marker if they were never cleaned up
+    for n in 0..10_000 {
+        index.insert(&id(n), &[0; 8]);
+        index.remove(&id(n));
+    }
+
+    assert!(index.is_empty());
+    assert_eq!(index.buckets().len(), 1031 * 40);
+    assert!(!index.contains(&id(1)));
+}
+
+#[test]
+fn test_chunk_index_refcounts() {
+    let mut index = ChunkIndex::new();
+
+    index.add(&id(1), 1, 100, 50);
+    index.add(&id(1), 2, 100, 50);
+    assert_eq!(
+        index.incref(&id(1)),
+        Some(ChunkIndexEntry {
+            refcount: 4,
+            size: 100,
+            csize: 50
+        })
+    );
+    assert_eq!(index.incref(&id(2)), None);
+
+    let mut other = ChunkIndex::new();
+    other.add(&id(1), MAX_REFCOUNT, 100, 50);
+    other.add(&id(2), 1, 10, 20);
+    index.merge(&other);
+
+    // refcounts saturate rather than overflowing into the bucket markers
+    assert_eq!(index.get(&id(1)).unwrap().refcount, MAX_REFCOUNT);
+    assert_eq!(index.get(&id(2)).unwrap().refcount, 1);
+    assert_eq!(index.len(), 2);
+
+    assert_eq!(index.decref(&id(1), 10), Some(MAX_REFCOUNT));
+    assert_eq!(index.decref(&id(2), 1), Some(0));
+    assert_eq!(index.get(&id(2)), None);
+    assert_eq!(index.decref(&id(2), 1), None);
+
+    index.add(&id(3), 3, 1, 1);
+    assert_eq!(index.decref(&id(3), 2), Some(1));
+}
+
+#[test]
+fn test_chunk_index_compact_roundtrip() {
+    let mut index = ChunkIndex::new();
+    for n in 0..100 {
+        index.add(&id(n), n + 1, n * 2, n * 3);
+    }
+
+    let mut compact = Vec::new();
+    index.inner().write_compact(&mut compact).unwrap();
+    assert_eq!(compact.len(), HEADER_SIZE + 100 * (32 + 12));
+
+    let read = ChunkIndex::read_compact(&mut compact.as_slice()).unwrap();
+    assert_eq!(read.len(), 100);
+    for n in 0..100 {
+        assert_eq!(read.get(&id(n)), index.get(&id(n)));
+    }
+
+    // the regular form is not compact unless the table happens to be full
+    let mut regular = Vec::new();
+    index.inner().write(&mut regular).unwrap();
+    assert!(ChunkIndex::read_compact(&mut regular.as_slice()).is_err());
+}
@@ -0,0 +1,160 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use rmp::encode::*;
+
+use crate::{
+    hints::{Hints, HintsFile},
+    init::{init, EncryptionMode},
+    lock::LockMode,
+    transaction::{RepositoryState, Transaction},
+    Repository,
+};
+
+use super::init::test_environment;
+
+#[test]
+fn test_hints_roundtrip() {
+    let hints = Hints {
+        segments: BTreeMap::from([(0, 1), (2, 0), (3, 70_000)]),
+        compact: BTreeMap::from([(2, 41), (3, 5_000_000_000)]),
+        storage_quota_use: 1234,
+        shadow_index: BTreeMap::from([([7; 32], vec![0, 2]), ([8; 32], vec![])]),
+    };
+
+    assert_eq!(
+        HintsFile::decode(&hints.encode().unwrap()).unwrap(),
+        HintsFile::V2(hints)
+    );
+}
+
+/// Hints the way borg 1.1 writes them, without the bin type, so that bytes
+/// look like strings that are not necessarily utf-8
+#[test]
+fn test_hints_legacy_encoding() {
+    let mut data = Vec::new();
+    write_map_len(&mut data, 5).unwrap();
+
+    write_str(&mut data, "version").unwrap();
+    write_uint(&mut data, 2).unwrap();
+
+    write_str(&mut data, "segments").unwrap();
+    write_map_len(&mut data, 1).unwrap();
+    write_uint(&mut data, 4).unwrap();
+    write_uint(&mut data, 3).unwrap();
+
+    write_str(&mut data, "compact").unwrap();
+    write_map_len(&mut data, 1).unwrap();
+    write_uint(&mut data, 4).unwrap();
+    write_uint(&mut data, 100).unwrap();
+
+    write_str(&mut data, "storage_quota_use").unwrap();
+    write_uint(&mut data, 500).unwrap();
+
+    write_str(&mut data, "shadow_index").unwrap();
+    write_map_len(&mut data, 1).unwrap();
+    write_str_len(&mut data, 32).unwrap();
+    data.extend_from_slice(&[0xff; 32]);
+    write_array_len(&mut data, 1).unwrap();
+    write_uint(&mut data, 1).unwrap();
+
+    assert_eq!(
+        HintsFile::decode(&data).unwrap(),
+        HintsFile::V2(Hints {
+            segments: BTreeMap::from([(4, 3)]),
+            compact: BTreeMap::from([(4, 100)]),
+            storage_quota_use: 500,
+            shadow_index: BTreeMap::from([([0xff; 32], vec![1])]),
+        })
+    );
+}
+
+fn hints_v1(segments: &BTreeMap<u32, u32>, compact: &[u32]) -> Vec<u8> {
+    let mut data = Vec::new();
+    write_map_len(&mut data, 3).unwrap();
+
+    write_str(&mut data, "version").unwrap();
+    write_uint(&mut data, 1).unwrap();
+
+    write_str(&mut data, "segments").unwrap();
+    write_map_len(&mut data, segments.len() as u32).unwrap();
+    for (&segment, &count) in segments {
+        write_uint(&mut data, segment as u64).unwrap();
+        write_uint(&mut data, count as u64).unwrap();
+    }
+
+    write_str(&mut data, "compact").unwrap();
+    write_array_len(&mut data, compact.len() as u32).unwrap();
+    for &segment in compact {
+        write_uint(&mut data, segment as u64).unwrap();
+    }
+
+    data
+}
+
+#[test]
+fn test_hints_version_1() {
+    let segments = BTreeMap::from([(0, 2), (1, 0)]);
+
+    assert_eq!(
+        HintsFile::decode(&hints_v1(&segments, &[1])).unwrap(),
+        HintsFile::V1 {
+            segments,
+            compact: vec![1]
+        }
+    );
+}
+
+#[test]
+fn test_hints_unknown_version() {
+    let mut data = Vec::new();
+    write_map_len(&mut data, 1).unwrap();
+    write_str(&mut data, "version").unwrap();
+    write_uint(&mut data, 3).unwrap();
+
+    assert!(HintsFile::decode(&data).is_err());
+}
+
+/// Like borg, version 1 hints are upgraded by counting what the listed
+/// segments hold besides live objects
+#[test]
+fn test_hints_version_1_upgrade() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    init(&path, EncryptionMode::None).unwrap();
+
+    let repository = Repository::open(path.clone(), LockMode::Exclusive, Duration::ZERO).unwrap();
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    transaction.put(&[1; 32], &[1; 100]).unwrap();
+    transaction.put(&[2; 32], &[2; 50]).unwrap();
+    transaction.commit().unwrap();
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    transaction.delete(&[1; 32]).unwrap();
+    let transaction_id = transaction.commit().unwrap();
+
+    let written = RepositoryState::load(&repository).unwrap();
+    assert_eq!(written.hints.compact[&2], 41 + 100);
+    assert_eq!(written.hints.compact[&4], 41);
+
+    std::fs::write(
+        path.join(format!("hints.{transaction_id}")),
+        hints_v1(&written.hints.segments, &[2, 4]),
+    )
+    .unwrap();
+    std::fs::remove_file(path.join(format!("integrity.{transaction_id}"))).unwrap();
+
+    let upgraded = RepositoryState::load(&repository).unwrap();
+    assert_eq!(upgraded.transaction_id, Some(transaction_id));
+    assert_eq!(upgraded.hints.segments, written.hints.segments);
+
+    // a segment without live objects can be freed completely
+    assert_eq!(
+        upgraded.hints.compact,
+        BTreeMap::from([(2, 41 + 100), (4, 8 + 41)])
+    );
+    assert_eq!(upgraded.hints.storage_quota_use, 0);
+    assert!(upgraded.hints.shadow_index.is_empty());
+}
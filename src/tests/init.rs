@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use crate::{
+    init::{init, EncryptionMode},
+    key::{Key, KeyType},
+    lock::LockMode,
+    transaction::RepositoryState,
+    unpack_data, Manifest, Repository, MANIFEST_ID,
+};
+
+/// Points borg's per-user directories and passphrase at test locations, the
+/// same for every test since the environment is shared
+pub fn test_environment() {
+    let base = std::env::temp_dir().join("bork-tests");
+
+    std::env::set_var("BORG_SECURITY_DIR", base.join("security"));
+    std::env::set_var("BORG_KEYS_DIR", base.join("keys"));
+    std::env::set_var("BORG_CACHE_DIR", base.join("cache"));
+    std::env::set_var("BORG_PASSPHRASE", "correct horse battery staple");
+    std::env::set_var("BORG_NEW_PASSPHRASE", "correct horse battery staple");
+}
+
+fn open(path: &std::path::Path) -> Repository {
+    Repository::open(path.to_path_buf(), LockMode::Shared, Duration::ZERO).unwrap()
+}
+
+fn check_new_repository(mode: EncryptionMode, key_type: KeyType) {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+
+    init(&path, mode).unwrap();
+
+    for file in [
+        "README", "config", "data/0/0", "data/0/1", "index.1", "hints.1",
+    ] {
+        assert!(path.join(file).exists(), "{file} is missing");
+    }
+
+    let repository = open(&path);
+
+    let manifest_data = RepositoryState::load(&repository)
+        .unwrap()
+        .get(&repository, &MANIFEST_ID)
+        .unwrap()
+        .unwrap();
+    assert_eq!(manifest_data[0], key_type.byte());
+
+    let key = Key::load(&repository, &manifest_data).unwrap();
+    let manifest =
+        Manifest::decode(&key, &unpack_data(&key, &manifest_data).unwrap(), false).unwrap();
+    assert!(manifest.archives.is_empty());
+    assert!(manifest.tam.is_some());
+
+    // the written index and hints must be exactly what replaying the
+    // segments produces
+    let state = RepositoryState::load(&repository).unwrap();
+    let replayed = RepositoryState::replay(&repository, 1).unwrap();
+
+    assert_eq!(state.transaction_id, Some(1));
+    assert_eq!(state.index.get(&MANIFEST_ID), Some((0, 8)));
+    assert_eq!(state.index.len(), replayed.index.len());
+    assert_eq!(state.hints, replayed.hints);
+}
+
+#[test]
+fn test_init_none() {
+    check_new_repository(EncryptionMode::None, KeyType::Plaintext);
+}
+
+#[test]
+fn test_init_authenticated() {
+    check_new_repository(EncryptionMode::Authenticated, KeyType::Authenticated);
+}
+
+#[test]
+fn test_init_repokey() {
+    check_new_repository(EncryptionMode::Repokey, KeyType::Repokey);
+}
+
+#[test]
+fn test_init_refuses_existing_repository() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+
+    init(&path, EncryptionMode::None).unwrap();
+    assert!(init(&path, EncryptionMode::None).is_err());
+
+    std::fs::write(dir.path().join("file"), b"").unwrap();
+    assert!(init(dir.path(), EncryptionMode::None).is_err());
+}
@@ -0,0 +1,150 @@
+use std::{os::unix::fs::MetadataExt, time::Duration};
+
+use qrcodegen::{QrCode, QrCodeEcc};
+
+use crate::{
+    init::{init, EncryptionMode},
+    key::{encode_blob, EncryptedKey, KeyAlgorithm},
+    keymanager::{
+        change_passphrase, html_key, keyfile_data, load_keyblob, paper_key, parse_paper_key,
+    },
+    lock::LockMode,
+    Repository,
+};
+
+use super::init::test_environment;
+
+const REPOSITORY_ID: &str = "5ea6ab6a24da4a1b9d06c1a0b8c6c8a4f0e5b3b2a1d0c9e8f7a6b5c4d3e2f1a0";
+
+fn blob() -> String {
+    encode_blob(&(0..=200).collect::<Vec<u8>>())
+}
+
+#[test]
+fn test_paper_key_roundtrip() {
+    let paper = paper_key(REPOSITORY_ID, &blob()).unwrap();
+
+    assert_eq!(parse_paper_key(REPOSITORY_ID, &paper).unwrap(), blob());
+}
+
+#[test]
+fn test_paper_key_typo() {
+    let paper = paper_key(REPOSITORY_ID, &blob()).unwrap();
+    let typo = paper.replacen(" 3: 24", " 3: 25", 1);
+
+    assert_ne!(paper, typo);
+
+    let err = parse_paper_key(REPOSITORY_ID, &typo).unwrap_err();
+    assert!(err.to_string().contains("line 3"), "{err}");
+}
+
+#[test]
+fn test_paper_key_other_repository() {
+    let paper = paper_key(REPOSITORY_ID, &blob()).unwrap();
+
+    assert!(parse_paper_key(&REPOSITORY_ID.replace('5', "6"), &paper).is_err());
+}
+
+#[test]
+fn test_html_key_qr_code() {
+    // about the size of a real argon2 key blob
+    let blob = encode_blob(&(0..1000).map(|i| i as u8).collect::<Vec<u8>>());
+    let html = html_key(REPOSITORY_ID, &blob).unwrap();
+
+    // the dark modules drawn in the SVG, shifted by the quiet zone
+    let path = html.split(r#"<path d=""#).nth(1).unwrap();
+    let path = &path[..path.find('"').unwrap()];
+    let drawn: Vec<(i32, i32)> = path
+        .split('M')
+        .skip(1)
+        .map(|module| {
+            let (x, y) = module.trim_end_matches("h1v1h-1z").split_once(',').unwrap();
+            (x.parse::<i32>().unwrap() - 4, y.parse::<i32>().unwrap() - 4)
+        })
+        .collect();
+
+    // are those of the keyfile's QR code
+    let qr = QrCode::encode_text(&keyfile_data(REPOSITORY_ID, &blob), QrCodeEcc::Low).unwrap();
+    let expected: Vec<_> = (0..qr.size())
+        .flat_map(|y| (0..qr.size()).map(move |x| (x, y)))
+        .filter(|&(x, y)| qr.get_module(x, y))
+        .collect();
+    assert_eq!(drawn, expected);
+}
+
+#[test]
+fn test_reencrypt_key_pbkdf2() {
+    let encrypted = EncryptedKey::encrypt(b"key material", "old", KeyAlgorithm::Pbkdf2).unwrap();
+    let decoded = EncryptedKey::decode(&encrypted.encode().unwrap()).unwrap();
+
+    assert_eq!(decoded.decrypt("old").unwrap(), b"key material");
+    assert!(decoded.decrypt("new").is_err());
+}
+
+#[test]
+fn test_reencrypt_key_argon2() {
+    let encrypted = EncryptedKey::encrypt(b"key material", "old", KeyAlgorithm::Argon2).unwrap();
+    let decoded = EncryptedKey::decode(&encrypted.encode().unwrap()).unwrap();
+
+    assert_eq!(decoded.algorithm().unwrap(), KeyAlgorithm::Argon2);
+    assert_eq!(decoded.decrypt("old").unwrap(), b"key material");
+    assert!(decoded.decrypt("new").is_err());
+}
+
+#[test]
+fn test_change_passphrase_repokey() {
+    test_environment();
+    let old = std::env::var("BORG_PASSPHRASE").unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    init(&path, EncryptionMode::Repokey).unwrap();
+
+    let open = || Repository::open(path.clone(), LockMode::Exclusive, Duration::ZERO).unwrap();
+    let blob = |repository: &Repository| {
+        EncryptedKey::decode(&load_keyblob(repository).unwrap().1).unwrap()
+    };
+
+    let material = blob(&open()).decrypt(&old).unwrap();
+    let inode = std::fs::metadata(path.join("config")).unwrap().ino();
+
+    assert!(change_passphrase(&mut open(), "wrong", "new passphrase").is_err());
+    change_passphrase(&mut open(), &old, "new passphrase").unwrap();
+
+    // the reopened repository holds the same key under the new passphrase
+    let reencrypted = blob(&open());
+    assert_eq!(reencrypted.decrypt("new passphrase").unwrap(), material);
+    assert!(reencrypted.decrypt(&old).is_err());
+
+    // the config was replaced by a new file rather than rewritten in place
+    assert_ne!(std::fs::metadata(path.join("config")).unwrap().ino(), inode);
+    assert!(!path.join("config.tmp").exists());
+}
+
+#[test]
+fn test_load_keyblob_ignores_uncommitted_segment() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("repo");
+    init(&path, EncryptionMode::Repokey).unwrap();
+
+    let open = || Repository::open(path.clone(), LockMode::Exclusive, Duration::ZERO).unwrap();
+    let blob = load_keyblob(&open()).unwrap();
+
+    // a segment cut short by a crash, after the last commit
+    let segments = path.join("data/0");
+    let last = std::fs::read_dir(&segments)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter_map(|name| name.parse::<u32>().ok())
+        .max()
+        .unwrap();
+    std::fs::write(
+        segments.join((last + 1).to_string()),
+        b"BORG_SEG\xff\xff\xff\xff\x10",
+    )
+    .unwrap();
+
+    assert_eq!(load_keyblob(&open()).unwrap(), blob);
+}
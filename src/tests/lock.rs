@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use crate::lock::{break_lock, Lock, LockMode, ProcessId};
+
+/// Pretend to be another thread of this process, so that locks taken by the
+/// same test do not count as already held
+fn thread(thread: u32) -> ProcessId {
+    ProcessId {
+        thread,
+        ..ProcessId::current()
+    }
+}
+
+#[test]
+fn test_shared_locks_coexist() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lock");
+
+    let first = Lock::acquire_as(&path, LockMode::Shared, Duration::ZERO, thread(1)).unwrap();
+    let second = Lock::acquire_as(&path, LockMode::Shared, Duration::ZERO, thread(2)).unwrap();
+
+    assert!(Lock::acquire_as(&path, LockMode::Exclusive, Duration::ZERO, thread(3)).is_err());
+
+    drop(first);
+    drop(second);
+
+    assert!(!dir.path().join("lock.roster").exists());
+    assert!(!dir.path().join("lock.exclusive").exists());
+
+    Lock::acquire_as(&path, LockMode::Exclusive, Duration::ZERO, thread(3)).unwrap();
+}
+
+#[test]
+fn test_exclusive_lock_excludes_readers() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lock");
+
+    let exclusive =
+        Lock::acquire_as(&path, LockMode::Exclusive, Duration::ZERO, thread(1)).unwrap();
+
+    assert!(Lock::acquire_as(&path, LockMode::Shared, Duration::ZERO, thread(2)).is_err());
+
+    drop(exclusive);
+
+    Lock::acquire_as(&path, LockMode::Shared, Duration::ZERO, thread(2)).unwrap();
+}
+
+#[test]
+fn test_stale_lock_is_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lock");
+
+    // a process id that cannot exist, as if its owner had died
+    let dead = ProcessId {
+        pid: i32::MAX as u32,
+        ..ProcessId::current()
+    };
+
+    std::mem::forget(Lock::acquire_as(&path, LockMode::Exclusive, Duration::ZERO, dead).unwrap());
+
+    Lock::acquire_as(&path, LockMode::Exclusive, Duration::ZERO, thread(1)).unwrap();
+}
+
+#[test]
+fn test_break_lock() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lock");
+
+    std::mem::forget(
+        Lock::acquire_as(&path, LockMode::Exclusive, Duration::ZERO, thread(1)).unwrap(),
+    );
+
+    break_lock(&path).unwrap();
+
+    Lock::acquire_as(&path, LockMode::Exclusive, Duration::ZERO, thread(2)).unwrap();
+}
+
+#[test]
+fn test_host_id_is_stable() {
+    let host = ProcessId::current().host;
+
+    // borg's `fqdn@node`, with a node of 48 bits, unless overridden
+    if std::env::var_os("BORG_HOST_ID").is_none() {
+        let (name, node) = host.rsplit_once('@').unwrap();
+        assert!(!name.is_empty());
+        assert!(node.parse::<u64>().unwrap() < 1 << 48);
+    }
+
+    // a random node is picked once, or no lock would be recognized as ours
+    assert_eq!(ProcessId::current().host, host);
+}
+
+#[test]
+fn test_exclusive_lock_names_thread_in_hex() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("lock");
+
+    let owner = thread(0xbeef);
+    let _lock =
+        Lock::acquire_as(&path, LockMode::Exclusive, Duration::ZERO, owner.clone()).unwrap();
+
+    let names: Vec<_> = std::fs::read_dir(dir.path().join("lock.exclusive"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names, [format!("{}.{}-beef", owner.host, owner.pid)]);
+}
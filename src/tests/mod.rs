@@ -1,6 +1,41 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use crate::extract;
+use crate::{
+    cache::FilesCacheMode,
+    chunker::{ChunkerParams, DEFAULT_CHUNKER_PARAMS},
+    compression::Compression,
+    create::create,
+    extract,
+    init::{init, EncryptionMode},
+    key::Key,
+    lock::LockMode,
+    transaction::RepositoryState,
+    unpack_data, Manifest, Options, Repository, MANIFEST_ID,
+};
+
+mod cache;
+mod check;
+mod chunker;
+mod compact;
+mod compression;
+mod config;
+mod create;
+mod debug;
+mod delete;
+mod glob;
+mod hashindex;
+mod hints;
+mod init;
+mod keymanager;
+mod lock;
+mod prune;
+mod recover;
+mod salvage;
+mod segment_writer;
+mod tam;
 
 #[test]
 fn test_roundtrip_small_file() {
@@ -34,9 +69,134 @@ fn test_roundtrip_small_file() {
         .wait()
         .unwrap();
 
-    extract(PathBuf::from("./example/backup")).unwrap();
+    extract(PathBuf::from("./example/backup"), &Options::default()).unwrap();
 
     let data = std::fs::read_to_string("example/extracted/example__original__file.txt").unwrap();
 
     assert_eq!(data, CONTENTS);
 }
+
+/// Run a borg command against a repository created by bork and check that it
+/// succeeds
+fn borg_accepts(command: &str, repository: &Path) {
+    let status = std::process::Command::new("borg")
+        .arg(command)
+        .arg(repository)
+        .env("BORG_UNKNOWN_UNENCRYPTED_REPO_ACCESS_IS_OK", "no")
+        .env("BORG_RELOCATED_REPO_ACCESS_IS_OK", "no")
+        .spawn()
+        .unwrap()
+        .wait()
+        .unwrap();
+
+    assert!(
+        status.success(),
+        "borg {command} failed on {}",
+        repository.display()
+    );
+}
+
+/// Back up `source` as the archive `name`, in small fixed size chunks
+fn backup(repository: &Path, name: &str, source: &Path) {
+    backup_with_files_cache(repository, name, source, FilesCacheMode::default());
+}
+
+fn backup_with_files_cache(repository: &Path, name: &str, source: &Path, mode: FilesCacheMode) {
+    backup_archive(repository, name, source, mode, "");
+}
+
+/// Like `backup`, but with an archive comment
+fn backup_with_comment(repository: &Path, name: &str, source: &Path, comment: &str) {
+    backup_archive(repository, name, source, FilesCacheMode::default(), comment);
+}
+
+fn backup_archive(
+    repository: &Path,
+    name: &str,
+    source: &Path,
+    mode: FilesCacheMode,
+    comment: &str,
+) {
+    create(
+        &format!("{}::{name}", repository.display()),
+        &[source.to_path_buf()],
+        ChunkerParams::parse("fixed,4096").unwrap(),
+        &Compression::default(),
+        mode,
+        comment.to_string(),
+        &Options::default(),
+    )
+    .unwrap();
+}
+
+/// The key and the manifest of a repository with the manifest's ID, or
+/// `None` if the manifest is gone
+fn read_manifest(repository: &Path) -> Option<(Key, Manifest, [u8; 32])> {
+    let repository =
+        Repository::open(repository.to_path_buf(), LockMode::Shared, Duration::ZERO).unwrap();
+
+    let manifest_data = RepositoryState::load(&repository)
+        .unwrap()
+        .get(&repository, &MANIFEST_ID)
+        .unwrap()?;
+    let key = Key::load(&repository, &manifest_data).unwrap();
+    let plain = unpack_data(&key, &manifest_data).unwrap();
+    let id = key.id_hash(&plain).unwrap();
+    let manifest = Manifest::decode(&key, &plain, false).unwrap();
+
+    Some((key, manifest, id))
+}
+
+fn check_borg_accepts_init(mode: EncryptionMode) {
+    init::test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+
+    init(&repository, mode).unwrap();
+
+    borg_accepts("list", &repository);
+    borg_accepts("check", &repository);
+}
+
+#[test]
+fn test_borg_accepts_init_none() {
+    check_borg_accepts_init(EncryptionMode::None);
+}
+
+#[test]
+fn test_borg_accepts_init_authenticated() {
+    check_borg_accepts_init(EncryptionMode::Authenticated);
+}
+
+#[test]
+fn test_borg_accepts_init_repokey() {
+    check_borg_accepts_init(EncryptionMode::Repokey);
+}
+
+#[test]
+fn test_borg_accepts_create() {
+    init::test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::write(source.join("sub/file.txt"), "hello from file.txt").unwrap();
+
+    init(&repository, EncryptionMode::Repokey).unwrap();
+    create(
+        &format!("{}::archive", repository.display()),
+        &[source],
+        DEFAULT_CHUNKER_PARAMS,
+        &Compression::parse("auto,zstd,10").unwrap(),
+        FilesCacheMode::default(),
+        String::new(),
+        &Options::default(),
+    )
+    .unwrap();
+
+    borg_accepts("list", &repository);
+    borg_accepts("check", &repository);
+}
@@ -0,0 +1,364 @@
+use std::{path::Path, time::Duration};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::{
+    init::{init, EncryptionMode},
+    prune::{keep_archives, parse_interval, prune, strip_checkpoint, KeepRules},
+    Options,
+};
+
+use super::{backup, init::test_environment, read_manifest};
+
+fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(year, month, day, hour, minute, second)
+        .unwrap()
+}
+
+/// The archives of borg's own prune tests, named by their number there
+fn borg_test_archives() -> Vec<(String, DateTime<Utc>)> {
+    let mut archives = vec![
+        // years apart
+        ("1", utc(2015, 1, 1, 10, 0, 0)),
+        ("2", utc(2016, 1, 1, 10, 0, 0)),
+        ("3", utc(2017, 1, 1, 10, 0, 0)),
+        // months apart
+        ("4", utc(2017, 2, 1, 10, 0, 0)),
+        ("5", utc(2017, 3, 1, 10, 0, 0)),
+        // days apart
+        ("6", utc(2017, 3, 2, 10, 0, 0)),
+        ("7", utc(2017, 3, 3, 10, 0, 0)),
+        ("8", utc(2017, 3, 4, 10, 0, 0)),
+        // minutes apart
+        ("9", utc(2017, 10, 1, 9, 45, 0)),
+        ("10", utc(2017, 10, 1, 9, 55, 0)),
+        // seconds apart
+        ("11", utc(2017, 10, 1, 10, 0, 1)),
+        ("12", utc(2017, 10, 1, 10, 0, 3)),
+        ("13", utc(2017, 10, 1, 10, 0, 5)),
+    ];
+    archives.reverse();
+
+    archives
+        .into_iter()
+        .map(|(name, time)| (name.to_string(), time))
+        .collect()
+}
+
+fn kept(archives: &[(String, DateTime<Utc>)], rules: KeepRules, now: DateTime<Utc>) -> Vec<String> {
+    let archives: Vec<_> = archives
+        .iter()
+        .map(|(name, time)| (name.as_str(), *time))
+        .collect();
+
+    let kept = keep_archives(&archives, &rules, now);
+
+    // newest first, like the archives
+    archives
+        .iter()
+        .filter(|(name, _)| kept.contains_key(*name))
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+#[test]
+fn test_prune_split() {
+    let archives = borg_test_archives();
+    let now = Utc::now();
+
+    for (rules, expected) in [
+        (
+            KeepRules {
+                yearly: Some(3),
+                ..Default::default()
+            },
+            &["13", "2", "1"][..],
+        ),
+        (
+            KeepRules {
+                monthly: Some(3),
+                ..Default::default()
+            },
+            &["13", "8", "4"],
+        ),
+        (
+            KeepRules {
+                weekly: Some(2),
+                ..Default::default()
+            },
+            &["13", "8"],
+        ),
+        (
+            KeepRules {
+                daily: Some(3),
+                ..Default::default()
+            },
+            &["13", "8", "7"],
+        ),
+        (
+            KeepRules {
+                hourly: Some(3),
+                ..Default::default()
+            },
+            &["13", "10", "8"],
+        ),
+        (
+            KeepRules {
+                minutely: Some(3),
+                ..Default::default()
+            },
+            &["13", "10", "9"],
+        ),
+        (
+            KeepRules {
+                secondly: Some(4),
+                ..Default::default()
+            },
+            &["13", "12", "11", "10"],
+        ),
+        (
+            KeepRules {
+                daily: Some(0),
+                ..Default::default()
+            },
+            &[],
+        ),
+    ] {
+        assert_eq!(kept(&archives, rules, now), expected, "{rules:?}");
+    }
+
+    // later rules skip what earlier rules keep, and -1 keeps every period
+    let rules = KeepRules {
+        daily: Some(2),
+        monthly: Some(-1),
+        ..Default::default()
+    };
+    assert_eq!(kept(&archives, rules, now), ["13", "8", "4", "3", "2", "1"]);
+}
+
+#[test]
+fn test_prune_split_keep_oldest() {
+    let archives: Vec<_> = [
+        // oldest backup, but not last in its year
+        ("1", utc(2018, 1, 1, 10, 0, 0)),
+        // an interim backup
+        ("2", utc(2018, 12, 30, 10, 0, 0)),
+        // year end backups
+        ("3", utc(2018, 12, 31, 10, 0, 0)),
+        ("4", utc(2019, 12, 31, 10, 0, 0)),
+    ]
+    .into_iter()
+    .rev()
+    .map(|(name, time)| (name.to_string(), time))
+    .collect();
+
+    let yearly = |count| KeepRules {
+        yearly: Some(count),
+        ..Default::default()
+    };
+
+    // the oldest is kept when the rule cannot keep as many archives otherwise
+    let oldest = keep_archives(
+        &archives
+            .iter()
+            .map(|(name, time)| (name.as_str(), *time))
+            .collect::<Vec<_>>(),
+        &yearly(3),
+        Utc::now(),
+    );
+    assert_eq!(oldest["1"].rule, "yearly[oldest]");
+    assert_eq!(oldest["1"].number, 3);
+    assert_eq!(kept(&archives, yearly(3), Utc::now()), ["4", "3", "1"]);
+    assert_eq!(kept(&archives, yearly(2), Utc::now()), ["4", "3"]);
+}
+
+#[test]
+fn test_prune_within() {
+    let now = Utc::now();
+
+    // 1 minute, 1.5 hours, 2.5 hours, 3.5 hours, 25 hours, 49 hours
+    let archives: Vec<_> = [60, 90 * 60, 150 * 60, 210 * 60, 25 * 60 * 60, 49 * 60 * 60]
+        .into_iter()
+        .enumerate()
+        .map(|(i, seconds)| (i.to_string(), now - chrono::Duration::seconds(seconds)))
+        .collect();
+
+    for (within, expected) in [
+        ("1H", &["0"][..]),
+        ("2H", &["0", "1"]),
+        ("3H", &["0", "1", "2"]),
+        ("24H", &["0", "1", "2", "3"]),
+        ("26H", &["0", "1", "2", "3", "4"]),
+        ("2d", &["0", "1", "2", "3", "4"]),
+        ("50H", &["0", "1", "2", "3", "4", "5"]),
+        ("3d", &["0", "1", "2", "3", "4", "5"]),
+        ("1w", &["0", "1", "2", "3", "4", "5"]),
+        ("1m", &["0", "1", "2", "3", "4", "5"]),
+        ("1y", &["0", "1", "2", "3", "4", "5"]),
+        ("1000000y", &["0", "1", "2", "3", "4", "5"]),
+        ("18446744073709551615H", &["0", "1", "2", "3", "4", "5"]),
+    ] {
+        let rules = KeepRules {
+            within: Some(parse_interval(within).unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(kept(&archives, rules, now), expected, "{within}");
+    }
+}
+
+#[test]
+fn test_parse_interval() {
+    assert_eq!(parse_interval("1H").unwrap(), 1);
+    assert_eq!(parse_interval("2d").unwrap(), 48);
+    assert_eq!(parse_interval("1w").unwrap(), 168);
+    assert_eq!(parse_interval("1m").unwrap(), 744);
+    assert_eq!(parse_interval("1y").unwrap(), 8760);
+
+    for invalid in [
+        "",
+        "H",
+        "0d",
+        "-1d",
+        "1",
+        "1x",
+        "1.5d",
+        "18446744073709551615y",
+    ] {
+        assert!(parse_interval(invalid).is_err(), "{invalid} is invalid");
+    }
+}
+
+#[test]
+fn test_strip_checkpoint() {
+    assert_eq!(strip_checkpoint("a.checkpoint"), Some("a"));
+    assert_eq!(strip_checkpoint("a.checkpoint.12"), Some("a"));
+    assert_eq!(strip_checkpoint("a"), None);
+    assert_eq!(strip_checkpoint("a.checkpoint."), None);
+    assert_eq!(strip_checkpoint("a.checkpoint.x"), None);
+    assert_eq!(strip_checkpoint("a.checkpointed"), None);
+}
+
+fn archive_names(repository: &Path) -> Vec<String> {
+    let (_, manifest, _) = read_manifest(repository).unwrap();
+
+    let mut names: Vec<_> = manifest.archives.into_keys().collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_prune() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join("file.txt"), "contents").unwrap();
+
+    init(&repository, EncryptionMode::Repokey).unwrap();
+    for name in ["a-1", "a-2", "a-3", "b-1"] {
+        backup(&repository, name, &source);
+
+        // archives within the same second fall into the same period
+        std::thread::sleep(Duration::from_millis(1100));
+    }
+
+    let keep_last = |count| KeepRules {
+        secondly: Some(count),
+        ..Default::default()
+    };
+
+    assert!(prune(
+        repository.clone(),
+        &KeepRules::default(),
+        None,
+        false,
+        false,
+        &Options::default()
+    )
+    .is_err());
+
+    prune(
+        repository.clone(),
+        &keep_last(1),
+        Some("a-*"),
+        true,
+        true,
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(archive_names(&repository), ["a-1", "a-2", "a-3", "b-1"]);
+
+    prune(
+        repository.clone(),
+        &keep_last(2),
+        Some("a-*"),
+        false,
+        true,
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(archive_names(&repository), ["a-2", "a-3", "b-1"]);
+
+    prune(
+        repository.clone(),
+        &keep_last(1),
+        None,
+        false,
+        false,
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(archive_names(&repository), ["b-1"]);
+}
+
+#[test]
+fn test_prune_glob_matches_whole_name() {
+    test_environment();
+
+    let dir = tempfile::tempdir().unwrap();
+    let repository = dir.path().join("repo");
+    let source = dir.path().join("source");
+
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join("file.txt"), "contents").unwrap();
+
+    init(&repository, EncryptionMode::Repokey).unwrap();
+    for name in ["foo.checkpoint", "foo"] {
+        backup(&repository, name, &source);
+        std::thread::sleep(Duration::from_millis(1100));
+    }
+
+    // as in borg, `foo` does not select the checkpoint, which survives
+    // although a complete archive is newer
+    prune(
+        repository.clone(),
+        &KeepRules {
+            secondly: Some(1),
+            ..Default::default()
+        },
+        Some("foo"),
+        false,
+        false,
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(archive_names(&repository), ["foo", "foo.checkpoint"]);
+
+    // unlike a pattern that covers it
+    prune(
+        repository.clone(),
+        &KeepRules {
+            secondly: Some(1),
+            ..Default::default()
+        },
+        Some("foo*"),
+        false,
+        false,
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(archive_names(&repository), ["foo"]);
+}
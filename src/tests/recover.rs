@@ -0,0 +1,88 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    init::{init, EncryptionMode},
+    lock::LockMode,
+    recover::recover_manifest,
+    transaction::Transaction,
+    Manifest, Options, Repository, MANIFEST_ID,
+};
+
+use super::{backup, init::test_environment, read_manifest};
+
+/// A repository with two archives, returned with the manifest listing them
+fn repository(dir: &Path, mode: EncryptionMode) -> (PathBuf, Manifest) {
+    test_environment();
+
+    let path = dir.join("repo");
+    let source = dir.join("source");
+    std::fs::create_dir(&source).unwrap();
+    std::fs::write(source.join("file"), "some contents").unwrap();
+
+    init(&path, mode).unwrap();
+    backup(&path, "first", &source);
+    std::fs::write(source.join("other"), "more contents").unwrap();
+    backup(&path, "second", &source);
+
+    let (_, manifest, _) = read_manifest(&path).unwrap();
+
+    (path, manifest)
+}
+
+fn delete_manifest(path: &Path) {
+    let repository =
+        Repository::open(path.to_path_buf(), LockMode::Exclusive, Duration::ZERO).unwrap();
+
+    let mut transaction = Transaction::begin(&repository).unwrap();
+    transaction.delete(&MANIFEST_ID).unwrap();
+    transaction.commit().unwrap();
+}
+
+fn check_recover(mode: EncryptionMode) {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, manifest) = repository(dir.path(), mode);
+
+    delete_manifest(&path);
+
+    std::env::set_var("BORK_RECOVER_MANIFEST_I_KNOW_WHAT_I_AM_DOING", "YES");
+    recover_manifest(path.clone(), false, &Options::default()).unwrap();
+
+    let (_, recovered, _) = read_manifest(&path).unwrap();
+    assert_eq!(recovered.archives.len(), 2);
+    for (name, archive) in &manifest.archives {
+        assert_eq!(recovered.archives[name].id, archive.id);
+        assert_eq!(recovered.archives[name].time, archive.time);
+    }
+}
+
+#[test]
+fn test_recover_manifest_none() {
+    check_recover(EncryptionMode::None);
+}
+
+#[test]
+fn test_recover_manifest_repokey() {
+    check_recover(EncryptionMode::Repokey);
+}
+
+#[test]
+fn test_recover_manifest_dry_run() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = repository(dir.path(), EncryptionMode::None);
+
+    delete_manifest(&path);
+    recover_manifest(path.clone(), true, &Options::default()).unwrap();
+
+    assert!(read_manifest(&path).is_none());
+}
+
+#[test]
+fn test_recover_intact_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = repository(dir.path(), EncryptionMode::None);
+
+    assert!(recover_manifest(path, true, &Options::default()).is_err());
+}
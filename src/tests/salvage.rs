@@ -0,0 +1,135 @@
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    delete::delete,
+    init::{init, EncryptionMode},
+    salvage::{extract, list},
+    Options,
+};
+
+use super::{backup, init::test_environment};
+
+/// A repository with the archives "kept" and "deleted", the second deleted
+/// again, returned with the directory that was backed up
+fn repository(dir: &Path, mode: EncryptionMode) -> (PathBuf, PathBuf) {
+    test_environment();
+
+    let path = dir.join("repo");
+    let source = dir.join("source");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::write(source.join("file"), "kept contents").unwrap();
+
+    init(&path, mode).unwrap();
+    backup(&path, "kept", &source);
+
+    std::fs::write(source.join("sub/deleted"), vec![7; 10_000]).unwrap();
+    std::os::unix::fs::symlink("../file", source.join("sub/link")).unwrap();
+    set_mode(&source.join("sub"), 0o555);
+    backup(&path, "deleted", &source);
+    set_mode(&source.join("sub"), 0o755);
+
+    delete(
+        &path.display().to_string(),
+        &["deleted".to_string()],
+        None,
+        false,
+        false,
+        &Options::default(),
+    )
+    .unwrap();
+
+    (path, source)
+}
+
+fn set_mode(path: &Path, mode: u32) {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+}
+
+/// Where `path` ends up below `destination`, which is without its leading
+/// `/` like every path in an archive
+fn extracted(destination: &Path, path: &Path) -> PathBuf {
+    destination.join(path.strip_prefix("/").unwrap())
+}
+
+fn check_extract(mode: EncryptionMode) {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, source) = repository(dir.path(), mode);
+    let destination = dir.path().join("salvaged");
+
+    list(path.clone(), &Options::default()).unwrap();
+    extract(path, "deleted", &destination, &Options::default()).unwrap();
+
+    let restored = extracted(&destination, &source);
+    assert_eq!(
+        std::fs::read(restored.join("file")).unwrap(),
+        b"kept contents"
+    );
+    assert_eq!(
+        std::fs::read(restored.join("sub/deleted")).unwrap(),
+        vec![7; 10_000]
+    );
+    assert_eq!(
+        std::fs::read_link(restored.join("sub/link")).unwrap(),
+        Path::new("../file")
+    );
+
+    // applied only after the directory's contents were written
+    let mode = std::fs::metadata(restored.join("sub"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o7777, 0o555);
+    set_mode(&restored.join("sub"), 0o755);
+}
+
+#[test]
+fn test_salvage_extract_none() {
+    check_extract(EncryptionMode::None);
+}
+
+#[test]
+fn test_salvage_extract_repokey() {
+    check_extract(EncryptionMode::Repokey);
+}
+
+#[test]
+fn test_salvage_kept_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, _) = repository(dir.path(), EncryptionMode::None);
+
+    // the manifest still lists it, so there is nothing to salvage
+    let error = extract(
+        path,
+        "kept",
+        &dir.path().join("salvaged"),
+        &Options::default(),
+    )
+    .unwrap_err();
+
+    assert_eq!(error.to_string(), "no deleted archive kept found");
+}
+
+#[test]
+fn test_salvage_refuses_symlinked_parent() {
+    let dir = tempfile::tempdir().unwrap();
+    let (path, source) = repository(dir.path(), EncryptionMode::None);
+    let destination = dir.path().join("salvaged");
+    let outside = dir.path().join("outside");
+    std::fs::create_dir(&outside).unwrap();
+
+    // as if an archive had a symlink where the next one has a directory
+    let restored = extracted(&destination, &source);
+    std::fs::create_dir_all(&restored).unwrap();
+    std::os::unix::fs::symlink(&outside, restored.join("sub")).unwrap();
+
+    extract(path, "deleted", &destination, &Options::default()).unwrap();
+
+    assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+    assert_eq!(
+        std::fs::read(restored.join("file")).unwrap(),
+        b"kept contents"
+    );
+}
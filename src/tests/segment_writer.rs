@@ -0,0 +1,57 @@
+use crate::{config::RepositoryConfig, segment_writer::SegmentWriter, LogEntry, Segment};
+
+fn read_segment(writer: &SegmentWriter, id: u32) -> Vec<LogEntry> {
+    Segment {
+        id,
+        path: writer.segment_path(id),
+    }
+    .open()
+    .unwrap()
+    .collect::<eyre::Result<_>>()
+    .unwrap()
+}
+
+#[test]
+fn test_segment_writer_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = RepositoryConfig::new("00".repeat(32));
+
+    let mut writer = SegmentWriter::new(dir.path(), &config, 0);
+    assert_eq!(writer.put(&[1; 32], b"hello").unwrap(), (0, 8));
+    assert_eq!(writer.delete(&[2; 32]).unwrap(), (0, 8 + 41 + 5));
+    assert_eq!(writer.commit().unwrap(), 1);
+
+    let entries = read_segment(&writer, 0);
+    assert!(
+        matches!(&entries[..], [LogEntry::Put { key, data }, LogEntry::Delete { key: deleted }]
+            if key == &[1; 32] && data == b"hello" && deleted == &[2; 32])
+    );
+
+    assert!(matches!(&read_segment(&writer, 1)[..], [LogEntry::Commit]));
+
+    // the CRC covers everything after itself
+    let raw = std::fs::read(writer.segment_path(1)).unwrap();
+    assert_eq!(&raw[8..12], crc32fast::hash(&raw[12..]).to_le_bytes());
+}
+
+#[test]
+fn test_segment_writer_rotation() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = RepositoryConfig::new("00".repeat(32));
+    config.segments_per_dir = 2;
+    config.max_segment_size = 200;
+
+    let mut writer = SegmentWriter::new(dir.path(), &config, 0);
+    for i in 0..5 {
+        writer.put(&[i; 32], &[i; 60]).unwrap();
+    }
+    assert_eq!(writer.commit().unwrap(), 3);
+
+    assert_eq!(read_segment(&writer, 0).len(), 2);
+    assert_eq!(read_segment(&writer, 1).len(), 2);
+    assert_eq!(read_segment(&writer, 2).len(), 1);
+
+    assert!(dir.path().join("data/0/1").exists());
+    assert!(dir.path().join("data/1/2").exists());
+    assert!(dir.path().join("data/1/3").exists());
+}
@@ -0,0 +1,199 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+
+use crate::{
+    key::{Key, KeyType},
+    tam,
+    tam::TamContext,
+    Archive, Manifest,
+};
+
+const SALT: [u8; 64] = [7; 64];
+
+/// Encode a minimal manifest the way borg does, optionally with a TAM whose
+/// HMAC is left zeroed
+fn manifest_msgpack(timestamp: &str, with_tam: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    rmp::encode::write_map_len(&mut buf, if with_tam { 6 } else { 5 }).unwrap();
+    rmp::encode::write_str(&mut buf, "version").unwrap();
+    rmp::encode::write_uint(&mut buf, 1).unwrap();
+    rmp::encode::write_str(&mut buf, "timestamp").unwrap();
+    rmp::encode::write_str(&mut buf, timestamp).unwrap();
+    rmp::encode::write_str(&mut buf, "item_keys").unwrap();
+    rmp::encode::write_array_len(&mut buf, 1).unwrap();
+    rmp::encode::write_str(&mut buf, "path").unwrap();
+    rmp::encode::write_str(&mut buf, "config").unwrap();
+    rmp::encode::write_map_len(&mut buf, 0).unwrap();
+    rmp::encode::write_str(&mut buf, "archives").unwrap();
+    rmp::encode::write_map_len(&mut buf, 0).unwrap();
+
+    if with_tam {
+        rmp::encode::write_str(&mut buf, "tam").unwrap();
+        rmp::encode::write_map_len(&mut buf, 3).unwrap();
+        rmp::encode::write_str(&mut buf, "type").unwrap();
+        rmp::encode::write_str(&mut buf, "HKDF_HMAC_SHA512").unwrap();
+        rmp::encode::write_str(&mut buf, "hmac").unwrap();
+        rmp::encode::write_bin(&mut buf, &[0; 64]).unwrap();
+        rmp::encode::write_str(&mut buf, "salt").unwrap();
+        rmp::encode::write_bin(&mut buf, &SALT).unwrap();
+    }
+
+    buf
+}
+
+/// Encode a minimal archive the way borg does, optionally with a TAM whose
+/// HMAC is left zeroed
+fn archive_msgpack(comment: &str, with_tam: bool) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    rmp::encode::write_map_len(&mut buf, if with_tam { 10 } else { 9 }).unwrap();
+    rmp::encode::write_str(&mut buf, "version").unwrap();
+    rmp::encode::write_uint(&mut buf, 1).unwrap();
+    rmp::encode::write_str(&mut buf, "name").unwrap();
+    rmp::encode::write_str(&mut buf, "archive").unwrap();
+    rmp::encode::write_str(&mut buf, "items").unwrap();
+    rmp::encode::write_array_len(&mut buf, 1).unwrap();
+    rmp::encode::write_bin(&mut buf, &[1; 32]).unwrap();
+    rmp::encode::write_str(&mut buf, "cmdline").unwrap();
+    rmp::encode::write_array_len(&mut buf, 1).unwrap();
+    rmp::encode::write_str(&mut buf, "borg").unwrap();
+    for field in ["hostname", "username"] {
+        rmp::encode::write_str(&mut buf, field).unwrap();
+        rmp::encode::write_str(&mut buf, "test").unwrap();
+    }
+    for field in ["time", "time_end"] {
+        rmp::encode::write_str(&mut buf, field).unwrap();
+        rmp::encode::write_str(&mut buf, "2023-01-01T00:00:00.000000").unwrap();
+    }
+    rmp::encode::write_str(&mut buf, "comment").unwrap();
+    rmp::encode::write_str(&mut buf, comment).unwrap();
+
+    if with_tam {
+        rmp::encode::write_str(&mut buf, "tam").unwrap();
+        rmp::encode::write_map_len(&mut buf, 3).unwrap();
+        rmp::encode::write_str(&mut buf, "type").unwrap();
+        rmp::encode::write_str(&mut buf, "HKDF_HMAC_SHA512").unwrap();
+        rmp::encode::write_str(&mut buf, "hmac").unwrap();
+        rmp::encode::write_bin(&mut buf, &[0; 64]).unwrap();
+        rmp::encode::write_str(&mut buf, "salt").unwrap();
+        rmp::encode::write_bin(&mut buf, &SALT).unwrap();
+    }
+
+    buf
+}
+
+/// Fill in the HMAC of a manifest produced by `manifest_msgpack`
+fn authenticate(key: &Key, data: Vec<u8>) -> Vec<u8> {
+    authenticate_as(key, data, b"manifest")
+}
+
+/// Fill in the zeroed HMAC of an object for the given TAM context
+fn authenticate_as(key: &Key, mut data: Vec<u8>, context: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(&key.tam_key(&SALT, context).unwrap()).unwrap();
+    mac.update(&data);
+    let hmac = mac.finalize().into_bytes();
+
+    let offset = data.windows(64).position(|w| w == [0; 64]).unwrap();
+    data[offset..offset + 64].copy_from_slice(&hmac);
+
+    data
+}
+
+/// A key with secret material, whose repositories require TAMs
+fn repokey() -> Key {
+    Key::generate(KeyType::Repokey, &[0; 32]).unwrap()
+}
+
+#[test]
+fn test_manifest_tam_valid() {
+    let key = Key::plaintext();
+    let data = authenticate(&key, manifest_msgpack("2023-01-01T00:00:00.000000", true));
+
+    Manifest::decode(&key, &data, false).unwrap();
+}
+
+#[test]
+fn test_manifest_tam_tampered() {
+    let key = Key::plaintext();
+    let mut tampered = authenticate(&key, manifest_msgpack("2023-01-01T00:00:00.000000", true));
+
+    let offset = tampered.windows(4).position(|w| w == b"2023").unwrap();
+    tampered[offset..offset + 4].copy_from_slice(b"2099");
+
+    assert!(Manifest::decode(&key, &tampered, false).is_err());
+}
+
+#[test]
+fn test_manifest_tam_missing() {
+    let key = Key::plaintext();
+    let data = manifest_msgpack("2023-01-01T00:00:00.000000", false);
+
+    let manifest = Manifest::decode(&key, &data, false).unwrap();
+    assert!(manifest.tam.is_none());
+
+    assert!(tam::verify(&key, &data, None, TamContext::Manifest, true).is_err());
+}
+
+#[test]
+fn test_tam_context_is_bound() {
+    let key = Key::plaintext();
+    let data = authenticate(&key, manifest_msgpack("2023-01-01T00:00:00.000000", true));
+    let manifest = Manifest::decode(&key, &data, false).unwrap();
+
+    assert!(tam::verify(
+        &key,
+        &data,
+        manifest.tam.as_ref(),
+        TamContext::Archive,
+        false
+    )
+    .is_err());
+}
+
+#[test]
+fn test_archive_tam_valid() {
+    let key = repokey();
+    let data = authenticate_as(&key, archive_msgpack("a comment", true), b"archive");
+
+    let archive = Archive::decode(&key, &data, false).unwrap();
+    assert_eq!(archive.comment, "a comment");
+    assert!(archive.tam.is_some());
+}
+
+#[test]
+fn test_archive_tam_tampered() {
+    let key = repokey();
+    let mut tampered = authenticate_as(&key, archive_msgpack("a comment", true), b"archive");
+
+    let offset = tampered.windows(9).position(|w| w == b"a comment").unwrap();
+    tampered[offset..offset + 9].copy_from_slice(b"A COMMENT");
+
+    assert!(Archive::decode(&key, &tampered, false).is_err());
+    assert!(Archive::decode(&key, &tampered, true).is_err());
+}
+
+#[test]
+fn test_archive_tam_missing() {
+    let key = repokey();
+    assert!(key.tam_required());
+    let data = archive_msgpack("a comment", false);
+
+    let error = Archive::decode(&key, &data, false).unwrap_err();
+    assert!(format!("{error:#}").contains("archive is not authenticated"));
+
+    // unless unauthenticated archives are explicitly allowed
+    let archive = Archive::decode(&key, &data, true).unwrap();
+    assert!(archive.tam.is_none());
+}
+
+#[test]
+fn test_archive_rejects_manifest_tam() {
+    let key = repokey();
+
+    // a valid TAM, but made for a manifest
+    let data = authenticate_as(&key, archive_msgpack("a comment", true), b"manifest");
+
+    assert!(Archive::decode(&key, &data, false).is_err());
+    assert!(Archive::decode(&key, &data, true).is_err());
+}
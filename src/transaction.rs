@@ -0,0 +1,676 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use eyre::{bail, eyre, Context, Result};
+
+use crate::{
+    hashindex::NsIndex,
+    hints::{Hints, HintsFile},
+    integrity::{hashindex_digests, IntegrityFile, IntegrityHasher},
+    segment_writer::{sync_dir, SegmentWriter},
+    LogEntry, Repository, Segment, ENTRY_HEADER_SIZE, ENTRY_KEY_HEADER_SIZE, TAG_COMMIT, TAG_PUT,
+};
+
+/// The index and hints describing a repository as of its last commit
+#[derive(Debug, Clone, Default)]
+pub struct RepositoryState {
+    /// The ID of the last committed transaction, `None` for a repository
+    /// nothing was ever committed to
+    pub transaction_id: Option<u32>,
+    pub index: NsIndex,
+    pub hints: Hints,
+}
+
+impl RepositoryState {
+    /// Load the index and hints of the last transaction, rebuilding them from
+    /// the segments if they are missing, stale or corrupted
+    pub fn load(repository: &Repository) -> Result<Self> {
+        let Some(transaction_id) = repository.last_committed_segment()? else {
+            return Ok(Self::default());
+        };
+
+        match Self::read(repository, transaction_id) {
+            Ok(Some(state)) => return Ok(state),
+            Ok(None) => {}
+            Err(e) => eprintln!("warning: rebuilding index: {e:#}"),
+        }
+
+        Self::replay(repository, transaction_id)
+    }
+
+    /// The stored data of the object with the given ID as of the last
+    /// commit, `None` if there is no such object
+    pub fn get(&self, repository: &Repository, id: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(id) {
+            Some((segment, offset)) => repository.read_put(segment, offset, id).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Read `index.N` and `hints.N`, checking them against `integrity.N` if it
+    /// exists. Returns `None` if either file is missing. Hints of version 1
+    /// are upgraded the way borg does it.
+    pub fn read(repository: &Repository, transaction_id: u32) -> Result<Option<Self>> {
+        let path = &repository.path;
+        let index_name = format!("index.{transaction_id}");
+        let hints_name = format!("hints.{transaction_id}");
+
+        let (Ok(index_data), Ok(hints_data)) = (
+            std::fs::read(path.join(&index_name)),
+            std::fs::read(path.join(&hints_name)),
+        ) else {
+            return Ok(None);
+        };
+
+        if let Ok(integrity) = std::fs::read(path.join(format!("integrity.{transaction_id}"))) {
+            let integrity = IntegrityFile::decode(&integrity)?;
+
+            integrity
+                .index()?
+                .verify(&hashindex_digests(&index_name, &index_data), &index_name)?;
+            integrity
+                .hints()?
+                .verify(&hints_digests(&hints_name, &hints_data), &hints_name)?;
+        }
+
+        let index = NsIndex::read(&mut index_data.as_slice())
+            .wrap_err_with(|| format!("read {index_name}"))?;
+
+        let hints =
+            HintsFile::decode(&hints_data).wrap_err_with(|| format!("read {hints_name}"))?;
+
+        let mut state = Self {
+            transaction_id: Some(transaction_id),
+            index,
+            hints: Hints::default(),
+        };
+
+        match hints {
+            HintsFile::V1 { segments, compact } => {
+                state.hints.segments = segments;
+
+                for segment in compact {
+                    state.rebuild_sparse(repository, segment)?;
+                }
+            }
+            HintsFile::V2(hints) => state.hints = hints,
+        }
+
+        Ok(Some(state))
+    }
+
+    /// Count how many bytes compacting `segment` would free, as far as the
+    /// index tells. Version 1 hints only listed the segments.
+    fn rebuild_sparse(&mut self, repository: &Repository, segment: u32) -> Result<()> {
+        let path = repository.segment_path(segment);
+
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            self.hints.compact.remove(&segment);
+            self.hints.segments.remove(&segment);
+            return Ok(());
+        };
+
+        if self.hints.segments.get(&segment) == Some(&0) {
+            self.hints.compact.insert(segment, metadata.len());
+            return Ok(());
+        }
+
+        let mut freeable = 0;
+        for entry in (Segment { id: segment, path }).open()?.with_offsets() {
+            match entry? {
+                (offset, LogEntry::Put { key, data }) => {
+                    if self.index.get(&key) != Some((segment, offset)) {
+                        freeable += ENTRY_KEY_HEADER_SIZE as u64 + data.len() as u64;
+                    }
+                }
+                (_, LogEntry::Delete { .. }) => freeable += ENTRY_KEY_HEADER_SIZE as u64,
+                (_, LogEntry::Commit) => {}
+            }
+        }
+
+        self.hints.compact.insert(segment, freeable);
+
+        Ok(())
+    }
+
+    /// Rebuild the index and hints by replaying every committed segment
+    pub fn replay(repository: &Repository, transaction_id: u32) -> Result<Self> {
+        let mut state = Self {
+            transaction_id: Some(transaction_id),
+            ..Self::default()
+        };
+
+        for segment in repository.segments()? {
+            if segment.id > transaction_id {
+                break;
+            }
+
+            state.hints.segments.entry(segment.id).or_insert(0);
+
+            for entry in segment.open()?.with_offsets() {
+                let (offset, entry) = entry?;
+
+                match entry {
+                    LogEntry::Put { key, data } => {
+                        if let Some((old_segment, old_offset)) = state.index.get(&key) {
+                            let size = repository.entry_size(old_segment, old_offset)?;
+                            state.forget(old_segment, size);
+                        }
+
+                        state.index.insert(&key, segment.id, offset);
+                        *state.hints.segments.entry(segment.id).or_insert(0) += 1;
+                        state.hints.storage_quota_use +=
+                            ENTRY_KEY_HEADER_SIZE as u64 + data.len() as u64;
+                    }
+                    LogEntry::Delete { key } => {
+                        if let Some((old_segment, old_offset)) = state.index.remove(&key) {
+                            let size = repository.entry_size(old_segment, old_offset)?;
+                            state.forget(old_segment, size);
+                            state
+                                .hints
+                                .shadow_index
+                                .entry(key)
+                                .or_default()
+                                .push(old_segment);
+                        }
+
+                        *state.hints.compact.entry(segment.id).or_insert(0) +=
+                            ENTRY_KEY_HEADER_SIZE as u64;
+                    }
+                    LogEntry::Commit => {
+                        *state.hints.compact.entry(segment.id).or_insert(0) +=
+                            ENTRY_HEADER_SIZE as u64;
+                    }
+                }
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Account for an entry of `size` bytes in `segment` that is no longer
+    /// live
+    fn forget(&mut self, segment: u32, size: u32) {
+        if let Some(count) = self.hints.segments.get_mut(&segment) {
+            *count = count.saturating_sub(1);
+        }
+
+        *self.hints.compact.entry(segment).or_insert(0) += size as u64;
+    }
+
+    /// Write `index.N`, `hints.N` and `integrity.N` for the current
+    /// transaction and remove those of older transactions. Like borg, the
+    /// integrity file is renamed into place first, so it always describes
+    /// files at least as new as the ones on disk.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let transaction_id = self
+            .transaction_id
+            .ok_or_else(|| eyre!("cannot write the index of an empty repository"))?;
+
+        let hints_name = format!("hints.{transaction_id}");
+        let index_name = format!("index.{transaction_id}");
+        let integrity_name = format!("integrity.{transaction_id}");
+
+        let hints_data = self.hints.encode()?;
+
+        let mut index_data = Vec::new();
+        self.index.inner().write(&mut index_data)?;
+
+        let integrity = IntegrityFile::new(
+            &hints_digests(&hints_name, &hints_data),
+            &hashindex_digests(&index_name, &index_data),
+        )?;
+        let integrity_data =
+            rmp_serde::to_vec_named(&integrity).wrap_err("encode integrity file")?;
+
+        write_tmp(path, &hints_name, &hints_data)?;
+        write_tmp(path, &index_name, &index_data)?;
+        write_tmp(path, &integrity_name, &integrity_data)?;
+
+        rename_tmp(path, &integrity_name)?;
+        sync_dir(path)?;
+        rename_tmp(path, &hints_name)?;
+        rename_tmp(path, &index_name)?;
+        sync_dir(path)?;
+
+        let current = format!(".{transaction_id}");
+
+        for dir_entry in std::fs::read_dir(path)? {
+            let name = dir_entry?.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+
+            if ["index.", "hints.", "integrity."]
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+                && !name.ends_with(&current)
+            {
+                std::fs::remove_file(path.join(name))
+                    .wrap_err_with(|| format!("remove old {name}"))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn hints_digests(name: &str, data: &[u8]) -> crate::integrity::IntegrityData {
+    let mut hasher = IntegrityHasher::new(name);
+    hasher.update(data);
+
+    hasher.finish()
+}
+
+fn write_tmp(path: &Path, name: &str, data: &[u8]) -> Result<()> {
+    let tmp_path = path.join(format!("{name}.tmp"));
+
+    let mut file =
+        File::create(&tmp_path).wrap_err_with(|| format!("create {}", tmp_path.display()))?;
+    file.write_all(data)
+        .wrap_err_with(|| format!("write {}", tmp_path.display()))?;
+    file.sync_all()
+        .wrap_err_with(|| format!("sync {}", tmp_path.display()))
+}
+
+fn rename_tmp(path: &Path, name: &str) -> Result<()> {
+    std::fs::rename(path.join(format!("{name}.tmp")), path.join(name))
+        .wrap_err_with(|| format!("rename {name}.tmp"))
+}
+
+/// A set of changes to a repository that become visible all at once when
+/// committed. Mirrors the bookkeeping borg does for every PUT and DELETE, so
+/// the index and hints written on commit are the ones borg would write.
+#[derive(Debug)]
+pub struct Transaction<'a> {
+    repository: &'a Repository,
+    writer: SegmentWriter,
+    state: RepositoryState,
+}
+
+impl<'a> Transaction<'a> {
+    /// Start a transaction on a repository opened with an exclusive lock.
+    /// Segments left behind by an interrupted transaction are removed first,
+    /// the same way borg cleans up.
+    pub fn begin(repository: &'a Repository) -> Result<Self> {
+        let state = RepositoryState::load(repository)?;
+        let next_segment = state.transaction_id.map_or(0, |id| id + 1);
+
+        for segment in repository.segments()? {
+            if segment.id >= next_segment {
+                eprintln!("warning: removing uncommitted segment {}", segment.id);
+                std::fs::remove_file(&segment.path)
+                    .wrap_err_with(|| format!("remove {}", segment.path.display()))?;
+            }
+        }
+
+        Ok(Self {
+            repository,
+            writer: SegmentWriter::new(&repository.path, &repository.config, next_segment),
+            state,
+        })
+    }
+
+    pub fn index(&self) -> &NsIndex {
+        &self.state.index
+    }
+
+    pub fn contains(&self, id: &[u8; 32]) -> bool {
+        self.state.index.get(id).is_some()
+    }
+
+    /// Read the current contents of an object, including objects stored by
+    /// this transaction
+    pub fn get(&mut self, id: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+        let Some((segment, offset)) = self.state.index.get(id) else {
+            return Ok(None);
+        };
+
+        if segment == self.writer.segment() {
+            self.writer.flush()?;
+        }
+
+        self.repository.read_put(segment, offset, id).map(Some)
+    }
+
+    /// Store an object, replacing any previous object with the same ID
+    pub fn put(&mut self, id: &[u8; 32], data: &[u8]) -> Result<()> {
+        if let Some((segment, offset)) = self.state.index.get(id) {
+            // the old PUT is shadowed by the new one, which ends up in the
+            // index, so the shadow index does not need to know about it
+            self.delete_entry(id, segment, offset)?;
+        }
+
+        let (segment, offset) = self.writer.put(id, data)?;
+
+        self.state.hints.storage_quota_use += ENTRY_KEY_HEADER_SIZE as u64 + data.len() as u64;
+        *self.state.hints.segments.entry(segment).or_insert(0) += 1;
+        self.state.index.insert(id, segment, offset);
+
+        Ok(())
+    }
+
+    /// Delete an object, failing if it does not exist
+    pub fn delete(&mut self, id: &[u8; 32]) -> Result<()> {
+        let (segment, offset) = self
+            .state
+            .index
+            .remove(id)
+            .ok_or_else(|| eyre!("object {} not found", crate::key::hex_lower(id)))?;
+
+        self.state
+            .hints
+            .shadow_index
+            .entry(*id)
+            .or_default()
+            .push(segment);
+
+        self.delete_entry(id, segment, offset)
+    }
+
+    fn delete_entry(&mut self, id: &[u8; 32], segment: u32, offset: u32) -> Result<()> {
+        let size = self.entry_size(segment, offset)?;
+        self.state.forget(segment, size);
+
+        let (segment, _) = self.writer.delete(id)?;
+        self.state.hints.segments.entry(segment).or_insert(0);
+        *self.state.hints.compact.entry(segment).or_insert(0) += ENTRY_KEY_HEADER_SIZE as u64;
+
+        Ok(())
+    }
+
+    /// The size of an entry, which may be in a segment this transaction is
+    /// still writing
+    fn entry_size(&mut self, segment: u32, offset: u32) -> Result<u32> {
+        if segment == self.writer.segment() {
+            self.writer.flush()?;
+        }
+
+        self.repository.entry_size(segment, offset)
+    }
+
+    /// Write the COMMIT entry, then the index and hints of the new
+    /// transaction. Returns the transaction ID.
+    pub fn commit(mut self) -> Result<u32> {
+        let transaction_id = self.writer.commit()?;
+
+        self.state.transaction_id = Some(transaction_id);
+        self.state.hints.segments.entry(transaction_id).or_insert(0);
+        *self.state.hints.compact.entry(transaction_id).or_insert(0) += ENTRY_HEADER_SIZE as u64;
+
+        self.state.write(&self.repository.path)?;
+
+        Ok(transaction_id)
+    }
+
+    /// Commit, then compact the segments in which more than `threshold` of
+    /// the bytes are freeable according to the hints, the same way borg
+    /// does. Live PUTs are copied to new segments and DELETEs are kept as
+    /// long as a PUT they shadow may still exist. Once a new segment is full
+    /// it is committed and the segments emptied so far are removed, so only
+    /// a segment's worth of extra space is ever needed. Returns the number
+    /// of bytes freed from the storage quota.
+    pub fn commit_compacted(mut self, threshold: f64) -> Result<u64> {
+        if self.repository.config.append_only {
+            bail!("{} is in append-only mode", self.repository.path.display());
+        }
+
+        // segments up to the one of the index on disk are known to be stable
+        let index_transaction_id = self.state.transaction_id;
+        let quota_use_before = self.state.hints.storage_quota_use;
+
+        let transaction_id = self.writer.commit()?;
+        self.state.hints.segments.entry(transaction_id).or_insert(0);
+        *self.state.hints.compact.entry(transaction_id).or_insert(0) += ENTRY_HEADER_SIZE as u64;
+
+        let mut unused = Vec::new();
+
+        let candidates: Vec<_> = self
+            .state
+            .hints
+            .compact
+            .iter()
+            .map(|(&segment, &freeable)| (segment, freeable))
+            .collect();
+
+        for (segment, freeable) in candidates {
+            let path = self.repository.segment_path(segment);
+
+            let Ok(metadata) = std::fs::metadata(&path) else {
+                eprintln!("warning: segment {segment} not found, but listed in compaction data");
+                self.state.hints.compact.remove(&segment);
+                continue;
+            };
+
+            if freeable as f64 / metadata.len() as f64 <= threshold {
+                continue;
+            }
+
+            self.state.hints.segments.entry(segment).or_insert(0);
+
+            let entries = Segment { id: segment, path }
+                .open()
+                .wrap_err_with(|| format!("open segment {segment}"))?;
+
+            for entry in entries.with_offsets() {
+                let (offset, entry) = entry.wrap_err_with(|| format!("read segment {segment}"))?;
+
+                match entry {
+                    LogEntry::Commit => {}
+                    LogEntry::Put { key, data } => {
+                        if self.state.index.get(&key) == Some((segment, offset)) {
+                            if self.writer.is_full() {
+                                self.complete_xfer(&mut unused, true)?;
+                            }
+
+                            let (new_segment, new_offset) = self.writer.put(&key, &data)?;
+                            self.state.index.insert(&key, new_segment, new_offset);
+
+                            let segments = &mut self.state.hints.segments;
+                            *segments.entry(new_segment).or_insert(0) += 1;
+                            let count = segments.entry(segment).or_insert(0);
+                            *count = count.checked_sub(1).ok_or_else(|| {
+                                eyre!("corrupted segment reference count for segment {segment}, the index or hints are corrupted")
+                            })?;
+                        } else {
+                            // a shadowed PUT is gone with this segment, the
+                            // empty list is still needed for the DELETEs below
+                            if let Some(shadowed) = self.state.hints.shadow_index.get_mut(&key) {
+                                shadowed.retain(|&s| s != segment);
+                            }
+
+                            // hints without a quota start it at zero, where
+                            // borg's counter would simply go negative
+                            self.state.hints.storage_quota_use =
+                                self.state.hints.storage_quota_use.saturating_sub(
+                                    ENTRY_KEY_HEADER_SIZE as u64 + data.len() as u64,
+                                );
+                        }
+                    }
+                    LogEntry::Delete { key } => {
+                        if self.state.index.get(&key).is_some() {
+                            continue;
+                        }
+
+                        let shadowed_put_exists = self
+                            .state
+                            .hints
+                            .shadow_index
+                            .get(&key)
+                            .is_none_or(|shadowed| {
+                                shadowed.iter().any(|&shadowed| shadowed < segment)
+                            });
+
+                        // dropping a DELETE that is newer than the index on
+                        // disk could bring an object back after a crash
+                        let delete_is_not_stable =
+                            index_transaction_id.is_none_or(|id| segment > id);
+
+                        if shadowed_put_exists || delete_is_not_stable {
+                            if self.writer.is_full() {
+                                self.complete_xfer(&mut unused, true)?;
+                            }
+
+                            let (new_segment, _) = self.writer.delete(&key)?;
+                            self.state.hints.segments.entry(new_segment).or_insert(0);
+                            *self.state.hints.compact.entry(new_segment).or_insert(0) +=
+                                ENTRY_KEY_HEADER_SIZE as u64;
+                        } else if self
+                            .state
+                            .hints
+                            .shadow_index
+                            .get(&key)
+                            .is_some_and(Vec::is_empty)
+                        {
+                            self.state.hints.shadow_index.remove(&key);
+                        }
+                    }
+                }
+            }
+
+            if self.state.hints.segments[&segment] != 0 {
+                bail!("corrupted segment reference count for segment {segment}, the index or hints are corrupted");
+            }
+
+            unused.push(segment);
+        }
+
+        let transaction_id = self.complete_xfer(&mut unused, false)?;
+        self.clear_empty_dirs()?;
+
+        self.state.transaction_id = Some(transaction_id);
+        self.state.write(&self.repository.path)?;
+
+        Ok(quota_use_before.saturating_sub(self.state.hints.storage_quota_use))
+    }
+
+    /// Commit the segments compaction wrote so far, then remove the segments
+    /// it emptied. Returns the ID of the segment with the COMMIT.
+    fn complete_xfer(&mut self, unused: &mut Vec<u32>, intermediate: bool) -> Result<u32> {
+        let transaction_id = if intermediate {
+            self.writer.commit_intermediate()?
+        } else {
+            self.writer.commit()?
+        };
+
+        self.state.hints.segments.entry(transaction_id).or_insert(0);
+        *self.state.hints.compact.entry(transaction_id).or_insert(0) += ENTRY_HEADER_SIZE as u64;
+
+        for segment in unused.drain(..) {
+            if self.state.hints.segments.remove(&segment) != Some(0) {
+                bail!("corrupted segment reference count for segment {segment}, the index or hints are corrupted");
+            }
+
+            self.state.hints.compact.remove(&segment);
+
+            let path = self.repository.segment_path(segment);
+            std::fs::remove_file(&path)
+                .wrap_err_with(|| format!("remove segment {}", path.display()))?;
+        }
+
+        Ok(transaction_id)
+    }
+
+    /// Remove the segment directories that compaction left empty
+    fn clear_empty_dirs(&self) -> Result<()> {
+        for dir_entry in std::fs::read_dir(self.repository.path.join("data"))? {
+            let path = dir_entry?.path();
+
+            if path.is_dir() && std::fs::read_dir(&path)?.next().is_none() {
+                std::fs::remove_dir(&path)
+                    .wrap_err_with(|| format!("remove {}", path.display()))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Repository {
+    /// The ID of the newest segment that ends with a valid COMMIT entry, which
+    /// is the ID of the last committed transaction
+    pub fn last_committed_segment(&self) -> Result<Option<u32>> {
+        for segment in self.segments()?.iter().rev() {
+            if is_committed(&segment.path)? {
+                return Ok(Some(segment.id));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Path of the segment file with the given ID
+    pub fn segment_path(&self, segment: u32) -> std::path::PathBuf {
+        self.path
+            .join("data")
+            .join((segment / self.config.segments_per_dir).to_string())
+            .join(segment.to_string())
+    }
+
+    /// Read the data of the PUT entry for `id` at `offset` in `segment`,
+    /// checking its CRC
+    pub fn read_put(&self, segment: u32, offset: u32, id: &[u8; 32]) -> Result<Vec<u8>> {
+        let path = self.segment_path(segment);
+        let mut file = File::open(&path).wrap_err_with(|| format!("open {}", path.display()))?;
+
+        file.seek(SeekFrom::Start(offset as u64))?;
+
+        let crc = file.read_u32::<LittleEndian>()?;
+        let size = file.read_u32::<LittleEndian>()?;
+
+        if size < ENTRY_KEY_HEADER_SIZE {
+            bail!("invalid entry size {size} at {offset} in segment {segment}");
+        }
+
+        let mut entry = vec![0; size as usize - 4];
+        file.read_exact(&mut entry[4..])
+            .wrap_err_with(|| format!("read entry at {offset} in segment {segment}"))?;
+        entry[..4].copy_from_slice(&size.to_le_bytes());
+
+        if crc32fast::hash(&entry) != crc {
+            bail!("CRC mismatch for entry at {offset} in segment {segment}");
+        }
+
+        if entry[4] != TAG_PUT || entry[5..37] != id[..] {
+            bail!("entry at {offset} in segment {segment} is not a PUT of the expected object");
+        }
+
+        entry.drain(..ENTRY_KEY_HEADER_SIZE as usize - 4);
+
+        Ok(entry)
+    }
+
+    /// Read the size field of the entry at `offset` in `segment`
+    pub fn entry_size(&self, segment: u32, offset: u32) -> Result<u32> {
+        let path = self.segment_path(segment);
+        let mut file = File::open(&path).wrap_err_with(|| format!("open {}", path.display()))?;
+
+        file.seek(SeekFrom::Start(offset as u64 + 4))?;
+
+        file.read_u32::<LittleEndian>()
+            .wrap_err_with(|| format!("read entry at {offset} in segment {segment}"))
+    }
+}
+
+fn is_committed(path: &Path) -> Result<bool> {
+    let mut file = File::open(path).wrap_err_with(|| format!("open {}", path.display()))?;
+
+    let length = file.seek(SeekFrom::End(0))?;
+    if length < 8 + ENTRY_HEADER_SIZE as u64 {
+        return Ok(false);
+    }
+
+    file.seek(SeekFrom::End(-(ENTRY_HEADER_SIZE as i64)))?;
+
+    let mut entry = [0; ENTRY_HEADER_SIZE as usize];
+    file.read_exact(&mut entry)?;
+
+    let mut expected = ENTRY_HEADER_SIZE.to_le_bytes().to_vec();
+    expected.push(TAG_COMMIT);
+
+    Ok(entry[4..] == expected[..] && entry[..4] == crc32fast::hash(&entry[4..]).to_le_bytes())
+}